@@ -1,4 +1,10 @@
+use akula::{
+    rpc::{serve_http, serve_ws, RpcHandler},
+    RemoteKvClient,
+};
+use std::net::SocketAddr;
 use structopt::StructOpt;
+use tracing::*;
 use tracing_subscriber::{prelude::*, EnvFilter};
 
 #[derive(StructOpt)]
@@ -6,8 +12,17 @@ use tracing_subscriber::{prelude::*, EnvFilter};
 pub struct Opt {
     #[structopt(long, env)]
     pub tokio_console: bool,
+    /// KV GRPC service URL as 'http://host:port'
     #[structopt(long, env)]
     pub kv_address: String,
+
+    /// Serve JSON-RPC over HTTP on this address
+    #[structopt(long = "http.addr", default_value = "127.0.0.1:8545")]
+    pub http_addr: SocketAddr,
+
+    /// Serve JSON-RPC over WebSocket on this address
+    #[structopt(long = "ws.addr")]
+    pub ws_addr: Option<SocketAddr>,
 }
 
 #[tokio::main]
@@ -37,5 +52,16 @@ async fn main() -> anyhow::Result<()> {
         registry.with(filter).init();
     }
 
+    let client = RemoteKvClient::connect(opt.kv_address).await?;
+    let handler = RpcHandler::new(client);
+
+    serve_http(opt.http_addr, handler.clone()).await?;
+    if let Some(addr) = opt.ws_addr {
+        serve_ws(addr, handler).await?;
+    }
+
+    tokio::signal::ctrl_c().await?;
+    info!("Shutting down");
+
     Ok(())
 }
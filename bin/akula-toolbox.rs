@@ -1,5 +1,22 @@
-use akula::{mdbx_table_sizes, stagedsync};
-use std::path::PathBuf;
+use akula::{
+    downloader::{
+        sentry_address::SentryAddress, sentry_client_impl::SentryClientImpl,
+        sentry_client_reactor::SentryClientReactor,
+    },
+    kv::{tables, traits::MutableKV},
+    mdbx_table_sizes,
+    stagedsync::{
+        self,
+        stage::{ExecOutput, Stage, StageInput, UnwindInput},
+    },
+    stages, Cursor, MutableTransaction, StageId, Transaction,
+};
+use anyhow::{anyhow, bail, Context};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
@@ -20,18 +37,257 @@ pub enum Opt {
         #[structopt(parse(from_os_str))]
         chaindata: PathBuf,
     },
+
+    /// Run, unwind or inspect individual sync stages
+    Stage(StageCommand),
+
+    /// Print stages of the node's sync pipeline in execution order
+    Pipeline {
+        /// Sentry GRPC service URL as 'http://host:port', not connected to
+        #[structopt(long = "sentry.api.addr", default_value = "http://localhost:8000")]
+        sentry_api_addr: SentryAddress,
+    },
 }
 
-async fn blockhashes(chaindata: PathBuf) -> anyhow::Result<()> {
-    let env = akula::MdbxEnvironment::<mdbx::NoWriteMap>::open_ro(
+#[derive(StructOpt)]
+pub enum StageCommand {
+    /// Execute a stage from its current progress
+    Run {
+        #[structopt(parse(from_os_str))]
+        chaindata: PathBuf,
+        /// Stage ID
+        id: String,
+        /// Block to sync to, defaults to progress of the stage it syncs after
+        #[structopt(long)]
+        to: Option<u64>,
+    },
+
+    /// Unwind a stage
+    Unwind {
+        #[structopt(parse(from_os_str))]
+        chaindata: PathBuf,
+        /// Stage ID
+        id: String,
+        /// Block to unwind to
+        #[structopt(long)]
+        to: u64,
+    },
+
+    /// Print progress, pending unwind and prune progress of all stages
+    Progress {
+        #[structopt(parse(from_os_str))]
+        chaindata: PathBuf,
+    },
+}
+
+/// Stages which do not need network access, so can be run against the database alone.
+fn offline_stages<'db, RwTx: MutableTransaction<'db>>() -> Vec<Box<dyn Stage<'db, RwTx>>> {
+    vec![
+        Box::new(stages::BlockHashes),
+        Box::new(stages::SenderRecovery::default()),
+        // Value transfers only until an EVM is added, hence not in the node pipeline
+        Box::new(stages::Execution::default()),
+        Box::new(stages::HashState),
+        Box::new(stages::IntermediateHashes),
+        Box::new(stages::AccountHistoryIndex),
+        Box::new(stages::StorageHistoryIndex),
+        Box::new(stages::LogIndex),
+        Box::new(stages::CallTraces),
+        Box::new(stages::TxLookup),
+    ]
+}
+
+fn find_stage<'db, RwTx: MutableTransaction<'db>>(
+    id: &str,
+) -> anyhow::Result<Box<dyn Stage<'db, RwTx>>> {
+    let stages = offline_stages();
+    let ids = stages
+        .iter()
+        .map(|stage| stage.id().to_string())
+        .collect::<Vec<_>>();
+    match stages.into_iter().find(|stage| stage.id().0 == id) {
+        Some(stage) => Ok(stage),
+        None => bail!("unknown stage {}, available: {}", id, ids.join(", ")),
+    }
+}
+
+fn open_rw(chaindata: &Path) -> anyhow::Result<akula::MdbxEnvironment<mdbx::NoWriteMap>> {
+    akula::MdbxEnvironment::<mdbx::NoWriteMap>::open_rw(
         mdbx::Environment::new(),
-        &chaindata,
-        &akula::kv::tables::TABLE_MAP,
-    )?;
+        chaindata,
+        &tables::TABLE_MAP,
+    )
+}
+
+async fn stage_run(chaindata: PathBuf, id: String, to: Option<u64>) -> anyhow::Result<()> {
+    let env = open_rw(&chaindata)?;
+    let stage = find_stage(&id)?;
+    let stage_id = stage.id();
+
+    // Stage may not sync past the prerequisite which is furthest behind
+    let tx = env.begin_mutable().await?;
+    let mut previous_stage: Option<(StageId, u64)> = None;
+    for &prerequisite in stages::prerequisites(stage_id) {
+        let progress = prerequisite.get_progress(&tx).await?.unwrap_or(0);
+        if previous_stage.map_or(true, |(_, previous)| progress < previous) {
+            previous_stage = Some((prerequisite, progress));
+        }
+    }
+    drop(tx);
+    let (previous_id, previous_progress) = previous_stage
+        .ok_or_else(|| anyhow!("stage {} does not sync after any other stage", stage_id))?;
+    let to = to.unwrap_or(previous_progress);
+
+    let mut restarted = false;
+    loop {
+        let mut tx = env.begin_mutable().await?;
+        let stage_progress = stage_id.get_progress(&tx).await?;
+        match stage
+            .execute(
+                &mut tx,
+                StageInput {
+                    restarted,
+                    previous_stage: Some((previous_id, to)),
+                    stage_progress,
+                },
+            )
+            .await?
+        {
+            ExecOutput::Progress {
+                stage_progress,
+                done,
+                ..
+            } => {
+                stage_id.save_progress(&tx, stage_progress).await?;
+                tx.commit().await?;
+                println!("{} progress: {}", stage_id, stage_progress);
+
+                if done {
+                    return Ok(());
+                }
+                restarted = true;
+            }
+            ExecOutput::Unwind { unwind_to } => {
+                bail!("{} requested unwind to {}", stage_id, unwind_to);
+            }
+        }
+    }
+}
+
+async fn stage_unwind(chaindata: PathBuf, id: String, to: u64) -> anyhow::Result<()> {
+    let env = open_rw(&chaindata)?;
+    let stage = find_stage(&id)?;
+    let stage_id = stage.id();
+
+    let mut tx = env.begin_mutable().await?;
+    let stage_progress = stage_id.get_progress(&tx).await?.unwrap_or(0);
+    if stage_progress <= to {
+        println!(
+            "{} progress {} is already at or below {}",
+            stage_id, stage_progress, to
+        );
+        return Ok(());
+    }
+
+    stage
+        .unwind(
+            &mut tx,
+            UnwindInput {
+                stage_progress,
+                unwind_to: to,
+            },
+        )
+        .await?;
+    stage_id.save_progress(&tx, to).await?;
+    tx.commit().await?;
+    println!("{} unwound from {} to {}", stage_id, stage_progress, to);
+
+    Ok(())
+}
+
+async fn read_stage_table<'db, Tx: Transaction<'db>, T: akula::kv::Table>(
+    tx: &Tx,
+    table: &T,
+) -> anyhow::Result<BTreeMap<String, u64>> {
+    let mut out = BTreeMap::new();
+    let mut cursor = tx.cursor(table).await?;
+    let mut entry = cursor.first().await?;
+    while let Some((k, v)) = entry {
+        let id = String::from_utf8_lossy(&k).into_owned();
+        let mut block = [0; 8];
+        block.copy_from_slice(
+            v.get(..8)
+                .with_context(|| format!("failed to read block number of stage {}", id))?,
+        );
+        out.insert(id, u64::from_be_bytes(block));
+        entry = cursor.next().await?;
+    }
+
+    Ok(out)
+}
+
+async fn stage_progress(chaindata: PathBuf) -> anyhow::Result<()> {
+    let env = open_rw(&chaindata)?;
+    let tx = env.begin_mutable().await?;
+
+    let progress = read_stage_table(&tx, &tables::SyncStage).await?;
+    let unwind = read_stage_table(&tx, &tables::SyncStageUnwind).await?;
+    let prune = read_stage_table(&tx, &tables::SyncStagePrune).await?;
+
+    let mut ids = progress.keys().collect::<Vec<_>>();
+    ids.extend(unwind.keys().chain(prune.keys()));
+    ids.sort();
+    ids.dedup();
+
+    let show = |v: Option<&u64>| v.map_or_else(|| "-".to_string(), |v| v.to_string());
+    println!("Stage - progress / unwind / prune");
+    for id in ids {
+        println!(
+            "{} - {} / {} / {}",
+            id,
+            show(progress.get(id)),
+            show(unwind.get(id)),
+            show(prune.get(id))
+        );
+    }
+
+    Ok(())
+}
+
+async fn blockhashes(chaindata: PathBuf) -> anyhow::Result<()> {
+    let env = open_rw(&chaindata)?;
 
     let mut staged_sync = stagedsync::StagedSync::new();
     staged_sync.push(akula::stages::BlockHashes);
-    staged_sync.run(&env).await?;
+    staged_sync.set_exit_after_sync(true);
+    staged_sync.run(&env).await
+}
+
+async fn pipeline(sentry_api_addr: SentryAddress) -> anyhow::Result<()> {
+    let sentry = SentryClientReactor::new(Box::new(SentryClientImpl::new_lazy(sentry_api_addr)?));
+    let staged_sync =
+        akula::stages::pipeline::<akula::MdbxEnvironment<mdbx::NoWriteMap>>(Arc::new(sentry))
+            .build()?;
+
+    for (i, (id, description, prerequisites)) in staged_sync.pipeline().enumerate() {
+        let prerequisites = prerequisites
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>();
+        if prerequisites.is_empty() {
+            println!("{}. {} - {}", i + 1, id, description);
+        } else {
+            println!(
+                "{}. {} - {} (after {})",
+                i + 1,
+                id,
+                description,
+                prerequisites.join(", ")
+            );
+        }
+    }
+
+    Ok(())
 }
 
 async fn table_sizes(chaindata: PathBuf, csv: bool) -> anyhow::Result<()> {
@@ -74,6 +330,12 @@ async fn main() -> anyhow::Result<()> {
     match opt {
         Opt::DbStats { chaindata, csv } => table_sizes(chaindata, csv).await?,
         Opt::Blockhashes { chaindata } => blockhashes(chaindata).await?,
+        Opt::Stage(StageCommand::Run { chaindata, id, to }) => stage_run(chaindata, id, to).await?,
+        Opt::Stage(StageCommand::Unwind { chaindata, id, to }) => {
+            stage_unwind(chaindata, id, to).await?
+        }
+        Opt::Stage(StageCommand::Progress { chaindata }) => stage_progress(chaindata).await?,
+        Opt::Pipeline { sentry_api_addr } => pipeline(sentry_api_addr).await?,
     }
 
     Ok(())
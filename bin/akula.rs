@@ -1,5 +1,26 @@
-use akula::stagedsync;
+use akula::{
+    accessors::metadata,
+    downloader::{
+        chain_config::ChainsConfig,
+        sentry_address::SentryAddress,
+        sentry_client::{SentryClient, Status},
+        sentry_client_impl::SentryClientImpl,
+        sentry_client_reactor::SentryClientReactor,
+    },
+    genesis,
+    kv::{tables, traits::MutableKV},
+    stagedsync::{metrics::Metrics, stage::PruneSettings, CancellationToken},
+    MdbxEnvironment, MutableTransaction,
+};
+use anyhow::{anyhow, Context};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
 use structopt::StructOpt;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+};
+use tracing::*;
 use tracing_subscriber::{prelude::*, EnvFilter};
 
 #[derive(StructOpt)]
@@ -7,6 +28,131 @@ use tracing_subscriber::{prelude::*, EnvFilter};
 pub struct Opt {
     #[structopt(long, env)]
     pub tokio_console: bool,
+
+    /// Directory to keep node data in, chain database goes to its `chaindata` subdirectory
+    #[structopt(long, parse(from_os_str), default_value = "data")]
+    pub datadir: PathBuf,
+
+    /// Name of the chain to sync
+    #[structopt(long, default_value = "mainnet")]
+    pub chain: String,
+
+    /// Upper bound of the database size, e.g. '2TiB'
+    #[structopt(long = "mdbx.map-size", parse(try_from_str = parse_size))]
+    pub mdbx_map_size: Option<usize>,
+
+    /// Step by which the database file grows, e.g. '2GiB'
+    #[structopt(long = "mdbx.growth-step", parse(try_from_str = parse_size))]
+    pub mdbx_growth_step: Option<usize>,
+
+    /// Sentry GRPC service URL as 'http://host:port'
+    #[structopt(long = "sentry.api.addr", default_value = "http://localhost:8000")]
+    pub sentry_api_addr: SentryAddress,
+
+    /// Stop once all stages have synced up to this block
+    #[structopt(long)]
+    pub max_block: Option<u64>,
+
+    /// Stop after one full cycle through all stages
+    #[structopt(long)]
+    pub exit_after_sync: bool,
+
+    /// Serve stage metrics in Prometheus text format on this address
+    #[structopt(long = "metrics.addr")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Keep only this many last blocks of state history
+    #[structopt(long = "prune.history")]
+    pub prune_history: Option<u64>,
+
+    /// Keep only this many last blocks of receipts and logs
+    #[structopt(long = "prune.receipts")]
+    pub prune_receipts: Option<u64>,
+
+    /// Keep only this many last blocks of call traces
+    #[structopt(long = "prune.call-traces")]
+    pub prune_call_traces: Option<u64>,
+}
+
+fn parse_size(s: &str) -> anyhow::Result<usize> {
+    Ok(byte_unit::Byte::from_str(s)
+        .map_err(|e| anyhow!("invalid size {}: {}", s, e))?
+        .get_bytes() as usize)
+}
+
+fn open_database(opt: &Opt) -> anyhow::Result<MdbxEnvironment<mdbx::NoWriteMap>> {
+    let chaindata = opt.datadir.join("chaindata");
+    std::fs::create_dir_all(&chaindata)
+        .with_context(|| format!("failed to create {}", chaindata.display()))?;
+
+    let mut builder = mdbx::Environment::<mdbx::NoWriteMap>::new();
+    builder.set_geometry(mdbx::Geometry {
+        size: opt.mdbx_map_size.map(|map_size| 0..map_size),
+        growth_step: opt.mdbx_growth_step.map(|growth_step| growth_step as isize),
+        shrink_threshold: None,
+        page_size: None,
+    });
+
+    MdbxEnvironment::open_rw(builder, &chaindata, &tables::TABLE_MAP)
+}
+
+/// Cancels the sync on SIGINT or SIGTERM, so that it stops after the current stage commits. A second signal exits the process right away.
+fn cancel_on_signal(token: CancellationToken) -> anyhow::Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = sigterm.recv() => {},
+        }
+        info!("Shutting down after the current stage, send the signal again to exit immediately");
+        token.cancel();
+
+        // Network stages do not observe the token and may wait on peers indefinitely
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = sigterm.recv() => {},
+        }
+        warn!("Exiting without waiting for the current stage");
+        std::process::exit(1);
+    });
+
+    Ok(())
+}
+
+/// Answers every HTTP request with the current stage metrics.
+async fn serve_metrics(addr: SocketAddr, metrics: Metrics) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Serving metrics on {}", addr);
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("Failed to accept metrics connection: {}", e);
+                    continue;
+                }
+            };
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                // Request itself does not matter, only wait for it to arrive
+                let mut buf = [0; 1024];
+                if stream.read(&mut buf).await.is_err() {
+                    return;
+                }
+
+                let body = metrics.to_prometheus();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+
+    Ok(())
 }
 
 #[tokio::main]
@@ -36,13 +182,46 @@ async fn main() -> anyhow::Result<()> {
         registry.with(filter).init();
     }
 
-    let db = akula::new_mem_database()?;
+    let chains_config = ChainsConfig::new()?;
+    let chain_config = chains_config.0.get(&opt.chain).cloned().ok_or_else(|| {
+        anyhow!(
+            "unknown chain {}, available: {}",
+            opt.chain,
+            chains_config.chain_names().join(", ")
+        )
+    })?;
+
+    let db = open_database(&opt)?;
+    let tx = db.begin_mutable().await?;
+    metadata::ensure_chain(&tx, &opt.chain).await?;
+    genesis::initialize(&tx, &opt.chain, chain_config.genesis_block_hash).await?;
+    tx.commit().await?;
+    info!(chain = %opt.chain, datadir = %opt.datadir.display(), "Database opened");
+    let mut sentry_client = SentryClientImpl::new(opt.sentry_api_addr).await?;
+    sentry_client
+        .set_status(Status {
+            total_difficulty: ethereum_types::U256::zero(),
+            best_hash: chain_config.genesis_block_hash,
+            chain_fork_config: chain_config,
+            max_block: 0,
+        })
+        .await?;
+    let mut sentry = SentryClientReactor::new(Box::new(sentry_client));
+    sentry.start();
 
-    let mut staged_sync = stagedsync::StagedSync::new();
-    staged_sync.push(akula::stages::HeaderDownload);
-    // staged_sync.push(akula::stages::BlockHashes);
-    // staged_sync.push(akula::stages::ExecutionStage);
+    let sentry = Arc::new(sentry);
 
-    // stagedsync::StagedSync::new(vec![], vec![]);
-    staged_sync.run(&db).await?;
+    let mut staged_sync = akula::stages::pipeline(sentry).build()?;
+    staged_sync.set_max_block(opt.max_block);
+    staged_sync.set_exit_after_sync(opt.exit_after_sync);
+    staged_sync.set_prune(PruneSettings {
+        history: opt.prune_history,
+        receipts: opt.prune_receipts,
+        call_traces: opt.prune_call_traces,
+    });
+    cancel_on_signal(staged_sync.cancellation_token())?;
+    if let Some(addr) = opt.metrics_addr {
+        serve_metrics(addr, staged_sync.metrics()).await?;
+    }
+    staged_sync.run(&db).await
 }
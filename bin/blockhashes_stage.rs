@@ -40,5 +40,6 @@ async fn main() -> anyhow::Result<()> {
 
     let mut staged_sync = stagedsync::StagedSync::new();
     staged_sync.push(akula::stages::BlockHashes);
-    staged_sync.run(&db).await?;
+    staged_sync.set_exit_after_sync(true);
+    staged_sync.run(&db).await
 }
@@ -77,6 +77,62 @@ pub mod header_number {
 
         Ok(None)
     }
+
+    pub async fn write<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(
+        tx: &'tx RwTx,
+        hash: H256,
+        number: u64,
+    ) -> anyhow::Result<()> {
+        trace!("Writing block number {} for hash {:?}", number, hash);
+
+        tx.set(
+            &tables::HeaderNumber,
+            &hash.to_fixed_bytes(),
+            &encode_block_number(number),
+        )
+        .await
+    }
+}
+
+/// Blocks found invalid after their headers were accepted, which must not become canonical again.
+pub mod bad_header {
+    use super::*;
+
+    pub async fn read<'db: 'tx, 'tx, Tx: Transaction<'db>>(
+        tx: &'tx Tx,
+        hash: H256,
+    ) -> anyhow::Result<Option<u64>> {
+        trace!("Reading bad block number for hash {:?}", hash);
+
+        if let Some(b) = tx
+            .get(&tables::BadHeaderNumber, &hash.to_fixed_bytes())
+            .await?
+        {
+            match b.len() {
+                common::BLOCK_NUMBER_LENGTH => {
+                    return Ok(Some(u64::from_be_bytes(*array_ref![b, 0, 8])))
+                }
+                other => bail!("invalid length: {}", other),
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub async fn write<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(
+        tx: &'tx RwTx,
+        hash: H256,
+        number: u64,
+    ) -> anyhow::Result<()> {
+        trace!("Marking block {} with hash {:?} as bad", number, hash);
+
+        tx.set(
+            &tables::BadHeaderNumber,
+            &hash.to_fixed_bytes(),
+            &encode_block_number(number),
+        )
+        .await
+    }
 }
 
 pub mod header {
@@ -95,6 +151,19 @@ pub mod header {
 
         Ok(None)
     }
+
+    pub async fn write<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(
+        tx: &'tx RwTx,
+        hash: H256,
+        number: u64,
+        header: &HeaderType,
+    ) -> anyhow::Result<()> {
+        trace!("Writing header for block {}/{:?}", number, hash);
+
+        let data = rlp::encode(header);
+        let mut cursor = tx.mutable_cursor(&tables::Header).await?;
+        cursor.put(&header_key(number, hash), &data).await
+    }
 }
 
 pub mod tx {
@@ -209,6 +278,55 @@ pub mod tx_sender {
     }
 }
 
+pub mod tx_lookup {
+    use super::*;
+    use ethereum::EnvelopedEncodable;
+
+    pub async fn read<'db: 'tx, 'tx, Tx: Transaction<'db>>(
+        tx: &'tx Tx,
+        hash: H256,
+    ) -> anyhow::Result<Option<u64>> {
+        trace!("Reading block number for transaction {:?}", hash);
+
+        if let Some(b) = tx
+            .get(&tables::BlockTransactionLookup, hash.as_bytes())
+            .await?
+        {
+            match b.len() {
+                common::BLOCK_NUMBER_LENGTH => {
+                    return Ok(Some(u64::from_be_bytes(*array_ref![b, 0, 8])))
+                }
+                other => bail!("invalid length: {}", other),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Finds canonical transaction by its hash, returns it along with its block number and index in that block.
+    pub async fn read_transaction<'db: 'tx, 'tx, Tx: Transaction<'db>>(
+        tx: &'tx Tx,
+        hash: H256,
+    ) -> anyhow::Result<Option<(u64, usize, ethereum::TransactionV2)>> {
+        if let Some(block_number) = read(tx, hash).await? {
+            if let Some(block_hash) = canonical_hash::read(tx, block_number).await? {
+                if let Some(body) = storage_body::read(tx, block_hash, block_number).await? {
+                    return Ok(super::tx::read(tx, body.base_tx_id, body.tx_amount)
+                        .await?
+                        .into_iter()
+                        .enumerate()
+                        .find(|(_, eth_tx)| {
+                            common::hash_data(&EnvelopedEncodable::encode(eth_tx)) == hash
+                        })
+                        .map(|(index, eth_tx)| (block_number, index, eth_tx)));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
 pub mod storage_body {
     use bytes::Bytes;
 
@@ -288,6 +406,272 @@ pub mod td {
 
         Ok(None)
     }
+
+    pub async fn write<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(
+        tx: &'tx RwTx,
+        hash: H256,
+        number: u64,
+        td: U256,
+    ) -> anyhow::Result<()> {
+        trace!(
+            "Writing total difficulty {} at block {}/{:?}",
+            td,
+            number,
+            hash
+        );
+
+        tx.set(
+            &tables::HeadersTotalDifficulty,
+            &header_key(number, hash),
+            &rlp::encode(&td),
+        )
+        .await
+    }
+}
+
+pub mod receipt {
+    use super::*;
+
+    /// Reads receipts of the block, with the logs of each transaction from `TransactionLog`.
+    pub async fn read<'db: 'tx, 'tx, Tx: Transaction<'db>>(
+        tx: &'tx Tx,
+        number: u64,
+    ) -> anyhow::Result<Option<Vec<Receipt>>> {
+        trace!("Reading receipts for block {}", number);
+
+        if let Some(b) = tx
+            .get(&tables::Receipt, &encode_block_number(number))
+            .await?
+        {
+            let receipts: Vec<ReceiptForStorage> = rlp::Rlp::new(&b).as_list()?;
+
+            let mut out = Vec::with_capacity(receipts.len());
+            for (tx_index, receipt) in receipts.into_iter().enumerate() {
+                out.push(Receipt {
+                    success: receipt.success,
+                    cumulative_gas_used: receipt.cumulative_gas_used,
+                    logs: super::log::read(tx, number, tx_index as u32).await?,
+                });
+            }
+            return Ok(Some(out));
+        }
+
+        Ok(None)
+    }
+
+    /// Writes receipts of the block to `Receipt`, and non-empty logs of each transaction to `TransactionLog`.
+    pub async fn write<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(
+        tx: &'tx RwTx,
+        number: u64,
+        receipts: &[Receipt],
+    ) -> anyhow::Result<()> {
+        trace!("Writing {} receipts for block {}", receipts.len(), number);
+
+        tx.set(
+            &tables::Receipt,
+            &encode_block_number(number),
+            &rlp::encode_list::<ReceiptForStorage, _>(
+                &receipts
+                    .iter()
+                    .map(ReceiptForStorage::from)
+                    .collect::<Vec<_>>(),
+            ),
+        )
+        .await?;
+
+        for (tx_index, receipt) in receipts.iter().enumerate() {
+            if !receipt.logs.is_empty() {
+                super::log::write(tx, number, tx_index as u32, &receipt.logs).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub mod log {
+    use super::*;
+
+    pub async fn read<'db: 'tx, 'tx, Tx: Transaction<'db>>(
+        tx: &'tx Tx,
+        number: u64,
+        tx_index: u32,
+    ) -> anyhow::Result<Vec<Log>> {
+        trace!(
+            "Reading logs for transaction {} in block {}",
+            tx_index,
+            number
+        );
+
+        if let Some(b) = tx
+            .get(&tables::TransactionLog, &log_key(number, tx_index))
+            .await?
+        {
+            return Ok(rlp::Rlp::new(&b).as_list()?);
+        }
+
+        Ok(vec![])
+    }
+
+    pub async fn write<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(
+        tx: &'tx RwTx,
+        number: u64,
+        tx_index: u32,
+        logs: &[Log],
+    ) -> anyhow::Result<()> {
+        trace!(
+            "Writing {} logs for transaction {} in block {}",
+            logs.len(),
+            tx_index,
+            number
+        );
+
+        tx.set(
+            &tables::TransactionLog,
+            &log_key(number, tx_index),
+            &rlp::encode_list(logs),
+        )
+        .await
+    }
+}
+
+pub mod call_trace {
+    use super::*;
+
+    pub async fn read<'db: 'tx, 'tx, Tx: Transaction<'db>>(
+        tx: &'tx Tx,
+        number: u64,
+    ) -> anyhow::Result<Vec<CallTrace>> {
+        trace!("Reading call traces for block {}", number);
+
+        let mut cursor = tx.cursor_dup_sort(&tables::CallTraceSet).await?;
+
+        let start_key = encode_block_number(number);
+        let walker = txdb::walk(&mut cursor, &start_key, (start_key.len() * 8) as u64);
+
+        pin!(walker);
+
+        let mut out = vec![];
+        while let Some((_, v)) = walker.try_next().await? {
+            out.push(CallTrace::decode(&v)?);
+        }
+
+        Ok(out)
+    }
+
+    pub async fn write<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(
+        tx: &'tx RwTx,
+        number: u64,
+        traces: &[CallTrace],
+    ) -> anyhow::Result<()> {
+        trace!("Writing {} call traces for block {}", traces.len(), number);
+
+        let key = encode_block_number(number);
+        let mut cursor = tx.mutable_cursor_dupsort(&tables::CallTraceSet).await?;
+        for call_trace in traces {
+            cursor.put(&key, &call_trace.encode()).await?;
+        }
+
+        Ok(())
+    }
+}
+
+pub mod log_index {
+    use super::*;
+    use crate::bitmapdb;
+    use roaring::RoaringTreemap;
+
+    async fn read_any<'db: 'tx, 'tx, 'k, Tx: Transaction<'db>, T: crate::kv::Table>(
+        tx: &'tx Tx,
+        table: &T,
+        keys: impl Iterator<Item = &'k [u8]>,
+        from: u64,
+        to: u64,
+    ) -> anyhow::Result<RoaringTreemap> {
+        let mut out = RoaringTreemap::new();
+        for key in keys {
+            out = out | bitmapdb::get(tx, table, key, from, to).await?;
+        }
+
+        Ok(out)
+    }
+
+    /// Largest range of blocks returned for an empty filter, which matches every block of the range.
+    pub const MAX_UNFILTERED_BLOCKS: u64 = 10_000;
+
+    /// Returns numbers of blocks in `from..=to` which may have logs matching the filter.
+    ///
+    /// A log matches if it is emitted by any of `addresses`, and for every non-empty entry of `topics` has any of its topics.
+    /// Empty filters match any log, and are limited to `MAX_UNFILTERED_BLOCKS` blocks.
+    /// Positions of topics are not indexed, so logs of returned blocks have to be checked against the filter.
+    pub async fn read_blocks<'db: 'tx, 'tx, Tx: Transaction<'db>>(
+        tx: &'tx Tx,
+        addresses: &[Address],
+        topics: &[Vec<H256>],
+        from: u64,
+        to: u64,
+    ) -> anyhow::Result<RoaringTreemap> {
+        trace!(
+            "Reading blocks with logs from {:?} with topics {:?} in {}..={}",
+            addresses,
+            topics,
+            from,
+            to
+        );
+
+        if from > to {
+            return Ok(RoaringTreemap::new());
+        }
+
+        let mut out = None;
+        if !addresses.is_empty() {
+            out = Some(
+                read_any(
+                    tx,
+                    &tables::LogAddressIndex,
+                    addresses.iter().map(|address| address.as_bytes()),
+                    from,
+                    to,
+                )
+                .await?,
+            );
+        }
+        for position in topics.iter().filter(|position| !position.is_empty()) {
+            let blocks = read_any(
+                tx,
+                &tables::LogTopicIndex,
+                position.iter().map(|topic| topic.as_bytes()),
+                from,
+                to,
+            )
+            .await?;
+            out = Some(match out {
+                Some(out) => out & blocks,
+                None => blocks,
+            });
+        }
+
+        Ok(match out {
+            Some(mut out) => {
+                out.remove_range(0..from);
+                if to < u64::MAX {
+                    out.remove_range(to + 1..=u64::MAX);
+                }
+                out
+            }
+            None => {
+                if to - from >= MAX_UNFILTERED_BLOCKS {
+                    bail!(
+                        "empty log filter over {}..={} exceeds {} blocks",
+                        from,
+                        to,
+                        MAX_UNFILTERED_BLOCKS
+                    );
+                }
+                (from..=to).collect()
+            }
+        })
+    }
 }
 
 #[cfg(test)]
@@ -365,4 +749,32 @@ mod tests {
         assert_eq!(txs, *recovered_txs);
         assert_eq!(senders, *recovered_senders);
     }
+
+    #[tokio::test]
+    async fn log_index_range_end() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        let address = Address::random();
+        let mut index = crate::bitmapdb::IndexCollector::default();
+        for block in [5, u64::MAX - 1, u64::MAX] {
+            index.insert(address.as_bytes(), block).unwrap();
+        }
+        index.load(&tx, &tables::LogAddressIndex).await.unwrap();
+
+        for (to, expected) in [
+            (u64::MAX - 2, vec![5]),
+            (u64::MAX - 1, vec![5, u64::MAX - 1]),
+            (u64::MAX, vec![5, u64::MAX - 1, u64::MAX]),
+        ] {
+            assert_eq!(
+                log_index::read_blocks(&tx, &[address], &[], 0, to)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .collect::<Vec<_>>(),
+                expected
+            );
+        }
+    }
 }
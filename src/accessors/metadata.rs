@@ -1,8 +1,21 @@
-use crate::{kv::*, models::*, Transaction};
+use crate::{accessors::chain, kv::*, models::*, MutableTransaction, Transaction};
 use anyhow::Context;
 use ethereum_types::H256;
+use thiserror::Error;
 use tracing::*;
 
+const CHAIN_KEY: &[u8] = b"chain";
+
+#[derive(Debug, Error, PartialEq)]
+pub enum MetadataError {
+    #[error("Database belongs to chain {stored}, not {requested}")]
+    ChainMismatch { stored: String, requested: String },
+    #[error("Genesis block not found")]
+    GenesisNotFound,
+    #[error("Chain config for genesis {0:?} not found")]
+    ChainConfigNotFound(H256),
+}
+
 pub async fn read_chain_config<'db: 'tx, 'tx, Tx: Transaction<'db>>(
     tx: &'tx Tx,
     block: H256,
@@ -23,3 +36,101 @@ pub async fn read_chain_config<'db: 'tx, 'tx, Tx: Transaction<'db>>(
 
     Ok(None)
 }
+
+/// Reads config of the chain the database belongs to, as stored for its genesis block.
+pub async fn read_genesis_chain_config<'db: 'tx, 'tx, Tx: Transaction<'db>>(
+    tx: &'tx Tx,
+) -> anyhow::Result<ChainConfig> {
+    let genesis_hash = chain::canonical_hash::read(tx, 0)
+        .await?
+        .ok_or(MetadataError::GenesisNotFound)?;
+
+    Ok(read_chain_config(tx, genesis_hash)
+        .await?
+        .ok_or(MetadataError::ChainConfigNotFound(genesis_hash))?)
+}
+
+pub async fn write_chain_config<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(
+    tx: &'tx RwTx,
+    block: H256,
+    config: &ChainConfig,
+) -> anyhow::Result<()> {
+    trace!("Writing chain config for block {:?}", block);
+
+    tx.set(
+        &tables::Config,
+        block.as_bytes(),
+        &serde_json::to_vec(config)?,
+    )
+    .await
+}
+
+pub async fn read_chain_name<'db: 'tx, 'tx, Tx: Transaction<'db>>(
+    tx: &'tx Tx,
+) -> anyhow::Result<Option<String>> {
+    trace!("Reading chain name");
+
+    if let Some(b) = tx.get(&tables::DbInfo, CHAIN_KEY).await? {
+        return Ok(Some(
+            String::from_utf8(b.to_vec()).context("invalid chain name")?,
+        ));
+    }
+
+    Ok(None)
+}
+
+pub async fn write_chain_name<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(
+    tx: &'tx RwTx,
+    name: &str,
+) -> anyhow::Result<()> {
+    trace!("Writing chain name {}", name);
+
+    tx.set(&tables::DbInfo, CHAIN_KEY, name.as_bytes()).await
+}
+
+/// Records `name` as the chain of a fresh database, or checks that an existing database belongs to it.
+pub async fn ensure_chain<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(
+    tx: &'tx RwTx,
+    name: &str,
+) -> anyhow::Result<()> {
+    match read_chain_name(tx).await? {
+        Some(stored) if stored != name => Err(MetadataError::ChainMismatch {
+            stored,
+            requested: name.to_string(),
+        }
+        .into()),
+        Some(_) => Ok(()),
+        None => write_chain_name(tx, name).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{kv::traits::MutableKV, new_mem_database};
+
+    #[tokio::test]
+    async fn chain_name() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        assert_eq!(read_chain_name(&tx).await.unwrap(), None);
+        ensure_chain(&tx, "ropsten").await.unwrap();
+        ensure_chain(&tx, "ropsten").await.unwrap();
+        assert_eq!(
+            read_chain_name(&tx).await.unwrap(),
+            Some("ropsten".to_string())
+        );
+        assert_eq!(
+            ensure_chain(&tx, "mainnet")
+                .await
+                .unwrap_err()
+                .downcast::<MetadataError>()
+                .unwrap(),
+            MetadataError::ChainMismatch {
+                stored: "ropsten".to_string(),
+                requested: "mainnet".to_string(),
+            }
+        );
+    }
+}
@@ -1,9 +1,19 @@
-use crate::{common, dbutils, kv::*, models::*, Transaction};
+use crate::{
+    changeset::{AccountHistory, HistoryKind},
+    common, dbutils,
+    kv::*,
+    models::*,
+    state::{self, find_data_by_history, get_account_data_as_of, get_storage_as_of},
+    Cursor, Transaction,
+};
+use arrayref::array_ref;
+use async_trait::async_trait;
 use bytes::Bytes;
-use dbutils::plain_generate_composite_storage_key;
-use ethereum_types::Address;
+use ethereum_types::{Address, H256};
+use roaring::RoaringTreemap;
 use std::marker::PhantomData;
 
+/// Reads state as it was after execution of `block_nr`.
 pub struct StateReader<'db: 'tx, 'tx, Tx: Transaction<'db> + ?Sized> {
     block_nr: u64,
     tx: &'tx Tx,
@@ -18,24 +28,279 @@ impl<'db: 'tx, 'tx, Tx: Transaction<'db> + ?Sized> StateReader<'db, 'tx, Tx> {
             _marker: PhantomData,
         }
     }
+}
+
+impl<'db: 'tx, 'tx, Tx: Transaction<'db>> StateReader<'db, 'tx, Tx> {
+    async fn read_code_hash(
+        &self,
+        address: Address,
+        incarnation: common::Incarnation,
+    ) -> anyhow::Result<Option<H256>> {
+        Ok(self
+            .tx
+            .get(
+                &tables::PlainCodeHash,
+                &dbutils::plain_generate_storage_prefix(address, incarnation),
+            )
+            .await?
+            .map(|code_hash| H256::from_slice(&code_hash)))
+    }
+
+    /// Last block not later than `block_nr` in which the account has changed.
+    async fn last_change(&self, address: Address) -> anyhow::Result<Option<u64>> {
+        let mut cursor = self.tx.cursor(&tables::AccountHistory).await?;
 
-    pub async fn read_account_data(&mut self, address: Address) -> anyhow::Result<Option<Account>> {
-        if let Some(enc) =
-            crate::state::get_account_data_as_of(self.tx, address, self.block_nr + 1).await?
+        // Chunks are keyed by their last block, so earlier blocks may be in preceding chunks
+        let mut entry = match cursor
+            .seek(&AccountHistory::index_chunk_key(address, self.block_nr))
+            .await?
         {
-            return Account::decode_for_storage(&enc);
+            Some(entry) => Some(entry),
+            None => cursor.last().await?,
+        };
+        let mut first = true;
+        while let Some((k, v)) = entry {
+            if k.starts_with(address.as_bytes()) {
+                if let Some(block) = RoaringTreemap::deserialize_from(&*v)?
+                    .iter()
+                    .take_while(|block| *block <= self.block_nr)
+                    .last()
+                {
+                    return Ok(Some(block));
+                }
+            } else if !first {
+                break;
+            }
+
+            first = false;
+            entry = cursor.prev().await?;
         }
 
         Ok(None)
     }
+}
+
+#[async_trait]
+impl<'db: 'tx, 'tx, Tx: Transaction<'db>> state::StateReader<'tx> for StateReader<'db, 'tx, Tx> {
+    async fn read_account_data(&self, address: Address) -> anyhow::Result<Option<Account>> {
+        let mut account = match get_account_data_as_of(self.tx, address, self.block_nr + 1).await? {
+            Some(enc) => match Account::decode_for_storage(&enc)? {
+                Some(account) => account,
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+
+        if account.incarnation > 0 && account.code_hash.is_none() {
+            account.code_hash = self.read_code_hash(address, account.incarnation).await?;
+        }
+
+        Ok(Some(account))
+    }
 
-    pub async fn read_account_storage(
-        &mut self,
+    async fn read_account_storage(
+        &self,
         address: Address,
         incarnation: common::Incarnation,
         key: common::Hash,
     ) -> anyhow::Result<Option<Bytes<'tx>>> {
-        let composite_key = plain_generate_composite_storage_key(address, incarnation, key);
-        self.tx.get(&tables::PlainState, &composite_key).await
+        get_storage_as_of(self.tx, address, incarnation, key, self.block_nr + 1).await
+    }
+
+    async fn read_account_code(
+        &self,
+        _: Address,
+        _: common::Incarnation,
+        code_hash: common::Hash,
+    ) -> anyhow::Result<Option<Bytes<'tx>>> {
+        if code_hash == common::EMPTY_HASH {
+            return Ok(None);
+        }
+
+        self.tx.get(&tables::Code, code_hash.as_bytes()).await
+    }
+
+    async fn read_account_code_size(
+        &self,
+        address: Address,
+        incarnation: common::Incarnation,
+        code_hash: common::Hash,
+    ) -> anyhow::Result<usize> {
+        Ok(self
+            .read_account_code(address, incarnation, code_hash)
+            .await?
+            .map(|code| code.len())
+            .unwrap_or(0))
+    }
+
+    /// Incarnation of the last contract destroyed at `address` by the block.
+    async fn read_account_incarnation(&self, address: Address) -> anyhow::Result<Option<u64>> {
+        let enc = match find_data_by_history(self.tx, address, self.block_nr + 1).await? {
+            Some(enc) => enc,
+            // Account has not changed since the block, so current incarnation map applies
+            None => {
+                return Ok(self
+                    .tx
+                    .get(&tables::IncarnationMap, address.as_bytes())
+                    .await?
+                    .map(|b| u64::from_be_bytes(*array_ref!(&*b, 0, 8))))
+            }
+        };
+
+        if let Some(account) = Account::decode_for_storage(&enc)? {
+            // Live contract replaced the previous incarnation
+            return Ok((account.incarnation > 1).then(|| account.incarnation - 1));
+        }
+
+        // Account does not exist, incarnation is the one it had before its last change
+        if let Some(block) = self.last_change(address).await? {
+            let mut cursor = self.tx.cursor_dup_sort(&tables::AccountChangeSet).await?;
+            if let Some(enc) = AccountHistory::find(&mut cursor, block, &address).await? {
+                if let Some(account) = Account::decode_for_storage(&enc)? {
+                    return Ok((account.incarnation > 0).then(|| account.incarnation));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        kv::traits::MutableKV,
+        new_mem_database,
+        stagedsync::test_util::index_history,
+        state::{PlainStateWriter, StateReader as _, StateWriter, WriterWithChangesets},
+    };
+    use ethereum_types::U256;
+    use hex_literal::hex;
+
+    #[tokio::test]
+    async fn recreated_contract() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let address = Address::from_low_u64_be(0x1234);
+        let slot = H256::from_low_u64_be(1);
+        let code = [hex!("6001600055").to_vec(), hex!("6002600055").to_vec()];
+        let code_hash = [common::hash_data(&code[0]), common::hash_data(&code[1])];
+        let absent = Account {
+            initialised: false,
+            ..Default::default()
+        };
+        let contract = |incarnation: u64| Account {
+            nonce: 1,
+            code_hash: Some(code_hash[incarnation as usize - 1]),
+            incarnation,
+            ..Default::default()
+        };
+
+        // Block 1 deploys the contract, block 2 destroys it, block 3 deploys it again
+        let mut writer = PlainStateWriter::new(&tx, 1);
+        writer
+            .update_account_data(address, &absent, &contract(1))
+            .await
+            .unwrap();
+        writer
+            .update_account_code(address, 1, code_hash[0], &code[0])
+            .await
+            .unwrap();
+        writer
+            .write_account_storage(address, 1, slot, 0.into(), 1.into())
+            .await
+            .unwrap();
+        writer.write_changesets().await.unwrap();
+
+        let mut writer = PlainStateWriter::new(&tx, 2);
+        writer
+            .write_account_storage(address, 1, slot, 1.into(), 0.into())
+            .await
+            .unwrap();
+        writer.delete_account(address, &contract(1)).await.unwrap();
+        writer.write_changesets().await.unwrap();
+
+        let mut writer = PlainStateWriter::new(&tx, 3);
+        writer
+            .update_account_data(address, &absent, &contract(2))
+            .await
+            .unwrap();
+        writer
+            .update_account_code(address, 2, code_hash[1], &code[1])
+            .await
+            .unwrap();
+        writer
+            .write_account_storage(address, 2, slot, 0.into(), 2.into())
+            .await
+            .unwrap();
+        writer.write_changesets().await.unwrap();
+        index_history(&mut tx, 3).await;
+
+        let storage = |value: Option<Bytes>| value.map(|v| U256::from_big_endian(&v));
+
+        let reader = StateReader::new(&tx, 0);
+        assert_eq!(reader.read_account_data(address).await.unwrap(), None);
+        assert_eq!(
+            reader.read_account_incarnation(address).await.unwrap(),
+            None
+        );
+
+        let reader = StateReader::new(&tx, 1);
+        let account = reader.read_account_data(address).await.unwrap().unwrap();
+        assert_eq!(account.incarnation, 1);
+        assert_eq!(account.code_hash, Some(code_hash[0]));
+        assert_eq!(
+            reader
+                .read_account_code(address, 1, code_hash[0])
+                .await
+                .unwrap()
+                .unwrap()
+                .to_vec(),
+            code[0]
+        );
+        assert_eq!(
+            storage(reader.read_account_storage(address, 1, slot).await.unwrap()),
+            Some(1.into())
+        );
+        assert_eq!(
+            reader.read_account_incarnation(address).await.unwrap(),
+            None
+        );
+
+        let reader = StateReader::new(&tx, 2);
+        assert_eq!(reader.read_account_data(address).await.unwrap(), None);
+        assert_eq!(
+            reader.read_account_storage(address, 1, slot).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            reader.read_account_incarnation(address).await.unwrap(),
+            Some(1)
+        );
+
+        let reader = StateReader::new(&tx, 3);
+        let account = reader.read_account_data(address).await.unwrap().unwrap();
+        assert_eq!(account.incarnation, 2);
+        assert_eq!(
+            reader
+                .read_account_code_size(address, 2, account.code_hash.unwrap())
+                .await
+                .unwrap(),
+            code[1].len()
+        );
+        assert_eq!(
+            storage(reader.read_account_storage(address, 2, slot).await.unwrap()),
+            Some(2.into())
+        );
+        assert_eq!(
+            reader.read_account_storage(address, 1, slot).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            reader.read_account_incarnation(address).await.unwrap(),
+            Some(1)
+        );
     }
 }
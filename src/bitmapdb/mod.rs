@@ -1,6 +1,14 @@
-use std::iter::Peekable;
-
-use crate::{common, kv::Table, txdb, Transaction};
+use std::{collections::HashMap, iter::Peekable};
+
+use crate::{
+    common,
+    etl::{
+        collector::{Collector, OPTIMAL_BUFFER_CAPACITY},
+        data_provider::Entry,
+    },
+    kv::Table,
+    txdb, MutableCursor, MutableTransaction, Transaction,
+};
 use arrayref::array_ref;
 use pin_utils::pin_mut;
 use roaring::RoaringTreemap;
@@ -56,6 +64,208 @@ where
     Ok(out.unwrap_or_default())
 }
 
+/// Adds `bm` to the bitmap stored in chunks under `key`.
+/// Only the last chunk is rewritten, so all values in `bm` should be greater than those already stored.
+pub async fn append<'db, RwTx, T>(
+    tx: &RwTx,
+    table: &T,
+    key: &[u8],
+    mut bm: RoaringTreemap,
+) -> anyhow::Result<()>
+where
+    RwTx: MutableTransaction<'db>,
+    T: Table,
+{
+    let last_chunk_key = key
+        .iter()
+        .chain(&u64::MAX.to_be_bytes())
+        .copied()
+        .collect::<Vec<_>>();
+
+    if let Some(v) = tx.get(table, &last_chunk_key).await? {
+        bm = bm | RoaringTreemap::deserialize_from(v.as_ref())?;
+    }
+
+    let mut buf = vec![];
+    for (chunk_key, chunk) in Chunks::new(bm, CHUNK_LIMIT).with_keys(key) {
+        buf.clear();
+        chunk.serialize_into(&mut buf)?;
+        tx.set(table, &chunk_key, &buf).await?;
+    }
+
+    Ok(())
+}
+
+/// Removes values starting from `from` out of the bitmap stored in chunks under `key`.
+pub async fn truncate<'db, RwTx, T>(
+    tx: &RwTx,
+    table: &T,
+    key: &[u8],
+    from: u64,
+) -> anyhow::Result<()>
+where
+    RwTx: MutableTransaction<'db>,
+    T: Table,
+{
+    let from_key = key
+        .iter()
+        .chain(&from.to_be_bytes())
+        .copied()
+        .collect::<Vec<_>>();
+
+    // Chunk keys end with the chunk maximum, so only the chunks from this one onward are affected
+    let mut bm = RoaringTreemap::new();
+    let mut chunk_keys = vec![];
+    {
+        let mut c = tx.cursor(table).await?;
+        let s = txdb::walk(&mut c, &from_key, (key.len() * 8) as u64);
+        pin_mut!(s);
+        while let Some((k, v)) = s.try_next().await? {
+            bm = bm | RoaringTreemap::deserialize_from(v.as_ref())?;
+            chunk_keys.push(k.to_vec());
+        }
+    }
+
+    let mut c = tx.mutable_cursor(table).await?;
+    for chunk_key in chunk_keys {
+        c.delete(&chunk_key, &[]).await?;
+    }
+
+    bm.remove_range(from..u64::MAX);
+
+    let mut buf = vec![];
+    for (chunk_key, chunk) in Chunks::new(bm, CHUNK_LIMIT).with_keys(key) {
+        buf.clear();
+        chunk.serialize_into(&mut buf)?;
+        c.put(&chunk_key, &buf).await?;
+    }
+
+    Ok(())
+}
+
+/// Removes blocks before `before` from the chunks of `key`, deleting chunks left empty.
+pub async fn prune<'db, RwTx, T>(
+    tx: &RwTx,
+    table: &T,
+    key: &[u8],
+    before: u64,
+) -> anyhow::Result<()>
+where
+    RwTx: MutableTransaction<'db>,
+    T: Table,
+{
+    let start_key = key
+        .iter()
+        .chain(&0_u64.to_be_bytes())
+        .copied()
+        .collect::<Vec<_>>();
+
+    let mut c = tx.mutable_cursor(table).await?;
+    while let Some((k, v)) = c.seek(&start_key).await? {
+        if k.len() != key.len() + 8 || !k.starts_with(key) {
+            break;
+        }
+
+        // Chunk keys end with the chunk maximum, so whole chunks below `before` can be dropped
+        if u64::from_be_bytes(*array_ref!(k, key.len(), 8)) < before {
+            c.delete_current().await?;
+            continue;
+        }
+
+        let mut bm = RoaringTreemap::deserialize_from(v.as_ref())?;
+        if bm.iter().next().map_or(false, |first| first < before) {
+            let chunk_key = k.to_vec();
+            bm.remove_range(0..before);
+            let mut buf = vec![];
+            bm.serialize_into(&mut buf)?;
+            c.put(&chunk_key, &buf).await?;
+        }
+        break;
+    }
+
+    Ok(())
+}
+
+/// Number of keys to gather bitmaps for in memory before handing them over to the ETL collector.
+const INDEX_FLUSH_THRESHOLD: usize = 1_000_000;
+
+/// Gathers bitmaps per key, then appends them to chunks stored in an index table.
+pub struct IndexCollector {
+    bitmaps: HashMap<Vec<u8>, RoaringTreemap>,
+    collector: Collector,
+}
+
+impl Default for IndexCollector {
+    fn default() -> Self {
+        Self {
+            bitmaps: HashMap::new(),
+            collector: Collector::new(OPTIMAL_BUFFER_CAPACITY),
+        }
+    }
+}
+
+impl IndexCollector {
+    pub fn insert(&mut self, key: &[u8], n: u64) -> anyhow::Result<()> {
+        if let Some(bm) = self.bitmaps.get_mut(key) {
+            bm.insert(n);
+        } else {
+            let mut bm = RoaringTreemap::new();
+            bm.insert(n);
+            self.bitmaps.insert(key.to_vec(), bm);
+
+            if self.bitmaps.len() >= INDEX_FLUSH_THRESHOLD {
+                self.flush()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        for (key, bm) in self.bitmaps.drain() {
+            let mut value = vec![];
+            bm.serialize_into(&mut value)?;
+            self.collector.collect(Entry {
+                key,
+                value,
+                id: 0, // Irrelevant here, could be anything
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Appends gathered bitmaps to `table`, see `append`.
+    pub async fn load<'db, RwTx, T>(mut self, tx: &RwTx, table: &T) -> anyhow::Result<()>
+    where
+        RwTx: MutableTransaction<'db>,
+        T: Table,
+    {
+        self.flush()?;
+
+        // The same key may have been flushed several times, merge its bitmaps before writing
+        let mut current = None;
+        for entry in self.collector.into_sorted()? {
+            let (key, value) = entry?;
+            let bm = RoaringTreemap::deserialize_from(value.as_slice())?;
+
+            current = Some(match current.take() {
+                Some((current_key, current_bm)) if current_key == key => (key, current_bm | bm),
+                Some((current_key, current_bm)) => {
+                    append(tx, table, &current_key, current_bm).await?;
+                    (key, bm)
+                }
+                None => (key, bm),
+            });
+        }
+        if let Some((key, bm)) = current {
+            append(tx, table, &key, bm).await?;
+        }
+
+        Ok(())
+    }
+}
+
 fn cut_left(bm: &mut RoaringTreemap, size_limit: usize) -> Option<RoaringTreemap> {
     if bm.is_empty() {
         return None;
@@ -1,4 +1,5 @@
 use ethereum_types::{H256, U256};
+use hex_literal::hex;
 use sha3::{Digest, Keccak256};
 use std::mem::size_of;
 
@@ -12,6 +13,16 @@ pub const ADDRESS_LENGTH: usize = Address::len_bytes();
 pub const BLOCK_NUMBER_LENGTH: usize = size_of::<u64>();
 pub const INCARNATION_LENGTH: usize = size_of::<u64>();
 
+/// Keccak-256 hash of empty data, code hash of accounts without code.
+pub const EMPTY_HASH: H256 = H256(hex!(
+    "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+));
+
+/// Keccak-256 hash of RLP-encoded empty list, ommers hash of blocks without ommers.
+pub const EMPTY_LIST_HASH: H256 = H256(hex!(
+    "1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347"
+));
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct BlockNumber(pub u64);
 
@@ -5,6 +5,7 @@ use ethereum_types::H256;
 use std::io::Write;
 
 pub const HEADER_KEY_LEN: usize = common::BLOCK_NUMBER_LENGTH + common::HASH_LENGTH;
+pub const LOG_KEY_LEN: usize = common::BLOCK_NUMBER_LENGTH + 4;
 
 pub const COMPOSITE_STORAGE_KEY_LENGTH: usize = STORAGE_PREFIX_LENGTH + common::HASH_LENGTH;
 pub const PLAIN_COMPOSITE_STORAGE_KEY_LENGTH: usize =
@@ -13,6 +14,7 @@ pub const STORAGE_PREFIX_LENGTH: usize = common::HASH_LENGTH + common::INCARNATI
 pub const PLAIN_STORAGE_PREFIX_LENGTH: usize = common::ADDRESS_LENGTH + common::INCARNATION_LENGTH;
 
 pub type HeaderKey = [u8; HEADER_KEY_LEN];
+pub type LogKey = [u8; LOG_KEY_LEN];
 
 pub type CompositeStorageKey = [u8; COMPOSITE_STORAGE_KEY_LENGTH];
 pub type PlainCompositeStorageKey = [u8; PLAIN_COMPOSITE_STORAGE_KEY_LENGTH];
@@ -31,6 +33,17 @@ pub fn header_key(number: u64, hash: H256) -> HeaderKey {
     v
 }
 
+/// BlockNumber + TxIndex
+/// For logs of a transaction
+pub fn log_key(number: u64, tx_index: u32) -> LogKey {
+    let mut v: LogKey = [0; LOG_KEY_LEN];
+
+    v[..common::BLOCK_NUMBER_LENGTH].copy_from_slice(&encode_block_number(number));
+    v[common::BLOCK_NUMBER_LENGTH..].copy_from_slice(&tx_index.to_be_bytes());
+
+    v
+}
+
 /// AddrHash + incarnation + KeyHash
 /// For contract storage
 pub fn generate_composite_storage_key(
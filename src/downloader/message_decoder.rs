@@ -11,6 +11,12 @@ pub fn decode_rlp_message(id: EthMessageId, message_bytes: &[u8]) -> anyhow::Res
         EthMessageId::BlockHeaders => {
             Message::BlockHeaders(rlp::decode::<BlockHeadersMessage>(message_bytes)?)
         }
+        EthMessageId::GetBlockBodies => {
+            Message::GetBlockBodies(rlp::decode::<GetBlockBodiesMessage>(message_bytes)?)
+        }
+        EthMessageId::BlockBodies => {
+            Message::BlockBodies(rlp::decode::<BlockBodiesMessage>(message_bytes)?)
+        }
         EthMessageId::NewBlock => Message::NewBlock(rlp::decode::<NewBlockMessage>(message_bytes)?),
         EthMessageId::NewPooledTransactionHashes => Message::NewPooledTransactionHashes(
             rlp::decode::<NewPooledTransactionHashesMessage>(message_bytes)?,
@@ -26,6 +32,8 @@ impl rlp::Encodable for Message {
             Message::NewBlockHashes(message) => message.rlp_append(stream),
             Message::GetBlockHeaders(message) => message.rlp_append(stream),
             Message::BlockHeaders(message) => message.rlp_append(stream),
+            Message::GetBlockBodies(message) => message.rlp_append(stream),
+            Message::BlockBodies(message) => message.rlp_append(stream),
             Message::NewBlock(message) => message.rlp_append(stream),
             Message::NewPooledTransactionHashes(message) => message.rlp_append(stream),
         }
@@ -38,8 +46,8 @@ mod tests {
         block_id::BlockId,
         message_decoder::decode_rlp_message,
         messages::{
-            BlockHashAndNumber, EthMessageId, GetBlockHeadersMessage, GetBlockHeadersMessageParams,
-            Message, NewBlockHashesMessage,
+            BlockBodiesMessage, BlockBody, BlockHashAndNumber, EthMessageId, GetBlockBodiesMessage,
+            GetBlockHeadersMessage, GetBlockHeadersMessageParams, Message, NewBlockHashesMessage,
         },
     };
     use ethereum_types::H256;
@@ -117,4 +125,46 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn decode_get_block_bodies() {
+        let expected_bytes =
+            hex!("e5820457e1a000000000000000000000000000000000000000000000000000000000deadc0de");
+        let result = decode_rlp_message(EthMessageId::GetBlockBodies, &expected_bytes);
+        let some_message = result.unwrap();
+
+        let bytes = rlp::encode(&some_message);
+        assert_eq!(&*bytes, expected_bytes);
+
+        assert_eq!(
+            some_message,
+            Message::GetBlockBodies(GetBlockBodiesMessage {
+                request_id: 1111,
+                block_hashes: vec![H256(hex!(
+                    "00000000000000000000000000000000000000000000000000000000deadc0de"
+                ))],
+            })
+        );
+    }
+
+    #[test]
+    fn decode_block_bodies() {
+        let expected_bytes = hex!("c7820457c3c2c0c0");
+        let result = decode_rlp_message(EthMessageId::BlockBodies, &expected_bytes);
+        let some_message = result.unwrap();
+
+        let bytes = rlp::encode(&some_message);
+        assert_eq!(&*bytes, expected_bytes);
+
+        assert_eq!(
+            some_message,
+            Message::BlockBodies(BlockBodiesMessage {
+                request_id: 1111,
+                bodies: vec![BlockBody {
+                    transactions: vec![],
+                    ommers: vec![],
+                }],
+            })
+        );
+    }
 }
@@ -54,6 +54,24 @@ pub struct BlockHeadersMessage {
     pub headers: Vec<HeaderType>,
 }
 
+#[derive(rlp_derive::RlpEncodable, rlp_derive::RlpDecodable, Clone, PartialEq, Debug)]
+pub struct GetBlockBodiesMessage {
+    pub request_id: u64,
+    pub block_hashes: Vec<H256>,
+}
+
+#[derive(rlp_derive::RlpEncodable, rlp_derive::RlpDecodable, Clone, PartialEq, Debug)]
+pub struct BlockBody {
+    pub transactions: Vec<TransactionV2>,
+    pub ommers: Vec<HeaderType>,
+}
+
+#[derive(rlp_derive::RlpEncodable, rlp_derive::RlpDecodable, Clone, PartialEq, Debug)]
+pub struct BlockBodiesMessage {
+    pub request_id: u64,
+    pub bodies: Vec<BlockBody>,
+}
+
 #[derive(rlp_derive::RlpEncodable, rlp_derive::RlpDecodable, Clone, PartialEq, Debug)]
 pub struct NewBlockMessage {
     pub block: Box<BlockType<TransactionV2>>,
@@ -72,6 +90,8 @@ pub enum Message {
     NewBlockHashes(NewBlockHashesMessage),
     GetBlockHeaders(GetBlockHeadersMessage),
     BlockHeaders(BlockHeadersMessage),
+    GetBlockBodies(GetBlockBodiesMessage),
+    BlockBodies(BlockBodiesMessage),
     NewBlock(NewBlockMessage),
     NewPooledTransactionHashes(NewPooledTransactionHashesMessage),
 }
@@ -82,6 +102,8 @@ impl Message {
             Message::NewBlockHashes(_) => EthMessageId::NewBlockHashes,
             Message::GetBlockHeaders(_) => EthMessageId::GetBlockHeaders,
             Message::BlockHeaders(_) => EthMessageId::BlockHeaders,
+            Message::GetBlockBodies(_) => EthMessageId::GetBlockBodies,
+            Message::BlockBodies(_) => EthMessageId::BlockBodies,
             Message::NewBlock(_) => EthMessageId::NewBlock,
             Message::NewPooledTransactionHashes(_) => EthMessageId::NewPooledTransactionHashes,
         }
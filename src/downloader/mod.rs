@@ -8,10 +8,10 @@ pub mod opts;
 pub mod sentry_address;
 pub mod sentry_client;
 pub mod sentry_client_impl;
-mod sentry_client_mock;
+pub(crate) mod sentry_client_mock;
 
 #[cfg(test)]
 mod downloader_tests;
-mod sentry_client_reactor;
+pub mod sentry_client_reactor;
 
 pub use self::downloader_impl::Downloader;
@@ -28,7 +28,7 @@ pub struct MessageFromPeer {
 }
 
 #[async_trait]
-pub trait SentryClient: Send {
+pub trait SentryClient: Send + Sync {
     async fn set_status(&mut self, status: Status) -> anyhow::Result<()>;
 
     //async fn penalize_peer(&mut self) -> anyhow::Result<()>;
@@ -18,6 +18,14 @@ impl SentryClientImpl {
         let client = grpc_sentry::sentry_client::SentryClient::connect(addr.addr).await?;
         Ok(SentryClientImpl { client })
     }
+
+    /// Creates a client that connects to the sentry on first request.
+    pub fn new_lazy(addr: SentryAddress) -> anyhow::Result<Self> {
+        let channel = tonic::transport::Endpoint::from(addr.addr).connect_lazy()?;
+        Ok(SentryClientImpl {
+            client: grpc_sentry::sentry_client::SentryClient::new(channel),
+        })
+    }
 }
 
 #[async_trait]
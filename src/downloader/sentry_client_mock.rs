@@ -3,13 +3,17 @@ use crate::downloader::{
     sentry_client::{MessageFromPeer, PeerFilter, SentryClient, Status},
 };
 use futures_core::Stream;
-use std::{collections::HashSet, pin::Pin};
+use std::{
+    collections::{HashSet, VecDeque},
+    pin::Pin,
+};
 use tokio::sync::broadcast;
 use tokio_stream::{wrappers, StreamExt};
 
 pub struct SentryClientMock {
     message_sender: Option<broadcast::Sender<MessageFromPeer>>,
     message_receiver: Option<broadcast::Receiver<MessageFromPeer>>,
+    responses: Option<VecDeque<Message>>,
 }
 
 impl SentryClientMock {
@@ -18,6 +22,16 @@ impl SentryClientMock {
         SentryClientMock {
             message_sender: Some(message_sender),
             message_receiver: Some(message_receiver),
+            responses: None,
+        }
+    }
+
+    /// Replies to each sent message with the next scripted response.
+    /// Responses get the request id of the request they answer.
+    pub fn with_responses(responses: Vec<Message>) -> Self {
+        SentryClientMock {
+            responses: Some(responses.into()),
+            ..Self::new()
         }
     }
 
@@ -34,10 +48,36 @@ impl SentryClient for SentryClientMock {
 
     async fn send_message(
         &mut self,
-        _message: Message,
+        message: Message,
         _peer_filter: PeerFilter,
     ) -> anyhow::Result<u32> {
-        self.stop_receiving_messages();
+        let responses = match &mut self.responses {
+            Some(responses) => responses,
+            None => {
+                self.stop_receiving_messages();
+                return Ok(1);
+            }
+        };
+
+        if let Some(mut response) = responses.pop_front() {
+            match (&message, &mut response) {
+                (Message::GetBlockHeaders(request), Message::BlockHeaders(response)) => {
+                    response.request_id = request.request_id;
+                }
+                (Message::GetBlockBodies(request), Message::BlockBodies(response)) => {
+                    response.request_id = request.request_id;
+                }
+                _ => {}
+            }
+
+            if let Some(sender) = &self.message_sender {
+                let _ = sender.send(MessageFromPeer {
+                    message: response,
+                    from_peer_id: None,
+                });
+            }
+        }
+
         Ok(1)
     }
 
@@ -53,8 +93,10 @@ impl SentryClient for SentryClientMock {
         if let Some(receiver) = self.message_receiver.take() {
             let stream = wrappers::BroadcastStream::new(receiver)
                 .filter_map(|res| res.ok()) // ignore BroadcastStreamRecvError
+                // empty filter subscribes to all messages
                 .filter(move |message_from_peer| {
-                    filter_ids_set.contains(&message_from_peer.message.eth_id())
+                    filter_ids_set.is_empty()
+                        || filter_ids_set.contains(&message_from_peer.message.eth_id())
                 })
                 .map(Ok);
 
@@ -44,19 +44,88 @@ impl Collector {
         T: Table,
         C: MutableCursor<'tx, T>,
     {
+        self.load_entries(cursor, load_function, false).await
+    }
+
+    /// Same as `load`, but entries that sort after the last key in the table are appended instead of being searched for.
+    /// Should not be used with auto-dupsort tables.
+    pub async fn load_append<'tx, T, C>(&mut self, cursor: &mut C) -> anyhow::Result<()>
+    where
+        T: Table,
+        C: MutableCursor<'tx, T>,
+    {
+        self.load_entries(cursor, None, true).await
+    }
+
+    /// Consumes the collector, returning all collected entries sorted by key.
+    pub fn into_sorted(mut self) -> anyhow::Result<SortedEntries> {
+        self.buffer.sort_unstable();
+        if self.data_providers.is_empty() {
+            return Ok(SortedEntries {
+                buffer: self.buffer.into_iter(),
+                data_providers: vec![],
+                heap: BinaryHeap::new(),
+            });
+        }
+
+        if !self.buffer.is_empty() {
+            let current_id = self.data_providers.len();
+            self.data_providers.push(DataProvider::new(
+                std::mem::take(&mut self.buffer),
+                current_id,
+            )?);
+        }
+
+        let mut heap = BinaryHeap::new();
+        for (current_id, data_provider) in self.data_providers.iter_mut().enumerate() {
+            let (current_key, current_value) = data_provider.to_next()?;
+
+            heap.push(Reverse(Entry {
+                key: current_key,
+                value: current_value,
+                id: current_id,
+            }));
+        }
+
+        Ok(SortedEntries {
+            buffer: vec![].into_iter(),
+            data_providers: self.data_providers,
+            heap,
+        })
+    }
+
+    #[allow(clippy::type_complexity)]
+    async fn load_entries<'tx, T, C>(
+        &mut self,
+        cursor: &mut C,
+        load_function: Option<fn(&mut C, Vec<u8>, Vec<u8>)>,
+        append: bool,
+    ) -> anyhow::Result<()>
+    where
+        T: Table,
+        C: MutableCursor<'tx, T>,
+    {
+        // Keys greater than this one can be appended, inner `None` if the table is empty
+        let mut append_after = if append {
+            Some(cursor.last().await?.map(|(k, _)| k.to_vec()))
+        } else {
+            None
+        };
+
         // If only one data provider is found, then we we can write directly from memory to db without reading any files
         if self.data_providers.is_empty() {
             self.buffer.sort_unstable();
-            for entry in &self.buffer {
-                if let Some(f) = &load_function {
-                    (f)(cursor, entry.key.to_vec(), entry.value.to_vec());
-                } else {
-                    cursor
-                        .put(entry.key.as_slice(), entry.value.as_slice())
-                        .await?;
-                }
+            for entry in std::mem::take(&mut self.buffer) {
+                write_entry(
+                    cursor,
+                    entry.key,
+                    entry.value,
+                    load_function,
+                    &mut append_after,
+                )
+                .await?;
             }
-            self.buffer.clear();
+            self.buffer_size = 0;
             return Ok(());
         }
         // Flush buffer one more time
@@ -82,13 +151,14 @@ impl Collector {
 
         while let Some(e) = heap.pop() {
             let entry = e.0;
-            if let Some(f) = &load_function {
-                (f)(cursor, entry.key.to_vec(), entry.value.to_vec());
-            } else {
-                cursor
-                    .put(entry.key.as_slice(), entry.value.as_slice())
-                    .await?;
-            }
+            write_entry(
+                cursor,
+                entry.key,
+                entry.value,
+                load_function,
+                &mut append_after,
+            )
+            .await?;
             let (next_key, next_value) = self.data_providers[entry.id].to_next()?;
             if !next_key.is_empty() {
                 heap.push(Reverse(Entry {
@@ -102,6 +172,70 @@ impl Collector {
     }
 }
 
+/// Entries of a consumed collector, merged from memory and all of its data providers.
+pub struct SortedEntries {
+    buffer: std::vec::IntoIter<Entry>,
+    data_providers: Vec<DataProvider>,
+    heap: BinaryHeap<Reverse<Entry>>,
+}
+
+impl Iterator for SortedEntries {
+    type Item = anyhow::Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(entry) = self.buffer.next() {
+            return Some(Ok((entry.key, entry.value)));
+        }
+
+        let Reverse(entry) = self.heap.pop()?;
+        match self.data_providers[entry.id].to_next() {
+            Ok((next_key, next_value)) => {
+                if !next_key.is_empty() {
+                    self.heap.push(Reverse(Entry {
+                        key: next_key,
+                        value: next_value,
+                        id: entry.id,
+                    }));
+                }
+            }
+            Err(e) => return Some(Err(e)),
+        }
+
+        Some(Ok((entry.key, entry.value)))
+    }
+}
+
+async fn write_entry<'tx, T, C>(
+    cursor: &mut C,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    load_function: Option<fn(&mut C, Vec<u8>, Vec<u8>)>,
+    append_after: &mut Option<Option<Vec<u8>>>,
+) -> anyhow::Result<()>
+where
+    T: Table,
+    C: MutableCursor<'tx, T>,
+{
+    if let Some(f) = load_function {
+        (f)(cursor, key, value);
+        return Ok(());
+    }
+
+    if let Some(last_key) = append_after {
+        if last_key
+            .as_ref()
+            .map(|last_key| key > *last_key)
+            .unwrap_or(true)
+        {
+            cursor.append(&key, &value).await?;
+            *last_key = Some(key);
+            return Ok(());
+        }
+    }
+
+    cursor.put(&key, &value).await
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -185,4 +319,72 @@ mod tests {
             }
         }
     }
+
+    #[tokio::test]
+    async fn append_after_existing_keys() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+        let mut cursor = tx.mutable_cursor(&tables::HeaderNumber).await.unwrap();
+
+        for key in [2_u64, 4] {
+            cursor
+                .put(&key.to_be_bytes(), &key.to_be_bytes())
+                .await
+                .unwrap();
+        }
+
+        // Keys below the last one have to be put, the rest are appended
+        let mut collector = Collector::new(OPTIMAL_BUFFER_CAPACITY);
+        for key in [6_u64, 1, 3, 5] {
+            collector.collect(Entry {
+                key: key.to_be_bytes().to_vec(),
+                value: (key * 10).to_be_bytes().to_vec(),
+                id: 0,
+            });
+        }
+        collector.load_append(&mut cursor).await.unwrap();
+
+        for (key, value) in [(1_u64, 10_u64), (2, 2), (3, 30), (4, 4), (5, 50), (6, 60)] {
+            assert_eq!(
+                value.to_be_bytes().to_vec(),
+                tx.get(&tables::HeaderNumber, &key.to_be_bytes())
+                    .await
+                    .unwrap()
+                    .unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn sorted_entries() {
+        let mut rng = rand::thread_rng();
+        let range = Uniform::new(0, 1000_u64);
+        for capacity in [1000, OPTIMAL_BUFFER_CAPACITY] {
+            let mut entries: Vec<Entry> = (0..10000)
+                .map(|_| Entry {
+                    key: rng.sample(&range).to_be_bytes().to_vec(),
+                    value: rng.sample(&range).to_be_bytes().to_vec(),
+                    id: 0,
+                })
+                .collect();
+            let mut collector = Collector::new(capacity);
+            for entry in entries.clone() {
+                collector.collect(entry);
+            }
+
+            entries.sort_unstable();
+            let sorted = collector
+                .into_sorted()
+                .unwrap()
+                .collect::<anyhow::Result<Vec<_>>>()
+                .unwrap();
+            assert_eq!(
+                sorted,
+                entries
+                    .into_iter()
+                    .map(|entry| (entry.key, entry.value))
+                    .collect::<Vec<_>>()
+            );
+        }
+    }
 }
@@ -0,0 +1,9 @@
+//! Block execution, limited to value transfers until an EVM is wired in.
+//!
+//! Contract creation and calls to accounts with code fail with `ExecutionError::ContractExecutionNotSupported`,
+//! so no logs are emitted and only top-level calls are traced. Transactions of EIP-1559 type fail with
+//! `ExecutionError::UnsupportedTransactionType`, and the DAO fork state change is not applied.
+
+mod processor;
+
+pub use self::processor::*;
@@ -0,0 +1,391 @@
+use crate::{
+    accessors::chain,
+    common,
+    models::{Account, CallTrace, ChainConfig, Receipt},
+    state::{Buffer, StateReader, StateWriter},
+    MutableTransaction,
+};
+use ethereum::{Header, TransactionAction, TransactionV2};
+use ethereum_types::{Address, U256};
+use std::collections::{BTreeMap, HashMap};
+use thiserror::Error;
+
+const G_TRANSACTION: u64 = 21_000;
+const G_TX_DATA_ZERO: u64 = 4;
+const G_TX_DATA_NON_ZERO_FRONTIER: u64 = 68;
+const G_TX_DATA_NON_ZERO_ISTANBUL: u64 = 16;
+const G_ACCESS_LIST_ADDRESS: u64 = 2_400;
+const G_ACCESS_LIST_STORAGE_KEY: u64 = 1_900;
+
+#[derive(Error, Debug)]
+pub enum ExecutionError {
+    #[error("Block {block} has {transactions} transactions but {senders} senders")]
+    SendersMismatch {
+        block: u64,
+        transactions: usize,
+        senders: usize,
+    },
+    #[error("Transaction {index} in block {block} has nonce {got}, expected {expected}")]
+    WrongNonce {
+        block: u64,
+        index: usize,
+        expected: u64,
+        got: U256,
+    },
+    #[error("Sender of transaction {index} in block {block} has insufficient funds")]
+    InsufficientFunds { block: u64, index: usize },
+    #[error("Transaction {index} in block {block} has gas limit below intrinsic gas")]
+    IntrinsicGasTooLow { block: u64, index: usize },
+    #[error("Transaction {index} in block {block} exceeds block gas limit")]
+    BlockGasLimitExceeded { block: u64, index: usize },
+    #[error("Transaction {index} in block {block} requires contract execution, which is not supported yet")]
+    ContractExecutionNotSupported { block: u64, index: usize },
+    #[error("Transaction {index} in block {block} is of unsupported type")]
+    UnsupportedTransactionType { block: u64, index: usize },
+    #[error("Block {block} gas used mismatch: header says {expected}, executed {got}")]
+    WrongBlockGas {
+        block: u64,
+        expected: U256,
+        got: u64,
+    },
+}
+
+#[derive(Debug)]
+struct AccountEntry {
+    original: Option<Account>,
+    current: Option<Account>,
+}
+
+/// Account state and call traces of the block being executed, changes are buffered until the block is done.
+struct IntraBlockState<'b, 'db: 'tx, 'tx, Tx: MutableTransaction<'db>> {
+    reader: &'b Buffer<'db, 'tx, Tx>,
+    accounts: HashMap<Address, AccountEntry>,
+    call_traces: BTreeMap<Address, CallTrace>,
+}
+
+impl<'b, 'db: 'tx, 'tx, Tx: MutableTransaction<'db>> IntraBlockState<'b, 'db, 'tx, Tx> {
+    fn new(reader: &'b Buffer<'db, 'tx, Tx>) -> Self {
+        Self {
+            reader,
+            accounts: Default::default(),
+            call_traces: Default::default(),
+        }
+    }
+
+    /// Records top-level call of a transaction. Internal call frames are not traced until contract execution is supported.
+    fn trace_call(&mut self, from: Address, to: Address) {
+        self.call_trace(from).from = true;
+        self.call_trace(to).to = true;
+    }
+
+    /// Block and ommer rewards mark their recipients as call destinations, like in Erigon's call trace set.
+    fn trace_reward(&mut self, address: Address) {
+        self.call_trace(address).to = true;
+    }
+
+    fn call_trace(&mut self, address: Address) -> &mut CallTrace {
+        self.call_traces.entry(address).or_insert(CallTrace {
+            address,
+            from: false,
+            to: false,
+        })
+    }
+
+    async fn account(&mut self, address: Address) -> anyhow::Result<&mut Option<Account>> {
+        if !self.accounts.contains_key(&address) {
+            let original = self.reader.read_account_data(address).await?;
+            self.accounts.insert(
+                address,
+                AccountEntry {
+                    current: original.clone(),
+                    original,
+                },
+            );
+        }
+
+        Ok(&mut self.accounts.get_mut(&address).unwrap().current)
+    }
+
+    async fn add_balance(&mut self, address: Address, amount: U256) -> anyhow::Result<()> {
+        self.account(address)
+            .await?
+            .get_or_insert_with(Account::default)
+            .balance += amount;
+
+        Ok(())
+    }
+
+    /// EIP-161: touched accounts that end up empty are removed from the state.
+    async fn remove_if_empty(&mut self, address: Address) -> anyhow::Result<()> {
+        let account = self.account(address).await?;
+        if account
+            .as_ref()
+            .map(|acc| acc.nonce == 0 && acc.balance.is_zero() && !has_code(acc))
+            .unwrap_or(false)
+        {
+            *account = None;
+        }
+
+        Ok(())
+    }
+}
+
+async fn write_accounts<W: StateWriter + Send>(
+    writer: &mut W,
+    accounts: HashMap<Address, AccountEntry>,
+) -> anyhow::Result<()> {
+    let mut accounts = accounts.into_iter().collect::<Vec<_>>();
+    accounts.sort_unstable_by_key(|(address, _)| *address);
+
+    for (address, AccountEntry { original, current }) in accounts {
+        match (original, current) {
+            (original, Some(current)) => {
+                if original.as_ref() != Some(&current) {
+                    let original = original.unwrap_or(Account {
+                        initialised: false,
+                        ..Default::default()
+                    });
+                    writer
+                        .update_account_data(address, &original, &current)
+                        .await?;
+                }
+            }
+            (Some(original), None) => writer.delete_account(address, &original).await?,
+            (None, None) => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn has_code(account: &Account) -> bool {
+    account
+        .code_hash
+        .map(|code_hash| code_hash != common::EMPTY_HASH)
+        .unwrap_or(false)
+}
+
+fn intrinsic_gas(
+    config: &ChainConfig,
+    block_number: u64,
+    input: &[u8],
+    access_list_gas: u64,
+) -> u64 {
+    let non_zero_gas = if config.is_istanbul(block_number) {
+        G_TX_DATA_NON_ZERO_ISTANBUL
+    } else {
+        G_TX_DATA_NON_ZERO_FRONTIER
+    };
+
+    input
+        .iter()
+        .fold(G_TRANSACTION + access_list_gas, |gas, &b| {
+            gas + if b == 0 { G_TX_DATA_ZERO } else { non_zero_gas }
+        })
+}
+
+async fn execute_transaction<'db: 'tx, 'tx, Tx: MutableTransaction<'db>>(
+    state: &mut IntraBlockState<'_, 'db, 'tx, Tx>,
+    config: &ChainConfig,
+    header: &Header,
+    index: usize,
+    transaction: &TransactionV2,
+    sender: Address,
+    block_gas_used: u64,
+) -> anyhow::Result<u64> {
+    let block = header.number.as_u64();
+
+    let (nonce, gas_price, gas_limit, action, value, input, access_list_gas) = match transaction {
+        TransactionV2::Legacy(t) => (
+            t.nonce,
+            t.gas_price,
+            t.gas_limit,
+            &t.action,
+            t.value,
+            &t.input,
+            0,
+        ),
+        TransactionV2::EIP2930(t) => (
+            t.nonce,
+            t.gas_price,
+            t.gas_limit,
+            &t.action,
+            t.value,
+            &t.input,
+            t.access_list
+                .iter()
+                .map(|item| {
+                    G_ACCESS_LIST_ADDRESS + item.slots.len() as u64 * G_ACCESS_LIST_STORAGE_KEY
+                })
+                .sum(),
+        ),
+        TransactionV2::EIP1559(_) => {
+            return Err(ExecutionError::UnsupportedTransactionType { block, index }.into())
+        }
+    };
+
+    let to = match action {
+        TransactionAction::Call(to) => *to,
+        TransactionAction::Create => {
+            return Err(ExecutionError::ContractExecutionNotSupported { block, index }.into())
+        }
+    };
+
+    if U256::from(block_gas_used).saturating_add(gas_limit) > header.gas_limit {
+        return Err(ExecutionError::BlockGasLimitExceeded { block, index }.into());
+    }
+
+    let gas_used = intrinsic_gas(config, block, input, access_list_gas);
+    if gas_limit < gas_used.into() {
+        return Err(ExecutionError::IntrinsicGasTooLow { block, index }.into());
+    }
+
+    let sender_account = state.account(sender).await?.clone().unwrap_or_default();
+    if U256::from(sender_account.nonce) != nonce {
+        return Err(ExecutionError::WrongNonce {
+            block,
+            index,
+            expected: sender_account.nonce,
+            got: nonce,
+        }
+        .into());
+    }
+
+    let max_cost = gas_limit
+        .checked_mul(gas_price)
+        .and_then(|fee| fee.checked_add(value));
+    if max_cost
+        .map(|cost| sender_account.balance < cost)
+        .unwrap_or(true)
+    {
+        return Err(ExecutionError::InsufficientFunds { block, index }.into());
+    }
+
+    if state
+        .account(to)
+        .await?
+        .as_ref()
+        .map(has_code)
+        .unwrap_or(false)
+    {
+        return Err(ExecutionError::ContractExecutionNotSupported { block, index }.into());
+    }
+
+    state.trace_call(sender, to);
+
+    let fee = U256::from(gas_used) * gas_price;
+    {
+        let sender_account = state
+            .account(sender)
+            .await?
+            .get_or_insert_with(Account::default);
+        sender_account.nonce += 1;
+        sender_account.balance -= fee + value;
+    }
+    state.add_balance(to, value).await?;
+    state.add_balance(header.beneficiary, fee).await?;
+
+    if config.is_eip158(block) {
+        for &address in &[sender, to, header.beneficiary] {
+            state.remove_if_empty(address).await?;
+        }
+    }
+
+    Ok(gas_used)
+}
+
+async fn apply_rewards<'db: 'tx, 'tx, Tx: MutableTransaction<'db>>(
+    state: &mut IntraBlockState<'_, 'db, 'tx, Tx>,
+    config: &ChainConfig,
+    header: &Header,
+    ommers: &[Header],
+) -> anyhow::Result<()> {
+    let block = header.number.as_u64();
+    let block_reward = config.block_reward(block);
+
+    let mut miner_reward = block_reward;
+    for ommer in ommers {
+        let ommer_reward =
+            U256::from((8 + ommer.number.as_u64()).saturating_sub(block)) * block_reward / 8;
+        state.add_balance(ommer.beneficiary, ommer_reward).await?;
+        state.trace_reward(ommer.beneficiary);
+        miner_reward += block_reward / 32;
+    }
+
+    state.add_balance(header.beneficiary, miner_reward).await?;
+    state.trace_reward(header.beneficiary);
+
+    Ok(())
+}
+
+/// Executes block's transactions on top of the buffered state, then buffers the resulting state and changesets and writes receipts
+/// with their logs and call traces.
+///
+/// Only value transfers to accounts without code are supported for now.
+pub async fn execute_block<'db: 'tx, 'tx, Tx: MutableTransaction<'db>>(
+    buffer: &mut Buffer<'db, 'tx, Tx>,
+    config: &ChainConfig,
+    header: &Header,
+    ommers: &[Header],
+    transactions: &[TransactionV2],
+    senders: &[Address],
+) -> anyhow::Result<()> {
+    let block = header.number.as_u64();
+
+    if transactions.len() != senders.len() {
+        return Err(ExecutionError::SendersMismatch {
+            block,
+            transactions: transactions.len(),
+            senders: senders.len(),
+        }
+        .into());
+    }
+
+    let mut state = IntraBlockState::new(buffer);
+
+    let mut gas_used = 0;
+    let mut receipts = Vec::with_capacity(transactions.len());
+    for (index, (transaction, &sender)) in transactions.iter().zip(senders).enumerate() {
+        gas_used += execute_transaction(
+            &mut state,
+            config,
+            header,
+            index,
+            transaction,
+            sender,
+            gas_used,
+        )
+        .await?;
+        // Value transfers always succeed and emit no logs
+        receipts.push(Receipt {
+            success: true,
+            cumulative_gas_used: gas_used,
+            logs: vec![],
+        });
+    }
+
+    if header.gas_used != gas_used.into() {
+        return Err(ExecutionError::WrongBlockGas {
+            block,
+            expected: header.gas_used,
+            got: gas_used,
+        }
+        .into());
+    }
+
+    apply_rewards(&mut state, config, header, ommers).await?;
+
+    let IntraBlockState {
+        accounts,
+        call_traces,
+        ..
+    } = state;
+    let call_traces = call_traces.into_values().collect::<Vec<_>>();
+
+    buffer.begin_block(block);
+    write_accounts(buffer, accounts).await?;
+
+    let tx = buffer.tx();
+    chain::receipt::write(tx, block, &receipts).await?;
+    chain::call_trace::write(tx, block, &call_traces).await
+}
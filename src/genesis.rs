@@ -0,0 +1,247 @@
+//! Genesis blocks of the chains the node syncs, written into a fresh database before the first sync.
+//!
+//! Only the genesis header and chain config are written, allocations of the genesis state are not imported yet.
+
+use crate::{
+    accessors::{chain, metadata},
+    common,
+    models::ChainConfig,
+    trie::EMPTY_ROOT,
+    MutableTransaction,
+};
+use ethereum::Header;
+use ethereum_types::{Address, Bloom, H256, H64, U256};
+use hex_literal::hex;
+use thiserror::Error;
+use tracing::*;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum GenesisError {
+    #[error("Genesis block of chain {0} is not known")]
+    UnknownChain(String),
+    #[error("Genesis block of chain {chain} has hash {got:?}, expected {expected:?}")]
+    HashMismatch {
+        chain: String,
+        expected: H256,
+        got: H256,
+    },
+}
+
+const MAINNET_CONFIG: &str = r#"{"chainId":1,"homesteadBlock":1150000,"daoForkBlock":1920000,"daoForkSupport":true,"eip150Block":2463000,"eip150Hash":"0x2086799aeebeae135c246c65021c82b4e15a2c451340993aacfd2751886514f0","eip155Block":2675000,"eip158Block":2675000,"byzantiumBlock":4370000,"constantinopleBlock":7280000,"petersburgBlock":7280000,"istanbulBlock":9069000,"muirGlacierBlock":9200000,"londonBlock":12965000,"arrowGlacierBlock":13773000}"#;
+const ROPSTEN_CONFIG: &str = r#"{"chainId":3,"homesteadBlock":0,"daoForkSupport":false,"eip150Block":0,"eip150Hash":"0x41941023680923e0fe4d74a34bdac8141f2540e3ae90623718e47d66d1ca4a2d","eip155Block":10,"eip158Block":10,"byzantiumBlock":1700000,"constantinopleBlock":4230000,"petersburgBlock":4939394,"istanbulBlock":6485846,"muirGlacierBlock":7117117,"londonBlock":10499401}"#;
+const RINKEBY_CONFIG: &str = r#"{"chainId":4,"homesteadBlock":1,"daoForkSupport":false,"eip150Block":2,"eip150Hash":"0x9b095b36c15eaf13044373aef8ee0bd3a382a5abb92e402afa44b8249c3a90e9","eip155Block":3,"eip158Block":3,"byzantiumBlock":1035301,"constantinopleBlock":3660663,"petersburgBlock":4321234,"istanbulBlock":5435345}"#;
+const GOERLI_CONFIG: &str = r#"{"chainId":5,"homesteadBlock":0,"daoForkSupport":false,"eip150Block":0,"eip155Block":0,"eip158Block":0,"byzantiumBlock":0,"constantinopleBlock":0,"petersburgBlock":0,"istanbulBlock":1561651}"#;
+
+fn header(
+    state_root: H256,
+    difficulty: u64,
+    gas_limit: u64,
+    timestamp: u64,
+    extra_data: &[u8],
+    nonce: u64,
+) -> Header {
+    Header {
+        parent_hash: H256::zero(),
+        ommers_hash: common::EMPTY_LIST_HASH,
+        beneficiary: Address::zero(),
+        state_root,
+        transactions_root: EMPTY_ROOT,
+        receipts_root: EMPTY_ROOT,
+        logs_bloom: Bloom::zero(),
+        difficulty: difficulty.into(),
+        number: U256::zero(),
+        gas_limit: gas_limit.into(),
+        gas_used: U256::zero(),
+        timestamp,
+        extra_data: extra_data.to_vec(),
+        mix_hash: H256::zero(),
+        nonce: H64::from_low_u64_be(nonce),
+    }
+}
+
+/// Genesis header and chain config of a chain known by name.
+pub fn genesis(chain: &str) -> anyhow::Result<(Header, ChainConfig)> {
+    let (header, config) = match chain {
+        "mainnet" => (
+            header(
+                H256(hex!(
+                    "d7f8974fb5ac78d9ac099b9ad5018bedc2ce0a72dad1827a1709da30580f0544"
+                )),
+                0x4_0000_0000,
+                5000,
+                0,
+                &hex!("11bbe8db4e347b4e8c937c1c8370e4b5ed33adb3db69cbdb7a38e1e50b1b82fa"),
+                0x42,
+            ),
+            MAINNET_CONFIG,
+        ),
+        "ropsten" => (
+            header(
+                H256(hex!(
+                    "217b0bbcfb72e2d57e28f33cb361b9983513177755dc3f33ce3e7022ed62b77b"
+                )),
+                0x10_0000,
+                0x100_0000,
+                0,
+                &hex!("3535353535353535353535353535353535353535353535353535353535353535"),
+                0x42,
+            ),
+            ROPSTEN_CONFIG,
+        ),
+        "rinkeby" => (
+            header(
+                H256(hex!(
+                    "53580584816f617295ea26c0e17641e0120cab2f0a8ffb53a866fd53aa8e8c2d"
+                )),
+                1,
+                4_700_000,
+                1_492_009_146,
+                &hex!("52657370656374206d7920617574686f7269746168207e452e436172746d616e42eb768f2244c8811c63729a21a3569731535f067ffc57839b00206d1ad20c69a1981b489f772031b279182d99e65703f0076e4812653aab85fca0f00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000"),
+                0,
+            ),
+            RINKEBY_CONFIG,
+        ),
+        "goerli" => (
+            header(
+                H256(hex!(
+                    "5d6cded585e73c4e322c30c2f782a336316f17dd85a4863b9d838d2d4b8b3008"
+                )),
+                1,
+                0xa0_0000,
+                1_548_854_791,
+                &hex!("22466c6578692069732061207468696e6722202d204166726900000000000000e0a2bd4258d2768837baa26a28fe71dc079f84c70000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000"),
+                0,
+            ),
+            GOERLI_CONFIG,
+        ),
+        other => return Err(GenesisError::UnknownChain(other.to_string()).into()),
+    };
+
+    Ok((header, serde_json::from_str(config)?))
+}
+
+/// Writes genesis block of `chain` along with its chain config, unless the database already has a genesis block.
+/// Returns whether the genesis block has been written.
+pub async fn initialize<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(
+    tx: &'tx RwTx,
+    chain: &str,
+    expected_hash: H256,
+) -> anyhow::Result<bool> {
+    if chain::canonical_hash::read(tx, 0).await?.is_some() {
+        return Ok(false);
+    }
+
+    let (header, config) = genesis(chain)?;
+    let hash = header.hash();
+    if hash != expected_hash {
+        return Err(GenesisError::HashMismatch {
+            chain: chain.to_string(),
+            expected: expected_hash,
+            got: hash,
+        }
+        .into());
+    }
+
+    chain::header::write(tx, hash, 0, &header).await?;
+    chain::header_number::write(tx, hash, 0).await?;
+    chain::canonical_hash::write(tx, 0, hash).await?;
+    chain::td::write(tx, hash, 0, header.difficulty).await?;
+    metadata::write_chain_config(tx, hash, &config).await?;
+
+    info!(chain, ?hash, "Wrote genesis block");
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        downloader::{
+            chain_config::ChainsConfig,
+            messages::{BlockHeadersMessage, Message},
+            sentry_client_mock::SentryClientMock,
+            sentry_client_reactor::SentryClientReactor,
+        },
+        kv::traits::MutableKV,
+        new_mem_database,
+        stagedsync::stage::{ExecOutput, Stage, StageInput},
+        stages::HeaderDownload,
+    };
+    use std::sync::Arc;
+
+    #[test]
+    fn known_genesis_hashes() {
+        for (name, config) in ChainsConfig::new().unwrap().0 {
+            let (header, _) = genesis(&name).unwrap();
+            assert_eq!(header.hash(), config.genesis_block_hash, "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn fresh_database_starts_header_download() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let genesis_hash = ChainsConfig::new().unwrap().0["mainnet"].genesis_block_hash;
+        metadata::ensure_chain(&tx, "mainnet").await.unwrap();
+        assert!(initialize(&tx, "mainnet", genesis_hash).await.unwrap());
+        assert!(!initialize(&tx, "mainnet", genesis_hash).await.unwrap());
+        assert_eq!(
+            metadata::read_genesis_chain_config(&tx)
+                .await
+                .unwrap()
+                .chain_id(),
+            Some(1)
+        );
+
+        let mut sentry =
+            SentryClientReactor::new(Box::new(SentryClientMock::with_responses(vec![
+                Message::BlockHeaders(BlockHeadersMessage {
+                    request_id: 0,
+                    headers: vec![],
+                }),
+            ])));
+        sentry.start();
+        let stage = HeaderDownload::new(Arc::new(sentry));
+
+        assert_eq!(
+            stage
+                .execute(
+                    &mut tx,
+                    StageInput {
+                        restarted: false,
+                        previous_stage: None,
+                        stage_progress: None,
+                    },
+                )
+                .await
+                .unwrap(),
+            ExecOutput::Progress {
+                stage_progress: 0,
+                done: true,
+                must_commit: false,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_wrong_genesis() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        let (header, _) = genesis("goerli").unwrap();
+        assert_eq!(
+            initialize(&tx, "mainnet", header.hash())
+                .await
+                .unwrap_err()
+                .downcast::<GenesisError>()
+                .unwrap(),
+            GenesisError::HashMismatch {
+                chain: "mainnet".to_string(),
+                expected: header.hash(),
+                got: genesis("mainnet").unwrap().0.hash(),
+            }
+        );
+        assert_eq!(chain::canonical_hash::read(&tx, 0).await.unwrap(), None);
+    }
+}
@@ -146,7 +146,24 @@ where
         traits::Transaction::cursor(self, table).await
     }
 
-    async fn get<'s, T: Table>(&'s self, table: &T, k: &[u8]) -> anyhow::Result<Option<Bytes<'s>>> {
+    async fn get<'s, T: Table>(&'s self, table: &T, k: &[u8]) -> anyhow::Result<Option<Bytes<'s>>>
+    where
+        'env: 's,
+    {
+        let name = table.db_name();
+        if tables::DUP_SORT_TABLES
+            .get(&name.as_ref())
+            .and_then(|dup| dup.as_ref())
+            .filter(|dup| k.len() == dup.from)
+            .is_some()
+        {
+            // Full key is split between key and value of the auto dupsort table
+            let mut cursor = traits::Transaction::cursor(self, table).await?;
+            return Ok(Cursor::<T>::seek_exact(&mut cursor, k)
+                .await?
+                .map(|(_, v)| v));
+        }
+
         Ok(Self::get(
             self,
             &self.open_db(Some(table.db_name().as_ref()))?,
@@ -325,6 +342,7 @@ where
         if let Some(&AutoDupSortConfig { from, to }) = tables::DUP_SORT_TABLES
             .get(&self.t.as_ref())
             .and_then(|dup| dup.as_ref())
+            .filter(|dup| key.len() == dup.from)
         {
             return Ok(self
                 .inner
@@ -548,3 +566,159 @@ where
         Ok(self.inner.put(&key, &value, WriteFlags::APPEND_DUP)?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        dbutils,
+        kv::{
+            tables,
+            traits::{MutableKV, KV},
+        },
+        new_mem_database, Cursor, MutableCursor, MutableTransaction, Transaction,
+    };
+    use ethereum_types::{Address, H256};
+
+    #[tokio::test]
+    async fn auto_dupsort_seek_exact() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        let address = Address::from_low_u64_be(0x1234);
+        let storage_key =
+            dbutils::plain_generate_composite_storage_key(address, 1, H256::from_low_u64_be(1));
+
+        let mut cursor = tx.mutable_cursor(&tables::PlainState).await.unwrap();
+        cursor.put(address.as_bytes(), b"account").await.unwrap();
+        cursor.put(&storage_key, b"storage").await.unwrap();
+
+        // Keys shorter than the auto dupsort key length are plain keys
+        assert_eq!(
+            cursor
+                .seek_exact(address.as_bytes())
+                .await
+                .unwrap()
+                .map(|(k, v)| (k.to_vec(), v.to_vec())),
+            Some((address.as_bytes().to_vec(), b"account".to_vec()))
+        );
+        assert_eq!(
+            cursor
+                .seek_exact(&storage_key)
+                .await
+                .unwrap()
+                .map(|(k, v)| (k.to_vec(), v.to_vec())),
+            Some((storage_key[..28].to_vec(), b"storage".to_vec()))
+        );
+        assert!(cursor
+            .seek_exact(Address::from_low_u64_be(0x5678).as_bytes())
+            .await
+            .unwrap()
+            .is_none());
+
+        // Full key only matches its own location, not the next one stored under the same prefix
+        cursor
+            .put(
+                &dbutils::plain_generate_composite_storage_key(
+                    address,
+                    1,
+                    H256::from_low_u64_be(3),
+                ),
+                b"other storage",
+            )
+            .await
+            .unwrap();
+        assert!(cursor
+            .seek_exact(&dbutils::plain_generate_composite_storage_key(
+                address,
+                1,
+                H256::from_low_u64_be(2)
+            ))
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn auto_dupsort_get() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        let address = Address::from_low_u64_be(0x1234);
+        let storage_key = |location| {
+            dbutils::plain_generate_composite_storage_key(
+                address,
+                1,
+                H256::from_low_u64_be(location),
+            )
+        };
+
+        tx.set(&tables::PlainState, address.as_bytes(), b"account")
+            .await
+            .unwrap();
+        tx.set(&tables::PlainState, &storage_key(1), b"storage")
+            .await
+            .unwrap();
+
+        // Plain key shorter than the auto dupsort key length
+        assert_eq!(
+            Transaction::get(&tx, &tables::PlainState, address.as_bytes())
+                .await
+                .unwrap()
+                .map(|v| v.to_vec()),
+            Some(b"account".to_vec())
+        );
+        // Full key, split between key and value in the database
+        assert_eq!(
+            Transaction::get(&tx, &tables::PlainState, &storage_key(1))
+                .await
+                .unwrap()
+                .map(|v| v.to_vec()),
+            Some(b"storage".to_vec())
+        );
+        assert!(Transaction::get(&tx, &tables::PlainState, &storage_key(2))
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn sequence() {
+        let db = new_mem_database().unwrap();
+
+        let tx = db.begin_mutable().await.unwrap();
+        assert_eq!(
+            tx.read_sequence(&tables::BlockTransaction).await.unwrap(),
+            0
+        );
+        assert_eq!(
+            tx.increment_sequence(&tables::BlockTransaction, 3)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            tx.increment_sequence(&tables::BlockTransaction, 2)
+                .await
+                .unwrap(),
+            3
+        );
+        assert_eq!(tx.increment_sequence(&tables::Receipt, 1).await.unwrap(), 0);
+        tx.commit().await.unwrap();
+
+        // Sequences are kept in their own table, not in the tables they number
+        let tx = db.begin(0).await.unwrap();
+        assert_eq!(
+            tx.read_sequence(&tables::BlockTransaction).await.unwrap(),
+            5
+        );
+        assert_eq!(tx.read_sequence(&tables::Receipt).await.unwrap(), 1);
+        assert!(tx
+            .cursor(&tables::BlockTransaction)
+            .await
+            .unwrap()
+            .first()
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
@@ -1,7 +1,7 @@
 use self::kv_client::*;
 use super::*;
 use crate::kv::traits;
-use anyhow::Context;
+use anyhow::{bail, Context};
 use async_stream::stream;
 use async_trait::async_trait;
 use bytes::Bytes;
@@ -16,6 +16,40 @@ use tokio_stream::StreamExt;
 use tonic::{body::BoxBody, client::GrpcService, codegen::Body, Streaming};
 use tracing::*;
 
+/// Write operations, sent in `Cursor` messages next to the ones of `Op`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MutableOp {
+    /// Must be the first message of the stream, starts a write transaction instead of a read-only one.
+    Begin = 100,
+    Put = 101,
+    Append = 102,
+    AppendDup = 103,
+    Delete = 104,
+    DeleteCurrent = 105,
+    DeleteCurrentDuplicates = 106,
+    Count = 107,
+    Commit = 108,
+    Abort = 109,
+}
+
+impl MutableOp {
+    pub fn from_i32(value: i32) -> Option<Self> {
+        Some(match value {
+            100 => Self::Begin,
+            101 => Self::Put,
+            102 => Self::Append,
+            103 => Self::AppendDup,
+            104 => Self::Delete,
+            105 => Self::DeleteCurrent,
+            106 => Self::DeleteCurrentDuplicates,
+            107 => Self::Count,
+            108 => Self::Commit,
+            109 => Self::Abort,
+            _ => return None,
+        })
+    }
+}
+
 /// Remote transaction type via gRPC interface.
 #[derive(Debug)]
 pub struct RemoteTransaction {
@@ -111,11 +145,29 @@ impl<'tx, T: Table> RemoteCursor<'tx, T> {
         op: Op,
         key: Option<&[u8]>,
         value: Option<&[u8]>,
+    ) -> anyhow::Result<Option<(Bytes<'tx>, Bytes<'tx>)>> {
+        self.raw_op(op as i32, key, value).await
+    }
+
+    async fn mutable_op(
+        &mut self,
+        op: MutableOp,
+        key: Option<&[u8]>,
+        value: Option<&[u8]>,
+    ) -> anyhow::Result<Option<(Bytes<'tx>, Bytes<'tx>)>> {
+        self.raw_op(op as i32, key, value).await
+    }
+
+    async fn raw_op(
+        &mut self,
+        op: i32,
+        key: Option<&[u8]>,
+        value: Option<&[u8]>,
     ) -> anyhow::Result<Option<(Bytes<'tx>, Bytes<'tx>)>> {
         let mut io = self.transaction.io.lock().await;
 
         io.0.send(Cursor {
-            op: op as i32,
+            op,
             cursor: self.id,
             k: key.map(|v| v.to_vec().into()).unwrap_or_default(),
             v: value.map(|v| v.to_vec().into()).unwrap_or_default(),
@@ -182,8 +234,99 @@ impl<'tx, T: DupSort> traits::CursorDupSort<'tx, T> for RemoteCursor<'tx, T> {
     }
 }
 
+#[async_trait]
+impl<'tx, T: Table> traits::MutableCursor<'tx, T> for RemoteCursor<'tx, T> {
+    async fn put(&mut self, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+        self.mutable_op(MutableOp::Put, Some(key), Some(value))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn append(&mut self, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+        self.mutable_op(MutableOp::Append, Some(key), Some(value))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete(&mut self, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+        self.mutable_op(MutableOp::Delete, Some(key), Some(value))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_current(&mut self) -> anyhow::Result<()> {
+        self.mutable_op(MutableOp::DeleteCurrent, None, None)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn count(&mut self) -> anyhow::Result<usize> {
+        bail!("count is not supported over remote KV")
+    }
+}
+
+#[async_trait]
+impl<'tx, T: DupSort> traits::MutableCursorDupSort<'tx, T> for RemoteCursor<'tx, T> {
+    async fn delete_current_duplicates(&mut self) -> anyhow::Result<()> {
+        self.mutable_op(MutableOp::DeleteCurrentDuplicates, None, None)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn append_dup(&mut self, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+        self.mutable_op(MutableOp::AppendDup, Some(key), Some(value))
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<'env> crate::MutableTransaction<'env> for RemoteTransaction {
+    type MutableCursor<'tx, T: Table> = RemoteCursor<'tx, T>;
+    type MutableCursorDupSort<'tx, T: DupSort> = RemoteCursor<'tx, T>;
+
+    async fn mutable_cursor<'tx, T>(
+        &'tx self,
+        table: &T,
+    ) -> anyhow::Result<Self::MutableCursor<'tx, T>>
+    where
+        'env: 'tx,
+        T: Table,
+    {
+        crate::Transaction::cursor(self, table).await
+    }
+
+    async fn mutable_cursor_dupsort<'tx, T>(
+        &'tx self,
+        table: &T,
+    ) -> anyhow::Result<Self::MutableCursorDupSort<'tx, T>>
+    where
+        'env: 'tx,
+        T: DupSort,
+    {
+        crate::Transaction::cursor(self, table).await
+    }
+
+    async fn set<T: Table>(&self, table: &T, k: &[u8], v: &[u8]) -> anyhow::Result<()> {
+        let mut cursor = crate::MutableTransaction::mutable_cursor(self, table).await?;
+
+        traits::MutableCursor::put(&mut cursor, k, v).await
+    }
+
+    async fn commit(self) -> anyhow::Result<()> {
+        self.finish(MutableOp::Commit).await
+    }
+}
+
 impl RemoteTransaction {
-    pub async fn open<C>(mut client: KvClient<C>) -> anyhow::Result<Self>
+    /// Opens read-only transaction.
+    pub async fn open<C>(client: KvClient<C>) -> anyhow::Result<Self>
     where
         C: GrpcService<BoxBody>,
         <C as GrpcService<BoxBody>>::ResponseBody: Send + Sync + 'static,
@@ -191,27 +334,21 @@ impl RemoteTransaction {
             Into<Box<(dyn std::error::Error + Send + Sync + 'static)>> + Send,
     {
         trace!("Opening transaction");
-        let (sender, mut rx) = channel(1);
-        let mut receiver = client
-            .tx(stream! {
-                // Just a dummy message, workaround for
-                // https://github.com/hyperium/tonic/issues/515
-                yield Cursor {
-                    op: Op::Open as i32,
-                    bucket_name: "DUMMY".into(),
-                    cursor: Default::default(),
-                    k: Default::default(),
-                    v: Default::default(),
-                };
-                while let Some(v) = rx.recv().await {
-                    yield v;
-                }
-            })
-            .await?
-            .into_inner();
-
+        // Just a dummy message, workaround for
         // https://github.com/hyperium/tonic/issues/515
-        let cursor = receiver.message().await?.context("no response")?.cursor_id;
+        // Table must exist, otherwise the server fails to open the cursor.
+        let (sender, mut receiver, reply) = Self::start(
+            client,
+            Cursor {
+                op: Op::Open as i32,
+                bucket_name: tables::DbInfo.db_name().to_string(),
+                cursor: Default::default(),
+                k: Default::default(),
+                v: Default::default(),
+            },
+        )
+        .await?;
+        let cursor = reply.cursor_id;
 
         sender
             .send(Cursor {
@@ -231,4 +368,170 @@ impl RemoteTransaction {
             io: Arc::new(AsyncMutex::new((sender, receiver))),
         })
     }
+
+    /// Opens write transaction, the server allows only one at a time.
+    /// Changes are applied on `commit`, dropping the transaction or calling `abort` discards them.
+    pub async fn open_mutable<C>(client: KvClient<C>) -> anyhow::Result<Self>
+    where
+        C: GrpcService<BoxBody>,
+        <C as GrpcService<BoxBody>>::ResponseBody: Send + Sync + 'static,
+        <<C as GrpcService<BoxBody>>::ResponseBody as Body>::Error:
+            Into<Box<(dyn std::error::Error + Send + Sync + 'static)>> + Send,
+    {
+        trace!("Opening write transaction");
+        let (sender, receiver, _) = Self::start(
+            client,
+            Cursor {
+                op: MutableOp::Begin as i32,
+                bucket_name: Default::default(),
+                cursor: Default::default(),
+                k: Default::default(),
+                v: Default::default(),
+            },
+        )
+        .await?;
+
+        Ok(Self {
+            io: Arc::new(AsyncMutex::new((sender, receiver))),
+        })
+    }
+
+    /// Discards changes of write transaction.
+    pub async fn abort(self) -> anyhow::Result<()> {
+        self.finish(MutableOp::Abort).await
+    }
+
+    async fn finish(self, op: MutableOp) -> anyhow::Result<()> {
+        let mut io = self.io.lock().await;
+
+        io.0.send(Cursor {
+            op: op as i32,
+            bucket_name: Default::default(),
+            cursor: Default::default(),
+            k: Default::default(),
+            v: Default::default(),
+        })
+        .await?;
+
+        io.1.message().await?.context("no response")?;
+
+        Ok(())
+    }
+
+    async fn start<C>(
+        mut client: KvClient<C>,
+        first: Cursor,
+    ) -> anyhow::Result<(Sender<Cursor>, Streaming<Pair>, Pair)>
+    where
+        C: GrpcService<BoxBody>,
+        <C as GrpcService<BoxBody>>::ResponseBody: Send + Sync + 'static,
+        <<C as GrpcService<BoxBody>>::ResponseBody as Body>::Error:
+            Into<Box<(dyn std::error::Error + Send + Sync + 'static)>> + Send,
+    {
+        let (sender, mut rx) = channel(1);
+        let mut receiver = client
+            .tx(stream! {
+                yield first;
+                while let Some(v) = rx.recv().await {
+                    yield v;
+                }
+            })
+            .await?
+            .into_inner();
+
+        // https://github.com/hyperium/tonic/issues/515
+        let reply = receiver.message().await?.context("no response")?;
+
+        Ok((sender, receiver, reply))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        kv::{server::KvServer, traits::MutableKV},
+        new_mem_database, Cursor as _, MutableCursor as _, MutableTransaction, Transaction,
+    };
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::Channel;
+
+    async fn client() -> KvClient<Channel> {
+        let db = new_mem_database().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(kv_server::KvServer::new(KvServer::new(Arc::new(db))))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        KvClient::connect(format!("http://{}", addr)).await.unwrap()
+    }
+
+    async fn read(tx: &RemoteTransaction, key: &[u8]) -> Option<Vec<u8>> {
+        tx.get(&tables::Code, key)
+            .await
+            .unwrap()
+            .map(|v| v.to_vec())
+    }
+
+    #[tokio::test]
+    async fn read_write_round_trip() {
+        let client = client().await;
+
+        let tx = RemoteTransaction::open_mutable(client.clone())
+            .await
+            .unwrap();
+        assert!(RemoteTransaction::open_mutable(client.clone())
+            .await
+            .is_err());
+
+        tx.set(&tables::Code, b"a", b"1").await.unwrap();
+        {
+            let mut cursor = tx.mutable_cursor(&tables::Code).await.unwrap();
+            cursor.append(b"b", b"2").await.unwrap();
+            cursor.append(b"c", b"3").await.unwrap();
+            cursor.delete(b"b", &[]).await.unwrap();
+            assert!(cursor.count().await.is_err());
+        }
+        assert_eq!(read(&tx, b"a").await, Some(b"1".to_vec()));
+        tx.commit().await.unwrap();
+
+        let tx = RemoteTransaction::open(client.clone()).await.unwrap();
+        {
+            let mut cursor = tx.cursor(&tables::Code).await.unwrap();
+            let mut entries = vec![];
+            let mut entry = cursor.first().await.unwrap();
+            while let Some((k, v)) = entry {
+                entries.push((k.to_vec(), v.to_vec()));
+                entry = cursor.next().await.unwrap();
+            }
+            assert_eq!(
+                entries,
+                vec![
+                    (b"a".to_vec(), b"1".to_vec()),
+                    (b"c".to_vec(), b"3".to_vec())
+                ]
+            );
+        }
+        assert!(tx.set(&tables::Code, b"d", b"4").await.is_err());
+        drop(tx);
+
+        // Writer is released once the transaction is committed or aborted
+        let tx = RemoteTransaction::open_mutable(client.clone())
+            .await
+            .unwrap();
+        tx.set(&tables::Code, b"d", b"4").await.unwrap();
+        assert_eq!(read(&tx, b"d").await, Some(b"4".to_vec()));
+        tx.abort().await.unwrap();
+
+        let tx = RemoteTransaction::open_mutable(client.clone())
+            .await
+            .unwrap();
+        assert_eq!(read(&tx, b"d").await, None);
+        assert_eq!(read(&tx, b"c").await, Some(b"3".to_vec()));
+    }
 }
@@ -1,23 +1,238 @@
-use super::traits::KV;
-use crate::{kv::CustomTable, Cursor, CursorDupSort, Transaction};
+use super::{
+    remote::MutableOp,
+    traits::{MutableKV, KV},
+};
+use crate::{
+    kv::CustomTable, Cursor, CursorDupSort, MutableCursor, MutableCursorDupSort,
+    MutableTransaction, Transaction,
+};
 use async_trait::async_trait;
 use bytes::Bytes;
 use ethereum_interfaces::{
-    remotekv::{Op, Pair, StateChange, StateChangeRequest},
+    remotekv::{self, Op, Pair, StateChange, StateChangeRequest},
     types::VersionReply,
 };
 use futures_core::Stream;
-use std::{convert::TryFrom, pin::Pin, sync::Arc};
-use tokio::sync::mpsc::channel;
+use std::{convert::TryFrom, future::Future, pin::Pin, sync::Arc};
+use tokio::sync::{
+    mpsc::{channel, Sender},
+    Mutex as AsyncMutex, OwnedMutexGuard,
+};
 use tokio_stream::StreamExt;
-use tonic::Response;
+use tonic::{Response, Streaming};
 
-pub struct KvServer<DB: KV + Send + Sync> {
+type Replies = Sender<Result<Pair, tonic::Status>>;
+
+pub struct KvServer<DB: MutableKV + Send + Sync> {
     env: Arc<DB>,
+    // Locked for the whole lifetime of the write transaction
+    writer: Arc<AsyncMutex<()>>,
+}
+
+impl<DB: MutableKV + Send + Sync> KvServer<DB> {
+    pub fn new(env: Arc<DB>) -> Self {
+        Self {
+            env,
+            writer: Default::default(),
+        }
+    }
+}
+
+fn internal(e: anyhow::Error) -> tonic::Status {
+    tonic::Status::internal(e.to_string())
+}
+
+fn get_cursor<C>(cursors: &mut [Option<C>], id: u32) -> Result<&mut C, tonic::Status> {
+    cursors
+        .get_mut(id as usize)
+        .ok_or_else(|| tonic::Status::invalid_argument("invalid cursor"))?
+        .as_mut()
+        .ok_or_else(|| tonic::Status::invalid_argument("cursor closed"))
+}
+
+fn pair(cursor_id: u32, entry: Option<(Bytes, Bytes)>) -> Pair {
+    let (k, v) = entry.unwrap_or_else(|| (Bytes::new(), Bytes::new()));
+
+    Pair {
+        k: k.as_ref().to_vec().into(),
+        v: v.as_ref().to_vec().into(),
+        cursor_id,
+    }
+}
+
+/// Handles cursor opening, closing and positioning requests, common to read-only and write transactions.
+async fn cursor_request<'tx, C, F, Fut>(
+    cursors: &mut Vec<Option<C>>,
+    c: remotekv::Cursor,
+    open: F,
+) -> Result<Pair, tonic::Status>
+where
+    C: CursorDupSort<'tx, CustomTable>,
+    F: FnOnce(CustomTable) -> Fut,
+    Fut: Future<Output = anyhow::Result<C>>,
+{
+    let cursor = get_cursor(cursors, c.cursor);
+    let entry = match Op::from_i32(c.op)
+        .ok_or_else(|| tonic::Status::invalid_argument(format!("invalid op: {}", c.op)))?
+    {
+        Op::First => cursor?.first().await.map_err(internal)?,
+        Op::Seek => cursor?.seek(&*c.k).await.map_err(internal)?,
+        Op::SeekBoth => cursor?
+            .seek_both_range(&*c.k, &*c.v)
+            .await
+            .map_err(internal)?
+            .map(|v| (Bytes::new(), v)),
+        Op::Current => cursor?.current().await.map_err(internal)?,
+        Op::Last => cursor?.last().await.map_err(internal)?,
+        Op::Next => cursor?.next().await.map_err(internal)?,
+        Op::NextDup => cursor?.next_dup().await.map_err(internal)?,
+        Op::NextNoDup => cursor?.next_no_dup().await.map_err(internal)?,
+        Op::Prev => cursor?.prev().await.map_err(internal)?,
+        Op::SeekExact => cursor?.seek_exact(&*c.k).await.map_err(internal)?,
+        Op::FirstDup | Op::LastDup | Op::PrevDup | Op::PrevNoDup | Op::SeekBothExact => {
+            return Err(tonic::Status::unimplemented("not implemented"));
+        }
+        Op::Open => {
+            let cursor = open(CustomTable::from(c.bucket_name))
+                .await
+                .map_err(internal)?;
+            cursors.push(Some(cursor));
+            let cursor_id = u32::try_from(cursors.len() - 1)
+                .map_err(|_| tonic::Status::internal("overflow"))?;
+
+            return Ok(Pair {
+                cursor_id,
+                ..Default::default()
+            });
+        }
+        Op::Close => {
+            if let Some(cursor) = cursors.get_mut(c.cursor as usize) {
+                *cursor = None;
+            }
+
+            return Ok(Pair {
+                cursor_id: c.cursor,
+                ..Default::default()
+            });
+        }
+    };
+
+    Ok(pair(c.cursor, entry))
+}
+
+async fn mutable_cursor_request<'tx, C>(
+    cursor: &mut C,
+    op: MutableOp,
+    c: &remotekv::Cursor,
+) -> Result<Pair, tonic::Status>
+where
+    C: MutableCursorDupSort<'tx, CustomTable>,
+{
+    match op {
+        MutableOp::Put => cursor.put(&*c.k, &*c.v).await,
+        MutableOp::Append => cursor.append(&*c.k, &*c.v).await,
+        MutableOp::AppendDup => cursor.append_dup(&*c.k, &*c.v).await,
+        MutableOp::Delete => cursor.delete(&*c.k, &*c.v).await,
+        MutableOp::DeleteCurrent => cursor.delete_current().await,
+        MutableOp::DeleteCurrentDuplicates => cursor.delete_current_duplicates().await,
+        // Not supported by MDBX cursors yet
+        MutableOp::Count => {
+            return Err(tonic::Status::unimplemented("not implemented"));
+        }
+        MutableOp::Begin | MutableOp::Commit | MutableOp::Abort => {
+            return Err(tonic::Status::invalid_argument(
+                "transaction already started",
+            ));
+        }
+    }
+    .map_err(internal)?;
+
+    Ok(Pair {
+        cursor_id: c.cursor,
+        ..Default::default()
+    })
+}
+
+async fn serve<'db, Tx: Transaction<'db>>(
+    dbtx: Tx,
+    first: remotekv::Cursor,
+    req: &mut Streaming<remotekv::Cursor>,
+    replies: &Replies,
+) {
+    let mut cursors = vec![];
+
+    let mut next = Some(first);
+    while let Some(c) = next {
+        let reply = if MutableOp::from_i32(c.op).is_some() {
+            Err(tonic::Status::failed_precondition(
+                "transaction is read-only",
+            ))
+        } else {
+            let dbtx = &dbtx;
+            cursor_request(&mut cursors, c, |table| async move {
+                dbtx.cursor_dup_sort(&table).await
+            })
+            .await
+        };
+        let _ = replies.send(reply).await;
+
+        next = req.try_next().await.ok().flatten();
+    }
+}
+
+async fn serve_mutable<'db, Tx: MutableTransaction<'db>>(
+    dbtx: Tx,
+    writer: OwnedMutexGuard<()>,
+    req: &mut Streaming<remotekv::Cursor>,
+    replies: &Replies,
+) {
+    let end = {
+        let mut cursors = vec![];
+
+        loop {
+            let c = match req.try_next().await {
+                Ok(Some(c)) => c,
+                // Client went away, transaction is aborted
+                _ => break None,
+            };
+
+            let reply = match MutableOp::from_i32(c.op) {
+                Some(MutableOp::Commit) => break Some(true),
+                Some(MutableOp::Abort) => break Some(false),
+                Some(op) => match get_cursor(&mut cursors, c.cursor) {
+                    Ok(cursor) => mutable_cursor_request(cursor, op, &c).await,
+                    Err(e) => Err(e),
+                },
+                None => {
+                    let dbtx = &dbtx;
+                    cursor_request(&mut cursors, c, |table| async move {
+                        dbtx.mutable_cursor_dupsort(&table).await
+                    })
+                    .await
+                }
+            };
+            let _ = replies.send(reply).await;
+        }
+    };
+
+    let reply = match end {
+        Some(true) => dbtx.commit().await.map_err(internal),
+        Some(false) | None => {
+            drop(dbtx);
+            Ok(())
+        }
+    };
+
+    // Next writer may start as soon as the client learns about the outcome
+    drop(writer);
+
+    if end.is_some() {
+        let _ = replies.send(reply.map(|_| Pair::default())).await;
+    }
 }
 
 #[async_trait]
-impl<DB: KV + Send + Sync> ethereum_interfaces::remotekv::kv_server::Kv for KvServer<DB> {
+impl<DB: MutableKV + Send + Sync> ethereum_interfaces::remotekv::kv_server::Kv for KvServer<DB> {
     type TxStream =
         Pin<Box<dyn Stream<Item = Result<Pair, tonic::Status>> + Send + Sync + 'static>>;
     type StateChangesStream = tokio_stream::Pending<Result<StateChange, tonic::Status>>;
@@ -33,142 +248,50 @@ impl<DB: KV + Send + Sync> ethereum_interfaces::remotekv::kv_server::Kv for KvSe
         }))
     }
 
+    /// First message of the stream selects the kind of transaction: `MutableOp::Begin` starts a write transaction, anything else a read-only one.
     async fn tx(
         &self,
         request: tonic::Request<tonic::Streaming<ethereum_interfaces::remotekv::Cursor>>,
     ) -> Result<Response<Self::TxStream>, tonic::Status> {
         let mut req = request.into_inner();
         let env = self.env.clone();
+        let writer = self.writer.clone();
         let (tx, rx) = channel(1);
         tokio::spawn(async move {
-            let dbtx = env.begin(0).await.unwrap();
-
-            let mut cursors: Vec<
-                Option<<<DB as KV>::Tx<'_> as Transaction>::CursorDupSort<'_, CustomTable>>,
-            > = vec![];
-
-            fn get_cursor<'db: 'tx, 'tx: 'cur, 'cur, DB: KV>(
-                cursors: &'cur mut Vec<
-                    Option<
-                        <<DB as KV>::Tx<'db> as Transaction<'db>>::CursorDupSort<'tx, CustomTable>,
-                    >,
-                >,
-                id: usize,
-            ) -> Result<
-                &'cur mut <<DB as KV>::Tx<'db> as Transaction<'db>>::CursorDupSort<
-                    'tx,
-                    CustomTable,
-                >,
-                tonic::Status,
-            > {
-                cursors
-                    .get_mut(id)
-                    .ok_or_else(|| tonic::Status::invalid_argument("invalid cursor"))?
-                    .as_mut()
-                    .ok_or_else(|| tonic::Status::invalid_argument("cursor closed"))
-            }
+            let first = match req.try_next().await {
+                Ok(Some(c)) => c,
+                _ => return,
+            };
+
+            if MutableOp::from_i32(first.op) == Some(MutableOp::Begin) {
+                let writer = match writer.try_lock_owned() {
+                    Ok(writer) => writer,
+                    Err(_) => {
+                        let _ = tx
+                            .send(Err(tonic::Status::failed_precondition(
+                                "another write transaction is in progress",
+                            )))
+                            .await;
+                        return;
+                    }
+                };
 
-            while let Some(c) = req.try_next().await.unwrap() {
-                let _ = tx
-                    .send(
-                        async {
-                            let cursor_id = c.cursor;
-                            let cid = cursor_id as usize;
-                            let (k, v) = match Op::from_i32(c.op).ok_or_else(|| {
-                                tonic::Status::invalid_argument(format!("invalid op: {}", c.op))
-                            })? {
-                                Op::First => get_cursor::<DB>(&mut cursors, cid)?
-                                    .first()
-                                    .await
-                                    .map_err(|e| tonic::Status::internal(e.to_string()))?,
-                                Op::FirstDup => {
-                                    return Err(tonic::Status::unimplemented("not implemented"));
-                                }
-                                Op::Seek => get_cursor::<DB>(&mut cursors, cid)?
-                                    .seek(&*c.k)
-                                    .await
-                                    .map_err(|e| tonic::Status::internal(e.to_string()))?,
-                                Op::SeekBoth => get_cursor::<DB>(&mut cursors, cid)?
-                                    .seek_both_range(&*c.k, &*c.v)
-                                    .await
-                                    .map_err(|e| tonic::Status::internal(e.to_string()))?
-                                    .map(|v| (Bytes::new(), v)),
-                                Op::Current => get_cursor::<DB>(&mut cursors, cid)?
-                                    .current()
-                                    .await
-                                    .map_err(|e| tonic::Status::internal(e.to_string()))?,
-                                Op::Last => get_cursor::<DB>(&mut cursors, cid)?
-                                    .last()
-                                    .await
-                                    .map_err(|e| tonic::Status::internal(e.to_string()))?,
-                                Op::LastDup => {
-                                    return Err(tonic::Status::unimplemented("not implemented"));
-                                }
-                                Op::Next => get_cursor::<DB>(&mut cursors, cid)?
-                                    .next()
-                                    .await
-                                    .map_err(|e| tonic::Status::internal(e.to_string()))?,
-                                Op::NextDup => get_cursor::<DB>(&mut cursors, cid)?
-                                    .next_dup()
-                                    .await
-                                    .map_err(|e| tonic::Status::internal(e.to_string()))?,
-                                Op::NextNoDup => get_cursor::<DB>(&mut cursors, cid)?
-                                    .next_no_dup()
-                                    .await
-                                    .map_err(|e| tonic::Status::internal(e.to_string()))?,
-                                Op::Prev => get_cursor::<DB>(&mut cursors, cid)?
-                                    .prev()
-                                    .await
-                                    .map_err(|e| tonic::Status::internal(e.to_string()))?,
-                                Op::PrevDup => {
-                                    return Err(tonic::Status::unimplemented("not implemented"));
-                                }
-                                Op::PrevNoDup => {
-                                    return Err(tonic::Status::unimplemented("not implemented"));
-                                }
-                                Op::SeekExact => get_cursor::<DB>(&mut cursors, cid)?
-                                    .seek_exact(&*c.k)
-                                    .await
-                                    .map_err(|e| tonic::Status::internal(e.to_string()))?,
-                                Op::SeekBothExact => {
-                                    return Err(tonic::Status::unimplemented("not implemented"));
-                                }
-                                Op::Open => {
-                                    let cursor = dbtx
-                                        .cursor_dup_sort(&CustomTable::from(c.bucket_name))
-                                        .await
-                                        .map_err(|e| tonic::Status::internal(e.to_string()))?;
-                                    cursors.push(Some(cursor));
-                                    let cursor_id = u32::try_from(cursors.len() - 1)
-                                        .map_err(|_| tonic::Status::internal("overflow"))?;
-
-                                    return Ok(Pair {
-                                        cursor_id,
-                                        ..Default::default()
-                                    });
-                                }
-                                Op::Close => {
-                                    if let Some(cursor) = cursors.get_mut(c.cursor as usize) {
-                                        *cursor = None;
-                                    }
-
-                                    return Ok(Pair {
-                                        cursor_id: c.cursor,
-                                        ..Default::default()
-                                    });
-                                }
-                            }
-                            .unwrap_or_else(|| (Bytes::new(), Bytes::new()));
-
-                            Ok(Pair {
-                                k: k.as_ref().to_vec().into(),
-                                v: v.as_ref().to_vec().into(),
-                                cursor_id,
-                            })
-                        }
-                        .await,
-                    )
-                    .await;
+                match env.begin_mutable().await {
+                    Ok(dbtx) => {
+                        let _ = tx.send(Ok(Pair::default())).await;
+                        serve_mutable(dbtx, writer, &mut req, &tx).await;
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(internal(e))).await;
+                    }
+                }
+            } else {
+                match env.begin(0).await {
+                    Ok(dbtx) => serve(dbtx, first, &mut req, &tx).await,
+                    Err(e) => {
+                        let _ = tx.send(Err(internal(e))).await;
+                    }
+                }
             }
         });
 
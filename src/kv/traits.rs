@@ -52,7 +52,7 @@ pub trait Transaction<'db>: Send + Sync + Debug + Sized {
         T: Table,
     {
         Ok(self
-            .cursor(table)
+            .cursor(&tables::Sequence)
             .await?
             .seek_exact(table.db_name().as_bytes())
             .await?
@@ -93,7 +93,7 @@ pub trait MutableTransaction<'db>: Transaction<'db> {
     where
         T: Table,
     {
-        let mut c = self.mutable_cursor::<T>(table).await?;
+        let mut c = self.mutable_cursor(&tables::Sequence).await?;
 
         let current_v = c
             .seek_exact(table.db_name().as_bytes())
@@ -109,6 +109,19 @@ pub trait MutableTransaction<'db>: Transaction<'db> {
 
         Ok(current_v)
     }
+
+    /// Sets the sequence of the table to `value`, e.g. to give out ids of unwound entries again.
+    async fn reset_sequence<T>(&self, table: &T, value: u64) -> anyhow::Result<()>
+    where
+        T: Table,
+    {
+        self.set(
+            &tables::Sequence,
+            table.db_name().as_bytes(),
+            &value.to_be_bytes(),
+        )
+        .await
+    }
 }
 
 #[async_trait]
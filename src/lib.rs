@@ -22,11 +22,15 @@ mod crypto;
 mod dbutils;
 pub mod downloader;
 pub mod etl;
+mod execution;
+pub mod genesis;
 pub mod kv;
 mod models;
+pub mod rpc;
 pub mod stagedsync;
 pub mod stages;
 mod state;
+mod trie;
 pub mod txdb;
 
 pub use changeset::*;
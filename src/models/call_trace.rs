@@ -0,0 +1,42 @@
+use crate::common;
+use ethereum_types::Address;
+
+const FROM_FLAG: u8 = 1;
+const TO_FLAG: u8 = 2;
+
+/// Participation of an address in call frames of a block, as stored in `CallTraceSet`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CallTrace {
+    pub address: Address,
+    /// Address is the caller of some frame.
+    pub from: bool,
+    /// Address is the callee of some frame.
+    pub to: bool,
+}
+
+impl CallTrace {
+    pub fn encode(&self) -> [u8; common::ADDRESS_LENGTH + 1] {
+        let mut out = [0; common::ADDRESS_LENGTH + 1];
+        out[..common::ADDRESS_LENGTH].copy_from_slice(self.address.as_bytes());
+        if self.from {
+            out[common::ADDRESS_LENGTH] |= FROM_FLAG;
+        }
+        if self.to {
+            out[common::ADDRESS_LENGTH] |= TO_FLAG;
+        }
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() != common::ADDRESS_LENGTH + 1 {
+            anyhow::bail!("invalid call trace length: {}", data.len());
+        }
+
+        let flags = data[common::ADDRESS_LENGTH];
+        Ok(Self {
+            address: Address::from_slice(&data[..common::ADDRESS_LENGTH]),
+            from: flags & FROM_FLAG != 0,
+            to: flags & TO_FLAG != 0,
+        })
+    }
+}
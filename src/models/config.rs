@@ -1,9 +1,11 @@
-use ethereum_types::H256;
-use serde::Deserialize;
+use ethereum_types::{H256, U256};
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeSet;
 
+const ETHER: u64 = 1_000_000_000_000_000_000;
+
 #[allow(non_snake_case)]
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ChainConfig {
     chain_id: Option<u64>,
@@ -19,11 +21,70 @@ pub struct ChainConfig {
     petersburg_block: Option<u64>,
     istanbul_block: Option<u64>,
     muir_glacier_block: Option<u64>,
+    london_block: Option<u64>,
+    arrow_glacier_block: Option<u64>,
     yoloV2_block: Option<u64>,
     ewasm_block: Option<u64>,
 }
 
+fn is_forked(fork_block: Option<u64>, block_number: u64) -> bool {
+    fork_block.map(|b| b <= block_number).unwrap_or(false)
+}
+
 impl ChainConfig {
+    pub fn chain_id(&self) -> Option<u64> {
+        self.chain_id
+    }
+
+    pub fn is_homestead(&self, block_number: u64) -> bool {
+        is_forked(self.homestead_block, block_number)
+    }
+
+    pub fn is_eip155(&self, block_number: u64) -> bool {
+        is_forked(self.eip_155_block, block_number)
+    }
+
+    pub fn is_eip158(&self, block_number: u64) -> bool {
+        is_forked(self.eip_158_block, block_number)
+    }
+
+    pub fn is_byzantium(&self, block_number: u64) -> bool {
+        is_forked(self.byzantium_block, block_number)
+    }
+
+    pub fn is_constantinople(&self, block_number: u64) -> bool {
+        is_forked(self.constantinople_block, block_number)
+    }
+
+    pub fn is_istanbul(&self, block_number: u64) -> bool {
+        is_forked(self.istanbul_block, block_number)
+    }
+
+    pub fn is_muir_glacier(&self, block_number: u64) -> bool {
+        is_forked(self.muir_glacier_block, block_number)
+    }
+
+    pub fn is_london(&self, block_number: u64) -> bool {
+        is_forked(self.london_block, block_number)
+    }
+
+    pub fn is_arrow_glacier(&self, block_number: u64) -> bool {
+        is_forked(self.arrow_glacier_block, block_number)
+    }
+
+    /// Static reward paid to the miner of the block, excluding ommer inclusion rewards.
+    pub fn block_reward(&self, block_number: u64) -> U256 {
+        let ether = if self.is_constantinople(block_number) {
+            2
+        } else if self.is_byzantium(block_number) {
+            3
+        } else {
+            5
+        };
+
+        U256::from(ether) * U256::from(ETHER)
+    }
+
     pub fn gather_forks(&self) -> BTreeSet<u64> {
         [
             self.homestead_block,
@@ -36,6 +97,8 @@ impl ChainConfig {
             self.petersburg_block,
             self.istanbul_block,
             self.muir_glacier_block,
+            self.london_block,
+            self.arrow_glacier_block,
             self.yoloV2_block,
             self.ewasm_block,
         ]
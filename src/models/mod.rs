@@ -1,5 +1,7 @@
 mod account;
 mod block;
+mod call_trace;
 mod config;
+mod receipt;
 
-pub use self::{account::*, block::*, config::*};
+pub use self::{account::*, block::*, call_trace::*, config::*, receipt::*};
@@ -0,0 +1,33 @@
+use ethereum_types::{Address, H256};
+use rlp_derive::{RlpDecodable, RlpEncodable};
+
+/// Receipt of an executed transaction along with its logs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Receipt {
+    pub success: bool,
+    pub cumulative_gas_used: u64,
+    pub logs: Vec<Log>,
+}
+
+/// Receipt as stored in `Receipt` table, logs of the transaction are kept in `TransactionLog`.
+#[derive(RlpDecodable, RlpEncodable, Clone, Debug, PartialEq)]
+pub struct ReceiptForStorage {
+    pub success: bool,
+    pub cumulative_gas_used: u64,
+}
+
+impl From<&Receipt> for ReceiptForStorage {
+    fn from(receipt: &Receipt) -> Self {
+        Self {
+            success: receipt.success,
+            cumulative_gas_used: receipt.cumulative_gas_used,
+        }
+    }
+}
+
+#[derive(RlpDecodable, RlpEncodable, Clone, Debug, PartialEq)]
+pub struct Log {
+    pub address: Address,
+    pub topics: Vec<H256>,
+    pub data: Vec<u8>,
+}
@@ -0,0 +1,285 @@
+use super::BlockNumber;
+use crate::{
+    accessors::chain,
+    common::{self, EMPTY_HASH},
+    dbutils,
+    kv::tables,
+    models::Account,
+    stagedsync::stages::EXECUTION,
+    state::{get_account_data_as_of, get_storage_as_of},
+    Transaction,
+};
+use ethereum::{EnvelopedEncodable, TransactionAction, TransactionV2};
+use ethereum_types::{Address, H256, U256};
+use serde_json::{json, Value};
+
+pub fn quantity(n: u64) -> String {
+    format!("{:#x}", n)
+}
+
+pub fn data(bytes: &[u8]) -> String {
+    format!("0x{}", hex::encode(bytes))
+}
+
+/// Latest block whose state is available, i.e. progress of execution.
+pub async fn block_number<'db, Tx: Transaction<'db>>(tx: &Tx) -> anyhow::Result<u64> {
+    Ok(EXECUTION.get_progress(tx).await?.unwrap_or(0))
+}
+
+pub async fn resolve<'db, Tx: Transaction<'db>>(
+    tx: &Tx,
+    block: BlockNumber,
+) -> anyhow::Result<u64> {
+    Ok(match block {
+        BlockNumber::Earliest => 0,
+        BlockNumber::Latest | BlockNumber::Pending => block_number(tx).await?,
+        BlockNumber::Number(number) => number,
+    })
+}
+
+/// Reads account as it was after execution of `block`.
+async fn read_account<'db: 'tx, 'tx, Tx: Transaction<'db>>(
+    tx: &'tx Tx,
+    address: Address,
+    block: u64,
+) -> anyhow::Result<Option<Account>> {
+    match get_account_data_as_of(tx, address, block + 1).await? {
+        Some(data) => Account::decode_for_storage(&data),
+        None => Ok(None),
+    }
+}
+
+pub async fn get_balance<'db: 'tx, 'tx, Tx: Transaction<'db>>(
+    tx: &'tx Tx,
+    address: Address,
+    block: u64,
+) -> anyhow::Result<U256> {
+    Ok(read_account(tx, address, block)
+        .await?
+        .map(|account| account.balance)
+        .unwrap_or_default())
+}
+
+pub async fn get_transaction_count<'db: 'tx, 'tx, Tx: Transaction<'db>>(
+    tx: &'tx Tx,
+    address: Address,
+    block: u64,
+) -> anyhow::Result<u64> {
+    Ok(read_account(tx, address, block)
+        .await?
+        .map(|account| account.nonce)
+        .unwrap_or_default())
+}
+
+pub async fn get_code<'db: 'tx, 'tx, Tx: Transaction<'db>>(
+    tx: &'tx Tx,
+    address: Address,
+    block: u64,
+) -> anyhow::Result<Vec<u8>> {
+    let account = match read_account(tx, address, block).await? {
+        Some(account) => account,
+        None => return Ok(vec![]),
+    };
+
+    let code_hash = match account.code_hash {
+        Some(code_hash) => Some(code_hash),
+        None if account.incarnation > 0 => tx
+            .get(
+                &tables::PlainCodeHash,
+                &dbutils::plain_generate_storage_prefix(address, account.incarnation),
+            )
+            .await?
+            .map(|code_hash| H256::from_slice(&code_hash)),
+        None => None,
+    };
+
+    Ok(match code_hash {
+        Some(code_hash) if code_hash != EMPTY_HASH => tx
+            .get(&tables::Code, code_hash.as_bytes())
+            .await?
+            .map(|code| code.to_vec())
+            .unwrap_or_default(),
+        _ => vec![],
+    })
+}
+
+pub async fn get_storage_at<'db: 'tx, 'tx, Tx: Transaction<'db>>(
+    tx: &'tx Tx,
+    address: Address,
+    key: H256,
+    block: u64,
+) -> anyhow::Result<H256> {
+    let incarnation = match read_account(tx, address, block).await? {
+        Some(account) => account.incarnation,
+        None => return Ok(H256::zero()),
+    };
+
+    Ok(get_storage_as_of(tx, address, incarnation, key, block + 1)
+        .await?
+        .map(|value| H256::from_uint(&U256::from_big_endian(&value)))
+        .unwrap_or_default())
+}
+
+pub async fn get_block_by_number<'db: 'tx, 'tx, Tx: Transaction<'db>>(
+    tx: &'tx Tx,
+    number: u64,
+    full: bool,
+) -> anyhow::Result<Option<Value>> {
+    match chain::canonical_hash::read(tx, number).await? {
+        Some(hash) => get_block(tx, hash, number, full).await,
+        None => Ok(None),
+    }
+}
+
+pub async fn get_block_by_hash<'db: 'tx, 'tx, Tx: Transaction<'db>>(
+    tx: &'tx Tx,
+    hash: H256,
+    full: bool,
+) -> anyhow::Result<Option<Value>> {
+    match chain::header_number::read(tx, hash).await? {
+        Some(number) => get_block(tx, hash, number, full).await,
+        None => Ok(None),
+    }
+}
+
+async fn get_block<'db: 'tx, 'tx, Tx: Transaction<'db>>(
+    tx: &'tx Tx,
+    hash: H256,
+    number: u64,
+    full: bool,
+) -> anyhow::Result<Option<Value>> {
+    let header = match chain::header::read(tx, hash, number).await? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+
+    let (transactions, uncles) = match chain::storage_body::read(tx, hash, number).await? {
+        Some(body) => {
+            let txs = chain::tx::read(tx, body.base_tx_id, body.tx_amount).await?;
+            let transactions = if full {
+                let senders = chain::tx_sender::read(tx, body.base_tx_id, body.tx_amount).await?;
+                txs.iter()
+                    .enumerate()
+                    .map(|(index, eth_tx)| {
+                        transaction_object(eth_tx, senders.get(index).copied(), hash, number, index)
+                    })
+                    .collect()
+            } else {
+                txs.iter().map(|eth_tx| json!(tx_hash(eth_tx))).collect()
+            };
+            let uncles = body.uncles.iter().map(|uncle| uncle.hash()).collect();
+
+            (transactions, uncles)
+        }
+        None => (vec![], vec![]),
+    };
+
+    let total_difficulty = chain::td::read(tx, hash, number).await?;
+
+    Ok(Some(json!({
+        "number": quantity(number),
+        "hash": hash,
+        "parentHash": header.parent_hash,
+        "nonce": header.nonce,
+        "mixHash": header.mix_hash,
+        "sha3Uncles": header.ommers_hash,
+        "logsBloom": header.logs_bloom,
+        "transactionsRoot": header.transactions_root,
+        "stateRoot": header.state_root,
+        "receiptsRoot": header.receipts_root,
+        "miner": header.beneficiary,
+        "difficulty": header.difficulty,
+        "totalDifficulty": total_difficulty,
+        "extraData": data(&header.extra_data),
+        "gasLimit": header.gas_limit,
+        "gasUsed": header.gas_used,
+        "timestamp": quantity(header.timestamp),
+        "transactions": transactions,
+        "uncles": uncles,
+    })))
+}
+
+fn tx_hash(eth_tx: &TransactionV2) -> H256 {
+    common::hash_data(&EnvelopedEncodable::encode(eth_tx))
+}
+
+fn to(action: &TransactionAction) -> Option<Address> {
+    match action {
+        TransactionAction::Call(address) => Some(*address),
+        TransactionAction::Create => None,
+    }
+}
+
+fn transaction_object(
+    eth_tx: &TransactionV2,
+    from: Option<Address>,
+    block_hash: H256,
+    block_number: u64,
+    index: usize,
+) -> Value {
+    let mut object = json!({
+        "hash": tx_hash(eth_tx),
+        "blockHash": block_hash,
+        "blockNumber": quantity(block_number),
+        "transactionIndex": quantity(index as u64),
+        "from": from,
+    });
+
+    let fields = match eth_tx {
+        TransactionV2::Legacy(tx) => json!({
+            "type": quantity(0),
+            "nonce": tx.nonce,
+            "gasPrice": tx.gas_price,
+            "gas": tx.gas_limit,
+            "to": to(&tx.action),
+            "value": tx.value,
+            "input": data(&tx.input),
+            "chainId": tx.signature.chain_id().map(quantity),
+            "v": quantity(tx.signature.v()),
+            "r": tx.signature.r(),
+            "s": tx.signature.s(),
+        }),
+        TransactionV2::EIP2930(tx) => json!({
+            "type": quantity(1),
+            "chainId": quantity(tx.chain_id),
+            "nonce": tx.nonce,
+            "gasPrice": tx.gas_price,
+            "gas": tx.gas_limit,
+            "to": to(&tx.action),
+            "value": tx.value,
+            "input": data(&tx.input),
+            "accessList": access_list(&tx.access_list),
+            "v": quantity(tx.odd_y_parity as u64),
+            "r": tx.r,
+            "s": tx.s,
+        }),
+        TransactionV2::EIP1559(tx) => json!({
+            "type": quantity(2),
+            "chainId": quantity(tx.chain_id),
+            "nonce": tx.nonce,
+            "maxPriorityFeePerGas": tx.max_priority_fee_per_gas,
+            "maxFeePerGas": tx.max_fee_per_gas,
+            "gas": tx.gas_limit,
+            "to": to(&tx.action),
+            "value": tx.value,
+            "input": data(&tx.input),
+            "accessList": access_list(&tx.access_list),
+            "v": quantity(tx.odd_y_parity as u64),
+            "r": tx.r,
+            "s": tx.s,
+        }),
+    };
+
+    if let (Value::Object(object), Value::Object(fields)) = (&mut object, fields) {
+        object.extend(fields);
+    }
+
+    object
+}
+
+fn access_list(access_list: &[ethereum::AccessListItem]) -> Value {
+    access_list
+        .iter()
+        .map(|item| json!({ "address": item.address, "storageKeys": item.slots }))
+        .collect()
+}
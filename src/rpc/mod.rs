@@ -0,0 +1,562 @@
+//! Ethereum JSON-RPC API served on top of the remote KV interface.
+
+mod eth;
+mod server;
+
+pub use self::server::{serve_http, serve_ws};
+
+use crate::{kv::remote::kv_client::KvClient, RemoteTransaction, Transaction};
+use ethereum_types::H256;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tonic::transport::Channel;
+use tracing::*;
+
+#[derive(Debug, Error)]
+pub enum RpcError {
+    #[error("Parse error: {0}")]
+    Parse(String),
+    #[error("Invalid request")]
+    InvalidRequest,
+    #[error("Method not found: {0}")]
+    MethodNotFound(String),
+    #[error("Invalid params: {0}")]
+    InvalidParams(String),
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+impl RpcError {
+    pub fn code(&self) -> i64 {
+        match self {
+            Self::Parse(_) => -32700,
+            Self::InvalidRequest => -32600,
+            Self::MethodNotFound(_) => -32601,
+            Self::InvalidParams(_) => -32602,
+            Self::Internal(_) => -32000,
+        }
+    }
+}
+
+/// Block parameter of state and block queries.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockNumber {
+    Earliest,
+    Latest,
+    Pending,
+    Number(u64),
+}
+
+impl<'de> Deserialize<'de> for BlockNumber {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Ok(match s.as_str() {
+            "earliest" => Self::Earliest,
+            "latest" => Self::Latest,
+            "pending" => Self::Pending,
+            other => Self::Number(parse_quantity(other).ok_or_else(|| {
+                serde::de::Error::custom(format!("invalid block number: {}", other))
+            })?),
+        })
+    }
+}
+
+fn parse_quantity(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.strip_prefix("0x")?, 16).ok()
+}
+
+/// Parses storage position, which may be given both as a quantity and as 32 bytes of data.
+fn parse_storage_key(s: &str) -> Option<H256> {
+    let s = s.strip_prefix("0x")?;
+    if s.is_empty() || s.len() > 64 {
+        return None;
+    }
+
+    hex::decode(format!("{:0>64}", s))
+        .ok()
+        .map(|bytes| H256::from_slice(&bytes))
+}
+
+fn param<T: DeserializeOwned>(params: &[Value], index: usize) -> Result<T, RpcError> {
+    serde_json::from_value(params.get(index).cloned().unwrap_or(Value::Null))
+        .map_err(|e| RpcError::InvalidParams(format!("parameter {}: {}", index, e)))
+}
+
+fn block_param(params: &[Value], index: usize) -> Result<BlockNumber, RpcError> {
+    Ok(param::<Option<BlockNumber>>(params, index)?.unwrap_or(BlockNumber::Latest))
+}
+
+/// Executes a single JSON-RPC method within `tx`.
+pub async fn dispatch<'db, Tx: Transaction<'db>>(
+    tx: &Tx,
+    method: &str,
+    params: &[Value],
+) -> Result<Value, RpcError> {
+    Ok(match method {
+        "eth_blockNumber" => json!(eth::quantity(eth::block_number(tx).await?)),
+        "eth_getBlockByNumber" => {
+            let block = eth::resolve(tx, param(params, 0)?).await?;
+            let full = param::<Option<bool>>(params, 1)?.unwrap_or(false);
+            json!(eth::get_block_by_number(tx, block, full).await?)
+        }
+        "eth_getBlockByHash" => {
+            let full = param::<Option<bool>>(params, 1)?.unwrap_or(false);
+            json!(eth::get_block_by_hash(tx, param(params, 0)?, full).await?)
+        }
+        "eth_getBalance" => {
+            let block = eth::resolve(tx, block_param(params, 1)?).await?;
+            json!(eth::get_balance(tx, param(params, 0)?, block).await?)
+        }
+        "eth_getTransactionCount" => {
+            let block = eth::resolve(tx, block_param(params, 1)?).await?;
+            json!(eth::quantity(
+                eth::get_transaction_count(tx, param(params, 0)?, block).await?
+            ))
+        }
+        "eth_getCode" => {
+            let block = eth::resolve(tx, block_param(params, 1)?).await?;
+            json!(eth::data(
+                &eth::get_code(tx, param(params, 0)?, block).await?
+            ))
+        }
+        "eth_getStorageAt" => {
+            let key = param::<String>(params, 1)?;
+            let key = parse_storage_key(&key).ok_or_else(|| {
+                RpcError::InvalidParams(format!("invalid storage position: {}", key))
+            })?;
+            let block = eth::resolve(tx, block_param(params, 2)?).await?;
+            json!(eth::get_storage_at(tx, param(params, 0)?, key, block).await?)
+        }
+        other => return Err(RpcError::MethodNotFound(other.to_string())),
+    })
+}
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: String,
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Vec<Value>,
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {
+            "code": error.code(),
+            "message": error.to_string(),
+        },
+    })
+}
+
+/// Serves JSON-RPC requests, reading data through a new remote transaction for every call.
+#[derive(Clone, Debug)]
+pub struct RpcHandler {
+    client: KvClient<Channel>,
+}
+
+impl RpcHandler {
+    pub fn new(client: KvClient<Channel>) -> Self {
+        Self { client }
+    }
+
+    /// Handles single or batch request. Returns `None` if there is nothing to respond with,
+    /// i.e. all requests were notifications.
+    pub async fn handle(&self, body: &str) -> Option<String> {
+        let response = match serde_json::from_str::<Value>(body) {
+            Err(e) => Some(error_response(Value::Null, RpcError::Parse(e.to_string()))),
+            Ok(Value::Array(requests)) if requests.is_empty() => {
+                Some(error_response(Value::Null, RpcError::InvalidRequest))
+            }
+            Ok(Value::Array(requests)) => {
+                let mut responses = Vec::with_capacity(requests.len());
+                for request in requests {
+                    if let Some(response) = self.handle_request(request).await {
+                        responses.push(response);
+                    }
+                }
+
+                (!responses.is_empty()).then(|| Value::Array(responses))
+            }
+            Ok(request) => self.handle_request(request).await,
+        };
+
+        response.map(|response| response.to_string())
+    }
+
+    async fn handle_request(&self, request: Value) -> Option<Value> {
+        let request = match serde_json::from_value::<Request>(request) {
+            Ok(request) if request.jsonrpc == "2.0" => request,
+            _ => return Some(error_response(Value::Null, RpcError::InvalidRequest)),
+        };
+
+        let result = self.call(&request.method, &request.params).await;
+        if let Err(e) = &result {
+            debug!("RPC call {} failed: {}", request.method, e);
+        }
+
+        let id = request.id?;
+        Some(match result {
+            Ok(result) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "result": result,
+            }),
+            Err(e) => error_response(id, e),
+        })
+    }
+
+    pub async fn call(&self, method: &str, params: &[Value]) -> Result<Value, RpcError> {
+        trace!("RPC call {} with params {:?}", method, params);
+
+        let tx = RemoteTransaction::open(self.client.clone()).await?;
+        dispatch(&tx, method, params).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        accessors::chain,
+        common::{self, hash_data},
+        kv::{remote::kv_server::KvServer as GrpcKvServer, server::KvServer, traits::MutableKV},
+        models::{Account, BodyForStorage},
+        new_mem_database,
+        stagedsync::{stages::EXECUTION, test_util::index_history},
+        state::database::{PlainStateWriter, StateWriter, WriterWithChangesets},
+        MutableTransaction, RemoteKvClient,
+    };
+    use ethereum::{Header, TransactionAction, TransactionSignature, TransactionV2};
+    use ethereum_types::{Address, Bloom, H64};
+    use futures_util::{SinkExt, StreamExt};
+    use hex_literal::hex;
+    use std::sync::Arc;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tokio_tungstenite::tungstenite::Message;
+
+    const CODE: [u8; 5] = hex!("600160005500");
+
+    fn header(number: u64, parent_hash: H256) -> Header {
+        Header {
+            parent_hash,
+            ommers_hash: common::EMPTY_LIST_HASH,
+            beneficiary: Address::zero(),
+            state_root: H256::zero(),
+            transactions_root: H256::zero(),
+            receipts_root: H256::zero(),
+            logs_bloom: Bloom::zero(),
+            difficulty: 131_072.into(),
+            number: number.into(),
+            gas_limit: 5000.into(),
+            gas_used: 0.into(),
+            timestamp: number * 15,
+            extra_data: vec![],
+            mix_hash: H256::zero(),
+            nonce: H64::zero(),
+        }
+    }
+
+    fn transfer() -> TransactionV2 {
+        TransactionV2::Legacy(ethereum::LegacyTransaction {
+            nonce: 0.into(),
+            gas_limit: 21_000.into(),
+            gas_price: 1_000_000.into(),
+            action: TransactionAction::Call(Address::from_low_u64_be(0xbeef)),
+            value: 1.into(),
+            input: vec![],
+            signature: TransactionSignature::new(
+                0x25,
+                H256::from(hex!(
+                    "11d244ae19e3bb96d1bb864aa761d48e957984a154329f0de757cd105f9c7ac4"
+                )),
+                H256::from(hex!(
+                    "0e3828d13eed24036941eb5f7fd65de57aad1184342f2244130d2941554342ba"
+                )),
+            )
+            .unwrap(),
+        })
+    }
+
+    /// Writes genesis and block 1, which deploys a contract with one storage slot set.
+    async fn populate<'db, RwTx: MutableTransaction<'db>>(
+        tx: &mut RwTx,
+        contract: Address,
+    ) -> H256 {
+        let genesis = header(0, H256::zero());
+        let block1 = header(1, genesis.hash());
+        let sender = Address::from_low_u64_be(0xcafe);
+        for (number, header, txs, senders) in [
+            (0, &genesis, vec![], vec![]),
+            (1, &block1, vec![transfer()], vec![sender]),
+        ] {
+            let hash = header.hash();
+            chain::canonical_hash::write(tx, number, hash)
+                .await
+                .unwrap();
+            chain::header_number::write(tx, hash, number).await.unwrap();
+            chain::header::write(tx, hash, number, header)
+                .await
+                .unwrap();
+            chain::td::write(tx, hash, number, (number + 1).into())
+                .await
+                .unwrap();
+            chain::storage_body::write(
+                tx,
+                hash,
+                number,
+                &BodyForStorage {
+                    base_tx_id: number,
+                    tx_amount: txs.len() as u32,
+                    uncles: vec![],
+                },
+            )
+            .await
+            .unwrap();
+            chain::tx::write(tx, number, &txs).await.unwrap();
+            chain::tx_sender::write(tx, number, &senders).await.unwrap();
+        }
+
+        let code_hash = hash_data(&CODE);
+        let account = Account {
+            nonce: 1,
+            balance: 1000.into(),
+            code_hash: Some(code_hash),
+            incarnation: 1,
+            ..Default::default()
+        };
+
+        let mut writer = PlainStateWriter::new(tx, 1);
+        writer
+            .update_account_data(contract, &Account::default(), &account)
+            .await
+            .unwrap();
+        writer
+            .update_account_code(contract, 1, code_hash, &CODE)
+            .await
+            .unwrap();
+        writer
+            .write_account_storage(contract, 1, H256::from_low_u64_be(1), 0.into(), 0x2a.into())
+            .await
+            .unwrap();
+        writer.write_changesets().await.unwrap();
+        index_history(tx, 1).await;
+
+        EXECUTION.save_progress(tx, 1).await.unwrap();
+
+        block1.hash()
+    }
+
+    async fn handler(contract: Address) -> (RpcHandler, H256) {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+        let hash = populate(&mut tx, contract).await;
+        tx.commit().await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(GrpcKvServer::new(KvServer::new(Arc::new(db))))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let client = RemoteKvClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+
+        (RpcHandler::new(client), hash)
+    }
+
+    async fn call(handler: &RpcHandler, method: &str, params: Value) -> Value {
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+        let response = handler.handle(&request.to_string()).await.unwrap();
+        serde_json::from_str::<Value>(&response).unwrap()["result"].clone()
+    }
+
+    #[tokio::test]
+    async fn eth_api() {
+        let contract = Address::from_low_u64_be(0x1234);
+        let (handler, hash) = handler(contract).await;
+
+        assert_eq!(call(&handler, "eth_blockNumber", json!([])).await, "0x1");
+
+        assert_eq!(
+            call(&handler, "eth_getBalance", json!([contract, "latest"])).await,
+            "0x3e8"
+        );
+        assert_eq!(
+            call(&handler, "eth_getBalance", json!([contract, "0x0"])).await,
+            "0x0"
+        );
+        assert_eq!(
+            call(&handler, "eth_getTransactionCount", json!([contract])).await,
+            "0x1"
+        );
+        assert_eq!(
+            call(&handler, "eth_getCode", json!([contract, "latest"])).await,
+            "0x600160005500"
+        );
+        assert_eq!(
+            call(&handler, "eth_getCode", json!([contract, "earliest"])).await,
+            "0x"
+        );
+        assert_eq!(
+            call(
+                &handler,
+                "eth_getStorageAt",
+                json!([contract, "0x1", "latest"])
+            )
+            .await,
+            json!(H256::from_low_u64_be(0x2a))
+        );
+        assert_eq!(
+            call(
+                &handler,
+                "eth_getStorageAt",
+                json!([contract, "0x1", "0x0"])
+            )
+            .await,
+            json!(H256::zero())
+        );
+
+        let block = call(&handler, "eth_getBlockByNumber", json!(["latest", false])).await;
+        assert_eq!(block["number"], "0x1");
+        assert_eq!(block["hash"], json!(hash));
+        assert_eq!(block["totalDifficulty"], "0x2");
+        assert_eq!(
+            block["transactions"],
+            json!([hash_data(
+                &ethereum::EnvelopedEncodable::encode(&transfer())
+            )])
+        );
+
+        let block = call(&handler, "eth_getBlockByHash", json!([hash, true])).await;
+        assert_eq!(block["number"], "0x1");
+        assert_eq!(
+            block["transactions"][0]["from"],
+            json!(Address::from_low_u64_be(0xcafe))
+        );
+        assert_eq!(block["transactions"][0]["value"], "0x1");
+
+        assert_eq!(
+            call(&handler, "eth_getBlockByNumber", json!(["0x2", false])).await,
+            Value::Null
+        );
+    }
+
+    #[tokio::test]
+    async fn protocol_errors() {
+        let (handler, _) = handler(Address::zero()).await;
+
+        let response = handler
+            .handle(r#"{"jsonrpc":"2.0","id":7,"method":"eth_foo","params":[]}"#)
+            .await
+            .unwrap();
+        let response = serde_json::from_str::<Value>(&response).unwrap();
+        assert_eq!(response["id"], 7);
+        assert_eq!(response["error"]["code"], -32601);
+
+        let response = handler.handle("{").await.unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&response).unwrap()["error"]["code"],
+            -32700
+        );
+
+        let response = handler
+            .handle(r#"{"jsonrpc":"2.0","id":1,"method":"eth_getBalance","params":["0x12"]}"#)
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&response).unwrap()["error"]["code"],
+            -32602
+        );
+
+        // Notifications are not responded to
+        assert_eq!(
+            handler
+                .handle(r#"{"jsonrpc":"2.0","method":"eth_blockNumber"}"#)
+                .await,
+            None
+        );
+
+        let response = handler
+            .handle(
+                r#"[{"jsonrpc":"2.0","id":1,"method":"eth_blockNumber"},{"jsonrpc":"2.0","method":"eth_blockNumber"},{"jsonrpc":"2.0","id":2,"method":"eth_blockNumber"}]"#,
+            )
+            .await
+            .unwrap();
+        let response = serde_json::from_str::<Value>(&response).unwrap();
+        assert_eq!(response.as_array().unwrap().len(), 2);
+        assert_eq!(response[1]["id"], 2);
+        assert_eq!(response[1]["result"], "0x1");
+    }
+
+    #[tokio::test]
+    async fn http() {
+        let (handler, _) = handler(Address::zero()).await;
+        let addr = serve_http("127.0.0.1:0".parse().unwrap(), handler)
+            .await
+            .unwrap();
+
+        let body = r#"{"jsonrpc":"2.0","id":1,"method":"eth_blockNumber"}"#;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                format!(
+                    "POST / HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    addr,
+                    body.len(),
+                    body
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(body).unwrap(),
+            json!({ "jsonrpc": "2.0", "id": 1, "result": "0x1" })
+        );
+    }
+
+    #[tokio::test]
+    async fn ws() {
+        let (handler, _) = handler(Address::zero()).await;
+        let addr = serve_ws("127.0.0.1:0".parse().unwrap(), handler)
+            .await
+            .unwrap();
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+        for id in 1..=2 {
+            ws.send(Message::Text(
+                json!({ "jsonrpc": "2.0", "id": id, "method": "eth_blockNumber" }).to_string(),
+            ))
+            .await
+            .unwrap();
+
+            let response = match ws.next().await.unwrap().unwrap() {
+                Message::Text(text) => serde_json::from_str::<Value>(&text).unwrap(),
+                other => panic!("unexpected message {:?}", other),
+            };
+            assert_eq!(
+                response,
+                json!({ "jsonrpc": "2.0", "id": id, "result": "0x1" })
+            );
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use super::RpcHandler;
+use futures_util::{SinkExt, StreamExt};
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use std::{convert::Infallible, net::SocketAddr};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::*;
+
+/// Serves JSON-RPC over HTTP POST requests in background, returns the bound address.
+pub async fn serve_http(addr: SocketAddr, handler: RpcHandler) -> anyhow::Result<SocketAddr> {
+    let server = Server::try_bind(&addr)?.serve(make_service_fn(move |_| {
+        let handler = handler.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle_http(handler.clone(), request)
+            }))
+        }
+    }));
+    let local_addr = server.local_addr();
+    info!("Serving JSON-RPC over HTTP on {}", local_addr);
+
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            warn!("JSON-RPC HTTP server failed: {}", e);
+        }
+    });
+
+    Ok(local_addr)
+}
+
+async fn handle_http(
+    handler: RpcHandler,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    if request.method() != Method::POST {
+        return Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    let body = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(body) => body,
+        Err(e) => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(e.to_string()))
+                .unwrap())
+        }
+    };
+
+    let body = String::from_utf8_lossy(&body);
+    Ok(match handler.handle(&body).await {
+        Some(response) => Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(response))
+            .unwrap(),
+        None => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap(),
+    })
+}
+
+/// Serves JSON-RPC over WebSocket in background, returns the bound address.
+pub async fn serve_ws(addr: SocketAddr, handler: RpcHandler) -> anyhow::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
+    info!("Serving JSON-RPC over WebSocket on {}", local_addr);
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("Failed to accept WebSocket connection: {}", e);
+                    continue;
+                }
+            };
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_ws(handler, stream).await {
+                    debug!("WebSocket connection from {} closed: {}", peer, e);
+                }
+            });
+        }
+    });
+
+    Ok(local_addr)
+}
+
+async fn handle_ws(handler: RpcHandler, stream: TcpStream) -> anyhow::Result<()> {
+    let mut ws = tokio_tungstenite::accept_async(stream).await?;
+
+    while let Some(message) = ws.next().await {
+        let request = match message? {
+            Message::Text(text) => text,
+            Message::Binary(data) => String::from_utf8(data)?,
+            Message::Close(_) => break,
+            // Pings are answered by the protocol implementation
+            _ => continue,
+        };
+
+        if let Some(response) = handler.handle(&request).await {
+            ws.send(Message::Text(response)).await?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,196 @@
+use super::stages::StageId;
+use parking_lot::Mutex;
+use std::{fmt::Write, sync::Arc, time::Duration};
+
+/// Counters of a single stage, accumulated since the sync was started.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StageMetrics {
+    /// Block number the stage has synced to.
+    pub progress: u64,
+    /// Block number the stage is syncing to, i.e. progress of the previous stage.
+    pub target: Option<u64>,
+    /// Number of blocks processed by execution.
+    pub blocks: u64,
+    pub execute_time: Duration,
+    /// Time spent committing the stage's changes. The commit at the end of a cycle is counted towards the last stage.
+    pub commit_time: Duration,
+    pub unwinds: u64,
+    pub unwind_time: Duration,
+}
+
+impl StageMetrics {
+    pub fn blocks_per_second(&self) -> f64 {
+        let secs = self.execute_time.as_secs_f64();
+        if secs > 0.0 {
+            self.blocks as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Shared handle to metrics of all stages of `StagedSync`, in execution order.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics(Arc<Mutex<Vec<(StageId, StageMetrics)>>>);
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn update(&self, stage: StageId, f: impl FnOnce(&mut StageMetrics)) {
+        let mut stages = self.0.lock();
+        let pos = match stages.iter().position(|(id, _)| *id == stage) {
+            Some(pos) => pos,
+            None => {
+                stages.push((stage, StageMetrics::default()));
+                stages.len() - 1
+            }
+        };
+        f(&mut stages[pos].1)
+    }
+
+    pub fn get(&self, stage: StageId) -> Option<StageMetrics> {
+        self.0
+            .lock()
+            .iter()
+            .find(|(id, _)| *id == stage)
+            .map(|(_, metrics)| metrics.clone())
+    }
+
+    pub fn snapshot(&self) -> Vec<(StageId, StageMetrics)> {
+        self.0.lock().clone()
+    }
+
+    /// Renders metrics in Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let stages = self.snapshot();
+
+        let mut out = String::new();
+        write_family(
+            &mut out,
+            &stages,
+            "progress",
+            "gauge",
+            "Block number the stage has synced to",
+            |m| Some(m.progress as f64),
+        );
+        write_family(
+            &mut out,
+            &stages,
+            "target",
+            "gauge",
+            "Block number the stage is syncing to",
+            |m| m.target.map(|target| target as f64),
+        );
+        write_family(
+            &mut out,
+            &stages,
+            "blocks_total",
+            "counter",
+            "Blocks processed by the stage",
+            |m| Some(m.blocks as f64),
+        );
+        write_family(
+            &mut out,
+            &stages,
+            "blocks_per_second",
+            "gauge",
+            "Average blocks processed per second of execution",
+            |m| Some(m.blocks_per_second()),
+        );
+        write_family(
+            &mut out,
+            &stages,
+            "execute_seconds_total",
+            "counter",
+            "Time spent executing the stage",
+            |m| Some(m.execute_time.as_secs_f64()),
+        );
+        write_family(
+            &mut out,
+            &stages,
+            "commit_seconds_total",
+            "counter",
+            "Time spent committing changes of the stage",
+            |m| Some(m.commit_time.as_secs_f64()),
+        );
+        write_family(
+            &mut out,
+            &stages,
+            "unwinds_total",
+            "counter",
+            "Times the stage has been unwound",
+            |m| Some(m.unwinds as f64),
+        );
+        write_family(
+            &mut out,
+            &stages,
+            "unwind_seconds_total",
+            "counter",
+            "Time spent unwinding the stage",
+            |m| Some(m.unwind_time.as_secs_f64()),
+        );
+
+        out
+    }
+}
+
+fn write_family(
+    out: &mut String,
+    stages: &[(StageId, StageMetrics)],
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl Fn(&StageMetrics) -> Option<f64>,
+) {
+    writeln!(out, "# HELP akula_stage_{} {}", name, help).unwrap();
+    writeln!(out, "# TYPE akula_stage_{} {}", name, kind).unwrap();
+    for (stage, metrics) in stages {
+        if let Some(value) = value(metrics) {
+            writeln!(out, "akula_stage_{}{{stage=\"{}\"}} {}", name, stage, value).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prometheus_text() {
+        let metrics = Metrics::new();
+        metrics.update(StageId("Headers"), |m| {
+            m.progress = 100;
+            m.blocks = 100;
+            m.execute_time = Duration::from_secs(4);
+        });
+        metrics.update(StageId("Bodies"), |m| {
+            m.progress = 50;
+            m.target = Some(100);
+            m.unwinds = 1;
+        });
+        metrics.update(StageId("Headers"), |m| {
+            m.commit_time = Duration::from_millis(500)
+        });
+
+        assert_eq!(
+            metrics.get(StageId("Headers")).unwrap().blocks_per_second(),
+            25.0
+        );
+
+        let text = metrics.to_prometheus();
+        for line in [
+            "# TYPE akula_stage_progress gauge",
+            "akula_stage_progress{stage=\"Headers\"} 100",
+            "akula_stage_progress{stage=\"Bodies\"} 50",
+            "akula_stage_target{stage=\"Bodies\"} 100",
+            "akula_stage_blocks_per_second{stage=\"Headers\"} 25",
+            "akula_stage_commit_seconds_total{stage=\"Headers\"} 0.5",
+            "akula_stage_unwinds_total{stage=\"Bodies\"} 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{}", line);
+        }
+        assert!(!text.contains("akula_stage_target{stage=\"Headers\"}"));
+    }
+}
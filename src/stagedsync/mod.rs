@@ -1,10 +1,201 @@
+pub mod metrics;
 pub mod stage;
 pub mod stages;
+#[cfg(test)]
+pub(crate) mod test_util;
 
-use self::stage::{Stage, StageInput, UnwindInput};
+use self::{
+    metrics::Metrics,
+    stage::{PruneInput, PruneSettings, Stage, StageInput, UnwindInput},
+    stages::StageId,
+};
 use crate::{kv::traits::MutableKV, stagedsync::stage::ExecOutput, MutableTransaction};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use thiserror::Error;
 use tracing::*;
 
+#[derive(Debug, Error, PartialEq)]
+pub enum PipelineError {
+    #[error("Stage {0} is registered more than once")]
+    DuplicateStage(StageId),
+    #[error("Stage {stage} depends on unknown stage {prerequisite}")]
+    UnknownPrerequisite {
+        stage: StageId,
+        prerequisite: StageId,
+    },
+    #[error("Dependency cycle between stages {0:?}")]
+    Cycle(Vec<StageId>),
+    #[error("Stage {stage} depends on {prerequisite}, but runs after {previous} which does not")]
+    PreviousStageMismatch {
+        stage: StageId,
+        previous: StageId,
+        prerequisite: StageId,
+    },
+}
+
+/// Orders stages so that each one runs after its prerequisites.
+/// Stages that could run in any order keep their registration order.
+/// Returns indices of `stages` in execution order.
+pub fn resolve(stages: &[(StageId, &[StageId])]) -> Result<Vec<usize>, PipelineError> {
+    let mut indices = HashMap::new();
+    for (i, (id, _)) in stages.iter().enumerate() {
+        if indices.insert(*id, i).is_some() {
+            return Err(PipelineError::DuplicateStage(*id));
+        }
+    }
+    for (stage, prerequisites) in stages {
+        for prerequisite in prerequisites.iter() {
+            if !indices.contains_key(prerequisite) {
+                return Err(PipelineError::UnknownPrerequisite {
+                    stage: *stage,
+                    prerequisite: *prerequisite,
+                });
+            }
+        }
+    }
+
+    let mut order = Vec::with_capacity(stages.len());
+    let mut scheduled = vec![false; stages.len()];
+    while order.len() < stages.len() {
+        let next = (0..stages.len())
+            .find(|&i| !scheduled[i] && stages[i].1.iter().all(|p| scheduled[indices[p]]));
+
+        match next {
+            Some(i) => {
+                scheduled[i] = true;
+                order.push(i);
+            }
+            None => {
+                return Err(PipelineError::Cycle(
+                    stages
+                        .iter()
+                        .zip(&scheduled)
+                        .filter(|(_, scheduled)| !**scheduled)
+                        .map(|((id, _), _)| *id)
+                        .collect(),
+                ))
+            }
+        }
+    }
+
+    Ok(order)
+}
+
+/// Checks stages in execution order: ids must be unique, and since every stage
+/// syncs up to the progress of the one before it, each prerequisite must be
+/// either the previous stage or one of its own (transitive) prerequisites.
+fn validate(stages: &[(StageId, &[StageId])]) -> Result<(), PipelineError> {
+    let mut seen = HashSet::new();
+    let mut dependencies = HashMap::<StageId, HashSet<StageId>>::new();
+    let mut previous = None;
+    for (stage, prerequisites) in stages {
+        if !seen.insert(*stage) {
+            return Err(PipelineError::DuplicateStage(*stage));
+        }
+
+        let mut stage_dependencies = HashSet::new();
+        for prerequisite in prerequisites.iter() {
+            let previous = previous.ok_or(PipelineError::UnknownPrerequisite {
+                stage: *stage,
+                prerequisite: *prerequisite,
+            })?;
+
+            if *prerequisite != previous && !dependencies[&previous].contains(prerequisite) {
+                return Err(if seen.contains(prerequisite) {
+                    PipelineError::PreviousStageMismatch {
+                        stage: *stage,
+                        previous,
+                        prerequisite: *prerequisite,
+                    }
+                } else {
+                    PipelineError::UnknownPrerequisite {
+                        stage: *stage,
+                        prerequisite: *prerequisite,
+                    }
+                });
+            }
+
+            stage_dependencies.insert(*prerequisite);
+            stage_dependencies.extend(dependencies[prerequisite].iter().copied());
+        }
+        dependencies.insert(*stage, stage_dependencies);
+        previous = Some(*stage);
+    }
+
+    Ok(())
+}
+
+/// Registers stages along with the stages they depend on, and builds `StagedSync` running them in dependency order.
+pub struct StagedSyncBuilder<'db, DB: MutableKV> {
+    stages: Vec<(Box<dyn Stage<'db, DB::MutableTx<'db>>>, Vec<StageId>)>,
+}
+
+impl<'db, DB: MutableKV> Default for StagedSyncBuilder<'db, DB> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'db, DB: MutableKV> StagedSyncBuilder<'db, DB> {
+    pub fn new() -> Self {
+        Self { stages: Vec::new() }
+    }
+
+    /// Registers `stage`, which must run after all of `prerequisites`.
+    pub fn add<S>(mut self, stage: S, prerequisites: &[StageId]) -> Self
+    where
+        S: Stage<'db, DB::MutableTx<'db>> + 'static,
+    {
+        self.stages.push((Box::new(stage), prerequisites.to_vec()));
+        self
+    }
+
+    pub fn build(self) -> Result<StagedSync<'db, DB>, PipelineError> {
+        let order = resolve(
+            &self
+                .stages
+                .iter()
+                .map(|(stage, prerequisites)| (stage.id(), prerequisites.as_slice()))
+                .collect::<Vec<_>>(),
+        )?;
+
+        let mut stages = self.stages.into_iter().map(Some).collect::<Vec<_>>();
+        let mut staged_sync = StagedSync::new();
+        for i in order {
+            let (stage, prerequisites) = stages[i].take().unwrap();
+            staged_sync.stages.push(stage);
+            staged_sync.prerequisites.push(prerequisites);
+        }
+        staged_sync.validate()?;
+
+        Ok(staged_sync)
+    }
+}
+
+/// Requests `StagedSync` to stop. The stage being executed is allowed to finish, then its progress is committed.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
 /// Staged synchronization framework
 ///
 /// As the name suggests, the gist of this framework is splitting sync into logical _stages_ that are consecutively executed one after another.
@@ -17,8 +208,18 @@ use tracing::*;
 /// That means, that in the ideal scenario (no network interruptions, the app isn't restarted, etc), for the full initial sync, each stage will be executed exactly once.
 /// After the last stage is finished, the process starts from the beginning, by looking for the new headers to download.
 /// If the app is restarted in between stages, it restarts from the first stage. Absent new blocks, already completed stages are skipped.
+/// Unwind point is persisted before unwinding, so an unwind interrupted by a restart is finished before any stage is executed again.
+///
+/// By default the cycle is repeated until the sync is cancelled, see [`StagedSync::set_exit_after_sync`] and [`StagedSync::set_max_block`] for bounded runs.
+/// After each cycle stages prune data of old blocks, if retention is limited with [`StagedSync::set_prune`].
 pub struct StagedSync<'db, DB: MutableKV> {
     stages: Vec<Box<dyn Stage<'db, DB::MutableTx<'db>>>>,
+    prerequisites: Vec<Vec<StageId>>,
+    max_block: Option<u64>,
+    exit_after_sync: bool,
+    prune: PruneSettings,
+    cancellation: CancellationToken,
+    metrics: Metrics,
 }
 
 impl<'db, DB: MutableKV> Default for StagedSync<'db, DB> {
@@ -29,27 +230,171 @@ impl<'db, DB: MutableKV> Default for StagedSync<'db, DB> {
 
 impl<'db, DB: MutableKV> StagedSync<'db, DB> {
     pub fn new() -> Self {
-        Self { stages: Vec::new() }
+        Self {
+            stages: Vec::new(),
+            prerequisites: Vec::new(),
+            max_block: None,
+            exit_after_sync: false,
+            prune: PruneSettings::default(),
+            cancellation: CancellationToken::new(),
+            metrics: Metrics::new(),
+        }
+    }
+
+    pub fn builder() -> StagedSyncBuilder<'db, DB> {
+        StagedSyncBuilder::new()
     }
 
+    /// Appends `stage` without declared prerequisites.
     pub fn push<S>(&mut self, stage: S)
     where
         S: Stage<'db, DB::MutableTx<'db>> + 'static,
     {
-        self.stages.push(Box::new(stage))
+        self.stages.push(Box::new(stage));
+        self.prerequisites.push(Vec::new());
+    }
+
+    /// Stages in execution order, with their descriptions and prerequisites.
+    pub fn pipeline(&self) -> impl Iterator<Item = (StageId, &'static str, &[StageId])> {
+        self.stages
+            .iter()
+            .zip(&self.prerequisites)
+            .map(|(stage, prerequisites)| {
+                (stage.id(), stage.description(), prerequisites.as_slice())
+            })
+    }
+
+    /// Do not sync stages past `max_block`, and stop once the last stage has reached it.
+    /// The first stage is not bounded, since it does not follow any other stage.
+    pub fn set_max_block(&mut self, max_block: Option<u64>) {
+        self.max_block = max_block;
     }
 
-    pub async fn run(&self, db: &'db DB) -> anyhow::Result<!> {
+    /// Stop after the first complete cycle through all stages.
+    pub fn set_exit_after_sync(&mut self, exit_after_sync: bool) {
+        self.exit_after_sync = exit_after_sync;
+    }
+
+    /// Retention of historical data, pruned after each sync cycle.
+    pub fn set_prune(&mut self, prune: PruneSettings) {
+        self.prune = prune;
+    }
+
+    /// Token that stops [`StagedSync::run`] once the current stage is done.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    /// Handle to per-stage metrics, updated while the sync runs.
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    pub fn validate(&self) -> Result<(), PipelineError> {
+        validate(
+            &self
+                .pipeline()
+                .map(|(id, _, prerequisites)| (id, prerequisites))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    /// Lowest unwind point saved by any of the stages, if an unwind has not been completed.
+    async fn get_unwind_point(&self, tx: &DB::MutableTx<'db>) -> anyhow::Result<Option<u64>> {
+        let mut unwind_to: Option<u64> = None;
+        for stage in &self.stages {
+            if let Some(to) = stage.id().get_unwind(tx).await? {
+                unwind_to = Some(unwind_to.map_or(to, |unwind_to| unwind_to.min(to)));
+            }
+        }
+
+        Ok(unwind_to)
+    }
+
+    /// Lets every stage drop data older than retained by the prune settings, each stage in its own transaction.
+    async fn prune(&self, db: &'db DB) -> anyhow::Result<()> {
+        if self.prune == PruneSettings::default() {
+            return Ok(());
+        }
+
+        let num_stages = self.stages.len();
+        for (stage_index, stage) in self.stages.iter().enumerate() {
+            if self.cancellation.is_cancelled() {
+                return Ok(());
+            }
+
+            let stage_id = stage.id();
+            let mut tx = db.begin_mutable().await?;
+
+            let stage_progress = stage_id.get_progress(&tx).await?.unwrap_or(0);
+            let prune_progress = stage_id.get_prune_progress(&tx).await?;
+            let pruned = stage
+                .prune(
+                    &mut tx,
+                    PruneInput {
+                        stage_progress,
+                        prune_progress,
+                        settings: self.prune,
+                    },
+                )
+                .instrument(span!(
+                    Level::INFO,
+                    "",
+                    " Pruning {}/{} {} ",
+                    stage_index + 1,
+                    num_stages,
+                    AsRef::<str>::as_ref(&stage_id)
+                ))
+                .await?;
+
+            if let Some(prune_progress) = pruned {
+                stage_id.save_prune_progress(&tx, prune_progress).await?;
+                tx.commit().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs the sync until it is cancelled or, in a bounded run, reaches its end.
+    pub async fn run(&self, db: &'db DB) -> anyhow::Result<()> {
+        self.validate()?;
+
         let num_stages = self.stages.len();
 
-        let mut unwind_to = None;
+        let tx = db.begin_mutable().await?;
+        for stage in &self.stages {
+            let progress = stage.id().get_progress(&tx).await?.unwrap_or(0);
+            self.metrics.update(stage.id(), |m| m.progress = progress);
+        }
+
+        // Finish an unwind interrupted by a crash before executing anything
+        let mut unwind_to = self.get_unwind_point(&tx).await?;
+        drop(tx);
+        if let Some(to) = unwind_to {
+            info!(to, "Resuming interrupted unwind");
+        }
+
         'run_loop: loop {
             let mut tx = Some(db.begin_mutable().await?);
 
             if let Some(to) = unwind_to.take() {
-                let mut tx = tx.unwrap();
+                // Persist the unwind point, so that it is resumed if we crash while unwinding
+                let t = tx.take().unwrap();
+                for stage in &self.stages {
+                    stage.id().save_unwind(&t, to).await?;
+                }
+                t.commit().await?;
+
+                // Each stage is unwound in its own transaction
                 for (stage_index, stage) in self.stages.iter().rev().enumerate() {
+                    if self.cancellation.is_cancelled() {
+                        info!("Staged sync cancelled, unwind will be resumed on restart");
+                        return Ok(());
+                    }
+
                     let stage_id = stage.id();
+                    let mut tx = db.begin_mutable().await?;
 
                     let res: anyhow::Result<()> = async {
                         let stage_progress = stage_id.get_progress(&tx).await?.unwrap_or(0);
@@ -57,6 +402,7 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                         if stage_progress > to {
                             info!("RUNNING");
 
+                            let started = std::time::Instant::now();
                             stage
                                 .unwind(
                                     &mut tx,
@@ -68,6 +414,11 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                                 .await?;
 
                             stage_id.save_progress(&tx, to).await?;
+                            self.metrics.update(stage_id, |m| {
+                                m.unwinds += 1;
+                                m.unwind_time += started.elapsed();
+                                m.progress = to;
+                            });
 
                             info!("DONE");
                         } else {
@@ -91,8 +442,17 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                     .await;
 
                     res?;
+
+                    let started = std::time::Instant::now();
+                    tx.commit().await?;
+                    self.metrics
+                        .update(stage_id, |m| m.commit_time += started.elapsed());
                 }
 
+                let tx = db.begin_mutable().await?;
+                for stage in &self.stages {
+                    stage.id().clear_unwind(&tx).await?;
+                }
                 tx.commit().await?;
             } else {
                 let mut previous_stage = None;
@@ -106,6 +466,12 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                     let done_progress = loop {
                         let mut t = tx.take().unwrap();
 
+                        if self.cancellation.is_cancelled() {
+                            t.commit().await?;
+                            info!("Staged sync cancelled");
+                            return Ok(());
+                        }
+
                         let stage_progress = stage_id.get_progress(&t).await?;
 
                         let exec_output: anyhow::Result<_> = async {
@@ -113,6 +479,7 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                                 info!("RUNNING");
                             }
 
+                            let started = std::time::Instant::now();
                             let output = stage
                                 .execute(
                                     &mut t,
@@ -123,6 +490,21 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                                     },
                                 )
                                 .await?;
+                            let execute_time = started.elapsed();
+
+                            self.metrics.update(stage_id, |m| {
+                                m.execute_time += execute_time;
+                                m.target = previous_stage.map(|(_, height)| height);
+                                if let ExecOutput::Progress {
+                                    stage_progress: progress,
+                                    ..
+                                } = &output
+                                {
+                                    m.blocks +=
+                                        progress.saturating_sub(stage_progress.unwrap_or(0));
+                                    m.progress = *progress;
+                                }
+                            });
 
                             match &output {
                                 ExecOutput::Progress { done, .. } => {
@@ -156,7 +538,10 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                                 stage_id.save_progress(&t, stage_progress).await?;
 
                                 if must_commit {
+                                    let started = std::time::Instant::now();
                                     t.commit().await?;
+                                    self.metrics
+                                        .update(stage_id, |m| m.commit_time += started.elapsed());
                                     tx = Some(db.begin_mutable().await?);
                                 } else {
                                     // Return tx object back
@@ -170,6 +555,8 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                                 restarted = true
                             }
                             stage::ExecOutput::Unwind { unwind_to: to } => {
+                                // Keep what the stage recorded about the reason, such as a heavier fork or a bad block
+                                t.commit().await?;
                                 unwind_to = Some(to);
                                 continue 'run_loop;
                             }
@@ -177,9 +564,19 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                     };
                     timings.push((stage_id, std::time::Instant::now() - start_time));
 
-                    previous_stage = Some((stage_id, done_progress))
+                    previous_stage = Some((
+                        stage_id,
+                        self.max_block
+                            .map_or(done_progress, |max_block| done_progress.min(max_block)),
+                    ))
                 }
+
+                let started = std::time::Instant::now();
                 tx.unwrap().commit().await?;
+                if let Some((stage_id, _)) = previous_stage {
+                    self.metrics
+                        .update(stage_id, |m| m.commit_time += started.elapsed());
+                }
 
                 let t = timings
                     .into_iter()
@@ -187,7 +584,408 @@ impl<'db, DB: MutableKV> StagedSync<'db, DB> {
                         format!("{} {}={}ms", acc, stage_id, time.as_millis())
                     });
                 info!("Staged sync complete.{}", t);
+
+                self.prune(db).await?;
+
+                if self.exit_after_sync {
+                    return Ok(());
+                }
+
+                if let (Some(max_block), Some((_, progress))) = (self.max_block, previous_stage) {
+                    if progress >= max_block {
+                        info!(max_block, "Reached maximum block");
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::new_mem_database;
+    use async_trait::async_trait;
+
+    #[derive(Debug)]
+    struct Noop(StageId);
+
+    #[async_trait]
+    impl<'db, RwTx> Stage<'db, RwTx> for Noop
+    where
+        RwTx: MutableTransaction<'db>,
+    {
+        fn id(&self) -> StageId {
+            self.0
+        }
+
+        fn description(&self) -> &'static str {
+            "Doing nothing"
+        }
+
+        async fn execute<'tx>(&self, _: &'tx mut RwTx, _: StageInput) -> anyhow::Result<ExecOutput>
+        where
+            'db: 'tx,
+        {
+            Ok(ExecOutput::Progress {
+                stage_progress: 0,
+                done: true,
+                must_commit: false,
+            })
+        }
+
+        async fn unwind<'tx>(&self, _: &'tx mut RwTx, _: UnwindInput) -> anyhow::Result<()>
+        where
+            'db: 'tx,
+        {
+            Ok(())
+        }
+    }
+
+    /// Advances by `step` blocks when first in the pipeline, otherwise follows the previous stage.
+    /// Cancels the sync when given a token, and prunes according to history retention.
+    #[derive(Debug)]
+    struct Follow {
+        id: StageId,
+        step: u64,
+        cancel: Option<CancellationToken>,
+    }
+
+    #[async_trait]
+    impl<'db, RwTx> Stage<'db, RwTx> for Follow
+    where
+        RwTx: MutableTransaction<'db>,
+    {
+        fn id(&self) -> StageId {
+            self.id
+        }
+
+        fn description(&self) -> &'static str {
+            "Following previous stage"
+        }
+
+        async fn prune<'tx>(
+            &self,
+            _: &'tx mut RwTx,
+            input: PruneInput,
+        ) -> anyhow::Result<Option<u64>>
+        where
+            'db: 'tx,
+        {
+            Ok(input.prune_before(input.settings.history))
+        }
+
+        async fn execute<'tx>(
+            &self,
+            _: &'tx mut RwTx,
+            input: StageInput,
+        ) -> anyhow::Result<ExecOutput>
+        where
+            'db: 'tx,
+        {
+            if let Some(cancel) = &self.cancel {
+                cancel.cancel();
             }
+
+            Ok(ExecOutput::Progress {
+                stage_progress: match input.previous_stage {
+                    Some((_, height)) => height,
+                    None => input.stage_progress.unwrap_or(0) + self.step,
+                },
+                done: true,
+                must_commit: false,
+            })
+        }
+
+        async fn unwind<'tx>(&self, _: &'tx mut RwTx, _: UnwindInput) -> anyhow::Result<()>
+        where
+            'db: 'tx,
+        {
+            Ok(())
+        }
+    }
+
+    fn follow(id: StageId) -> Follow {
+        Follow {
+            id,
+            step: 100,
+            cancel: None,
+        }
+    }
+
+    async fn progress<DB: MutableKV>(db: &DB, stage: StageId) -> Option<u64> {
+        stage
+            .get_progress(&db.begin_mutable().await.unwrap())
+            .await
+            .unwrap()
+    }
+
+    /// Keeps its progress, records unwinds and fails them when `crash` is set.
+    #[derive(Debug)]
+    struct Unwinding {
+        id: StageId,
+        crash: bool,
+        unwound: Arc<parking_lot::Mutex<Vec<StageId>>>,
+    }
+
+    #[async_trait]
+    impl<'db, RwTx> Stage<'db, RwTx> for Unwinding
+    where
+        RwTx: MutableTransaction<'db>,
+    {
+        fn id(&self) -> StageId {
+            self.id
+        }
+
+        fn description(&self) -> &'static str {
+            "Unwinding"
+        }
+
+        async fn execute<'tx>(
+            &self,
+            _: &'tx mut RwTx,
+            input: StageInput,
+        ) -> anyhow::Result<ExecOutput>
+        where
+            'db: 'tx,
+        {
+            Ok(ExecOutput::Progress {
+                stage_progress: input.stage_progress.unwrap_or(0),
+                done: true,
+                must_commit: false,
+            })
+        }
+
+        async fn unwind<'tx>(&self, _: &'tx mut RwTx, _: UnwindInput) -> anyhow::Result<()>
+        where
+            'db: 'tx,
+        {
+            if self.crash {
+                anyhow::bail!("crash");
+            }
+            self.unwound.lock().push(self.id);
+            Ok(())
+        }
+    }
+
+    const A: StageId = StageId("A");
+    const B: StageId = StageId("B");
+    const C: StageId = StageId("C");
+    const D: StageId = StageId("D");
+
+    fn build<'db, DB: MutableKV>(
+        stages: &[(StageId, &[StageId])],
+    ) -> Result<StagedSync<'db, DB>, PipelineError> {
+        stages
+            .iter()
+            .fold(StagedSync::builder(), |builder, (id, prerequisites)| {
+                builder.add(Noop(*id), prerequisites)
+            })
+            .build()
+    }
+
+    fn order<DB: MutableKV>(staged_sync: &StagedSync<'_, DB>) -> Vec<StageId> {
+        staged_sync.pipeline().map(|(id, _, _)| id).collect()
+    }
+
+    #[test]
+    fn resolve_pipeline() {
+        type DB = crate::MdbxEnvironment<mdbx::NoWriteMap>;
+
+        // Registration order is kept unless prerequisites say otherwise
+        let staged_sync = build::<DB>(&[(C, &[B]), (A, &[]), (B, &[A]), (D, &[B])]).unwrap();
+        assert_eq!(order(&staged_sync), vec![A, B, C, D]);
+        assert_eq!(
+            staged_sync.pipeline().nth(3).map(|(_, _, p)| p.to_vec()),
+            Some(vec![B])
+        );
+
+        assert_eq!(
+            build::<DB>(&[(A, &[]), (B, &[A]), (A, &[])]).unwrap_err(),
+            PipelineError::DuplicateStage(A)
+        );
+        assert_eq!(
+            build::<DB>(&[(A, &[]), (B, &[C])]).unwrap_err(),
+            PipelineError::UnknownPrerequisite {
+                stage: B,
+                prerequisite: C
+            }
+        );
+        assert_eq!(
+            build::<DB>(&[(A, &[]), (B, &[A, D]), (C, &[B]), (D, &[C])]).unwrap_err(),
+            PipelineError::Cycle(vec![B, C, D])
+        );
+
+        // C would be handed B's progress instead of A's
+        assert_eq!(
+            build::<DB>(&[(A, &[]), (B, &[]), (C, &[A])]).unwrap_err(),
+            PipelineError::PreviousStageMismatch {
+                stage: C,
+                previous: B,
+                prerequisite: A
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn run_rejects_duplicate_stages() {
+        let db = new_mem_database().unwrap();
+
+        let mut staged_sync = StagedSync::new();
+        staged_sync.push(Noop(A));
+        staged_sync.push(Noop(A));
+
+        assert_eq!(
+            staged_sync
+                .run(&db)
+                .await
+                .unwrap_err()
+                .downcast::<PipelineError>()
+                .unwrap(),
+            PipelineError::DuplicateStage(A)
+        );
+    }
+
+    #[tokio::test]
+    async fn bounded_run() {
+        let db = new_mem_database().unwrap();
+
+        let mut staged_sync = StagedSync::new();
+        staged_sync.push(follow(A));
+        staged_sync.push(follow(B));
+        staged_sync.set_exit_after_sync(true);
+        staged_sync.run(&db).await.unwrap();
+        assert_eq!(progress(&db, A).await, Some(100));
+        assert_eq!(progress(&db, B).await, Some(100));
+
+        staged_sync.set_exit_after_sync(false);
+        staged_sync.set_max_block(Some(250));
+        staged_sync.run(&db).await.unwrap();
+        assert_eq!(progress(&db, A).await, Some(300));
+        assert_eq!(progress(&db, B).await, Some(250));
+
+        let metrics = staged_sync.metrics();
+        let a = metrics.get(A).unwrap();
+        assert_eq!((a.progress, a.target, a.blocks), (300, None, 300));
+        let b = metrics.get(B).unwrap();
+        assert_eq!((b.progress, b.target, b.blocks), (250, Some(250), 250));
+        assert_eq!(b.unwinds, 0);
+    }
+
+    #[tokio::test]
+    async fn cancelled_run() {
+        let db = new_mem_database().unwrap();
+
+        let mut staged_sync = StagedSync::new();
+        let cancel = staged_sync.cancellation_token();
+        staged_sync.push(follow(A));
+        staged_sync.push(Follow {
+            cancel: Some(cancel),
+            ..follow(B)
+        });
+        staged_sync.push(follow(C));
+        staged_sync.run(&db).await.unwrap();
+
+        // Progress of the cancelling stage is committed, the rest of the pipeline is not run
+        assert_eq!(progress(&db, A).await, Some(100));
+        assert_eq!(progress(&db, B).await, Some(100));
+        assert_eq!(progress(&db, C).await, None);
+    }
+
+    #[tokio::test]
+    async fn resume_unwind() {
+        let db = new_mem_database().unwrap();
+
+        let tx = db.begin_mutable().await.unwrap();
+        for stage in [A, B, C, D] {
+            stage.save_progress(&tx, 100).await.unwrap();
+        }
+        tx.commit().await.unwrap();
+
+        let unwound = Arc::new(parking_lot::Mutex::new(vec![]));
+        let pipeline = |crash_stage| {
+            let mut staged_sync = StagedSync::new();
+            for id in [A, B, C] {
+                staged_sync.push(Unwinding {
+                    id,
+                    crash: Some(id) == crash_stage,
+                    unwound: unwound.clone(),
+                });
+            }
+            staged_sync.push(Follow {
+                id: D,
+                step: 0,
+                cancel: None,
+            });
+            staged_sync.set_exit_after_sync(true);
+            staged_sync
+        };
+
+        // Request an unwind, then crash after C and D have been unwound
+        let tx = db.begin_mutable().await.unwrap();
+        for stage in [A, B, C, D] {
+            stage.save_unwind(&tx, 50).await.unwrap();
+        }
+        tx.commit().await.unwrap();
+
+        assert_eq!(
+            pipeline(Some(B)).run(&db).await.unwrap_err().to_string(),
+            "crash"
+        );
+        assert_eq!(*unwound.lock(), vec![C]);
+        assert_eq!(progress(&db, A).await, Some(100));
+        assert_eq!(progress(&db, B).await, Some(100));
+        assert_eq!(progress(&db, C).await, Some(50));
+        assert_eq!(progress(&db, D).await, Some(50));
+
+        // The unwind point is still there, so the next run finishes the unwind before executing stages
+        let tx = db.begin_mutable().await.unwrap();
+        for stage in [A, B, C, D] {
+            assert_eq!(stage.get_unwind(&tx).await.unwrap(), Some(50));
+        }
+        drop(tx);
+
+        let staged_sync = pipeline(None);
+        staged_sync.run(&db).await.unwrap();
+        assert_eq!(*unwound.lock(), vec![C, B, A]);
+        let metrics = staged_sync.metrics();
+        assert_eq!(metrics.get(B).unwrap().unwinds, 1);
+        assert_eq!(metrics.get(C).unwrap().unwinds, 0);
+        let tx = db.begin_mutable().await.unwrap();
+        for stage in [A, B, C, D] {
+            assert_eq!(stage.get_progress(&tx).await.unwrap(), Some(50));
+            assert_eq!(stage.get_unwind(&tx).await.unwrap(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn prune_after_cycle() {
+        let db = new_mem_database().unwrap();
+
+        let mut staged_sync = StagedSync::new();
+        staged_sync.push(follow(A));
+        staged_sync.push(Unwinding {
+            id: B,
+            crash: false,
+            unwound: Default::default(),
+        });
+        staged_sync.set_exit_after_sync(true);
+        staged_sync.set_prune(PruneSettings {
+            history: Some(30),
+            ..Default::default()
+        });
+
+        for prune_progress in [71, 171] {
+            staged_sync.run(&db).await.unwrap();
+
+            let tx = db.begin_mutable().await.unwrap();
+            assert_eq!(
+                A.get_prune_progress(&tx).await.unwrap(),
+                Some(prune_progress)
+            );
+            // Stages without pruning logic keep everything
+            assert_eq!(B.get_prune_progress(&tx).await.unwrap(), None);
         }
     }
 }
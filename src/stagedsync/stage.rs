@@ -2,7 +2,7 @@ use super::stages::StageId;
 use crate::MutableTransaction;
 use async_trait::async_trait;
 use auto_impl::auto_impl;
-use std::fmt::Debug;
+use std::{cmp, fmt::Debug};
 
 #[derive(Debug, PartialEq)]
 pub enum ExecOutput {
@@ -35,6 +35,15 @@ pub trait Stage<'db, RwTx: MutableTransaction<'db>>: Send + Sync + Debug {
     async fn unwind<'tx>(&self, tx: &'tx mut RwTx, input: UnwindInput) -> anyhow::Result<()>
     where
         'db: 'tx;
+    /// Called after each sync cycle to drop data of old blocks, according to retention settings.
+    /// Returns the first block which is kept, if anything has been pruned.
+    async fn prune<'tx>(&self, tx: &'tx mut RwTx, input: PruneInput) -> anyhow::Result<Option<u64>>
+    where
+        'db: 'tx,
+    {
+        let _ = (tx, input);
+        Ok(None)
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -49,3 +58,47 @@ pub struct UnwindInput {
     pub stage_progress: u64,
     pub unwind_to: u64,
 }
+
+/// Deepest unwind the sync is expected to handle. History of at least this many blocks is always kept,
+/// since `Execution` unwinds the state with changesets.
+pub const MAX_UNWIND_DEPTH: u64 = 90_000;
+
+/// How many most recent blocks of each kind of data to keep. `None` keeps all blocks.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PruneSettings {
+    /// Account and storage changesets along with their history indexes. Never less than `MAX_UNWIND_DEPTH`.
+    pub history: Option<u64>,
+    /// Receipts and logs along with log indexes.
+    pub receipts: Option<u64>,
+    /// Call traces along with call indexes.
+    pub call_traces: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PruneInput {
+    pub stage_progress: u64,
+    /// Blocks before this one have already been pruned.
+    pub prune_progress: Option<u64>,
+    pub settings: PruneSettings,
+}
+
+impl PruneInput {
+    /// First block to keep when retaining only the last `keep` blocks, or `None` if there is nothing new to prune.
+    pub fn prune_before(&self, keep: Option<u64>) -> Option<u64> {
+        let before = (self.stage_progress + 1).saturating_sub(keep?);
+        if before > self.prune_progress.unwrap_or(0) {
+            Some(before)
+        } else {
+            None
+        }
+    }
+
+    /// First block of history to keep, retaining no fewer than `MAX_UNWIND_DEPTH` blocks.
+    pub fn prune_history_before(&self) -> Option<u64> {
+        self.prune_before(
+            self.settings
+                .history
+                .map(|keep| cmp::max(keep, MAX_UNWIND_DEPTH)),
+        )
+    }
+}
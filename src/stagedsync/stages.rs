@@ -1,10 +1,10 @@
-use crate::{common, kv::*, MutableTransaction, Transaction};
+use crate::{common, kv::*, MutableCursor, MutableTransaction, Transaction};
 use anyhow::Context;
 use arrayref::array_ref;
 use std::fmt::Display;
 use tracing::*;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct StageId(pub &'static str);
 
 pub const HEADERS: StageId = StageId("Headers");
@@ -102,4 +102,32 @@ impl StageId {
     ) -> anyhow::Result<()> {
         self.save(tx, &tables::SyncStageUnwind, block).await
     }
+
+    #[instrument]
+    pub async fn get_prune_progress<'db, Tx: Transaction<'db>>(
+        &self,
+        tx: &Tx,
+    ) -> anyhow::Result<Option<u64>> {
+        self.get(tx, &tables::SyncStagePrune).await
+    }
+
+    #[instrument]
+    pub async fn save_prune_progress<'db, RwTx: MutableTransaction<'db>>(
+        &self,
+        tx: &RwTx,
+        block: u64,
+    ) -> anyhow::Result<()> {
+        self.save(tx, &tables::SyncStagePrune, block).await
+    }
+
+    #[instrument]
+    pub async fn clear_unwind<'db, RwTx: MutableTransaction<'db>>(
+        &self,
+        tx: &RwTx,
+    ) -> anyhow::Result<()> {
+        tx.mutable_cursor(&tables::SyncStageUnwind)
+            .await?
+            .delete(self.as_ref(), &[])
+            .await
+    }
 }
@@ -0,0 +1,87 @@
+use super::{
+    stage::{ExecOutput, Stage, StageInput, UnwindInput},
+    stages::{StageId, EXECUTION},
+    StagedSync,
+};
+use crate::{
+    kv::traits::MutableKV,
+    stages::{AccountHistoryIndex, StorageHistoryIndex},
+    MutableTransaction,
+};
+use async_trait::async_trait;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const UNWIND_COMPLETE: &str = "Unwind complete";
+
+/// Requests an unwind on the first run, and stops the sync on the next one.
+#[derive(Debug)]
+struct UnwindTrigger {
+    unwind_to: u64,
+    triggered: AtomicBool,
+}
+
+#[async_trait]
+impl<'db, RwTx> Stage<'db, RwTx> for UnwindTrigger
+where
+    RwTx: MutableTransaction<'db>,
+{
+    fn id(&self) -> StageId {
+        StageId("UnwindTrigger")
+    }
+
+    fn description(&self) -> &'static str {
+        "Requesting unwind"
+    }
+
+    async fn execute<'tx>(&self, _: &'tx mut RwTx, _: StageInput) -> anyhow::Result<ExecOutput>
+    where
+        'db: 'tx,
+    {
+        if self.triggered.swap(true, Ordering::SeqCst) {
+            anyhow::bail!(UNWIND_COMPLETE);
+        }
+
+        Ok(ExecOutput::Unwind {
+            unwind_to: self.unwind_to,
+        })
+    }
+
+    async fn unwind<'tx>(&self, _: &'tx mut RwTx, _: UnwindInput) -> anyhow::Result<()>
+    where
+        'db: 'tx,
+    {
+        Ok(())
+    }
+}
+
+/// Runs `StagedSync` with `stage` until it has been unwound to `unwind_to`.
+/// Stage progress should be saved beforehand, `stage` itself is never executed.
+pub async fn unwind_stage<'db, DB, S>(db: &'db DB, stage: S, unwind_to: u64)
+where
+    DB: MutableKV,
+    S: Stage<'db, DB::MutableTx<'db>> + 'static,
+{
+    let mut staged_sync = StagedSync::<DB>::new();
+    staged_sync.push(UnwindTrigger {
+        unwind_to,
+        triggered: AtomicBool::new(false),
+    });
+    staged_sync.push(stage);
+
+    let e = staged_sync.run(db).await.unwrap_err();
+    assert_eq!(e.to_string(), UNWIND_COMPLETE);
+}
+
+/// Builds history indexes of blocks `1..=to` from the changesets, as the history index stages do.
+pub async fn index_history<'db, RwTx>(tx: &mut RwTx, to: u64)
+where
+    RwTx: MutableTransaction<'db>,
+{
+    let input = || StageInput {
+        restarted: false,
+        previous_stage: Some((EXECUTION, to)),
+        stage_progress: None,
+    };
+    AccountHistoryIndex.execute(tx, input()).await.unwrap();
+    StorageHistoryIndex.execute(tx, input()).await.unwrap();
+}
@@ -1,12 +1,15 @@
 use crate::{
+    accessors::chain,
+    dbutils,
     etl::{
         collector::{Collector, OPTIMAL_BUFFER_CAPACITY},
         data_provider::Entry,
     },
     kv::tables,
-    stagedsync::stage::{ExecOutput, Stage, StageInput},
-    txdb, MutableTransaction, StageId,
+    stagedsync::stage::{ExecOutput, Stage, StageInput, UnwindInput},
+    txdb, MutableCursor, MutableTransaction, StageId, Transaction,
 };
+use arrayref::array_ref;
 use async_trait::async_trait;
 use tokio::pin;
 use tokio_stream::StreamExt;
@@ -33,43 +36,217 @@ where
         'db: 'tx,
     {
         let past_progress = input.stage_progress.unwrap_or(0);
+        let max_height = if let Some((_, height)) = input.previous_stage {
+            height
+        } else {
+            0
+        };
+        // Genesis hash has to be mapped as well
+        let start_height = input.stage_progress.map(|p| p + 1).unwrap_or(0);
 
-        let mut bodies_cursor = tx.mutable_cursor(&tables::BlockBody).await?;
+        if start_height > max_height {
+            return Ok(ExecOutput::Progress {
+                stage_progress: past_progress,
+                done: true,
+                must_commit: false,
+            });
+        }
+
+        let mut canonical_cursor = tx.cursor(&tables::CanonicalHeader).await?;
         let mut blockhashes_cursor = tx.mutable_cursor(&tables::HeaderNumber).await?;
-        let processed = 0;
+        let mut processed = None;
 
-        let start_key = past_progress.to_be_bytes();
+        let start_key = dbutils::encode_block_number(start_height);
         let mut collector = Collector::new(OPTIMAL_BUFFER_CAPACITY);
-        let walker = txdb::walk(&mut bodies_cursor, &start_key, 0);
+        let walker = txdb::walk(&mut canonical_cursor, &start_key, 0);
         pin!(walker);
 
-        while let Some((block_key, _)) = walker.try_next().await? {
-            // BlockBody Key is block_number + hash, so we just separate and collect
+        while let Some((block_key, hash)) = walker.try_next().await? {
+            let block_number = u64::from_be_bytes(*array_ref!(block_key, 0, 8));
+            // Stop at the first gap in the canonical chain, or once previous stage is reached
+            if block_number > max_height
+                || block_number != processed.map(|p| p + 1).unwrap_or(start_height)
+            {
+                break;
+            }
+
+            // CanonicalHeader maps block number to hash, we need it the other way around
             collector.collect(Entry {
-                key: block_key[8..].to_vec(),
-                value: block_key[..8].to_vec(),
+                key: hash.to_vec(),
+                value: block_key.to_vec(),
                 id: 0, // Irrelevant here, could be anything
             });
+            processed = Some(block_number);
         }
-        collector.load(&mut blockhashes_cursor, None).await?;
-        info!("Processed");
+        collector.load_append(&mut blockhashes_cursor).await?;
+
+        let stage_progress = processed.unwrap_or(past_progress);
+        info!(highest = stage_progress, "Processed");
         Ok(ExecOutput::Progress {
-            stage_progress: processed,
-            done: false,
-            must_commit: true,
+            stage_progress,
+            done: true,
+            must_commit: processed.is_some(),
         })
     }
 
-    async fn unwind<'tx>(
-        &self,
-        tx: &'tx mut RwTx,
-        input: crate::stagedsync::stage::UnwindInput,
-    ) -> anyhow::Result<()>
+    async fn unwind<'tx>(&self, tx: &'tx mut RwTx, input: UnwindInput) -> anyhow::Result<()>
     where
         'db: 'tx,
     {
-        let _ = tx;
-        let _ = input;
-        todo!()
+        let mut blockhashes_cursor = tx.mutable_cursor(&tables::HeaderNumber).await?;
+
+        for block_number in input.unwind_to + 1..=input.stage_progress {
+            if let Some(hash) = chain::canonical_hash::read(tx, block_number).await? {
+                blockhashes_cursor.delete(hash.as_bytes(), &[]).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{kv::traits::MutableKV, new_mem_database, stagedsync::test_util::unwind_stage};
+    use ethereum_types::H256;
+
+    #[tokio::test]
+    async fn map_canonical_hashes_incrementally() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let hashes = (0..=5).map(|_| H256::random()).collect::<Vec<_>>();
+        for (block_number, hash) in hashes.iter().enumerate() {
+            chain::canonical_hash::write(&tx, block_number as u64, *hash)
+                .await
+                .unwrap();
+        }
+        // Non-canonical block must not be mapped
+        let fork_hash = H256::random();
+        chain::storage_body::write(
+            &tx,
+            fork_hash,
+            2,
+            &crate::models::BodyForStorage {
+                base_tx_id: 0,
+                tx_amount: 0,
+                uncles: vec![],
+            },
+        )
+        .await
+        .unwrap();
+
+        let output = BlockHashes
+            .execute(
+                &mut tx,
+                StageInput {
+                    restarted: false,
+                    previous_stage: Some((StageId("HeaderDownload"), 3)),
+                    stage_progress: None,
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            output,
+            ExecOutput::Progress {
+                stage_progress: 3,
+                done: true,
+                must_commit: true,
+            }
+        );
+        for (block_number, hash) in hashes.iter().enumerate() {
+            let expected = (block_number <= 3).then(|| block_number as u64);
+            assert_eq!(
+                chain::header_number::read(&tx, *hash).await.unwrap(),
+                expected
+            );
+        }
+
+        let output = BlockHashes
+            .execute(
+                &mut tx,
+                StageInput {
+                    restarted: false,
+                    previous_stage: Some((StageId("HeaderDownload"), 5)),
+                    stage_progress: Some(3),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            output,
+            ExecOutput::Progress {
+                stage_progress: 5,
+                done: true,
+                must_commit: true,
+            }
+        );
+        for (block_number, hash) in hashes.iter().enumerate() {
+            assert_eq!(
+                chain::header_number::read(&tx, *hash).await.unwrap(),
+                Some(block_number as u64)
+            );
+        }
+        assert_eq!(
+            chain::header_number::read(&tx, fork_hash).await.unwrap(),
+            None
+        );
+
+        // Nothing left to do
+        let output = BlockHashes
+            .execute(
+                &mut tx,
+                StageInput {
+                    restarted: false,
+                    previous_stage: Some((StageId("HeaderDownload"), 5)),
+                    stage_progress: Some(5),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            output,
+            ExecOutput::Progress {
+                stage_progress: 5,
+                done: true,
+                must_commit: false,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn unwind_block_hashes() {
+        let db = new_mem_database().unwrap();
+
+        let hashes = (0..=3).map(|_| H256::random()).collect::<Vec<_>>();
+
+        let tx = db.begin_mutable().await.unwrap();
+        for (block_number, hash) in hashes.iter().enumerate() {
+            chain::canonical_hash::write(&tx, block_number as u64, *hash)
+                .await
+                .unwrap();
+            chain::header_number::write(&tx, *hash, block_number as u64)
+                .await
+                .unwrap();
+        }
+        StageId("BlockHashes").save_progress(&tx, 3).await.unwrap();
+        tx.commit().await.unwrap();
+
+        unwind_stage(&db, BlockHashes, 1).await;
+
+        let tx = db.begin_mutable().await.unwrap();
+        assert_eq!(
+            StageId("BlockHashes").get_progress(&tx).await.unwrap(),
+            Some(1)
+        );
+        for (block_number, hash) in hashes.iter().enumerate() {
+            let expected = (block_number <= 1).then(|| block_number as u64);
+            assert_eq!(
+                chain::header_number::read(&tx, *hash).await.unwrap(),
+                expected
+            );
+        }
     }
 }
@@ -0,0 +1,483 @@
+use crate::{
+    accessors::chain,
+    common, dbutils,
+    downloader::{
+        messages::{BlockBody, EthMessageId, GetBlockBodiesMessage, Message},
+        sentry_client::PeerFilter,
+        sentry_client_reactor::SentryClientReactor,
+    },
+    kv::tables,
+    models::BodyForStorage,
+    stagedsync::{
+        stage::{ExecOutput, Stage, StageInput, UnwindInput},
+        stages::BODIES,
+    },
+    MutableCursor, MutableTransaction, StageId,
+};
+use async_trait::async_trait;
+use ethereum::{util::ordered_trie_root, EnvelopedEncodable, Header};
+use ethereum_types::H256;
+use std::{
+    cmp,
+    fmt::{self, Debug},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::time::timeout;
+use tokio_stream::StreamExt;
+use tracing::*;
+
+const BODIES_REQUEST_LIMIT: u64 = 128;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Error, Debug)]
+pub enum BlockBodiesError {
+    #[error("Canonical hash for block {0} not found")]
+    HashNotFound(u64),
+    #[error("Header for block {0} not found")]
+    HeaderNotFound(u64),
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum BodyValidationError {
+    #[error("Block {0} body does not match transactions root")]
+    WrongTransactionsRoot(u64),
+    #[error("Block {0} body does not match ommers hash")]
+    WrongOmmersHash(u64),
+}
+
+fn verify_body(header: &Header, body: &BlockBody) -> Result<(), BodyValidationError> {
+    let number = header.number.as_u64();
+
+    let transactions_root = ordered_trie_root(
+        body.transactions
+            .iter()
+            .map(|tx| EnvelopedEncodable::encode(tx)),
+    );
+    if transactions_root != header.transactions_root {
+        return Err(BodyValidationError::WrongTransactionsRoot(number));
+    }
+
+    if common::hash_data(&rlp::encode_list(&body.ommers)) != header.ommers_hash {
+        return Err(BodyValidationError::WrongOmmersHash(number));
+    }
+
+    Ok(())
+}
+
+/// Downloads bodies of canonical blocks from peers through the sentry.
+pub struct BlockBodies {
+    sentry: Arc<SentryClientReactor>,
+    request_id: AtomicU64,
+}
+
+impl Debug for BlockBodies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockBodies")
+            .field("request_id", &self.request_id)
+            .finish()
+    }
+}
+
+impl BlockBodies {
+    pub fn new(sentry: Arc<SentryClientReactor>) -> Self {
+        Self {
+            sentry,
+            request_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Requests bodies from peers, returns the first matching response or `None` on timeout.
+    async fn request_bodies(
+        &self,
+        block_hashes: Vec<H256>,
+    ) -> anyhow::Result<Option<Vec<BlockBody>>> {
+        let request_id = self.request_id.fetch_add(1, Ordering::Relaxed);
+
+        // subscribe before sending the request, so that the response is not missed
+        let mut stream = self.sentry.receive_messages(EthMessageId::BlockBodies)?;
+
+        self.sentry
+            .send_message(
+                Message::GetBlockBodies(GetBlockBodiesMessage {
+                    request_id,
+                    block_hashes,
+                }),
+                PeerFilter::All,
+            )
+            .await?;
+
+        let response = timeout(REQUEST_TIMEOUT, async {
+            while let Some(message) = stream.next().await {
+                if let Message::BlockBodies(message) = message {
+                    if message.request_id == request_id {
+                        return Some(message.bodies);
+                    }
+                }
+            }
+
+            None
+        })
+        .await;
+
+        Ok(response.ok().flatten())
+    }
+}
+
+#[async_trait]
+impl<'db, RwTx> Stage<'db, RwTx> for BlockBodies
+where
+    RwTx: MutableTransaction<'db>,
+{
+    fn id(&self) -> StageId {
+        BODIES
+    }
+
+    fn description(&self) -> &'static str {
+        "Downloading block bodies"
+    }
+
+    async fn execute<'tx>(&self, tx: &'tx mut RwTx, input: StageInput) -> anyhow::Result<ExecOutput>
+    where
+        'db: 'tx,
+    {
+        let from_height = input.stage_progress.unwrap_or(0);
+        let max_height = if let Some((_, height)) = input.previous_stage {
+            height
+        } else {
+            0
+        };
+        let to_height = cmp::min(max_height, from_height + BODIES_REQUEST_LIMIT);
+
+        // Headers of blocks that still lack bodies, `None` for those already stored
+        let mut blocks = vec![];
+        for number in from_height + 1..=to_height {
+            let hash = chain::canonical_hash::read(tx, number)
+                .await?
+                .ok_or(BlockBodiesError::HashNotFound(number))?;
+
+            let header = if chain::storage_body::has(tx, hash, number).await? {
+                None
+            } else {
+                Some(
+                    chain::header::read(tx, hash, number)
+                        .await?
+                        .ok_or(BlockBodiesError::HeaderNotFound(number))?,
+                )
+            };
+
+            blocks.push((number, hash, header));
+        }
+
+        let wanted = blocks
+            .iter()
+            .filter(|(_, _, header)| header.is_some())
+            .map(|(_, hash, _)| *hash)
+            .collect::<Vec<_>>();
+
+        let bodies = if wanted.is_empty() {
+            vec![]
+        } else {
+            info!(
+                from = from_height + 1,
+                count = wanted.len(),
+                "Requesting bodies"
+            );
+            self.request_bodies(wanted).await?.unwrap_or_else(|| {
+                info!("No bodies received");
+                vec![]
+            })
+        };
+        let mut bodies = bodies.into_iter();
+
+        let mut height = from_height;
+        for (number, hash, header) in blocks {
+            if let Some(header) = header {
+                let body = match bodies.next() {
+                    Some(body) => body,
+                    None => break,
+                };
+
+                if let Err(e) = verify_body(&header, &body) {
+                    warn!("Discarding invalid block body: {}", e);
+                    break;
+                }
+
+                let tx_amount = body.transactions.len();
+                let base_tx_id = tx
+                    .increment_sequence(&tables::BlockTransaction, tx_amount as u64)
+                    .await?;
+
+                chain::tx::write(tx, base_tx_id, &body.transactions).await?;
+                chain::storage_body::write(
+                    tx,
+                    hash,
+                    number,
+                    &BodyForStorage {
+                        base_tx_id,
+                        tx_amount: tx_amount as u32,
+                        uncles: body.ommers,
+                    },
+                )
+                .await?;
+            }
+
+            height = number;
+        }
+
+        info!(highest = height, "Processed");
+
+        Ok(ExecOutput::Progress {
+            stage_progress: height,
+            done: height >= max_height || height == from_height,
+            must_commit: height > from_height,
+        })
+    }
+
+    async fn unwind<'tx>(&self, tx: &'tx mut RwTx, input: UnwindInput) -> anyhow::Result<()>
+    where
+        'db: 'tx,
+    {
+        let mut body_cursor = tx.mutable_cursor(&tables::BlockBody).await?;
+        let mut tx_cursor = tx.mutable_cursor(&tables::BlockTransaction).await?;
+        let mut first_tx_id = None;
+
+        for number in input.unwind_to + 1..=input.stage_progress {
+            let hash = chain::canonical_hash::read(tx, number)
+                .await?
+                .ok_or(BlockBodiesError::HashNotFound(number))?;
+
+            if let Some(body) = chain::storage_body::read(tx, hash, number).await? {
+                first_tx_id.get_or_insert(body.base_tx_id);
+                for id in body.base_tx_id..body.base_tx_id + body.tx_amount as u64 {
+                    tx_cursor.delete(&id.to_be_bytes(), &[]).await?;
+                }
+                body_cursor
+                    .delete(&dbutils::header_key(number, hash), &[])
+                    .await?;
+            }
+        }
+
+        // Transactions of re-downloaded bodies take over ids of the deleted ones
+        if let Some(id) = first_tx_id {
+            tx.reset_sequence(&tables::BlockTransaction, id).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        downloader::{messages::BlockBodiesMessage, sentry_client_mock::SentryClientMock},
+        kv::traits::MutableKV,
+        new_mem_database, Transaction,
+    };
+    use ethereum::{TransactionAction, TransactionSignature, TransactionV2};
+    use ethereum_types::{Address, Bloom, H64};
+
+    fn transfer(nonce: u64) -> TransactionV2 {
+        TransactionV2::Legacy(ethereum::LegacyTransaction {
+            nonce: nonce.into(),
+            gas_price: 1_000_000.into(),
+            gas_limit: 21_000.into(),
+            action: TransactionAction::Call(Address::repeat_byte(0xbb)),
+            value: 1000.into(),
+            input: vec![],
+            signature: TransactionSignature::new(27, H256::repeat_byte(2), H256::repeat_byte(3))
+                .unwrap(),
+        })
+    }
+
+    fn header(number: u64, body: &BlockBody) -> Header {
+        Header {
+            parent_hash: H256::zero(),
+            ommers_hash: common::hash_data(&rlp::encode_list(&body.ommers)),
+            beneficiary: Address::zero(),
+            state_root: H256::zero(),
+            transactions_root: ordered_trie_root(
+                body.transactions
+                    .iter()
+                    .map(|tx| EnvelopedEncodable::encode(tx)),
+            ),
+            receipts_root: H256::zero(),
+            logs_bloom: Bloom::zero(),
+            difficulty: 1.into(),
+            number: number.into(),
+            gas_limit: 10_000_000.into(),
+            gas_used: 0.into(),
+            timestamp: number,
+            extra_data: vec![],
+            mix_hash: H256::zero(),
+            nonce: H64::zero(),
+        }
+    }
+
+    async fn write_header<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(
+        tx: &'tx RwTx,
+        header: &Header,
+    ) {
+        let hash = header.hash();
+        let number = header.number.as_u64();
+        chain::header::write(tx, hash, number, header)
+            .await
+            .unwrap();
+        chain::canonical_hash::write(tx, number, hash)
+            .await
+            .unwrap();
+    }
+
+    fn stage(responses: Vec<Message>) -> BlockBodies {
+        let mut sentry =
+            SentryClientReactor::new(Box::new(SentryClientMock::with_responses(responses)));
+        sentry.start();
+        BlockBodies::new(Arc::new(sentry))
+    }
+
+    fn input(stage_progress: u64, headers_progress: u64) -> StageInput {
+        StageInput {
+            restarted: false,
+            previous_stage: Some((StageId("HeaderDownload"), headers_progress)),
+            stage_progress: Some(stage_progress),
+        }
+    }
+
+    #[tokio::test]
+    async fn download_and_unwind_bodies() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let bodies = vec![
+            BlockBody {
+                transactions: vec![transfer(0), transfer(1)],
+                ommers: vec![],
+            },
+            BlockBody {
+                transactions: vec![],
+                ommers: vec![],
+            },
+            BlockBody {
+                transactions: vec![transfer(2)],
+                ommers: vec![header(
+                    1,
+                    &BlockBody {
+                        transactions: vec![],
+                        ommers: vec![],
+                    },
+                )],
+            },
+        ];
+        let headers = bodies
+            .iter()
+            .enumerate()
+            .map(|(i, body)| header(i as u64 + 1, body))
+            .collect::<Vec<_>>();
+        for header in &headers {
+            write_header(&tx, header).await;
+        }
+
+        let stage = stage(vec![Message::BlockBodies(BlockBodiesMessage {
+            request_id: 0,
+            bodies: bodies.clone(),
+        })]);
+
+        assert_eq!(
+            stage.execute(&mut tx, input(0, 3)).await.unwrap(),
+            ExecOutput::Progress {
+                stage_progress: 3,
+                done: true,
+                must_commit: true,
+            }
+        );
+
+        let mut base_tx_id = 0;
+        for (header, body) in headers.iter().zip(&bodies) {
+            let stored = chain::storage_body::read(&tx, header.hash(), header.number.as_u64())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(stored.base_tx_id, base_tx_id);
+            assert_eq!(stored.tx_amount as usize, body.transactions.len());
+            assert_eq!(stored.uncles, body.ommers);
+            assert_eq!(
+                chain::tx::read(&tx, stored.base_tx_id, stored.tx_amount)
+                    .await
+                    .unwrap(),
+                body.transactions
+            );
+            base_tx_id += stored.tx_amount as u64;
+        }
+
+        stage
+            .unwind(
+                &mut tx,
+                UnwindInput {
+                    stage_progress: 3,
+                    unwind_to: 1,
+                },
+            )
+            .await
+            .unwrap();
+
+        assert!(chain::storage_body::has(&tx, headers[0].hash(), 1)
+            .await
+            .unwrap());
+        for header in &headers[1..] {
+            assert!(
+                !chain::storage_body::has(&tx, header.hash(), header.number.as_u64())
+                    .await
+                    .unwrap()
+            );
+        }
+        assert!(chain::tx::read(&tx, 2, 1).await.unwrap().is_empty());
+        assert_eq!(
+            tx.read_sequence(&tables::BlockTransaction).await.unwrap(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_body() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let valid = BlockBody {
+            transactions: vec![transfer(0)],
+            ommers: vec![],
+        };
+        let headers = vec![header(1, &valid), header(2, &valid)];
+        for header in &headers {
+            write_header(&tx, header).await;
+        }
+
+        // Second body misses a transaction
+        let stage = stage(vec![Message::BlockBodies(BlockBodiesMessage {
+            request_id: 0,
+            bodies: vec![
+                valid.clone(),
+                BlockBody {
+                    transactions: vec![],
+                    ommers: vec![],
+                },
+            ],
+        })]);
+
+        assert_eq!(
+            stage.execute(&mut tx, input(0, 2)).await.unwrap(),
+            ExecOutput::Progress {
+                stage_progress: 1,
+                done: false,
+                must_commit: true,
+            }
+        );
+        assert!(!chain::storage_body::has(&tx, headers[1].hash(), 2)
+            .await
+            .unwrap());
+    }
+}
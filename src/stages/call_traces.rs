@@ -0,0 +1,293 @@
+use crate::{
+    bitmapdb, dbutils,
+    kv::tables,
+    models::CallTrace,
+    stagedsync::{
+        stage::{ExecOutput, PruneInput, Stage, StageInput, UnwindInput},
+        stages::CALL_TRACES,
+    },
+    stages::prune::delete_blocks_before,
+    txdb, MutableTransaction, StageId, Transaction,
+};
+use arrayref::array_ref;
+use async_trait::async_trait;
+use std::collections::BTreeSet;
+use tokio::pin;
+use tokio_stream::StreamExt;
+use tracing::*;
+
+/// Reads call traces of blocks `from..=to`, returns them along with their block numbers.
+async fn read_call_traces<'db: 'tx, 'tx, Tx>(
+    tx: &'tx Tx,
+    from: u64,
+    to: u64,
+) -> anyhow::Result<Vec<(u64, CallTrace)>>
+where
+    Tx: Transaction<'db>,
+{
+    let mut out = vec![];
+
+    let start_key = dbutils::encode_block_number(from);
+    let mut cursor = tx.cursor_dup_sort(&tables::CallTraceSet).await?;
+    let walker = txdb::walk(&mut cursor, &start_key, 0);
+    pin!(walker);
+    while let Some((k, v)) = walker.try_next().await? {
+        let block_number = u64::from_be_bytes(*array_ref!(k, 0, 8));
+        if block_number > to {
+            break;
+        }
+
+        out.push((block_number, CallTrace::decode(&v)?));
+    }
+
+    Ok(out)
+}
+
+/// Builds `CallFromIndex` and `CallToIndex` of blocks in which each address calls or is called.
+#[derive(Debug)]
+pub struct CallTraces;
+
+#[async_trait]
+impl<'db, RwTx> Stage<'db, RwTx> for CallTraces
+where
+    RwTx: MutableTransaction<'db>,
+{
+    fn id(&self) -> StageId {
+        CALL_TRACES
+    }
+
+    fn description(&self) -> &'static str {
+        "Generating call trace indexes"
+    }
+
+    async fn execute<'tx>(&self, tx: &'tx mut RwTx, input: StageInput) -> anyhow::Result<ExecOutput>
+    where
+        'db: 'tx,
+    {
+        let from_height = input.stage_progress.unwrap_or(0);
+        let max_height = if let Some((_, height)) = input.previous_stage {
+            height
+        } else {
+            0
+        };
+
+        if max_height > from_height {
+            let mut from_index = bitmapdb::IndexCollector::default();
+            let mut to_index = bitmapdb::IndexCollector::default();
+            for (block_number, call_trace) in
+                read_call_traces(tx, from_height + 1, max_height).await?
+            {
+                if call_trace.from {
+                    from_index.insert(call_trace.address.as_bytes(), block_number)?;
+                }
+                if call_trace.to {
+                    to_index.insert(call_trace.address.as_bytes(), block_number)?;
+                }
+            }
+            from_index.load(tx, &tables::CallFromIndex).await?;
+            to_index.load(tx, &tables::CallToIndex).await?;
+        }
+
+        let stage_progress = std::cmp::max(from_height, max_height);
+        info!(highest = stage_progress, "Processed");
+        Ok(ExecOutput::Progress {
+            stage_progress,
+            done: true,
+            must_commit: stage_progress > from_height,
+        })
+    }
+
+    async fn unwind<'tx>(&self, tx: &'tx mut RwTx, input: UnwindInput) -> anyhow::Result<()>
+    where
+        'db: 'tx,
+    {
+        let mut from_addresses = BTreeSet::new();
+        let mut to_addresses = BTreeSet::new();
+        for (_, call_trace) in
+            read_call_traces(tx, input.unwind_to + 1, input.stage_progress).await?
+        {
+            if call_trace.from {
+                from_addresses.insert(call_trace.address);
+            }
+            if call_trace.to {
+                to_addresses.insert(call_trace.address);
+            }
+        }
+
+        for address in from_addresses {
+            bitmapdb::truncate(
+                tx,
+                &tables::CallFromIndex,
+                address.as_bytes(),
+                input.unwind_to + 1,
+            )
+            .await?;
+        }
+        for address in to_addresses {
+            bitmapdb::truncate(
+                tx,
+                &tables::CallToIndex,
+                address.as_bytes(),
+                input.unwind_to + 1,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn prune<'tx>(&self, tx: &'tx mut RwTx, input: PruneInput) -> anyhow::Result<Option<u64>>
+    where
+        'db: 'tx,
+    {
+        let before = if let Some(before) = input.prune_before(input.settings.call_traces) {
+            before
+        } else {
+            return Ok(None);
+        };
+
+        let mut from_addresses = BTreeSet::new();
+        let mut to_addresses = BTreeSet::new();
+        for (_, call_trace) in
+            read_call_traces(tx, input.prune_progress.unwrap_or(0), before - 1).await?
+        {
+            if call_trace.from {
+                from_addresses.insert(call_trace.address);
+            }
+            if call_trace.to {
+                to_addresses.insert(call_trace.address);
+            }
+        }
+
+        for address in from_addresses {
+            bitmapdb::prune(tx, &tables::CallFromIndex, address.as_bytes(), before).await?;
+        }
+        for address in to_addresses {
+            bitmapdb::prune(tx, &tables::CallToIndex, address.as_bytes(), before).await?;
+        }
+        delete_blocks_before(tx, &tables::CallTraceSet, before).await?;
+
+        info!(before, "Pruned");
+        Ok(Some(before))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        accessors::chain, kv::traits::MutableKV, new_mem_database,
+        stagedsync::test_util::unwind_stage,
+    };
+    use ethereum_types::Address;
+    use roaring::RoaringTreemap;
+
+    async fn read_index<'db: 'tx, 'tx, Tx: Transaction<'db>, T: crate::kv::Table>(
+        tx: &'tx Tx,
+        table: &T,
+        address: Address,
+    ) -> RoaringTreemap {
+        bitmapdb::get(tx, table, address.as_bytes(), 0, u64::MAX)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn call_traces() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let alice = Address::random();
+        let bob = Address::random();
+        let contract = Address::random();
+
+        // Alice calls the contract in every block, Bob calls Alice in every third one
+        for block_number in 1..=3000 {
+            let mut call_traces = vec![
+                CallTrace {
+                    address: alice,
+                    from: true,
+                    to: block_number % 3 == 0,
+                },
+                CallTrace {
+                    address: contract,
+                    from: false,
+                    to: true,
+                },
+            ];
+            if block_number % 3 == 0 {
+                call_traces.push(CallTrace {
+                    address: bob,
+                    from: true,
+                    to: false,
+                });
+            }
+            chain::call_trace::write(&tx, block_number, &call_traces)
+                .await
+                .unwrap();
+        }
+
+        for (stage_progress, height) in [(None, 1000), (Some(1000), 3000)] {
+            let output = CallTraces
+                .execute(
+                    &mut tx,
+                    StageInput {
+                        restarted: false,
+                        previous_stage: Some((StageId("Execution"), height)),
+                        stage_progress,
+                    },
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                output,
+                ExecOutput::Progress {
+                    stage_progress: height,
+                    done: true,
+                    must_commit: true,
+                }
+            );
+        }
+
+        let every_third = (3..=3000).step_by(3).collect::<RoaringTreemap>();
+        assert_eq!(
+            read_index(&tx, &tables::CallFromIndex, alice).await,
+            (1..=3000).collect::<RoaringTreemap>()
+        );
+        assert_eq!(
+            read_index(&tx, &tables::CallToIndex, alice).await,
+            every_third
+        );
+        assert_eq!(
+            read_index(&tx, &tables::CallFromIndex, bob).await,
+            every_third
+        );
+        assert!(read_index(&tx, &tables::CallToIndex, bob).await.is_empty());
+        assert!(read_index(&tx, &tables::CallFromIndex, contract)
+            .await
+            .is_empty());
+        assert_eq!(
+            read_index(&tx, &tables::CallToIndex, contract).await,
+            (1..=3000).collect::<RoaringTreemap>()
+        );
+
+        CALL_TRACES.save_progress(&tx, 3000).await.unwrap();
+        tx.commit().await.unwrap();
+
+        unwind_stage(&db, CallTraces, 1500).await;
+
+        let tx = db.begin_mutable().await.unwrap();
+        assert_eq!(
+            read_index(&tx, &tables::CallFromIndex, alice).await,
+            (1..=1500).collect::<RoaringTreemap>()
+        );
+        assert_eq!(
+            read_index(&tx, &tables::CallFromIndex, bob).await,
+            (3..=1500).step_by(3).collect::<RoaringTreemap>()
+        );
+        assert_eq!(
+            read_index(&tx, &tables::CallToIndex, contract).await,
+            (1..=1500).collect::<RoaringTreemap>()
+        );
+    }
+}
@@ -1,16 +1,336 @@
-use std::time::Duration;
-
 use crate::{
-    stagedsync::stage::{ExecOutput, Stage, StageInput},
-    MutableTransaction, StageId,
+    accessors::{chain, metadata},
+    common, dbutils,
+    downloader::{
+        block_id::BlockId,
+        messages::{EthMessageId, GetBlockHeadersMessage, GetBlockHeadersMessageParams, Message},
+        sentry_client::PeerFilter,
+        sentry_client_reactor::SentryClientReactor,
+    },
+    kv::tables,
+    models::ChainConfig,
+    stagedsync::stage::{ExecOutput, Stage, StageInput, UnwindInput},
+    MutableCursor, MutableTransaction, StageId, Transaction,
 };
 use async_trait::async_trait;
-use rand::Rng;
-use tokio::time::sleep;
+use ethereum::Header;
+use ethereum_types::{H256, U256};
+use std::{
+    cmp,
+    fmt::{self, Debug},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::time::timeout;
+use tokio_stream::StreamExt;
 use tracing::*;
 
-#[derive(Debug)]
-pub struct HeaderDownload;
+const HEADERS_REQUEST_LIMIT: u64 = 192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MINIMUM_DIFFICULTY: u64 = 131_072;
+const DIFFICULTY_BOUND_DIVISOR: u64 = 2048;
+const EXP_DIFFICULTY_PERIOD: u64 = 100_000;
+
+#[derive(Error, Debug)]
+pub enum HeaderDownloadError {
+    #[error("Canonical hash for block {0} not found")]
+    HashNotFound(u64),
+    #[error("Header for block {0} not found")]
+    HeaderNotFound(u64),
+    #[error("Total difficulty for block {0} not found")]
+    TotalDifficultyNotFound(u64),
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum HeaderValidationError {
+    #[error("Header {got} does not follow block {parent}")]
+    WrongNumber { parent: u64, got: u64 },
+    #[error("Header {0} does not link to its parent")]
+    ParentHashMismatch(u64),
+    #[error("Header {0} has timestamp not greater than its parent")]
+    InvalidTimestamp(u64),
+    #[error("Header {number} has difficulty {got}, expected {expected}")]
+    WrongDifficulty {
+        number: u64,
+        expected: U256,
+        got: U256,
+    },
+}
+
+/// Ethash difficulty of the block with given number and timestamp on top of `parent`.
+fn canonical_difficulty(
+    config: &ChainConfig,
+    number: u64,
+    timestamp: u64,
+    parent: &Header,
+) -> U256 {
+    let elapsed = timestamp.saturating_sub(parent.timestamp);
+
+    let factor: i64 = if config.is_byzantium(number) {
+        // EIP-100: account for ommers
+        let ommers = if parent.ommers_hash == common::EMPTY_LIST_HASH {
+            1
+        } else {
+            2
+        };
+        cmp::max(ommers - cmp::min(elapsed / 9, 100) as i64, -99)
+    } else if config.is_homestead(number) {
+        cmp::max(1 - cmp::min(elapsed / 10, 100) as i64, -99)
+    } else if elapsed < 13 {
+        1
+    } else {
+        -1
+    };
+
+    let adjustment = parent.difficulty / DIFFICULTY_BOUND_DIVISOR;
+    let mut difficulty = if factor >= 0 {
+        parent.difficulty + adjustment * U256::from(factor as u64)
+    } else {
+        parent
+            .difficulty
+            .saturating_sub(adjustment * U256::from(factor.unsigned_abs()))
+    };
+    difficulty = cmp::max(difficulty, MINIMUM_DIFFICULTY.into());
+
+    let bomb_delay = if config.is_arrow_glacier(number) {
+        // EIP-4345
+        10_700_000
+    } else if config.is_london(number) {
+        // EIP-3554
+        9_700_000
+    } else if config.is_muir_glacier(number) {
+        9_000_000
+    } else if config.is_constantinople(number) {
+        5_000_000
+    } else if config.is_byzantium(number) {
+        3_000_000
+    } else {
+        0
+    };
+    let period_count = number.saturating_sub(bomb_delay) / EXP_DIFFICULTY_PERIOD;
+    if period_count > 1 {
+        difficulty += U256::one() << (period_count - 2) as usize;
+    }
+
+    difficulty
+}
+
+fn verify_header(
+    config: &ChainConfig,
+    header: &Header,
+    parent: &Header,
+) -> Result<(), HeaderValidationError> {
+    let number = header.number.as_u64();
+    let parent_number = parent.number.as_u64();
+
+    if number != parent_number + 1 {
+        return Err(HeaderValidationError::WrongNumber {
+            parent: parent_number,
+            got: number,
+        });
+    }
+
+    if header.parent_hash != parent.hash() {
+        return Err(HeaderValidationError::ParentHashMismatch(number));
+    }
+
+    if header.timestamp <= parent.timestamp {
+        return Err(HeaderValidationError::InvalidTimestamp(number));
+    }
+
+    let expected = canonical_difficulty(config, number, header.timestamp, parent);
+    if header.difficulty != expected {
+        return Err(HeaderValidationError::WrongDifficulty {
+            number,
+            expected,
+            got: header.difficulty,
+        });
+    }
+
+    Ok(())
+}
+
+/// Verifies that `headers` form a chain on top of `parent`, returns total difficulty of the chain tip.
+fn verify_chain(
+    config: &ChainConfig,
+    parent: &Header,
+    parent_td: U256,
+    headers: &[Header],
+) -> Result<U256, HeaderValidationError> {
+    let mut td = parent_td;
+    let mut parent = parent;
+    for header in headers {
+        verify_header(config, header, parent)?;
+        td += header.difficulty;
+        parent = header;
+    }
+
+    Ok(td)
+}
+
+async fn write_headers<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(
+    tx: &'tx RwTx,
+    parent_td: U256,
+    headers: &[Header],
+    canonical: bool,
+) -> anyhow::Result<()> {
+    let mut td = parent_td;
+    for header in headers {
+        let hash = header.hash();
+        let number = header.number.as_u64();
+        td += header.difficulty;
+
+        chain::header::write(tx, hash, number, header).await?;
+        chain::td::write(tx, hash, number, td).await?;
+        chain::header_number::write(tx, hash, number).await?;
+        if canonical {
+            chain::canonical_hash::write(tx, number, hash).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Drops the first header marked bad by a later stage along with all headers after it, which descend from it.
+async fn drop_bad_headers<'db: 'tx, 'tx, Tx: Transaction<'db>>(
+    tx: &'tx Tx,
+    headers: &mut Vec<Header>,
+) -> anyhow::Result<()> {
+    for (i, header) in headers.iter().enumerate() {
+        if chain::bad_header::read(tx, header.hash()).await?.is_some() {
+            warn!(block = header.number.as_u64(), "Discarding bad block");
+            headers.truncate(i);
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Downloads headers from peers through the sentry, extending the canonical chain.
+pub struct HeaderDownload {
+    sentry: Arc<SentryClientReactor>,
+    request_id: AtomicU64,
+}
+
+impl Debug for HeaderDownload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HeaderDownload")
+            .field("request_id", &self.request_id)
+            .finish()
+    }
+}
+
+impl HeaderDownload {
+    pub fn new(sentry: Arc<SentryClientReactor>) -> Self {
+        Self {
+            sentry,
+            request_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Requests headers from peers, returns the first matching response or `None` on timeout.
+    async fn request_headers(
+        &self,
+        start_block: BlockId,
+        limit: u64,
+        reverse: bool,
+    ) -> anyhow::Result<Option<Vec<Header>>> {
+        let request_id = self.request_id.fetch_add(1, Ordering::Relaxed);
+
+        // subscribe before sending the request, so that the response is not missed
+        let mut stream = self.sentry.receive_messages(EthMessageId::BlockHeaders)?;
+
+        self.sentry
+            .send_message(
+                Message::GetBlockHeaders(GetBlockHeadersMessage {
+                    request_id,
+                    params: GetBlockHeadersMessageParams {
+                        start_block,
+                        limit,
+                        skip: 0,
+                        reverse: reverse as u8,
+                    },
+                }),
+                PeerFilter::All,
+            )
+            .await?;
+
+        let response = timeout(REQUEST_TIMEOUT, async {
+            while let Some(message) = stream.next().await {
+                if let Message::BlockHeaders(message) = message {
+                    if message.request_id == request_id {
+                        return Some(message.headers);
+                    }
+                }
+            }
+
+            None
+        })
+        .await;
+
+        Ok(response.ok().flatten())
+    }
+
+    /// Fetches ancestors of `headers` until they connect to the local canonical chain.
+    /// Returns the fork point and the headers after it, or `None` if peers did not provide them.
+    async fn find_fork<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(
+        &self,
+        tx: &'tx RwTx,
+        mut headers: Vec<Header>,
+    ) -> anyhow::Result<Option<(u64, Vec<Header>)>> {
+        loop {
+            // Ancestors may reach back into the local chain. Canonical ones come first, the last of them is the fork point.
+            let mut fork_point = None;
+            let mut canonical = 0;
+            for header in &headers {
+                let number = header.number.as_u64();
+                if chain::canonical_hash::read(tx, number).await? != Some(header.hash()) {
+                    break;
+                }
+                fork_point = Some(number);
+                canonical += 1;
+            }
+            if let Some(fork_point) = fork_point {
+                headers.drain(..canonical);
+                return Ok(Some((fork_point, headers)));
+            }
+
+            let first = &headers[0];
+            if let Some(number) = chain::header_number::read(tx, first.parent_hash).await? {
+                if chain::canonical_hash::read(tx, number).await? == Some(first.parent_hash) {
+                    return Ok(Some((number, headers)));
+                }
+            }
+
+            let parent_number = match first.number.as_u64().checked_sub(1) {
+                Some(parent_number) => parent_number,
+                // different genesis
+                None => return Ok(None),
+            };
+
+            // Chain may be shorter than a full request
+            let mut ancestors = match self
+                .request_headers(
+                    BlockId::Hash(first.parent_hash),
+                    cmp::min(HEADERS_REQUEST_LIMIT, parent_number + 1),
+                    true,
+                )
+                .await?
+            {
+                Some(ancestors) if !ancestors.is_empty() => ancestors,
+                _ => return Ok(None),
+            };
+            ancestors.reverse();
+            ancestors.append(&mut headers);
+            headers = ancestors;
+        }
+    }
+}
 
 #[async_trait]
 impl<'db, RwTx> Stage<'db, RwTx> for HeaderDownload
@@ -29,57 +349,532 @@ where
     where
         'db: 'tx,
     {
-        let _ = tx;
+        let config = metadata::read_genesis_chain_config(tx).await?;
+
         let past_progress = input.stage_progress.unwrap_or(0);
+        let no_progress = ExecOutput::Progress {
+            stage_progress: past_progress,
+            done: true,
+            must_commit: false,
+        };
 
-        if !input.restarted {
-            info!("Waiting for headers...");
-            let dur = Duration::from_millis(rand::thread_rng().gen_range(3000..6000));
-            sleep(dur).await;
+        let tip_hash = chain::canonical_hash::read(tx, past_progress)
+            .await?
+            .ok_or(HeaderDownloadError::HashNotFound(past_progress))?;
+        let tip = chain::header::read(tx, tip_hash, past_progress)
+            .await?
+            .ok_or(HeaderDownloadError::HeaderNotFound(past_progress))?;
+        let tip_td = chain::td::read(tx, tip_hash, past_progress)
+            .await?
+            .ok_or(HeaderDownloadError::TotalDifficultyNotFound(past_progress))?;
+
+        info!(from = past_progress + 1, "Requesting headers");
+        let mut headers = self
+            .request_headers(
+                BlockId::Number(past_progress + 1),
+                HEADERS_REQUEST_LIMIT,
+                false,
+            )
+            .await?
+            .unwrap_or_default();
+        drop_bad_headers(tx, &mut headers).await?;
+        if headers.is_empty() {
+            info!("No new headers");
+            return Ok(no_progress);
         }
 
-        info!("Processing headers");
+        if headers[0].parent_hash != tip_hash {
+            let (fork_point, mut fork) = match self.find_fork(tx, headers).await? {
+                Some(fork) => fork,
+                None => {
+                    warn!("Could not find where received headers fork off, discarding");
+                    return Ok(no_progress);
+                }
+            };
+            drop_bad_headers(tx, &mut fork).await?;
+            if fork.is_empty() {
+                return Ok(no_progress);
+            }
 
-        let target = past_progress + 100;
+            let fork_hash = chain::canonical_hash::read(tx, fork_point)
+                .await?
+                .ok_or(HeaderDownloadError::HashNotFound(fork_point))?;
+            let fork_parent = chain::header::read(tx, fork_hash, fork_point)
+                .await?
+                .ok_or(HeaderDownloadError::HeaderNotFound(fork_point))?;
+            let fork_parent_td = chain::td::read(tx, fork_hash, fork_point)
+                .await?
+                .ok_or(HeaderDownloadError::TotalDifficultyNotFound(fork_point))?;
 
-        let commit_block =
-            rand::random::<bool>().then(|| past_progress + rand::thread_rng().gen_range(0..target));
+            let fork_td = match verify_chain(&config, &fork_parent, fork_parent_td, &fork) {
+                Ok(td) => td,
+                Err(e) => {
+                    warn!("Discarding invalid fork: {}", e);
+                    return Ok(no_progress);
+                }
+            };
 
-        let mut processed = past_progress;
-        let mut must_commit = false;
-        for block in past_progress..=target {
-            info!(block = block, "(mock) Downloading");
+            if fork_td <= tip_td {
+                info!(fork_point, "Ignoring lighter fork");
+                return Ok(no_progress);
+            }
 
-            processed += 1;
+            // Keep the fork around, it becomes canonical once the old chain is unwound
+            write_headers(tx, fork_parent_td, &fork, false).await?;
 
-            if let Some(commit_block) = commit_block {
-                if block == commit_block {
-                    must_commit = true;
-                    break;
-                }
-            }
+            info!(fork_point, "Found heavier fork, unwinding");
+            return Ok(ExecOutput::Unwind {
+                unwind_to: fork_point,
+            });
+        }
 
-            let dur = Duration::from_millis(rand::thread_rng().gen_range(0..500));
-            sleep(dur).await;
+        if let Err(e) = verify_chain(&config, &tip, tip_td, &headers) {
+            warn!("Discarding invalid headers: {}", e);
+            return Ok(no_progress);
         }
-        info!(highest = target, "Processed");
+
+        write_headers(tx, tip_td, &headers, true).await?;
+
+        let stage_progress = headers.last().unwrap().number.as_u64();
+        info!(highest = stage_progress, "Processed");
+
         Ok(ExecOutput::Progress {
-            stage_progress: processed,
-            done: !must_commit,
-            must_commit,
+            stage_progress,
+            done: (headers.len() as u64) < HEADERS_REQUEST_LIMIT,
+            must_commit: true,
         })
     }
 
-    async fn unwind<'tx>(
-        &self,
-        tx: &'tx mut RwTx,
-        input: crate::stagedsync::stage::UnwindInput,
-    ) -> anyhow::Result<()>
+    async fn unwind<'tx>(&self, tx: &'tx mut RwTx, input: UnwindInput) -> anyhow::Result<()>
     where
         'db: 'tx,
     {
-        let _ = tx;
-        let _ = input;
-        todo!()
+        let mut cursor = tx.mutable_cursor(&tables::CanonicalHeader).await?;
+
+        // Headers themselves are kept, a fork may become canonical again
+        for block_number in input.unwind_to + 1..=input.stage_progress {
+            cursor
+                .delete(&dbutils::encode_block_number(block_number), &[])
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Helpers to build header chains and serve them to `HeaderDownload` in tests of this and later stages.
+#[cfg(test)]
+pub(crate) mod test_util {
+    use super::*;
+    use crate::downloader::{messages::BlockHeadersMessage, sentry_client_mock::SentryClientMock};
+    use ethereum_types::{Bloom, H64};
+
+    pub const CONFIG: &str = r#"{"chainId":1,"homesteadBlock":0,"daoForkSupport":false,"eip155Block":0,"eip158Block":0,"byzantiumBlock":0,"constantinopleBlock":0,"petersburgBlock":0,"istanbulBlock":0}"#;
+
+    pub fn child(parent: &Header, timestamp_delta: u64, extra_data: u8) -> Header {
+        let config = serde_json::from_str::<ChainConfig>(CONFIG).unwrap();
+        let number = parent.number.as_u64() + 1;
+        let timestamp = parent.timestamp + timestamp_delta;
+
+        Header {
+            parent_hash: parent.hash(),
+            difficulty: canonical_difficulty(&config, number, timestamp, parent),
+            number: number.into(),
+            timestamp,
+            extra_data: vec![extra_data],
+            ..parent.clone()
+        }
+    }
+
+    pub fn genesis() -> Header {
+        Header {
+            parent_hash: H256::zero(),
+            ommers_hash: common::EMPTY_LIST_HASH,
+            beneficiary: Default::default(),
+            state_root: H256::zero(),
+            transactions_root: H256::zero(),
+            receipts_root: H256::zero(),
+            logs_bloom: Bloom::zero(),
+            difficulty: MINIMUM_DIFFICULTY.into(),
+            number: 0.into(),
+            gas_limit: 5000.into(),
+            gas_used: 0.into(),
+            timestamp: 0,
+            extra_data: vec![],
+            mix_hash: H256::zero(),
+            nonce: H64::zero(),
+        }
+    }
+
+    pub fn make_chain(
+        parent: &Header,
+        len: usize,
+        timestamp_delta: u64,
+        extra_data: u8,
+    ) -> Vec<Header> {
+        let mut headers: Vec<Header> = vec![];
+        for _ in 0..len {
+            let header = child(
+                headers.last().unwrap_or(parent),
+                timestamp_delta,
+                extra_data,
+            );
+            headers.push(header);
+        }
+        headers
+    }
+
+    pub fn response(headers: Vec<Header>) -> Message {
+        Message::BlockHeaders(BlockHeadersMessage {
+            request_id: 0,
+            headers,
+        })
+    }
+
+    pub fn stage(responses: Vec<Message>) -> HeaderDownload {
+        let mut sentry =
+            SentryClientReactor::new(Box::new(SentryClientMock::with_responses(responses)));
+        sentry.start();
+        HeaderDownload::new(Arc::new(sentry))
+    }
+
+    pub async fn write_genesis<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(
+        tx: &'tx RwTx,
+        genesis: &Header,
+    ) {
+        let hash = genesis.hash();
+        chain::header::write(tx, hash, 0, genesis).await.unwrap();
+        chain::canonical_hash::write(tx, 0, hash).await.unwrap();
+        chain::header_number::write(tx, hash, 0).await.unwrap();
+        chain::td::write(tx, hash, 0, genesis.difficulty)
+            .await
+            .unwrap();
+        metadata::write_chain_config(
+            tx,
+            hash,
+            &serde_json::from_str::<ChainConfig>(CONFIG).unwrap(),
+        )
+        .await
+        .unwrap();
+    }
+
+    pub fn input(stage_progress: u64) -> StageInput {
+        StageInput {
+            restarted: false,
+            previous_stage: None,
+            stage_progress: Some(stage_progress),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{test_util::*, *};
+    use crate::{kv::traits::MutableKV, new_mem_database, stagedsync::test_util::unwind_stage};
+
+    #[test]
+    fn difficulty_bomb_delays() {
+        let number = 10_700_000;
+        // Without the bomb the difficulty stays the same, since the block comes 9 seconds after its parent
+        let parent = Header {
+            number: (number - 1).into(),
+            difficulty: 1_000_000_000_000_u64.into(),
+            ..genesis()
+        };
+
+        for (forks, bomb) in [
+            (r#""muirGlacierBlock":0"#, 1_u64 << 15),
+            (r#""muirGlacierBlock":0,"londonBlock":0"#, 1 << 8),
+            (
+                r#""muirGlacierBlock":0,"londonBlock":0,"arrowGlacierBlock":0"#,
+                0,
+            ),
+        ] {
+            let config = serde_json::from_str::<ChainConfig>(&format!(
+                r#"{{"daoForkSupport":false,"byzantiumBlock":0,"constantinopleBlock":0,{}}}"#,
+                forks
+            ))
+            .unwrap();
+            assert_eq!(
+                canonical_difficulty(&config, number, 9, &parent),
+                parent.difficulty + U256::from(bomb)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn download_headers() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let genesis = genesis();
+        write_genesis(&tx, &genesis).await;
+
+        let headers = make_chain(&genesis, 3, 10, 0);
+        let stage = stage(vec![response(headers.clone())]);
+
+        assert_eq!(
+            stage.execute(&mut tx, input(0)).await.unwrap(),
+            ExecOutput::Progress {
+                stage_progress: 3,
+                done: true,
+                must_commit: true,
+            }
+        );
+
+        let mut td = genesis.difficulty;
+        for header in &headers {
+            let hash = header.hash();
+            let number = header.number.as_u64();
+            td += header.difficulty;
+
+            assert_eq!(
+                chain::canonical_hash::read(&tx, number).await.unwrap(),
+                Some(hash)
+            );
+            assert_eq!(
+                chain::header::read(&tx, hash, number).await.unwrap(),
+                Some(header.clone())
+            );
+            assert_eq!(
+                chain::header_number::read(&tx, hash).await.unwrap(),
+                Some(number)
+            );
+            assert_eq!(chain::td::read(&tx, hash, number).await.unwrap(), Some(td));
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_headers() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let genesis = genesis();
+        write_genesis(&tx, &genesis).await;
+
+        let mut wrong_difficulty = make_chain(&genesis, 2, 10, 0);
+        wrong_difficulty[1].difficulty += U256::one();
+
+        let mut wrong_timestamp = make_chain(&genesis, 2, 10, 0);
+        wrong_timestamp[1].timestamp = wrong_timestamp[0].timestamp;
+
+        let mut broken_link = make_chain(&genesis, 2, 10, 0);
+        broken_link[1].parent_hash = H256::repeat_byte(0xaa);
+
+        let stage = stage(vec![
+            response(wrong_difficulty),
+            response(wrong_timestamp),
+            response(broken_link),
+        ]);
+
+        for _ in 0..3 {
+            assert_eq!(
+                stage.execute(&mut tx, input(0)).await.unwrap(),
+                ExecOutput::Progress {
+                    stage_progress: 0,
+                    done: true,
+                    must_commit: false,
+                }
+            );
+            assert_eq!(chain::canonical_hash::read(&tx, 1).await.unwrap(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn skip_bad_headers() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let genesis = genesis();
+        write_genesis(&tx, &genesis).await;
+
+        let headers = make_chain(&genesis, 3, 10, 0);
+        chain::bad_header::write(&tx, headers[1].hash(), 2)
+            .await
+            .unwrap();
+
+        let stage = stage(vec![response(headers.clone())]);
+
+        assert_eq!(
+            stage.execute(&mut tx, input(0)).await.unwrap(),
+            ExecOutput::Progress {
+                stage_progress: 1,
+                done: true,
+                must_commit: true,
+            }
+        );
+        assert_eq!(
+            chain::canonical_hash::read(&tx, 1).await.unwrap(),
+            Some(headers[0].hash())
+        );
+        for number in 2..=3 {
+            assert_eq!(
+                chain::canonical_hash::read(&tx, number).await.unwrap(),
+                None
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unwind_to_heavier_fork() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let genesis = genesis();
+        write_genesis(&tx, &genesis).await;
+
+        let local = make_chain(&genesis, 3, 10, 0);
+        // Faster blocks have higher difficulty, making the fork heavier
+        let fork = make_chain(&local[0], 3, 1, 1);
+
+        let stage = stage(vec![
+            response(local.clone()),
+            // Request from block 4 returns the fork tip, followed by its ancestors by hash
+            response(vec![fork[2].clone()]),
+            response(vec![fork[1].clone(), fork[0].clone()]),
+        ]);
+
+        stage.execute(&mut tx, input(0)).await.unwrap();
+
+        assert_eq!(
+            stage.execute(&mut tx, input(3)).await.unwrap(),
+            ExecOutput::Unwind { unwind_to: 1 }
+        );
+
+        // Canonical chain is untouched until unwind
+        assert_eq!(
+            chain::canonical_hash::read(&tx, 2).await.unwrap(),
+            Some(local[1].hash())
+        );
+        assert_eq!(
+            chain::header_number::read(&tx, fork[2].hash())
+                .await
+                .unwrap(),
+            Some(4)
+        );
+    }
+
+    #[tokio::test]
+    async fn fork_with_overlapping_ancestors() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let genesis = genesis();
+        write_genesis(&tx, &genesis).await;
+
+        let local = make_chain(&genesis, 3, 10, 0);
+        let fork = make_chain(&local[0], 3, 1, 1);
+
+        // Peer returns ancestors down to genesis, past the fork point
+        let stage = stage(vec![
+            response(local.clone()),
+            response(vec![fork[2].clone()]),
+            response(vec![
+                fork[1].clone(),
+                fork[0].clone(),
+                local[0].clone(),
+                genesis.clone(),
+            ]),
+        ]);
+
+        stage.execute(&mut tx, input(0)).await.unwrap();
+
+        assert_eq!(
+            stage.execute(&mut tx, input(3)).await.unwrap(),
+            ExecOutput::Unwind { unwind_to: 1 }
+        );
+        for header in &fork {
+            assert_eq!(
+                chain::header_number::read(&tx, header.hash())
+                    .await
+                    .unwrap(),
+                Some(header.number.as_u64())
+            );
+        }
+        assert_eq!(
+            chain::canonical_hash::read(&tx, 1).await.unwrap(),
+            Some(local[0].hash())
+        );
+    }
+
+    #[tokio::test]
+    async fn ignore_lighter_fork() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let genesis = Header {
+            difficulty: 1_000_000_000.into(),
+            ..genesis()
+        };
+        write_genesis(&tx, &genesis).await;
+
+        // Slow blocks have decreasing difficulty, so the longer fork is still lighter
+        let local = make_chain(&genesis, 20, 1, 0);
+        let fork = make_chain(&genesis, 21, 1000, 1);
+
+        let stage = stage(vec![
+            response(local.clone()),
+            response(vec![fork[20].clone()]),
+            response(fork[..20].iter().rev().cloned().collect()),
+        ]);
+
+        stage.execute(&mut tx, input(0)).await.unwrap();
+
+        assert_eq!(
+            stage.execute(&mut tx, input(20)).await.unwrap(),
+            ExecOutput::Progress {
+                stage_progress: 20,
+                done: true,
+                must_commit: false,
+            }
+        );
+        assert_eq!(
+            chain::canonical_hash::read(&tx, 20).await.unwrap(),
+            Some(local[19].hash())
+        );
+    }
+
+    #[tokio::test]
+    async fn unwind_canonical_headers() {
+        let db = new_mem_database().unwrap();
+
+        let genesis = genesis();
+        let headers = make_chain(&genesis, 3, 10, 0);
+
+        let tx = db.begin_mutable().await.unwrap();
+        write_genesis(&tx, &genesis).await;
+        write_headers(&tx, genesis.difficulty, &headers, true)
+            .await
+            .unwrap();
+        StageId("HeaderDownload")
+            .save_progress(&tx, 3)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        unwind_stage(&db, stage(vec![]), 1).await;
+
+        let tx = db.begin_mutable().await.unwrap();
+        assert_eq!(
+            StageId("HeaderDownload").get_progress(&tx).await.unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            chain::canonical_hash::read(&tx, 1).await.unwrap(),
+            Some(headers[0].hash())
+        );
+        for header in &headers[1..] {
+            let number = header.number.as_u64();
+            assert_eq!(
+                chain::canonical_hash::read(&tx, number).await.unwrap(),
+                None
+            );
+            assert_eq!(
+                chain::header::read(&tx, header.hash(), number)
+                    .await
+                    .unwrap(),
+                Some(header.clone())
+            );
+        }
     }
 }
@@ -1 +1,642 @@
+use crate::{
+    accessors::{chain, metadata},
+    changeset::{AccountHistory, HistoryKind, StorageHistory},
+    dbutils, execution,
+    kv::tables,
+    models::{Account, ChainConfig},
+    stagedsync::{
+        stage::{ExecOutput, Stage, StageInput, UnwindInput},
+        stages::{ACCOUNT_HISTORY_INDEX, STORAGE_HISTORY_INDEX},
+    },
+    state::{Buffer, CodeCache},
+    txdb, MutableCursor, MutableCursorDupSort, MutableTransaction, StageId, Transaction,
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use ethereum_types::H256;
+use std::cmp;
+use thiserror::Error;
+use tokio::pin;
+use tokio_stream::StreamExt;
 
+#[derive(Error, Debug)]
+pub enum ExecutionStageError {
+    #[error("Canonical hash for block {0} not found")]
+    HashNotFound(u64),
+    #[error("Header for block {0} not found")]
+    HeaderNotFound(u64),
+    #[error("Block body for block {0} not found")]
+    BlockBodyNotFound(u64),
+    #[error(
+        "Cannot unwind to block {unwind_to}, changesets before block {pruned_before} are pruned"
+    )]
+    HistoryPruned { unwind_to: u64, pruned_before: u64 },
+}
+
+const BUFFER_SIZE: u64 = 5000;
+/// Buffered state changes are written out once they take this many bytes.
+const FLUSH_THRESHOLD: usize = 256 * 1024 * 1024;
+
+async fn process_block<'db: 'tx, 'tx, RwTx>(
+    buffer: &mut Buffer<'db, 'tx, RwTx>,
+    config: &ChainConfig,
+    height: u64,
+) -> anyhow::Result<()>
+where
+    RwTx: MutableTransaction<'db>,
+{
+    let tx = buffer.tx();
+    let hash = chain::canonical_hash::read(tx, height)
+        .await?
+        .ok_or(ExecutionStageError::HashNotFound(height))?;
+    let header = chain::header::read(tx, hash, height)
+        .await?
+        .ok_or(ExecutionStageError::HeaderNotFound(height))?;
+    let body = chain::storage_body::read(tx, hash, height)
+        .await?
+        .ok_or(ExecutionStageError::BlockBodyNotFound(height))?;
+    let txs = chain::tx::read(tx, body.base_tx_id, body.tx_amount).await?;
+    let senders = chain::tx_sender::read(tx, body.base_tx_id, body.tx_amount).await?;
+
+    execution::execute_block(buffer, config, &header, &body.uncles, &txs, &senders).await
+}
+
+async fn unwind_account_changes<'db: 'tx, 'tx, RwTx>(
+    tx: &'tx RwTx,
+    block_number: u64,
+) -> anyhow::Result<()>
+where
+    RwTx: MutableTransaction<'db>,
+{
+    let block_key = dbutils::encode_block_number(block_number);
+
+    let mut changes = vec![];
+    {
+        let mut cursor = tx.cursor_dup_sort(&tables::AccountChangeSet).await?;
+        let walker = txdb::walk(&mut cursor, &block_key, 8 * 8);
+        pin!(walker);
+
+        while let Some((k, v)) = walker.try_next().await? {
+            let (_, change) = AccountHistory::decode(k, v);
+            changes.push((change.key, change.value.to_vec()));
+        }
+    }
+
+    for (address, value) in changes {
+        if let Some(mut account) = Account::decode_for_storage(&value)? {
+            // Changesets omit code hashes, restore them
+            if account.incarnation > 0 && account.code_hash.is_none() {
+                if let Some(code_hash) = tx
+                    .get(
+                        &tables::PlainCodeHash,
+                        &dbutils::plain_generate_storage_prefix(address, account.incarnation),
+                    )
+                    .await?
+                {
+                    account.code_hash = Some(H256::from_slice(&*code_hash));
+                }
+            }
+
+            tx.set(
+                &tables::PlainState,
+                address.as_bytes(),
+                &account.encode_for_storage(),
+            )
+            .await?;
+        } else {
+            tx.mutable_cursor(&tables::PlainState)
+                .await?
+                .delete(address.as_bytes(), &[])
+                .await?;
+        }
+    }
+
+    let mut cursor = tx.mutable_cursor_dupsort(&tables::AccountChangeSet).await?;
+    if cursor.seek_exact(&block_key).await?.is_some() {
+        cursor.delete_current_duplicates().await?;
+    }
+
+    Ok(())
+}
+
+async fn unwind_storage_changes<'db: 'tx, 'tx, RwTx>(
+    tx: &'tx RwTx,
+    block_number: u64,
+) -> anyhow::Result<()>
+where
+    RwTx: MutableTransaction<'db>,
+{
+    let block_key = dbutils::encode_block_number(block_number);
+
+    let mut changes = vec![];
+    let mut changeset_keys = vec![];
+    {
+        let mut cursor = tx.cursor_dup_sort(&tables::StorageChangeSet).await?;
+        let walker = txdb::walk(&mut cursor, &block_key, 8 * 8);
+        pin!(walker);
+
+        while let Some((k, v)) = walker.try_next().await? {
+            if changeset_keys
+                .last()
+                .map(|last: &Vec<u8>| last[..] != k[..])
+                .unwrap_or(true)
+            {
+                changeset_keys.push(k.to_vec());
+            }
+
+            let (_, change) = StorageHistory::decode(k, v);
+            changes.push((change.key, change.value.to_vec()));
+        }
+    }
+
+    let mut plain_state = tx.mutable_cursor(&tables::PlainState).await?;
+    for (key, value) in changes {
+        if value.is_empty() {
+            plain_state.delete(&key, &[]).await?;
+        } else {
+            plain_state.put(&key, &value).await?;
+        }
+    }
+
+    let mut cursor = tx.mutable_cursor_dupsort(&tables::StorageChangeSet).await?;
+    for key in changeset_keys {
+        if cursor.seek_exact(&key).await?.is_some() {
+            cursor.delete_current_duplicates().await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn unwind_receipts<'db: 'tx, 'tx, RwTx>(
+    tx: &'tx RwTx,
+    block_number: u64,
+) -> anyhow::Result<()>
+where
+    RwTx: MutableTransaction<'db>,
+{
+    let block_key = dbutils::encode_block_number(block_number);
+
+    tx.mutable_cursor(&tables::Receipt)
+        .await?
+        .delete(&block_key, &[])
+        .await?;
+
+    let mut cursor = tx.mutable_cursor(&tables::TransactionLog).await?;
+    while let Some((k, _)) = cursor.seek(&block_key).await? {
+        if !k.starts_with(&block_key) {
+            break;
+        }
+        cursor.delete_current().await?;
+    }
+
+    Ok(())
+}
+
+async fn unwind_call_traces<'db: 'tx, 'tx, RwTx>(
+    tx: &'tx RwTx,
+    block_number: u64,
+) -> anyhow::Result<()>
+where
+    RwTx: MutableTransaction<'db>,
+{
+    let mut cursor = tx.mutable_cursor_dupsort(&tables::CallTraceSet).await?;
+    if cursor
+        .seek_exact(&dbutils::encode_block_number(block_number))
+        .await?
+        .is_some()
+    {
+        cursor.delete_current_duplicates().await?;
+    }
+
+    Ok(())
+}
+
+/// Executes blocks, keeping contract code cached between runs.
+///
+/// Only partially implemented: value transfers are executed, but there is no EVM yet, so the stage stops
+/// at the first block with a contract transaction, see `execution` module for the scope.
+/// For that reason it is left out of `stages::pipeline` and can only be run with the toolbox.
+#[derive(Debug, Default)]
+pub struct Execution {
+    code_cache: CodeCache,
+}
+
+impl Execution {
+    pub fn with_code_cache(code_cache: CodeCache) -> Self {
+        Self { code_cache }
+    }
+
+    fn buffer<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(
+        &self,
+        tx: &'tx RwTx,
+    ) -> Buffer<'db, 'tx, RwTx> {
+        Buffer::with_code_cache(tx, self.code_cache.clone())
+    }
+}
+
+#[async_trait]
+impl<'db, RwTx> Stage<'db, RwTx> for Execution
+where
+    RwTx: MutableTransaction<'db>,
+{
+    fn id(&self) -> StageId {
+        StageId("Execution")
+    }
+
+    fn description(&self) -> &'static str {
+        "Executing value transfers and updating the state"
+    }
+
+    async fn execute<'tx>(&self, tx: &'tx mut RwTx, input: StageInput) -> anyhow::Result<ExecOutput>
+    where
+        'db: 'tx,
+    {
+        let config = metadata::read_genesis_chain_config(tx).await?;
+
+        let from_height = input.stage_progress.unwrap_or(0);
+        let max_height = if let Some((_, height)) = input.previous_stage {
+            height
+        } else {
+            0
+        };
+        let to_height = cmp::min(max_height, from_height + BUFFER_SIZE);
+
+        let mut buffer = self.buffer(&*tx);
+        let mut height = from_height;
+        while height < to_height {
+            process_block(&mut buffer, &config, height + 1)
+                .await
+                .with_context(|| format!("Failed to execute block {}", height + 1))?;
+            height += 1;
+
+            if buffer.size() >= FLUSH_THRESHOLD {
+                buffer.flush().await?;
+            }
+        }
+        buffer.flush().await?;
+
+        Ok(ExecOutput::Progress {
+            stage_progress: height,
+            done: height >= max_height,
+            must_commit: height > from_height,
+        })
+    }
+
+    async fn unwind<'tx>(&self, tx: &'tx mut RwTx, input: UnwindInput) -> anyhow::Result<()>
+    where
+        'db: 'tx,
+    {
+        // Changesets of blocks after `unwind_to` are needed, history index stages prune them
+        for stage in [ACCOUNT_HISTORY_INDEX, STORAGE_HISTORY_INDEX] {
+            if let Some(pruned_before) = stage.get_prune_progress(tx).await? {
+                if input.unwind_to + 1 < pruned_before {
+                    return Err(ExecutionStageError::HistoryPruned {
+                        unwind_to: input.unwind_to,
+                        pruned_before,
+                    }
+                    .into());
+                }
+            }
+        }
+
+        // Apply changesets newest first, so that the state ends up as it was after `unwind_to`
+        for block_number in (input.unwind_to + 1..=input.stage_progress).rev() {
+            unwind_account_changes(tx, block_number).await?;
+            unwind_storage_changes(tx, block_number).await?;
+            unwind_receipts(tx, block_number).await?;
+            unwind_call_traces(tx, block_number).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Helpers to write blocks of value transfers for `Execution` in tests of this and later stages.
+#[cfg(test)]
+pub(crate) mod test_util {
+    use super::*;
+    use crate::models::BodyForStorage;
+    use ethereum::{Header, TransactionAction, TransactionSignature, TransactionV2};
+    use ethereum_types::{Address, Bloom, H64};
+
+    pub const CONFIG: &str = r#"{"chainId":1,"homesteadBlock":0,"daoForkSupport":false,"eip155Block":0,"eip158Block":0,"byzantiumBlock":0,"constantinopleBlock":0,"petersburgBlock":0,"istanbulBlock":0}"#;
+
+    pub fn transfer(nonce: u64, to: Address, value: u64) -> TransactionV2 {
+        TransactionV2::Legacy(ethereum::LegacyTransaction {
+            nonce: nonce.into(),
+            gas_price: 1_000_000.into(),
+            gas_limit: 21_000.into(),
+            action: TransactionAction::Call(to),
+            value: value.into(),
+            input: vec![],
+            signature: TransactionSignature::new(27, H256::repeat_byte(2), H256::repeat_byte(3))
+                .unwrap(),
+        })
+    }
+
+    /// Writes canonical hash of the genesis block and the chain config, returns the hash and the config.
+    pub async fn write_genesis<'db: 'tx, 'tx, RwTx>(tx: &'tx RwTx) -> (H256, ChainConfig)
+    where
+        RwTx: MutableTransaction<'db>,
+    {
+        let genesis_hash = H256::random();
+        chain::canonical_hash::write(tx, 0, genesis_hash)
+            .await
+            .unwrap();
+        let config = serde_json::from_str::<ChainConfig>(CONFIG).unwrap();
+        metadata::write_chain_config(tx, genesis_hash, &config)
+            .await
+            .unwrap();
+
+        (genesis_hash, config)
+    }
+
+    /// Writes canonical block `number` with `transactions` from `sender`, which must all be transfers,
+    /// and its transactions starting from id `base_tx_id`. Returns hash of the block.
+    pub async fn write_block<'db: 'tx, 'tx, RwTx>(
+        tx: &'tx RwTx,
+        parent_hash: H256,
+        number: u64,
+        miner: Address,
+        base_tx_id: u64,
+        transactions: &[TransactionV2],
+        sender: Address,
+    ) -> H256
+    where
+        RwTx: MutableTransaction<'db>,
+    {
+        let header = Header {
+            parent_hash,
+            ommers_hash: H256::zero(),
+            beneficiary: miner,
+            state_root: H256::zero(),
+            transactions_root: H256::zero(),
+            receipts_root: H256::zero(),
+            logs_bloom: Bloom::zero(),
+            difficulty: 1.into(),
+            number: number.into(),
+            gas_limit: 10_000_000.into(),
+            gas_used: (transactions.len() as u64 * 21_000).into(),
+            timestamp: number,
+            extra_data: vec![],
+            mix_hash: H256::zero(),
+            nonce: H64::zero(),
+        };
+        let hash = header.hash();
+        let body = BodyForStorage {
+            base_tx_id,
+            tx_amount: transactions.len() as u32,
+            uncles: vec![],
+        };
+
+        chain::header::write(tx, hash, number, &header)
+            .await
+            .unwrap();
+        chain::canonical_hash::write(tx, number, hash)
+            .await
+            .unwrap();
+        chain::storage_body::write(tx, hash, number, &body)
+            .await
+            .unwrap();
+        chain::tx::write(tx, base_tx_id, transactions)
+            .await
+            .unwrap();
+        chain::tx_sender::write(tx, base_tx_id, &vec![sender; transactions.len()])
+            .await
+            .unwrap();
+
+        hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{test_util::*, *};
+    use crate::{
+        common,
+        kv::traits::MutableKV,
+        models::{CallTrace, Receipt},
+        new_mem_database,
+        state::{PlainStateReader, StateReader},
+    };
+    use ethereum_types::{Address, U256};
+    use hex_literal::hex;
+
+    const ETHER: u64 = 1_000_000_000_000_000_000;
+
+    #[tokio::test]
+    async fn execute_and_unwind_transfers() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let sender = Address::random();
+        let recipient = Address::random();
+        let miner = Address::random();
+
+        let (genesis_hash, config) = write_genesis(&tx).await;
+
+        let initial_balance = U256::from(10) * U256::from(ETHER);
+        tx.set(
+            &tables::PlainState,
+            sender.as_bytes(),
+            &Account {
+                balance: initial_balance,
+                ..Default::default()
+            }
+            .encode_for_storage(),
+        )
+        .await
+        .unwrap();
+
+        write_block(
+            &tx,
+            genesis_hash,
+            1,
+            miner,
+            1,
+            &[transfer(0, recipient, 1000), transfer(1, recipient, 2000)],
+            sender,
+        )
+        .await;
+
+        let output = Execution::default()
+            .execute(
+                &mut tx,
+                StageInput {
+                    restarted: false,
+                    previous_stage: Some((StageId("SenderRecovery"), 1)),
+                    stage_progress: Some(0),
+                },
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            output,
+            ExecOutput::Progress {
+                stage_progress: 1,
+                done: true,
+                must_commit: true,
+            }
+        );
+
+        let fee = U256::from(21_000 * 1_000_000);
+        let reader = PlainStateReader::new(&tx);
+
+        let sender_account = reader.read_account_data(sender).await.unwrap().unwrap();
+        assert_eq!(sender_account.nonce, 2);
+        assert_eq!(
+            sender_account.balance,
+            initial_balance - fee * 2 - U256::from(3000)
+        );
+
+        let recipient_account = reader.read_account_data(recipient).await.unwrap().unwrap();
+        assert_eq!(recipient_account.balance, U256::from(3000));
+
+        let miner_account = reader.read_account_data(miner).await.unwrap().unwrap();
+        assert_eq!(miner_account.balance, config.block_reward(1) + fee * 2);
+
+        assert_eq!(
+            chain::receipt::read(&tx, 1).await.unwrap(),
+            Some(vec![
+                Receipt {
+                    success: true,
+                    cumulative_gas_used: 21_000,
+                    logs: vec![],
+                },
+                Receipt {
+                    success: true,
+                    cumulative_gas_used: 42_000,
+                    logs: vec![],
+                },
+            ])
+        );
+
+        let mut call_traces = chain::call_trace::read(&tx, 1).await.unwrap();
+        call_traces.sort_unstable_by_key(|call_trace| call_trace.address);
+        let mut expected_call_traces = vec![
+            CallTrace {
+                address: sender,
+                from: true,
+                to: false,
+            },
+            CallTrace {
+                address: recipient,
+                from: false,
+                to: true,
+            },
+            CallTrace {
+                address: miner,
+                from: false,
+                to: true,
+            },
+        ];
+        expected_call_traces.sort_unstable_by_key(|call_trace| call_trace.address);
+        assert_eq!(call_traces, expected_call_traces);
+
+        Execution::default()
+            .unwind(
+                &mut tx,
+                UnwindInput {
+                    stage_progress: 1,
+                    unwind_to: 0,
+                },
+            )
+            .await
+            .unwrap();
+
+        let reader = PlainStateReader::new(&tx);
+        let sender_account = reader.read_account_data(sender).await.unwrap().unwrap();
+        assert_eq!(sender_account.nonce, 0);
+        assert_eq!(sender_account.balance, initial_balance);
+        assert_eq!(reader.read_account_data(recipient).await.unwrap(), None);
+        assert_eq!(reader.read_account_data(miner).await.unwrap(), None);
+
+        assert!(tx
+            .get(&tables::AccountChangeSet, &dbutils::encode_block_number(1))
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(chain::receipt::read(&tx, 1).await.unwrap(), None);
+        assert_eq!(chain::call_trace::read(&tx, 1).await.unwrap(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn unwind_into_pruned_history() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        ACCOUNT_HISTORY_INDEX
+            .save_prune_progress(&tx, 100)
+            .await
+            .unwrap();
+
+        let e = Execution::default()
+            .unwind(
+                &mut tx,
+                UnwindInput {
+                    stage_progress: 200,
+                    unwind_to: 98,
+                },
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<ExecutionStageError>(),
+            Some(ExecutionStageError::HistoryPruned {
+                unwind_to: 98,
+                pruned_before: 100
+            })
+        ));
+
+        // Changesets of blocks from 100 on are still there
+        Execution::default()
+            .unwind(
+                &mut tx,
+                UnwindInput {
+                    stage_progress: 200,
+                    unwind_to: 99,
+                },
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn code_cache_shared_between_runs() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        let address = Address::from_low_u64_be(0x1234);
+        let code = hex!("6001600055");
+        let code_hash = common::hash_data(&code);
+        tx.set(&tables::Code, code_hash.as_bytes(), &code)
+            .await
+            .unwrap();
+
+        let read = |stage: &Execution| {
+            let buffer = stage.buffer(&tx);
+            async move {
+                buffer
+                    .read_account_code(address, 1, code_hash)
+                    .await
+                    .unwrap()
+                    .map(|code| code.to_vec())
+            }
+        };
+
+        let stage = Execution::default();
+        assert_eq!(read(&stage).await, Some(code.to_vec()));
+
+        // Code is gone from the database, but the next run of the same stage still has it cached
+        tx.mutable_cursor(&tables::Code)
+            .await
+            .unwrap()
+            .delete(code_hash.as_bytes(), &[])
+            .await
+            .unwrap();
+        assert_eq!(read(&stage).await, Some(code.to_vec()));
+        assert_eq!(read(&Execution::default()).await, None);
+    }
+}
@@ -0,0 +1,479 @@
+use crate::{
+    changeset::{AccountHistory, HistoryKind, StorageHistory},
+    common, dbutils,
+    etl::{
+        collector::{Collector, OPTIMAL_BUFFER_CAPACITY},
+        data_provider::Entry,
+    },
+    kv::tables,
+    models::Account,
+    stagedsync::{
+        stage::{ExecOutput, Stage, StageInput, UnwindInput},
+        stages::HASH_STATE,
+    },
+    txdb, Cursor, MutableCursor, MutableTransaction, StageId, Transaction,
+};
+use arrayref::array_ref;
+use async_trait::async_trait;
+use ethereum_types::{Address, H256};
+use std::collections::{BTreeMap, BTreeSet};
+use tokio::pin;
+use tokio_stream::StreamExt;
+use tracing::*;
+
+fn hashed_account_key(address: Address) -> H256 {
+    common::hash_data(address.as_bytes())
+}
+
+fn hashed_storage_key(plain_key: &[u8]) -> dbutils::CompositeStorageKey {
+    let (address, incarnation, location) = dbutils::plain_parse_composite_storage_key(array_ref!(
+        plain_key,
+        0,
+        dbutils::PLAIN_COMPOSITE_STORAGE_KEY_LENGTH
+    ));
+
+    dbutils::generate_composite_storage_key(
+        hashed_account_key(address),
+        incarnation,
+        common::hash_data(location.as_bytes()),
+    )
+}
+
+/// Hashes the whole `PlainState` into `HashedAccount` and `HashedStorage`.
+async fn promote_clean_state<'db: 'tx, 'tx, RwTx>(tx: &'tx RwTx) -> anyhow::Result<()>
+where
+    RwTx: MutableTransaction<'db>,
+{
+    let mut account_collector = Collector::new(OPTIMAL_BUFFER_CAPACITY);
+    let mut storage_collector = Collector::new(OPTIMAL_BUFFER_CAPACITY);
+
+    let mut cursor = tx.cursor(&tables::PlainState).await?;
+    let walker = txdb::walk(&mut cursor, &[], 0);
+    pin!(walker);
+
+    while let Some((k, v)) = walker.try_next().await? {
+        if k.len() == common::ADDRESS_LENGTH {
+            account_collector.collect(Entry {
+                key: hashed_account_key(Address::from_slice(&k)).to_vec(),
+                value: v.to_vec(),
+                id: 0, // Irrelevant here, could be anything
+            });
+        } else {
+            storage_collector.collect(Entry {
+                key: hashed_storage_key(&k).to_vec(),
+                value: v.to_vec(),
+                id: 0, // Irrelevant here, could be anything
+            });
+        }
+    }
+
+    account_collector
+        .load_append(&mut tx.mutable_cursor(&tables::HashedAccount).await?)
+        .await?;
+    // Auto-dupsort tables cannot be appended to
+    storage_collector
+        .load(&mut tx.mutable_cursor(&tables::HashedStorage).await?, None)
+        .await?;
+
+    Ok(())
+}
+
+/// Gathers keys changed in blocks `from..=to` according to the changesets.
+async fn changed_keys<'db: 'tx, 'tx, Tx>(
+    tx: &'tx Tx,
+    from: u64,
+    to: u64,
+) -> anyhow::Result<(
+    BTreeSet<Address>,
+    BTreeSet<<StorageHistory as HistoryKind>::Key>,
+)>
+where
+    Tx: Transaction<'db>,
+{
+    let start_key = dbutils::encode_block_number(from);
+
+    let mut accounts = BTreeSet::new();
+    let mut cursor = tx.cursor_dup_sort(&tables::AccountChangeSet).await?;
+    let walker = txdb::walk(&mut cursor, &start_key, 0);
+    pin!(walker);
+    while let Some((k, v)) = walker.try_next().await? {
+        let (block_number, change) = AccountHistory::decode(k, v);
+        if block_number > to {
+            break;
+        }
+        accounts.insert(change.key);
+    }
+
+    let mut storage = BTreeSet::new();
+    let mut cursor = tx.cursor_dup_sort(&tables::StorageChangeSet).await?;
+    let walker = txdb::walk(&mut cursor, &start_key, 0);
+    pin!(walker);
+    while let Some((k, v)) = walker.try_next().await? {
+        let (block_number, change) = StorageHistory::decode(k, v);
+        if block_number > to {
+            break;
+        }
+        storage.insert(change.key);
+    }
+
+    Ok((accounts, storage))
+}
+
+/// Rehashes accounts and storage changed in blocks `from..=to` from their current plain state.
+async fn promote_changes<'db: 'tx, 'tx, RwTx>(
+    tx: &'tx RwTx,
+    from: u64,
+    to: u64,
+) -> anyhow::Result<()>
+where
+    RwTx: MutableTransaction<'db>,
+{
+    let (accounts, storage) = changed_keys(tx, from, to).await?;
+
+    let mut hashed_accounts = tx.mutable_cursor(&tables::HashedAccount).await?;
+    for address in accounts {
+        let hashed_key = hashed_account_key(address);
+        if let Some(v) = tx.get(&tables::PlainState, address.as_bytes()).await? {
+            hashed_accounts.put(hashed_key.as_bytes(), &v).await?;
+        } else {
+            hashed_accounts.delete(hashed_key.as_bytes(), &[]).await?;
+        }
+    }
+
+    let mut plain_state = tx.cursor(&tables::PlainState).await?;
+    let mut hashed_storage = tx.mutable_cursor(&tables::HashedStorage).await?;
+    for plain_key in storage {
+        let hashed_key = hashed_storage_key(&plain_key);
+        if let Some((_, v)) = plain_state.seek_exact(&plain_key).await? {
+            hashed_storage.put(&hashed_key, &v).await?;
+        } else {
+            hashed_storage.delete(&hashed_key, &[]).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Restores hashed accounts and storage to their values before block `from`.
+async fn unwind_changes<'db: 'tx, 'tx, RwTx>(
+    tx: &'tx RwTx,
+    from: u64,
+    to: u64,
+) -> anyhow::Result<()>
+where
+    RwTx: MutableTransaction<'db>,
+{
+    let start_key = dbutils::encode_block_number(from);
+
+    // Only the earliest change in the range holds the value we are unwinding to
+    let mut accounts = BTreeMap::new();
+    {
+        let mut cursor = tx.cursor_dup_sort(&tables::AccountChangeSet).await?;
+        let walker = txdb::walk(&mut cursor, &start_key, 0);
+        pin!(walker);
+        while let Some((k, v)) = walker.try_next().await? {
+            let (block_number, change) = AccountHistory::decode(k, v);
+            if block_number > to {
+                break;
+            }
+            accounts
+                .entry(change.key)
+                .or_insert_with(|| change.value.to_vec());
+        }
+    }
+
+    let mut storage = BTreeMap::new();
+    {
+        let mut cursor = tx.cursor_dup_sort(&tables::StorageChangeSet).await?;
+        let walker = txdb::walk(&mut cursor, &start_key, 0);
+        pin!(walker);
+        while let Some((k, v)) = walker.try_next().await? {
+            let (block_number, change) = StorageHistory::decode(k, v);
+            if block_number > to {
+                break;
+            }
+            storage
+                .entry(change.key)
+                .or_insert_with(|| change.value.to_vec());
+        }
+    }
+
+    let mut hashed_accounts = tx.mutable_cursor(&tables::HashedAccount).await?;
+    for (address, value) in accounts {
+        let hashed_key = hashed_account_key(address);
+        if let Some(mut account) = Account::decode_for_storage(&value)? {
+            // Changesets omit code hashes, restore them
+            if account.incarnation > 0 && account.code_hash.is_none() {
+                if let Some(code_hash) = tx
+                    .get(
+                        &tables::PlainCodeHash,
+                        &dbutils::plain_generate_storage_prefix(address, account.incarnation),
+                    )
+                    .await?
+                {
+                    account.code_hash = Some(H256::from_slice(&*code_hash));
+                }
+            }
+
+            hashed_accounts
+                .put(hashed_key.as_bytes(), &account.encode_for_storage())
+                .await?;
+        } else {
+            hashed_accounts.delete(hashed_key.as_bytes(), &[]).await?;
+        }
+    }
+
+    let mut hashed_storage = tx.mutable_cursor(&tables::HashedStorage).await?;
+    for (plain_key, value) in storage {
+        let hashed_key = hashed_storage_key(&plain_key);
+        if value.iter().all(|&b| b == 0) {
+            hashed_storage.delete(&hashed_key, &[]).await?;
+        } else {
+            hashed_storage.put(&hashed_key, &value).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Keeps `HashedAccount` and `HashedStorage` in sync with `PlainState`.
+#[derive(Debug)]
+pub struct HashState;
+
+#[async_trait]
+impl<'db, RwTx> Stage<'db, RwTx> for HashState
+where
+    RwTx: MutableTransaction<'db>,
+{
+    fn id(&self) -> StageId {
+        HASH_STATE
+    }
+
+    fn description(&self) -> &'static str {
+        "Hashing the key in the state"
+    }
+
+    async fn execute<'tx>(&self, tx: &'tx mut RwTx, input: StageInput) -> anyhow::Result<ExecOutput>
+    where
+        'db: 'tx,
+    {
+        let max_height = if let Some((_, height)) = input.previous_stage {
+            height
+        } else {
+            0
+        };
+
+        let from_height = if let Some(from_height) = input.stage_progress {
+            if from_height >= max_height {
+                return Ok(ExecOutput::Progress {
+                    stage_progress: from_height,
+                    done: true,
+                    must_commit: false,
+                });
+            }
+
+            promote_changes(tx, from_height + 1, max_height).await?;
+            from_height
+        } else {
+            info!("Promoting clean state");
+            promote_clean_state(tx).await?;
+            0
+        };
+
+        info!(highest = max_height, "Processed");
+        Ok(ExecOutput::Progress {
+            stage_progress: max_height,
+            done: true,
+            must_commit: input.stage_progress.is_none() || max_height > from_height,
+        })
+    }
+
+    async fn unwind<'tx>(&self, tx: &'tx mut RwTx, input: UnwindInput) -> anyhow::Result<()>
+    where
+        'db: 'tx,
+    {
+        unwind_changes(tx, input.unwind_to + 1, input.stage_progress).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        kv::traits::MutableKV,
+        new_mem_database,
+        stagedsync::test_util::unwind_stage,
+        state::database::{PlainStateWriter, StateWriter, WriterWithChangesets},
+    };
+    use ethereum_types::U256;
+
+    fn no_account() -> Account {
+        Account {
+            initialised: false,
+            ..Default::default()
+        }
+    }
+
+    fn account(balance: u64) -> Account {
+        Account {
+            initialised: true,
+            balance: balance.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Balance of the hashed account, if it exists.
+    async fn hashed_account<'db: 'tx, 'tx, Tx: Transaction<'db>>(
+        tx: &'tx Tx,
+        address: Address,
+    ) -> Option<U256> {
+        let v = tx
+            .get(
+                &tables::HashedAccount,
+                hashed_account_key(address).as_bytes(),
+            )
+            .await
+            .unwrap()?;
+        Account::decode_for_storage(&v)
+            .unwrap()
+            .map(|account| account.balance)
+    }
+
+    async fn hashed_storage<'db: 'tx, 'tx, Tx: Transaction<'db>>(
+        tx: &'tx Tx,
+        address: Address,
+        location: H256,
+    ) -> Option<U256> {
+        let key = hashed_storage_key(&dbutils::plain_generate_composite_storage_key(
+            address, 1, location,
+        ));
+        let mut cursor = tx.cursor(&tables::HashedStorage).await.unwrap();
+        cursor
+            .seek_exact(&key)
+            .await
+            .unwrap()
+            .map(|(_, v)| U256::from_big_endian(&v))
+    }
+
+    #[tokio::test]
+    async fn hash_state() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let address1 = Address::random();
+        let address2 = Address::random();
+        let address3 = Address::random();
+        let location1 = H256::random();
+        let location2 = H256::random();
+
+        // Block 1: create two accounts and storage of the first one
+        let mut writer = PlainStateWriter::new(&tx, 1);
+        writer
+            .update_account_data(address1, &no_account(), &account(100))
+            .await
+            .unwrap();
+        writer
+            .update_account_data(address2, &no_account(), &account(200))
+            .await
+            .unwrap();
+        writer
+            .write_account_storage(address1, 1, location1, 0.into(), 0xaa.into())
+            .await
+            .unwrap();
+        writer.write_changesets().await.unwrap();
+
+        let output = HashState
+            .execute(
+                &mut tx,
+                StageInput {
+                    restarted: false,
+                    previous_stage: Some((StageId("Execution"), 1)),
+                    stage_progress: None,
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            output,
+            ExecOutput::Progress {
+                stage_progress: 1,
+                done: true,
+                must_commit: true,
+            }
+        );
+        assert_eq!(hashed_account(&tx, address1).await, Some(100.into()));
+        assert_eq!(hashed_account(&tx, address2).await, Some(200.into()));
+        assert_eq!(
+            hashed_storage(&tx, address1, location1).await,
+            Some(0xaa.into())
+        );
+
+        // Block 2: update one account, delete another, create a new one and change storage
+        let mut writer = PlainStateWriter::new(&tx, 2);
+        writer
+            .update_account_data(address1, &account(100), &account(150))
+            .await
+            .unwrap();
+        writer
+            .delete_account(address2, &account(200))
+            .await
+            .unwrap();
+        writer
+            .update_account_data(address3, &no_account(), &account(300))
+            .await
+            .unwrap();
+        writer
+            .write_account_storage(address1, 1, location1, 0xaa.into(), 0.into())
+            .await
+            .unwrap();
+        writer
+            .write_account_storage(address1, 1, location2, 0.into(), 0xbb.into())
+            .await
+            .unwrap();
+        writer.write_changesets().await.unwrap();
+
+        let output = HashState
+            .execute(
+                &mut tx,
+                StageInput {
+                    restarted: false,
+                    previous_stage: Some((StageId("Execution"), 2)),
+                    stage_progress: Some(1),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            output,
+            ExecOutput::Progress {
+                stage_progress: 2,
+                done: true,
+                must_commit: true,
+            }
+        );
+        assert_eq!(hashed_account(&tx, address1).await, Some(150.into()));
+        assert_eq!(hashed_account(&tx, address2).await, None);
+        assert_eq!(hashed_account(&tx, address3).await, Some(300.into()));
+        assert_eq!(hashed_storage(&tx, address1, location1).await, None);
+        assert_eq!(
+            hashed_storage(&tx, address1, location2).await,
+            Some(0xbb.into())
+        );
+
+        HASH_STATE.save_progress(&tx, 2).await.unwrap();
+        tx.commit().await.unwrap();
+
+        unwind_stage(&db, HashState, 1).await;
+
+        let tx = db.begin_mutable().await.unwrap();
+        assert_eq!(HASH_STATE.get_progress(&tx).await.unwrap(), Some(1));
+        assert_eq!(hashed_account(&tx, address1).await, Some(100.into()));
+        assert_eq!(hashed_account(&tx, address2).await, Some(200.into()));
+        assert_eq!(hashed_account(&tx, address3).await, None);
+        assert_eq!(
+            hashed_storage(&tx, address1, location1).await,
+            Some(0xaa.into())
+        );
+        assert_eq!(hashed_storage(&tx, address1, location2).await, None);
+    }
+}
@@ -0,0 +1,562 @@
+use crate::{
+    bitmapdb,
+    changeset::{AccountHistory, HistoryKind, StorageHistory},
+    dbutils,
+    kv::tables,
+    stagedsync::{
+        stage::{ExecOutput, PruneInput, Stage, StageInput, UnwindInput},
+        stages::{ACCOUNT_HISTORY_INDEX, STORAGE_HISTORY_INDEX},
+    },
+    stages::prune::delete_blocks_before,
+    txdb, MutableTransaction, StageId, Transaction,
+};
+use async_trait::async_trait;
+use std::collections::BTreeSet;
+use tokio::pin;
+use tokio_stream::StreamExt;
+use tracing::*;
+
+/// Adds blocks `from..=to` of the changeset to the history index of every changed key.
+async fn index_changes<'db: 'tx, 'tx, K, RwTx>(
+    tx: &'tx RwTx,
+    changeset_table: &K::ChangeSetTable,
+    from: u64,
+    to: u64,
+) -> anyhow::Result<()>
+where
+    K: HistoryKind,
+    RwTx: MutableTransaction<'db>,
+{
+    let mut collector = bitmapdb::IndexCollector::default();
+
+    let start_key = dbutils::encode_block_number(from);
+    let mut cursor = tx.cursor_dup_sort(changeset_table).await?;
+    let walker = txdb::walk(&mut cursor, &start_key, 0);
+    pin!(walker);
+    while let Some((k, v)) = walker.try_next().await? {
+        let (block_number, change) = K::decode(k, v);
+        if block_number > to {
+            break;
+        }
+
+        collector.insert(
+            &dbutils::composite_key_without_incarnation::<K>(&change.key),
+            block_number,
+        )?;
+    }
+
+    collector.load(tx, &K::IndexTable::default()).await?;
+
+    Ok(())
+}
+
+/// Returns history index keys changed in blocks `from..=to`.
+async fn changed_keys<'db: 'tx, 'tx, K, Tx>(
+    tx: &'tx Tx,
+    changeset_table: &K::ChangeSetTable,
+    from: u64,
+    to: u64,
+) -> anyhow::Result<BTreeSet<Vec<u8>>>
+where
+    K: HistoryKind,
+    Tx: Transaction<'db>,
+{
+    let mut keys = BTreeSet::new();
+
+    let start_key = dbutils::encode_block_number(from);
+    let mut cursor = tx.cursor_dup_sort(changeset_table).await?;
+    let walker = txdb::walk(&mut cursor, &start_key, 0);
+    pin!(walker);
+    while let Some((k, v)) = walker.try_next().await? {
+        let (block_number, change) = K::decode(k, v);
+        if block_number > to {
+            break;
+        }
+
+        keys.insert(dbutils::composite_key_without_incarnation::<K>(&change.key).to_vec());
+    }
+
+    Ok(keys)
+}
+
+/// Removes blocks after `unwind_to` from the history index of keys changed in them.
+async fn unwind_index<'db: 'tx, 'tx, K, RwTx>(
+    tx: &'tx RwTx,
+    changeset_table: &K::ChangeSetTable,
+    unwind_to: u64,
+    stage_progress: u64,
+) -> anyhow::Result<()>
+where
+    K: HistoryKind,
+    RwTx: MutableTransaction<'db>,
+{
+    let index_table = K::IndexTable::default();
+    for key in changed_keys::<K, _>(tx, changeset_table, unwind_to + 1, stage_progress).await? {
+        bitmapdb::truncate(tx, &index_table, &key, unwind_to + 1).await?;
+    }
+
+    Ok(())
+}
+
+/// Drops changesets of blocks before the retained history, and removes those blocks from the history index.
+async fn prune_index<'db: 'tx, 'tx, K, RwTx>(
+    tx: &'tx RwTx,
+    changeset_table: &K::ChangeSetTable,
+    input: PruneInput,
+) -> anyhow::Result<Option<u64>>
+where
+    K: HistoryKind,
+    RwTx: MutableTransaction<'db>,
+{
+    let before = if let Some(before) = input.prune_history_before() {
+        before
+    } else {
+        return Ok(None);
+    };
+
+    let index_table = K::IndexTable::default();
+    let from = input.prune_progress.unwrap_or(0);
+    for key in changed_keys::<K, _>(tx, changeset_table, from, before - 1).await? {
+        bitmapdb::prune(tx, &index_table, &key, before).await?;
+    }
+    delete_blocks_before(tx, changeset_table, before).await?;
+
+    info!(before, "Pruned");
+    Ok(Some(before))
+}
+
+async fn execute_index<'db: 'tx, 'tx, K, RwTx>(
+    tx: &'tx RwTx,
+    changeset_table: &K::ChangeSetTable,
+    input: StageInput,
+) -> anyhow::Result<ExecOutput>
+where
+    K: HistoryKind,
+    RwTx: MutableTransaction<'db>,
+{
+    let from_height = input.stage_progress.unwrap_or(0);
+    let max_height = if let Some((_, height)) = input.previous_stage {
+        height
+    } else {
+        0
+    };
+
+    if max_height > from_height {
+        index_changes::<K, _>(tx, changeset_table, from_height + 1, max_height).await?;
+    }
+
+    let stage_progress = std::cmp::max(from_height, max_height);
+    info!(highest = stage_progress, "Processed");
+    Ok(ExecOutput::Progress {
+        stage_progress,
+        done: true,
+        must_commit: stage_progress > from_height,
+    })
+}
+
+/// Builds `AccountHistory` index of blocks in which each account changed.
+#[derive(Debug)]
+pub struct AccountHistoryIndex;
+
+#[async_trait]
+impl<'db, RwTx> Stage<'db, RwTx> for AccountHistoryIndex
+where
+    RwTx: MutableTransaction<'db>,
+{
+    fn id(&self) -> StageId {
+        ACCOUNT_HISTORY_INDEX
+    }
+
+    fn description(&self) -> &'static str {
+        "Generating account history index"
+    }
+
+    async fn execute<'tx>(&self, tx: &'tx mut RwTx, input: StageInput) -> anyhow::Result<ExecOutput>
+    where
+        'db: 'tx,
+    {
+        execute_index::<AccountHistory, _>(tx, &tables::AccountChangeSet, input).await
+    }
+
+    async fn unwind<'tx>(&self, tx: &'tx mut RwTx, input: UnwindInput) -> anyhow::Result<()>
+    where
+        'db: 'tx,
+    {
+        unwind_index::<AccountHistory, _>(
+            tx,
+            &tables::AccountChangeSet,
+            input.unwind_to,
+            input.stage_progress,
+        )
+        .await
+    }
+
+    async fn prune<'tx>(&self, tx: &'tx mut RwTx, input: PruneInput) -> anyhow::Result<Option<u64>>
+    where
+        'db: 'tx,
+    {
+        prune_index::<AccountHistory, _>(tx, &tables::AccountChangeSet, input).await
+    }
+}
+
+/// Builds `StorageHistory` index of blocks in which each storage slot changed.
+#[derive(Debug)]
+pub struct StorageHistoryIndex;
+
+#[async_trait]
+impl<'db, RwTx> Stage<'db, RwTx> for StorageHistoryIndex
+where
+    RwTx: MutableTransaction<'db>,
+{
+    fn id(&self) -> StageId {
+        STORAGE_HISTORY_INDEX
+    }
+
+    fn description(&self) -> &'static str {
+        "Generating storage history index"
+    }
+
+    async fn execute<'tx>(&self, tx: &'tx mut RwTx, input: StageInput) -> anyhow::Result<ExecOutput>
+    where
+        'db: 'tx,
+    {
+        execute_index::<StorageHistory, _>(tx, &tables::StorageChangeSet, input).await
+    }
+
+    async fn unwind<'tx>(&self, tx: &'tx mut RwTx, input: UnwindInput) -> anyhow::Result<()>
+    where
+        'db: 'tx,
+    {
+        unwind_index::<StorageHistory, _>(
+            tx,
+            &tables::StorageChangeSet,
+            input.unwind_to,
+            input.stage_progress,
+        )
+        .await
+    }
+
+    async fn prune<'tx>(&self, tx: &'tx mut RwTx, input: PruneInput) -> anyhow::Result<Option<u64>>
+    where
+        'db: 'tx,
+    {
+        prune_index::<StorageHistory, _>(tx, &tables::StorageChangeSet, input).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        kv::traits::MutableKV,
+        models::Account,
+        new_mem_database,
+        stagedsync::{
+            stage::{PruneSettings, MAX_UNWIND_DEPTH},
+            test_util::unwind_stage,
+        },
+        state::database::{PlainStateWriter, StateWriter, WriterWithChangesets},
+        Cursor,
+    };
+    use ethereum_types::{Address, H256};
+    use roaring::RoaringTreemap;
+
+    /// Changes balance of every account at each block, and storage of the contract at even blocks.
+    async fn write_changes<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(
+        tx: &'tx RwTx,
+        addresses: &[Address],
+        contract: Address,
+        blocks: u64,
+    ) {
+        for block_number in 1..=blocks {
+            let mut writer = PlainStateWriter::new(tx, block_number);
+            for address in addresses {
+                writer
+                    .update_account_data(
+                        *address,
+                        &Account {
+                            balance: (block_number - 1).into(),
+                            ..Default::default()
+                        },
+                        &Account {
+                            balance: block_number.into(),
+                            ..Default::default()
+                        },
+                    )
+                    .await
+                    .unwrap();
+            }
+            if block_number % 2 == 0 {
+                writer
+                    .write_account_storage(
+                        contract,
+                        1,
+                        H256::from_low_u64_be(1),
+                        (block_number - 1).into(),
+                        block_number.into(),
+                    )
+                    .await
+                    .unwrap();
+            }
+            writer.write_changesets().await.unwrap();
+        }
+    }
+
+    fn blocks(range: impl IntoIterator<Item = u64>) -> RoaringTreemap {
+        range.into_iter().collect()
+    }
+
+    #[tokio::test]
+    async fn history_index() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let addresses = (0..10).map(|_| Address::random()).collect::<Vec<_>>();
+        let contract = Address::random();
+        write_changes(&tx, &addresses, contract, 3000).await;
+
+        // Index in two runs so that the second one has to extend existing chunks
+        for (stage_progress, height) in [(None, 1000), (Some(1000), 3000)] {
+            for output in [
+                AccountHistoryIndex
+                    .execute(
+                        &mut tx,
+                        StageInput {
+                            restarted: false,
+                            previous_stage: Some((StageId("Execution"), height)),
+                            stage_progress,
+                        },
+                    )
+                    .await
+                    .unwrap(),
+                StorageHistoryIndex
+                    .execute(
+                        &mut tx,
+                        StageInput {
+                            restarted: false,
+                            previous_stage: Some((StageId("Execution"), height)),
+                            stage_progress,
+                        },
+                    )
+                    .await
+                    .unwrap(),
+            ] {
+                assert_eq!(
+                    output,
+                    ExecOutput::Progress {
+                        stage_progress: height,
+                        done: true,
+                        must_commit: true,
+                    }
+                );
+            }
+        }
+
+        for address in &addresses {
+            assert_eq!(
+                bitmapdb::get(
+                    &tx,
+                    &tables::AccountHistory,
+                    address.as_bytes(),
+                    0,
+                    u64::MAX
+                )
+                .await
+                .unwrap(),
+                blocks(1..=3000)
+            );
+        }
+        let storage_key = [contract.as_bytes(), H256::from_low_u64_be(1).as_bytes()].concat();
+        assert_eq!(
+            bitmapdb::get(&tx, &tables::StorageHistory, &storage_key, 0, u64::MAX)
+                .await
+                .unwrap(),
+            blocks((2..=3000).step_by(2))
+        );
+
+        ACCOUNT_HISTORY_INDEX
+            .save_progress(&tx, 3000)
+            .await
+            .unwrap();
+        STORAGE_HISTORY_INDEX
+            .save_progress(&tx, 3000)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        unwind_stage(&db, AccountHistoryIndex, 1500).await;
+        unwind_stage(&db, StorageHistoryIndex, 1500).await;
+
+        let tx = db.begin_mutable().await.unwrap();
+        for address in &addresses {
+            assert_eq!(
+                bitmapdb::get(
+                    &tx,
+                    &tables::AccountHistory,
+                    address.as_bytes(),
+                    0,
+                    u64::MAX
+                )
+                .await
+                .unwrap(),
+                blocks(1..=1500)
+            );
+
+            // The last chunk is always keyed with the maximum block number
+            let mut last_key = address.as_bytes().to_vec();
+            last_key.extend_from_slice(&u64::MAX.to_be_bytes());
+            assert!(tx
+                .get(&tables::AccountHistory, &last_key)
+                .await
+                .unwrap()
+                .is_some());
+        }
+        assert_eq!(
+            bitmapdb::get(&tx, &tables::StorageHistory, &storage_key, 0, u64::MAX)
+                .await
+                .unwrap(),
+            blocks((2..=1500).step_by(2))
+        );
+    }
+
+    #[tokio::test]
+    async fn prune_history() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let addresses = (0..10).map(|_| Address::random()).collect::<Vec<_>>();
+        let contract = Address::random();
+        write_changes(&tx, &addresses, contract, 3000).await;
+
+        let input = StageInput {
+            restarted: false,
+            previous_stage: Some((StageId("Execution"), 3000)),
+            stage_progress: None,
+        };
+        AccountHistoryIndex.execute(&mut tx, input).await.unwrap();
+        StorageHistoryIndex.execute(&mut tx, input).await.unwrap();
+
+        let input = PruneInput {
+            stage_progress: 3000,
+            prune_progress: None,
+            settings: PruneSettings {
+                history: Some(1000),
+                ..Default::default()
+            },
+        };
+        // History needed to unwind is kept regardless of the setting
+        assert_eq!(
+            AccountHistoryIndex.prune(&mut tx, input).await.unwrap(),
+            None
+        );
+
+        // Pretend the chain is far enough ahead that blocks before 2001 are out of unwind reach
+        let input = PruneInput {
+            stage_progress: 2000 + MAX_UNWIND_DEPTH,
+            ..input
+        };
+        assert_eq!(
+            AccountHistoryIndex.prune(&mut tx, input).await.unwrap(),
+            Some(2001)
+        );
+        assert_eq!(
+            StorageHistoryIndex.prune(&mut tx, input).await.unwrap(),
+            Some(2001)
+        );
+        ACCOUNT_HISTORY_INDEX
+            .save_prune_progress(&tx, 2001)
+            .await
+            .unwrap();
+        STORAGE_HISTORY_INDEX
+            .save_prune_progress(&tx, 2001)
+            .await
+            .unwrap();
+
+        // Nothing new to prune until more blocks are synced
+        assert_eq!(
+            AccountHistoryIndex
+                .prune(
+                    &mut tx,
+                    PruneInput {
+                        prune_progress: Some(2001),
+                        ..input
+                    }
+                )
+                .await
+                .unwrap(),
+            None
+        );
+
+        for address in &addresses {
+            assert_eq!(
+                bitmapdb::get(
+                    &tx,
+                    &tables::AccountHistory,
+                    address.as_bytes(),
+                    0,
+                    u64::MAX
+                )
+                .await
+                .unwrap(),
+                blocks(2001..=3000)
+            );
+        }
+        let storage_key = [contract.as_bytes(), H256::from_low_u64_be(1).as_bytes()].concat();
+        assert_eq!(
+            bitmapdb::get(&tx, &tables::StorageHistory, &storage_key, 0, u64::MAX)
+                .await
+                .unwrap(),
+            blocks((2002..=3000).step_by(2))
+        );
+
+        let (k, _) = tx
+            .cursor(&tables::AccountChangeSet)
+            .await
+            .unwrap()
+            .first()
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(k.as_ref(), &2001_u64.to_be_bytes());
+        let (k, _) = tx
+            .cursor(&tables::StorageChangeSet)
+            .await
+            .unwrap()
+            .first()
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&k[..8], &2002_u64.to_be_bytes());
+
+        // Retained history is still served, older blocks are reported as pruned
+        let account = crate::get_account_data_as_of(&tx, addresses[0], 2500)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            Account::decode_for_storage(&account)
+                .unwrap()
+                .unwrap()
+                .balance,
+            2499.into()
+        );
+        assert_eq!(
+            crate::get_account_data_as_of(&tx, addresses[0], 1500)
+                .await
+                .unwrap_err()
+                .downcast::<crate::HistoryError>()
+                .unwrap(),
+            crate::HistoryError::Pruned {
+                block: 1500,
+                available: 2001
+            }
+        );
+        assert!(
+            crate::get_storage_as_of(&tx, contract, 1, H256::from_low_u64_be(1), 1500)
+                .await
+                .unwrap_err()
+                .downcast::<crate::HistoryError>()
+                .is_ok()
+        );
+    }
+}
@@ -0,0 +1,1152 @@
+use crate::{
+    accessors::chain,
+    changeset::{AccountHistory, HistoryKind, StorageHistory},
+    common, dbutils,
+    etl::{
+        collector::{Collector, OPTIMAL_BUFFER_CAPACITY},
+        data_provider::Entry,
+    },
+    kv::{tables, traits::Table},
+    models::Account,
+    stagedsync::{
+        stage::{ExecOutput, Stage, StageInput, UnwindInput},
+        stages::INTERMEDIATE_HASHES,
+    },
+    trie::{self, BranchNode},
+    txdb, Cursor, MutableCursor, MutableTransaction, StageId, Transaction,
+};
+use anyhow::Context;
+use arrayref::array_ref;
+use async_trait::async_trait;
+use ethereum_types::{Address, H256, U256};
+use rlp::RlpStream;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    marker::PhantomData,
+};
+use thiserror::Error;
+use tokio::pin;
+use tokio_stream::StreamExt;
+use tracing::*;
+
+#[derive(Error, Debug)]
+pub enum IntermediateHashesError {
+    #[error("Canonical hash for block {0} not found")]
+    HashNotFound(u64),
+    #[error("Header for block {0} not found")]
+    HeaderNotFound(u64),
+}
+
+fn account_rlp(account: &Account, storage_root: H256) -> Vec<u8> {
+    let mut s = RlpStream::new_list(4);
+    s.append(&account.nonce);
+    s.append(&account.balance);
+    s.append(&storage_root);
+    s.append(&account.code_hash.unwrap_or(common::EMPTY_HASH));
+    s.out().to_vec()
+}
+
+fn storage_rlp(value: &[u8]) -> Vec<u8> {
+    rlp::encode(&U256::from_big_endian(value)).to_vec()
+}
+
+/// Changes of the stored branch nodes of a trie.
+#[derive(Default)]
+struct BranchUpdates {
+    deleted: Vec<Vec<u8>>,
+    written: Vec<(Vec<u8>, BranchNode)>,
+}
+
+enum Branches {
+    /// All branch nodes of the tries, replacing the stored ones.
+    Rebuilt {
+        account: Collector,
+        storage: Collector,
+    },
+    /// Branch nodes of the paths leading to changed keys.
+    Updated {
+        account: BranchUpdates,
+        storage: BranchUpdates,
+    },
+}
+
+impl Branches {
+    async fn write<'db: 'tx, 'tx, RwTx>(self, tx: &'tx RwTx) -> anyhow::Result<()>
+    where
+        RwTx: MutableTransaction<'db>,
+    {
+        match self {
+            Self::Rebuilt {
+                mut account,
+                mut storage,
+            } => {
+                clear_table(tx, &tables::TrieAccount).await?;
+                clear_table(tx, &tables::TrieStorage).await?;
+                account
+                    .load_append(&mut tx.mutable_cursor(&tables::TrieAccount).await?)
+                    .await?;
+                storage
+                    .load_append(&mut tx.mutable_cursor(&tables::TrieStorage).await?)
+                    .await?;
+            }
+            Self::Updated { account, storage } => {
+                write_updates(tx, &tables::TrieAccount, account).await?;
+                write_updates(tx, &tables::TrieStorage, storage).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct StateRoot {
+    root: H256,
+    branches: Branches,
+}
+
+fn collect_branch(collector: &mut Collector, prefix: &[u8], path: &[u8], node: BranchNode) {
+    collector.collect(Entry {
+        key: [prefix, path].concat(),
+        value: node.encode(),
+        id: 0, // Irrelevant here, could be anything
+    });
+}
+
+/// Computes state root from `HashedAccount` and `HashedStorage`, along with all the branch nodes of the tries.
+async fn compute_state_root<'db: 'tx, 'tx, Tx>(tx: &'tx Tx) -> anyhow::Result<StateRoot>
+where
+    Tx: Transaction<'db>,
+{
+    let mut account_branches = Collector::new(OPTIMAL_BUFFER_CAPACITY);
+    let mut storage_branches = Collector::new(OPTIMAL_BUFFER_CAPACITY);
+
+    let mut account_cursor = tx.cursor(&tables::HashedAccount).await?;
+    let mut storage_cursor = tx.cursor(&tables::HashedStorage).await?;
+
+    let mut builder = trie::HashBuilder::new(|path, node| {
+        // Root node of the account trie has empty path, which cannot be used as a key
+        if !path.is_empty() {
+            collect_branch(&mut account_branches, &[], path, node)
+        }
+    });
+
+    let walker = txdb::walk(&mut account_cursor, &[], 0);
+    pin!(walker);
+    while let Some((hashed_address, v)) = walker.try_next().await? {
+        let account = Account::decode_for_storage(&v)?
+            .with_context(|| format!("empty account {:?}", H256::from_slice(&hashed_address)))?;
+
+        let mut storage_root = trie::EMPTY_ROOT;
+        if account.incarnation > 0 {
+            let mut prefix = hashed_address.to_vec();
+            prefix.extend_from_slice(&account.incarnation.to_be_bytes());
+
+            let mut storage_builder = trie::HashBuilder::new(|path, node| {
+                collect_branch(&mut storage_branches, &prefix, path, node)
+            });
+            let storage_walker =
+                txdb::walk(&mut storage_cursor, &prefix, (prefix.len() * 8) as u64);
+            pin!(storage_walker);
+            while let Some((k, v)) = storage_walker.try_next().await? {
+                storage_builder.add(
+                    trie::unpack_nibbles(&k[prefix.len()..]),
+                    trie::Node::Leaf(storage_rlp(&v)),
+                );
+            }
+            storage_root = storage_builder.root();
+        }
+
+        builder.add(
+            trie::unpack_nibbles(&hashed_address),
+            trie::Node::Leaf(account_rlp(&account, storage_root)),
+        );
+    }
+    let root = builder.root();
+
+    Ok(StateRoot {
+        root,
+        branches: Branches::Rebuilt {
+            account: account_branches,
+            storage: storage_branches,
+        },
+    })
+}
+
+/// Values of changed leaves of a trie keyed by their paths, `None` for the deleted ones.
+type Changes = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
+fn changes_under<'a>(
+    changes: &'a Changes,
+    path: &'a [u8],
+) -> impl Iterator<Item = (&'a Vec<u8>, &'a Option<Vec<u8>>)> {
+    changes
+        .range(path.to_vec()..)
+        .take_while(move |(key, _)| key.starts_with(path))
+}
+
+/// Hashed state the tries are updated to, given by the keys changed since they were built.
+#[derive(Default)]
+struct ChangedState {
+    /// Accounts changed either themselves or in their storage.
+    accounts: Changes,
+    /// Changed storage, by prefix of the storage trie: hashed address and incarnation.
+    storage: BTreeMap<Vec<u8>, Changes>,
+}
+
+/// Plain keys changed in blocks `from..=to`, with their values before block `from`.
+async fn changesets<'db: 'tx, 'tx, Tx>(
+    tx: &'tx Tx,
+    from: u64,
+    to: u64,
+) -> anyhow::Result<(
+    BTreeMap<Address, Vec<u8>>,
+    BTreeMap<<StorageHistory as HistoryKind>::Key, Vec<u8>>,
+)>
+where
+    Tx: Transaction<'db>,
+{
+    let start_key = dbutils::encode_block_number(from);
+
+    // Only the earliest change in the range holds the value before it
+    let mut accounts = BTreeMap::new();
+    {
+        let mut cursor = tx.cursor_dup_sort(&tables::AccountChangeSet).await?;
+        let walker = txdb::walk(&mut cursor, &start_key, 0);
+        pin!(walker);
+        while let Some((k, v)) = walker.try_next().await? {
+            let (block_number, change) = AccountHistory::decode(k, v);
+            if block_number > to {
+                break;
+            }
+            accounts
+                .entry(change.key)
+                .or_insert_with(|| change.value.to_vec());
+        }
+    }
+
+    let mut storage = BTreeMap::new();
+    {
+        let mut cursor = tx.cursor_dup_sort(&tables::StorageChangeSet).await?;
+        let walker = txdb::walk(&mut cursor, &start_key, 0);
+        pin!(walker);
+        while let Some((k, v)) = walker.try_next().await? {
+            let (block_number, change) = StorageHistory::decode(k, v);
+            if block_number > to {
+                break;
+            }
+            storage
+                .entry(change.key)
+                .or_insert_with(|| change.value.to_vec());
+        }
+    }
+
+    Ok((accounts, storage))
+}
+
+/// Prefix of the storage trie and the hashed location of a plain storage key.
+fn storage_path(plain_key: &[u8]) -> (Vec<u8>, H256) {
+    let (address, incarnation, location) = dbutils::plain_parse_composite_storage_key(array_ref!(
+        plain_key,
+        0,
+        dbutils::PLAIN_COMPOSITE_STORAGE_KEY_LENGTH
+    ));
+
+    (
+        dbutils::generate_storage_prefix(common::hash_data(address.as_bytes()), incarnation)
+            .to_vec(),
+        common::hash_data(location.as_bytes()),
+    )
+}
+
+/// Current hashed state of the keys changed in blocks `from..=to`.
+async fn current_state<'db: 'tx, 'tx, Tx>(
+    tx: &'tx Tx,
+    from: u64,
+    to: u64,
+) -> anyhow::Result<ChangedState>
+where
+    Tx: Transaction<'db>,
+{
+    let (accounts, storage) = changesets(tx, from, to).await?;
+
+    let mut state = ChangedState::default();
+    let mut hashed_storage = tx.cursor(&tables::HashedStorage).await?;
+    for plain_key in storage.keys() {
+        let (prefix, location) = storage_path(plain_key);
+        let value = hashed_storage
+            .seek_exact(&[&prefix[..], location.as_bytes()].concat())
+            .await?
+            .map(|(_, v)| v.to_vec());
+        state
+            .storage
+            .entry(prefix)
+            .or_default()
+            .insert(trie::unpack_nibbles(location.as_bytes()), value);
+    }
+
+    let hashed_addresses = accounts
+        .keys()
+        .map(|address| common::hash_data(address.as_bytes()))
+        .chain(
+            state
+                .storage
+                .keys()
+                .map(|prefix| H256::from_slice(&prefix[..common::HASH_LENGTH])),
+        )
+        .collect::<BTreeSet<_>>();
+    for hashed_address in hashed_addresses {
+        let value = tx
+            .get(&tables::HashedAccount, hashed_address.as_bytes())
+            .await?
+            .map(|v| v.to_vec());
+        state
+            .accounts
+            .insert(trie::unpack_nibbles(hashed_address.as_bytes()), value);
+    }
+
+    Ok(state)
+}
+
+/// Hashed state before block `from` of the keys changed in blocks `from..=to`, restored from the changesets.
+async fn unwound_state<'db: 'tx, 'tx, Tx>(
+    tx: &'tx Tx,
+    from: u64,
+    to: u64,
+) -> anyhow::Result<ChangedState>
+where
+    Tx: Transaction<'db>,
+{
+    let (accounts, storage) = changesets(tx, from, to).await?;
+
+    let mut state = ChangedState::default();
+    for (plain_key, value) in storage {
+        let (prefix, location) = storage_path(&plain_key);
+        let value = if value.iter().all(|&b| b == 0) {
+            None
+        } else {
+            Some(value)
+        };
+        state
+            .storage
+            .entry(prefix)
+            .or_default()
+            .insert(trie::unpack_nibbles(location.as_bytes()), value);
+    }
+
+    for (address, value) in accounts {
+        let mut account = Account::decode_for_storage(&value)?;
+        if let Some(account) = &mut account {
+            // Changesets omit code hashes, restore them
+            if account.incarnation > 0 && account.code_hash.is_none() {
+                if let Some(code_hash) = tx
+                    .get(
+                        &tables::PlainCodeHash,
+                        &dbutils::plain_generate_storage_prefix(address, account.incarnation),
+                    )
+                    .await?
+                {
+                    account.code_hash = Some(H256::from_slice(&*code_hash));
+                }
+            }
+        }
+
+        state.accounts.insert(
+            trie::unpack_nibbles(common::hash_data(address.as_bytes()).as_bytes()),
+            account.map(|account| account.encode_for_storage()),
+        );
+    }
+
+    // Accounts with only their storage changed keep their current values
+    for prefix in state.storage.keys() {
+        let hashed_address = &prefix[..common::HASH_LENGTH];
+        let path = trie::unpack_nibbles(hashed_address);
+        if !state.accounts.contains_key(&path) {
+            let value = tx
+                .get(&tables::HashedAccount, hashed_address)
+                .await?
+                .map(|v| v.to_vec());
+            state.accounts.insert(path, value);
+        }
+    }
+
+    Ok(state)
+}
+
+enum Task {
+    /// Path to look for the items under.
+    Path(Vec<u8>),
+    /// Item found.
+    Item(Vec<u8>, trie::Node),
+}
+
+/// Items of the trie under `prefix` with the leaves in the state table updated by `changes`,
+/// produced in order of their paths.
+///
+/// Stored branch nodes with no changes under them are kept by their hashes, the others are expanded
+/// down to the leaves and marked deleted. Leaves come with their values as found in the state table.
+struct TrieItems<'a, B, S, BC, SC> {
+    branch_cursor: BC,
+    state_cursor: SC,
+    prefix: &'a [u8],
+    changes: &'a Changes,
+    /// Next tasks, the first one last.
+    tasks: Vec<Task>,
+    _marker: PhantomData<(B, S)>,
+}
+
+impl<'a, 'tx, B, S, BC, SC> TrieItems<'a, B, S, BC, SC>
+where
+    B: Table,
+    S: Table,
+    BC: Cursor<'tx, B>,
+    SC: Cursor<'tx, S>,
+{
+    fn new(
+        branch_cursor: BC,
+        state_cursor: SC,
+        prefix: &'a [u8],
+        changes: &'a Changes,
+        root_stored: bool,
+    ) -> Self {
+        Self {
+            branch_cursor,
+            state_cursor,
+            prefix,
+            changes,
+            tasks: if root_stored {
+                vec![Task::Path(vec![])]
+            } else {
+                (0..16)
+                    .rev()
+                    .map(|nibble| Task::Path(vec![nibble]))
+                    .collect()
+            },
+            _marker: PhantomData,
+        }
+    }
+
+    /// Next item, with keys of the expanded branch nodes pushed to `deleted`.
+    async fn next(
+        &mut self,
+        deleted: &mut Vec<Vec<u8>>,
+    ) -> anyhow::Result<Option<(Vec<u8>, trie::Node)>> {
+        let prefix = self.prefix;
+        let changes = self.changes;
+
+        while let Some(task) = self.tasks.pop() {
+            let path = match task {
+                Task::Item(path, node) => return Ok(Some((path, node))),
+                Task::Path(path) => path,
+            };
+
+            // The first stored branch under the path is the topmost one, covering all the leaves under the path
+            let key = [prefix, &path[..]].concat();
+            let (branch_path, node) = match self.branch_cursor.seek(&key).await? {
+                Some((k, v)) if k.starts_with(&key) => {
+                    (k[prefix.len()..].to_vec(), BranchNode::decode(&v)?)
+                }
+                _ => {
+                    // No branch means at most one leaf used to be there, so the leaves under the path now
+                    // are few enough to be sorted here
+                    let mut leaves = BTreeMap::new();
+                    let start_key = [prefix, &trie::pack_nibbles(&path)[..]].concat();
+                    let walker = txdb::walk(
+                        &mut self.state_cursor,
+                        &start_key,
+                        (prefix.len() * 8 + path.len() * 4) as u64,
+                    );
+                    pin!(walker);
+                    while let Some((k, v)) = walker.try_next().await? {
+                        let leaf = trie::unpack_nibbles(&k[prefix.len()..]);
+                        if !changes.contains_key(&leaf) {
+                            leaves.insert(leaf, v.to_vec());
+                        }
+                    }
+                    for (leaf, value) in changes_under(changes, &path) {
+                        if let Some(value) = value {
+                            leaves.insert(leaf.clone(), value.clone());
+                        }
+                    }
+                    self.tasks.extend(
+                        leaves
+                            .into_iter()
+                            .rev()
+                            .map(|(leaf, value)| Task::Item(leaf, trie::Node::Leaf(value))),
+                    );
+                    continue;
+                }
+            };
+
+            // Leaves changed under the path but not under the branch are new ones, lying either before
+            // or after the branch
+            let mut before = changes_under(changes, &path)
+                .filter(|(leaf, _)| !leaf.starts_with(&branch_path))
+                .filter_map(|(leaf, value)| Some((leaf.clone(), value.clone()?)))
+                .collect::<Vec<_>>();
+            let after = before.split_off(
+                before
+                    .iter()
+                    .position(|(leaf, _)| *leaf > branch_path)
+                    .unwrap_or(before.len()),
+            );
+
+            self.tasks.extend(
+                after
+                    .into_iter()
+                    .rev()
+                    .map(|(leaf, value)| Task::Item(leaf, trie::Node::Leaf(value))),
+            );
+            match node.rlp() {
+                Some(rlp) if changes_under(changes, &branch_path).next().is_none() => {
+                    self.tasks
+                        .push(Task::Item(branch_path, trie::Node::Branch(rlp)));
+                }
+                _ => {
+                    deleted.push([prefix, &branch_path[..]].concat());
+                    for nibble in (0..16).rev() {
+                        let child = [&branch_path[..], &[nibble][..]].concat();
+                        if node.state_mask & (1 << nibble) != 0
+                            || changes_under(changes, &child).next().is_some()
+                        {
+                            self.tasks.push(Task::Path(child));
+                        }
+                    }
+                }
+            }
+            self.tasks.extend(
+                before
+                    .into_iter()
+                    .rev()
+                    .map(|(leaf, value)| Task::Item(leaf, trie::Node::Leaf(value))),
+            );
+        }
+
+        Ok(None)
+    }
+}
+
+/// Root of the storage trie under `prefix`, updated by `changes` of its slots.
+async fn storage_trie_root<'db: 'tx, 'tx, Tx>(
+    tx: &'tx Tx,
+    prefix: &[u8],
+    changes: &Changes,
+    updates: &mut BranchUpdates,
+) -> anyhow::Result<H256>
+where
+    Tx: Transaction<'db>,
+{
+    let BranchUpdates { deleted, written } = updates;
+
+    let mut items = TrieItems::new(
+        tx.cursor(&tables::TrieStorage).await?,
+        tx.cursor(&tables::HashedStorage).await?,
+        prefix,
+        changes,
+        true,
+    );
+    let mut builder =
+        trie::HashBuilder::new(|path, node| written.push(([prefix, path].concat(), node)));
+    while let Some((path, node)) = items.next(deleted).await? {
+        let node = match node {
+            trie::Node::Leaf(value) => trie::Node::Leaf(storage_rlp(&value)),
+            branch => branch,
+        };
+        builder.add(path, node);
+    }
+
+    Ok(builder.root())
+}
+
+/// Computes state root for `state`, rebuilding only the paths of the tries that lead to changed keys.
+async fn update_state_root<'db: 'tx, 'tx, Tx>(
+    tx: &'tx Tx,
+    state: &ChangedState,
+) -> anyhow::Result<StateRoot>
+where
+    Tx: Transaction<'db>,
+{
+    let mut account_updates = BranchUpdates::default();
+    let mut storage_updates = BranchUpdates::default();
+
+    // Storage tries of deleted accounts and of replaced incarnations are not needed anymore
+    let mut cursor = tx.cursor(&tables::TrieStorage).await?;
+    for (path, value) in &state.accounts {
+        let hashed_address = trie::pack_nibbles(path);
+        let incarnation = value
+            .as_deref()
+            .map(Account::decode_for_storage)
+            .transpose()?
+            .flatten()
+            .map(|account| account.incarnation)
+            .filter(|&incarnation| incarnation > 0);
+
+        let mut entry = cursor.seek(&hashed_address).await?;
+        while let Some((k, _)) = entry {
+            if !k.starts_with(&hashed_address) {
+                break;
+            }
+
+            let stored_incarnation = u64::from_be_bytes(*array_ref!(
+                k,
+                common::HASH_LENGTH,
+                common::INCARNATION_LENGTH
+            ));
+            if Some(stored_incarnation) == incarnation {
+                // Skip the live storage trie
+                entry = match stored_incarnation.checked_add(1) {
+                    Some(next) => {
+                        cursor
+                            .seek(&[&hashed_address[..], &next.to_be_bytes()[..]].concat())
+                            .await?
+                    }
+                    None => None,
+                };
+                continue;
+            }
+
+            storage_updates.deleted.push(k.to_vec());
+            entry = cursor.next().await?;
+        }
+    }
+
+    let mut account_written = vec![];
+    let mut builder = trie::HashBuilder::new(|path, node| {
+        // Root node of the account trie is not stored
+        if !path.is_empty() {
+            account_written.push((path.to_vec(), node))
+        }
+    });
+
+    let no_changes = Changes::new();
+    let mut items = TrieItems::new(
+        tx.cursor(&tables::TrieAccount).await?,
+        tx.cursor(&tables::HashedAccount).await?,
+        &[],
+        &state.accounts,
+        false,
+    );
+    while let Some((path, node)) = items.next(&mut account_updates.deleted).await? {
+        let node = match node {
+            trie::Node::Leaf(value) => {
+                let hashed_address = H256::from_slice(&trie::pack_nibbles(&path));
+                let account = Account::decode_for_storage(&value)?
+                    .with_context(|| format!("empty account {:?}", hashed_address))?;
+
+                let mut storage_root = trie::EMPTY_ROOT;
+                if account.incarnation > 0 {
+                    let prefix =
+                        dbutils::generate_storage_prefix(hashed_address, account.incarnation)
+                            .to_vec();
+                    let changes = state.storage.get(&prefix).unwrap_or(&no_changes);
+                    storage_root =
+                        storage_trie_root(tx, &prefix, changes, &mut storage_updates).await?;
+                }
+
+                trie::Node::Leaf(account_rlp(&account, storage_root))
+            }
+            branch => branch,
+        };
+        builder.add(path, node);
+    }
+    let root = builder.root();
+    account_updates.written = account_written;
+
+    Ok(StateRoot {
+        root,
+        branches: Branches::Updated {
+            account: account_updates,
+            storage: storage_updates,
+        },
+    })
+}
+
+async fn write_updates<'db: 'tx, 'tx, RwTx, T>(
+    tx: &'tx RwTx,
+    table: &T,
+    updates: BranchUpdates,
+) -> anyhow::Result<()>
+where
+    RwTx: MutableTransaction<'db>,
+    T: Table,
+{
+    let mut cursor = tx.mutable_cursor(table).await?;
+    for key in updates.deleted {
+        cursor.delete(&key, &[]).await?;
+    }
+    for (key, node) in updates.written {
+        cursor.put(&key, &node.encode()).await?;
+    }
+
+    Ok(())
+}
+
+async fn clear_table<'db: 'tx, 'tx, RwTx, T>(tx: &'tx RwTx, table: &T) -> anyhow::Result<()>
+where
+    RwTx: MutableTransaction<'db>,
+    T: Table,
+{
+    let mut cursor = tx.mutable_cursor(table).await?;
+    while cursor.first().await?.is_some() {
+        cursor.delete_current().await?;
+    }
+
+    Ok(())
+}
+
+/// Computes the state root and checks it against the header of the last block.
+/// On mismatch the last block is marked bad and unwound.
+/// Branch nodes of the account and storage tries are kept in `TrieAccount` and `TrieStorage`.
+/// They are built from scratch on the first run, afterwards only the paths leading to keys
+/// changed in the executed or unwound blocks are rebuilt.
+#[derive(Debug)]
+pub struct IntermediateHashes;
+
+#[async_trait]
+impl<'db, RwTx> Stage<'db, RwTx> for IntermediateHashes
+where
+    RwTx: MutableTransaction<'db>,
+{
+    fn id(&self) -> StageId {
+        INTERMEDIATE_HASHES
+    }
+
+    fn description(&self) -> &'static str {
+        "Generating intermediate hashes and computing state root"
+    }
+
+    async fn execute<'tx>(&self, tx: &'tx mut RwTx, input: StageInput) -> anyhow::Result<ExecOutput>
+    where
+        'db: 'tx,
+    {
+        let from_height = input.stage_progress.unwrap_or(0);
+        let max_height = if let Some((_, height)) = input.previous_stage {
+            height
+        } else {
+            0
+        };
+
+        if from_height >= max_height {
+            return Ok(ExecOutput::Progress {
+                stage_progress: from_height,
+                done: true,
+                must_commit: false,
+            });
+        }
+
+        let hash = chain::canonical_hash::read(tx, max_height)
+            .await?
+            .ok_or(IntermediateHashesError::HashNotFound(max_height))?;
+        let header = chain::header::read(tx, hash, max_height)
+            .await?
+            .ok_or(IntermediateHashesError::HeaderNotFound(max_height))?;
+
+        let StateRoot { root, branches } = if from_height == 0 {
+            compute_state_root(tx).await?
+        } else {
+            let state = current_state(tx, from_height + 1, max_height).await?;
+            update_state_root(tx, &state).await?
+        };
+
+        if root != header.state_root {
+            error!(
+                "Wrong state root for block {}: expected {:?}, computed {:?}",
+                max_height, header.state_root, root
+            );
+            // Executing the same block again would give the same root, so mark it bad
+            // to keep it from being downloaded again, and unwind just that block
+            chain::bad_header::write(tx, hash, max_height).await?;
+            return Ok(ExecOutput::Unwind {
+                unwind_to: max_height - 1,
+            });
+        }
+
+        branches.write(tx).await?;
+
+        info!(highest = max_height, root = ?root, "Processed");
+        Ok(ExecOutput::Progress {
+            stage_progress: max_height,
+            done: true,
+            must_commit: true,
+        })
+    }
+
+    async fn unwind<'tx>(&self, tx: &'tx mut RwTx, input: UnwindInput) -> anyhow::Result<()>
+    where
+        'db: 'tx,
+    {
+        // Hashed state is unwound after this stage, so the unwound values are taken from the changesets
+        let state = unwound_state(tx, input.unwind_to + 1, input.stage_progress).await?;
+        update_state_root(tx, &state)
+            .await?
+            .branches
+            .write(tx)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        kv::traits::MutableKV,
+        new_mem_database,
+        stagedsync::test_util::unwind_stage,
+        stages::HashState,
+        state::database::{PlainStateWriter, StateWriter, WriterWithChangesets},
+    };
+    use ethereum::Header;
+    use ethereum_types::{Bloom, H160, H64};
+
+    const CONTRACT: Address = H160([0xc0; 20]);
+
+    fn header(number: u64, state_root: H256) -> Header {
+        Header {
+            parent_hash: H256::zero(),
+            ommers_hash: common::EMPTY_LIST_HASH,
+            beneficiary: Address::zero(),
+            state_root,
+            transactions_root: trie::EMPTY_ROOT,
+            receipts_root: trie::EMPTY_ROOT,
+            logs_bloom: Bloom::zero(),
+            difficulty: 1.into(),
+            number: number.into(),
+            gas_limit: 10_000_000.into(),
+            gas_used: 0.into(),
+            timestamp: number,
+            extra_data: vec![],
+            mix_hash: H256::zero(),
+            nonce: H64::zero(),
+        }
+    }
+
+    fn account(i: u64) -> Account {
+        Account {
+            nonce: i,
+            balance: (i * 1000).into(),
+            ..Default::default()
+        }
+    }
+
+    /// Writes hashed state and returns its root computed independently of the stage.
+    async fn write_state<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(tx: &'tx RwTx) -> H256 {
+        let mut accounts = vec![];
+        for i in 1..=50_u64 {
+            let account = account(i);
+            let hashed_address = common::hash_data(Address::from_low_u64_be(i).as_bytes());
+            tx.set(
+                &tables::HashedAccount,
+                hashed_address.as_bytes(),
+                &account.encode_for_storage(),
+            )
+            .await
+            .unwrap();
+            accounts.push((hashed_address, account_rlp(&account, trie::EMPTY_ROOT)));
+        }
+
+        let contract = Account {
+            incarnation: 1,
+            code_hash: Some(common::hash_data(b"code")),
+            ..Default::default()
+        };
+        let hashed_address = common::hash_data(CONTRACT.as_bytes());
+        tx.set(
+            &tables::HashedAccount,
+            hashed_address.as_bytes(),
+            &contract.encode_for_storage(),
+        )
+        .await
+        .unwrap();
+
+        let mut storage = vec![];
+        let mut cursor = tx.mutable_cursor(&tables::HashedStorage).await.unwrap();
+        for i in 1..=20_u64 {
+            let hashed_location = common::hash_data(H256::from_low_u64_be(i).as_bytes());
+            let value = U256::from(i * 7);
+            cursor
+                .put(
+                    &dbutils::generate_composite_storage_key(hashed_address, 1, hashed_location),
+                    &common::value_to_bytes(value),
+                )
+                .await
+                .unwrap();
+            storage.push((
+                trie::unpack_nibbles(hashed_location.as_bytes()),
+                trie::Node::Leaf(rlp::encode(&value).to_vec()),
+            ));
+        }
+        storage.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let storage_root = trie::root_hash(&storage, |_, _| {});
+        accounts.push((hashed_address, account_rlp(&contract, storage_root)));
+
+        let mut accounts = accounts
+            .into_iter()
+            .map(|(k, v)| (trie::unpack_nibbles(k.as_bytes()), trie::Node::Leaf(v)))
+            .collect::<Vec<_>>();
+        accounts.sort_by(|(a, _), (b, _)| a.cmp(b));
+        trie::root_hash(&accounts, |_, _| {})
+    }
+
+    async fn write_header<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(
+        tx: &'tx RwTx,
+        number: u64,
+        state_root: H256,
+    ) {
+        let header = header(number, state_root);
+        let hash = header.hash();
+        chain::header::write(tx, hash, number, &header)
+            .await
+            .unwrap();
+        chain::canonical_hash::write(tx, number, hash)
+            .await
+            .unwrap();
+    }
+
+    async fn entries<'db: 'tx, 'tx, Tx: Transaction<'db>, T: Table>(
+        tx: &'tx Tx,
+        table: &T,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut cursor = tx.cursor(table).await.unwrap();
+        let walker = txdb::walk(&mut cursor, &[], 0);
+        pin!(walker);
+        let mut entries = vec![];
+        while let Some((k, v)) = walker.try_next().await.unwrap() {
+            entries.push((k.to_vec(), v.to_vec()));
+        }
+        entries
+    }
+
+    async fn execute<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(
+        tx: &'tx mut RwTx,
+        stage_progress: u64,
+        to: u64,
+    ) -> ExecOutput {
+        IntermediateHashes
+            .execute(
+                tx,
+                StageInput {
+                    restarted: false,
+                    previous_stage: Some((StageId("HashState"), to)),
+                    stage_progress: Some(stage_progress),
+                },
+            )
+            .await
+            .unwrap()
+    }
+
+    fn processed(stage_progress: u64) -> ExecOutput {
+        ExecOutput::Progress {
+            stage_progress,
+            done: true,
+            must_commit: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn compute_and_verify_state_root() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let root = write_state(&tx).await;
+        write_header(&tx, 1, root).await;
+
+        assert_eq!(execute(&mut tx, 0, 1).await, processed(1));
+
+        // 51 accounts spread over 16 root children need more branches than just the root
+        let account_branches = entries(&tx, &tables::TrieAccount).await;
+        let storage_branches = entries(&tx, &tables::TrieStorage).await;
+        assert!(!account_branches.is_empty());
+        assert!(!storage_branches.is_empty());
+
+        for (k, v) in &account_branches {
+            assert!(k.iter().all(|&nibble| nibble < 16));
+            let node = BranchNode::decode(v).unwrap();
+            assert_eq!(node.hashes.len(), node.hash_mask.count_ones() as usize);
+        }
+
+        INTERMEDIATE_HASHES.save_progress(&tx, 1).await.unwrap();
+        tx.commit().await.unwrap();
+
+        unwind_stage(&db, IntermediateHashes, 0).await;
+
+        // Nothing changed in block 1, so the tries stay the same
+        let tx = db.begin_mutable().await.unwrap();
+        assert_eq!(entries(&tx, &tables::TrieAccount).await, account_branches);
+        assert_eq!(entries(&tx, &tables::TrieStorage).await, storage_branches);
+    }
+
+    #[tokio::test]
+    async fn update_changed_paths() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let root = write_state(&tx).await;
+        write_header(&tx, 1, root).await;
+        assert_eq!(execute(&mut tx, 0, 1).await, processed(1));
+
+        let account_branches = entries(&tx, &tables::TrieAccount).await;
+        let storage_branches = entries(&tx, &tables::TrieStorage).await;
+
+        // Block 2: change balance of a single account and one storage slot of the contract
+        let address = Address::from_low_u64_be(7);
+        let mut writer = PlainStateWriter::new(&tx, 2);
+        writer
+            .update_account_data(
+                address,
+                &account(7),
+                &Account {
+                    balance: 1.into(),
+                    ..account(7)
+                },
+            )
+            .await
+            .unwrap();
+        writer
+            .write_account_storage(
+                CONTRACT,
+                1,
+                H256::from_low_u64_be(3),
+                21.into(),
+                0xff.into(),
+            )
+            .await
+            .unwrap();
+        writer.write_changesets().await.unwrap();
+
+        HashState
+            .execute(
+                &mut tx,
+                StageInput {
+                    restarted: false,
+                    previous_stage: Some((StageId("Execution"), 2)),
+                    stage_progress: Some(1),
+                },
+            )
+            .await
+            .unwrap();
+
+        let updated_root = compute_state_root(&tx).await.unwrap().root;
+        assert_ne!(updated_root, root);
+        write_header(&tx, 2, updated_root).await;
+
+        assert_eq!(execute(&mut tx, 1, 2).await, processed(2));
+
+        let updated_account_branches = entries(&tx, &tables::TrieAccount).await;
+        let updated_storage_branches = entries(&tx, &tables::TrieStorage).await;
+        assert_ne!(updated_storage_branches, storage_branches);
+
+        // Same branches as built from scratch
+        assert_eq!(execute(&mut tx, 0, 2).await, processed(2));
+        assert_eq!(
+            entries(&tx, &tables::TrieAccount).await,
+            updated_account_branches
+        );
+        assert_eq!(
+            entries(&tx, &tables::TrieStorage).await,
+            updated_storage_branches
+        );
+
+        IntermediateHashes
+            .unwind(
+                &mut tx,
+                UnwindInput {
+                    stage_progress: 2,
+                    unwind_to: 1,
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(entries(&tx, &tables::TrieAccount).await, account_branches);
+        assert_eq!(entries(&tx, &tables::TrieStorage).await, storage_branches);
+    }
+
+    #[tokio::test]
+    async fn unwind_on_state_root_mismatch() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        write_state(&tx).await;
+        write_header(&tx, 1, H256::random()).await;
+
+        assert_eq!(
+            execute(&mut tx, 0, 1).await,
+            ExecOutput::Unwind { unwind_to: 0 }
+        );
+        assert!(entries(&tx, &tables::TrieAccount).await.is_empty());
+
+        let hash = chain::canonical_hash::read(&tx, 1).await.unwrap().unwrap();
+        assert_eq!(chain::bad_header::read(&tx, hash).await.unwrap(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn skip_block_with_wrong_state_root() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let genesis = downloader::genesis();
+        downloader::write_genesis(&tx, &genesis).await;
+        let root = write_state(&tx).await;
+
+        // Block 2 changes nothing, yet its header claims another state root
+        let block1 = Header {
+            state_root: root,
+            ..downloader::child(&genesis, 10, 0)
+        };
+        let block2 = Header {
+            state_root: H256::random(),
+            ..downloader::child(&block1, 10, 0)
+        };
+
+        let headers = downloader::stage(vec![downloader::response(vec![
+            block1.clone(),
+            block2.clone(),
+        ])]);
+        assert_eq!(
+            headers
+                .execute(&mut tx, downloader::input(0))
+                .await
+                .unwrap(),
+            ExecOutput::Progress {
+                stage_progress: 2,
+                done: true,
+                must_commit: true,
+            }
+        );
+
+        assert_eq!(
+            execute(&mut tx, 0, 2).await,
+            ExecOutput::Unwind { unwind_to: 1 }
+        );
+        assert_eq!(
+            chain::bad_header::read(&tx, block2.hash()).await.unwrap(),
+            Some(2)
+        );
+        headers
+            .unwind(
+                &mut tx,
+                UnwindInput {
+                    stage_progress: 2,
+                    unwind_to: 1,
+                },
+            )
+            .await
+            .unwrap();
+
+        // Peers still serve the bad block, but the second run stops before it
+        let headers = downloader::stage(vec![downloader::response(vec![block2])]);
+        assert_eq!(
+            headers
+                .execute(&mut tx, downloader::input(1))
+                .await
+                .unwrap(),
+            ExecOutput::Progress {
+                stage_progress: 1,
+                done: true,
+                must_commit: false,
+            }
+        );
+        assert_eq!(chain::canonical_hash::read(&tx, 2).await.unwrap(), None);
+        assert_eq!(execute(&mut tx, 0, 1).await, processed(1));
+    }
+}
@@ -0,0 +1,397 @@
+use crate::{
+    bitmapdb, dbutils,
+    kv::tables,
+    models::Log,
+    stagedsync::{
+        stage::{ExecOutput, PruneInput, Stage, StageInput, UnwindInput},
+        stages::LOG_INDEX,
+    },
+    stages::prune::delete_blocks_before,
+    txdb, MutableTransaction, StageId, Transaction,
+};
+use arrayref::array_ref;
+use async_trait::async_trait;
+use std::collections::BTreeSet;
+use tokio::pin;
+use tokio_stream::StreamExt;
+use tracing::*;
+
+/// Reads logs of all transactions in blocks `from..=to`, returns them along with their block numbers.
+async fn read_logs<'db: 'tx, 'tx, Tx>(
+    tx: &'tx Tx,
+    from: u64,
+    to: u64,
+) -> anyhow::Result<Vec<(u64, Vec<Log>)>>
+where
+    Tx: Transaction<'db>,
+{
+    let mut out = vec![];
+
+    let start_key = dbutils::encode_block_number(from);
+    let mut cursor = tx.cursor(&tables::TransactionLog).await?;
+    let walker = txdb::walk(&mut cursor, &start_key, 0);
+    pin!(walker);
+    while let Some((k, v)) = walker.try_next().await? {
+        let block_number = u64::from_be_bytes(*array_ref!(k, 0, 8));
+        if block_number > to {
+            break;
+        }
+
+        out.push((block_number, rlp::Rlp::new(&v).as_list()?));
+    }
+
+    Ok(out)
+}
+
+/// Builds `LogAddressIndex` and `LogTopicIndex` of blocks in which each address and topic appear in logs.
+///
+/// Logs are written to `TransactionLog` along with receipts by the `Execution` stage. They are only emitted
+/// by contract execution though, so until an EVM is wired in there is nothing to index on a real chain.
+#[derive(Debug)]
+pub struct LogIndex;
+
+#[async_trait]
+impl<'db, RwTx> Stage<'db, RwTx> for LogIndex
+where
+    RwTx: MutableTransaction<'db>,
+{
+    fn id(&self) -> StageId {
+        LOG_INDEX
+    }
+
+    fn description(&self) -> &'static str {
+        "Generating log address and topic indexes"
+    }
+
+    async fn execute<'tx>(&self, tx: &'tx mut RwTx, input: StageInput) -> anyhow::Result<ExecOutput>
+    where
+        'db: 'tx,
+    {
+        let from_height = input.stage_progress.unwrap_or(0);
+        let max_height = if let Some((_, height)) = input.previous_stage {
+            height
+        } else {
+            0
+        };
+
+        if max_height > from_height {
+            let mut addresses = bitmapdb::IndexCollector::default();
+            let mut topics = bitmapdb::IndexCollector::default();
+            for (block_number, logs) in read_logs(tx, from_height + 1, max_height).await? {
+                for log in logs {
+                    addresses.insert(log.address.as_bytes(), block_number)?;
+                    for topic in log.topics {
+                        topics.insert(topic.as_bytes(), block_number)?;
+                    }
+                }
+            }
+            addresses.load(tx, &tables::LogAddressIndex).await?;
+            topics.load(tx, &tables::LogTopicIndex).await?;
+        }
+
+        let stage_progress = std::cmp::max(from_height, max_height);
+        info!(highest = stage_progress, "Processed");
+        Ok(ExecOutput::Progress {
+            stage_progress,
+            done: true,
+            must_commit: stage_progress > from_height,
+        })
+    }
+
+    async fn unwind<'tx>(&self, tx: &'tx mut RwTx, input: UnwindInput) -> anyhow::Result<()>
+    where
+        'db: 'tx,
+    {
+        let mut addresses = BTreeSet::new();
+        let mut topics = BTreeSet::new();
+        for (_, logs) in read_logs(tx, input.unwind_to + 1, input.stage_progress).await? {
+            for log in logs {
+                addresses.insert(log.address);
+                topics.extend(log.topics);
+            }
+        }
+
+        for address in addresses {
+            bitmapdb::truncate(
+                tx,
+                &tables::LogAddressIndex,
+                address.as_bytes(),
+                input.unwind_to + 1,
+            )
+            .await?;
+        }
+        for topic in topics {
+            bitmapdb::truncate(
+                tx,
+                &tables::LogTopicIndex,
+                topic.as_bytes(),
+                input.unwind_to + 1,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn prune<'tx>(&self, tx: &'tx mut RwTx, input: PruneInput) -> anyhow::Result<Option<u64>>
+    where
+        'db: 'tx,
+    {
+        let before = if let Some(before) = input.prune_before(input.settings.receipts) {
+            before
+        } else {
+            return Ok(None);
+        };
+
+        let mut addresses = BTreeSet::new();
+        let mut topics = BTreeSet::new();
+        for (_, logs) in read_logs(tx, input.prune_progress.unwrap_or(0), before - 1).await? {
+            for log in logs {
+                addresses.insert(log.address);
+                topics.extend(log.topics);
+            }
+        }
+
+        for address in addresses {
+            bitmapdb::prune(tx, &tables::LogAddressIndex, address.as_bytes(), before).await?;
+        }
+        for topic in topics {
+            bitmapdb::prune(tx, &tables::LogTopicIndex, topic.as_bytes(), before).await?;
+        }
+        delete_blocks_before(tx, &tables::Receipt, before).await?;
+        delete_blocks_before(tx, &tables::TransactionLog, before).await?;
+
+        info!(before, "Pruned");
+        Ok(Some(before))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        accessors::chain,
+        kv::traits::MutableKV,
+        models::Account,
+        new_mem_database,
+        stagedsync::test_util::unwind_stage,
+        stages::{execution::test_util as execution, Execution},
+    };
+    use ethereum_types::{Address, H256};
+    use roaring::RoaringTreemap;
+
+    fn log(address: Address, topics: &[H256]) -> Log {
+        Log {
+            address,
+            topics: topics.to_vec(),
+            data: vec![],
+        }
+    }
+
+    fn blocks(numbers: &[u64]) -> RoaringTreemap {
+        numbers.iter().copied().collect()
+    }
+
+    #[tokio::test]
+    async fn log_index() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let token = Address::random();
+        let exchange = Address::random();
+        let transfer = H256::random();
+        let approval = H256::random();
+        let alice = H256::random();
+        let bob = H256::random();
+
+        chain::log::write(&tx, 1, 0, &[log(token, &[transfer, alice, bob])])
+            .await
+            .unwrap();
+        chain::log::write(
+            &tx,
+            2,
+            3,
+            &[
+                log(token, &[approval, bob, alice]),
+                log(exchange, &[transfer, bob]),
+            ],
+        )
+        .await
+        .unwrap();
+        chain::log::write(&tx, 3, 1, &[log(exchange, &[approval])])
+            .await
+            .unwrap();
+        chain::log::write(&tx, 4, 0, &[log(token, &[transfer, alice])])
+            .await
+            .unwrap();
+
+        for (stage_progress, height) in [(None, 2), (Some(2), 4)] {
+            let output = LogIndex
+                .execute(
+                    &mut tx,
+                    StageInput {
+                        restarted: false,
+                        previous_stage: Some((StageId("Execution"), height)),
+                        stage_progress,
+                    },
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                output,
+                ExecOutput::Progress {
+                    stage_progress: height,
+                    done: true,
+                    must_commit: true,
+                }
+            );
+        }
+
+        for (addresses, topics, from, to, expected) in [
+            (vec![], vec![], 2, 3, blocks(&[2, 3])),
+            (vec![token], vec![], 0, 10, blocks(&[1, 2, 4])),
+            (vec![token, exchange], vec![], 3, 4, blocks(&[3, 4])),
+            (vec![], vec![vec![transfer]], 0, 10, blocks(&[1, 2, 4])),
+            (vec![exchange], vec![vec![transfer]], 0, 10, blocks(&[2])),
+            (
+                vec![],
+                vec![vec![approval], vec![alice, bob]],
+                0,
+                10,
+                blocks(&[2]),
+            ),
+            (vec![], vec![vec![], vec![alice]], 2, 10, blocks(&[2, 4])),
+            (vec![Address::random()], vec![], 0, 10, blocks(&[])),
+        ] {
+            assert_eq!(
+                chain::log_index::read_blocks(&tx, &addresses, &topics, from, to)
+                    .await
+                    .unwrap(),
+                expected
+            );
+        }
+
+        assert!(chain::log_index::read_blocks(
+            &tx,
+            &[],
+            &[vec![]],
+            0,
+            chain::log_index::MAX_UNFILTERED_BLOCKS
+        )
+        .await
+        .is_err());
+
+        LOG_INDEX.save_progress(&tx, 4).await.unwrap();
+        tx.commit().await.unwrap();
+
+        unwind_stage(&db, LogIndex, 2).await;
+
+        let tx = db.begin_mutable().await.unwrap();
+        assert_eq!(
+            chain::log_index::read_blocks(&tx, &[token, exchange], &[], 0, 10)
+                .await
+                .unwrap(),
+            blocks(&[1, 2])
+        );
+        assert_eq!(
+            chain::log_index::read_blocks(&tx, &[], &[vec![approval]], 0, 10)
+                .await
+                .unwrap(),
+            blocks(&[2])
+        );
+    }
+
+    #[tokio::test]
+    async fn index_execution_output() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let sender = Address::random();
+        let recipient = Address::random();
+        let topic = H256::random();
+
+        let (genesis_hash, _) = execution::write_genesis(&tx).await;
+        tx.set(
+            &tables::PlainState,
+            sender.as_bytes(),
+            &Account {
+                balance: u64::MAX.into(),
+                ..Default::default()
+            }
+            .encode_for_storage(),
+        )
+        .await
+        .unwrap();
+        let hash = execution::write_block(
+            &tx,
+            genesis_hash,
+            1,
+            Address::random(),
+            1,
+            &[execution::transfer(0, recipient, 1000)],
+            sender,
+        )
+        .await;
+        execution::write_block(
+            &tx,
+            hash,
+            2,
+            Address::random(),
+            2,
+            &[execution::transfer(1, recipient, 1000)],
+            sender,
+        )
+        .await;
+
+        Execution::default()
+            .execute(
+                &mut tx,
+                StageInput {
+                    restarted: false,
+                    previous_stage: Some((StageId("SenderRecovery"), 2)),
+                    stage_progress: None,
+                },
+            )
+            .await
+            .unwrap();
+
+        // Value transfers emit no logs, give the transaction of block 2 the ones of a contract call
+        let mut receipts = chain::receipt::read(&tx, 2).await.unwrap().unwrap();
+        assert_eq!(receipts[0].logs, vec![]);
+        receipts[0].logs.push(log(recipient, &[topic]));
+        chain::receipt::write(&tx, 2, &receipts).await.unwrap();
+
+        let output = LogIndex
+            .execute(
+                &mut tx,
+                StageInput {
+                    restarted: false,
+                    previous_stage: Some((StageId("Execution"), 2)),
+                    stage_progress: None,
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            output,
+            ExecOutput::Progress {
+                stage_progress: 2,
+                done: true,
+                must_commit: true,
+            }
+        );
+
+        assert_eq!(
+            chain::log_index::read_blocks(&tx, &[recipient], &[], 0, 10)
+                .await
+                .unwrap(),
+            blocks(&[2])
+        );
+        assert_eq!(
+            chain::log_index::read_blocks(&tx, &[], &[vec![topic]], 0, 10)
+                .await
+                .unwrap(),
+            blocks(&[2])
+        );
+    }
+}
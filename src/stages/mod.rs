@@ -1,7 +1,88 @@
 mod block_hashes;
+mod bodies;
+mod call_traces;
 mod downloader;
 mod execution;
+mod hashstate;
+mod history_index;
+mod intermediate_hashes;
+mod log_index;
+mod prune;
 mod sender_recovery;
+mod tx_lookup;
 
 pub use block_hashes::BlockHashes;
+pub use bodies::BlockBodies;
+pub use call_traces::CallTraces;
 pub use downloader::HeaderDownload;
+pub use execution::Execution;
+pub use hashstate::HashState;
+pub use history_index::{AccountHistoryIndex, StorageHistoryIndex};
+pub use intermediate_hashes::IntermediateHashes;
+pub use log_index::LogIndex;
+pub use sender_recovery::{BadBlockPolicy, SenderRecovery, SenderRecoveryError};
+pub use tx_lookup::TxLookup;
+
+use crate::{
+    downloader::sentry_client_reactor::SentryClientReactor,
+    kv::traits::MutableKV,
+    stagedsync::{
+        stages::{
+            ACCOUNT_HISTORY_INDEX, BODIES, CALL_TRACES, HASH_STATE, INTERMEDIATE_HASHES, LOG_INDEX,
+            STORAGE_HISTORY_INDEX, TX_LOOKUP,
+        },
+        StagedSyncBuilder,
+    },
+    StageId,
+};
+use std::sync::Arc;
+
+/// Stages which the given stage syncs after, including stages not enabled in `pipeline` yet.
+pub fn prerequisites(id: StageId) -> &'static [StageId] {
+    match id {
+        BODIES | StageId("BlockHashes") => &[StageId("HeaderDownload")],
+        StageId("SenderRecovery") | TX_LOOKUP => &[BODIES],
+        StageId("Execution") => &[StageId("SenderRecovery")],
+        HASH_STATE | ACCOUNT_HISTORY_INDEX | STORAGE_HISTORY_INDEX | LOG_INDEX | CALL_TRACES => {
+            &[StageId("Execution")]
+        }
+        INTERMEDIATE_HASHES => &[HASH_STATE],
+        _ => &[],
+    }
+}
+
+/// Stages run by the node, each registered with the stages it syncs after.
+pub fn pipeline<'db, DB: MutableKV>(
+    sentry: Arc<SentryClientReactor>,
+) -> StagedSyncBuilder<'db, DB> {
+    // Execution has no EVM yet and stops at the first contract transaction, so it stays out along with
+    // the stages relying on its output: HashState, IntermediateHashes, AccountHistoryIndex,
+    // StorageHistoryIndex, LogIndex and CallTraces.
+    // Not enabled yet either: BlockHashes, SenderRecovery and TxLookup
+    StagedSyncBuilder::new()
+        .add(
+            HeaderDownload::new(sentry.clone()),
+            prerequisites(StageId("HeaderDownload")),
+        )
+        .add(BlockBodies::new(sentry), prerequisites(BODIES))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downloader::sentry_client_mock::SentryClientMock;
+
+    #[test]
+    fn pipeline_without_execution() {
+        let sentry = SentryClientReactor::new(Box::new(SentryClientMock::with_responses(vec![])));
+
+        let staged_sync = pipeline::<crate::MdbxEnvironment<mdbx::NoWriteMap>>(Arc::new(sentry))
+            .build()
+            .unwrap();
+        let ids = staged_sync
+            .pipeline()
+            .map(|(id, _, _)| id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![StageId("HeaderDownload"), BODIES]);
+    }
+}
@@ -0,0 +1,23 @@
+use crate::{kv::Table, MutableCursor, MutableTransaction};
+use arrayref::array_ref;
+
+/// Deletes entries of blocks before `before` from a table keyed by block number.
+pub async fn delete_blocks_before<'db: 'tx, 'tx, RwTx, T>(
+    tx: &'tx RwTx,
+    table: &T,
+    before: u64,
+) -> anyhow::Result<()>
+where
+    RwTx: MutableTransaction<'db>,
+    T: Table,
+{
+    let mut cursor = tx.mutable_cursor(table).await?;
+    while let Some((k, _)) = cursor.first().await? {
+        if u64::from_be_bytes(*array_ref!(k, 0, 8)) >= before {
+            break;
+        }
+        cursor.delete_current().await?;
+    }
+
+    Ok(())
+}
@@ -1,14 +1,17 @@
 use crate::{
-    accessors::chain,
+    accessors::{chain, metadata},
+    kv::tables,
+    models::{BodyForStorage, ChainConfig},
     stagedsync::stage::{ExecOutput, Stage, StageInput, UnwindInput},
-    MutableTransaction, StageId,
+    MutableCursor, MutableTransaction, StageId,
 };
 use anyhow::Context;
 use async_trait::async_trait;
 use ethereum::{
     EIP1559TransactionMessage, EIP2930TransactionMessage, LegacyTransactionMessage, TransactionV2,
 };
-use ethereum_types::Address;
+use ethereum_types::{Address, H256};
+use hex_literal::hex;
 use secp256k1::{
     recovery::{RecoverableSignature, RecoveryId},
     Message, SECP256K1,
@@ -24,45 +27,168 @@ pub enum SenderRecoveryError {
     HashNotFound(u64),
     #[error("Block body for block {0} not found")]
     BlockBodyNotFound(u64),
+    #[error("Invalid signature of transaction {tx_index} in block {block_number}")]
+    InvalidSignature { block_number: u64, tx_index: usize },
+    #[error(
+        "Transaction {tx_index} in block {block_number} is signed for wrong chain id {chain_id}"
+    )]
+    WrongChainId {
+        block_number: u64,
+        tx_index: usize,
+        chain_id: u64,
+    },
+    #[error("Transaction {tx_index} in block {block_number} has high s value, which is invalid after Homestead")]
+    HighS { block_number: u64, tx_index: usize },
 }
 
-const BUFFER_SIZE: u64 = 5000;
+impl SenderRecoveryError {
+    /// Block containing the offending transaction, if the error is about one.
+    pub fn bad_block(&self) -> Option<u64> {
+        match *self {
+            Self::InvalidSignature { block_number, .. }
+            | Self::WrongChainId { block_number, .. }
+            | Self::HighS { block_number, .. } => Some(block_number),
+            _ => None,
+        }
+    }
+}
 
-fn recover_sender(tx: &TransactionV2) -> anyhow::Result<Address> {
-    let mut sig = [0u8; 64];
+/// What `SenderRecovery` does upon encountering a transaction with an invalid signature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BadBlockPolicy {
+    /// Fail the stage with the recovery error.
+    Fail,
+    /// Report the bad block and stop at the block before it without failing the sync.
+    Halt,
+    /// Report the bad block, mark it as bad and unwind to the block before it, so that another block can be downloaded in its place.
+    Unwind,
+}
 
-    let (r, s, v) = match tx {
+impl Default for BadBlockPolicy {
+    fn default() -> Self {
+        Self::Fail
+    }
+}
+
+const BUFFER_SIZE: u64 = 5000;
+/// Maximum number of transactions to recover in parallel before writing them out.
+const RECOVERY_BATCH_SIZE: usize = 50_000;
+
+const SECP256K1N: H256 = H256(hex!(
+    "fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364141"
+));
+const SECP256K1N_HALF: H256 = H256(hex!(
+    "7fffffffffffffffffffffffffffffff5d576e7357a4501ddfe92f46681b20a0"
+));
+
+fn recover_sender(
+    config: &ChainConfig,
+    block_number: u64,
+    tx_index: usize,
+    tx: &TransactionV2,
+) -> Result<Address, SenderRecoveryError> {
+    let invalid_signature = || SenderRecoveryError::InvalidSignature {
+        block_number,
+        tx_index,
+    };
+
+    let (r, s, v, chain_id) = match tx {
         TransactionV2::Legacy(tx) => (
             tx.signature.r(),
             tx.signature.s(),
             tx.signature.standard_v(),
+            tx.signature.chain_id(),
         ),
-        TransactionV2::EIP2930(tx) => (&tx.r, &tx.s, tx.odd_y_parity as u8),
-        TransactionV2::EIP1559(tx) => (&tx.r, &tx.s, tx.odd_y_parity as u8),
+        TransactionV2::EIP2930(tx) => (&tx.r, &tx.s, tx.odd_y_parity as u8, Some(tx.chain_id)),
+        TransactionV2::EIP1559(tx) => (&tx.r, &tx.s, tx.odd_y_parity as u8, Some(tx.chain_id)),
     };
 
+    if r.is_zero() || s.is_zero() || *r >= SECP256K1N || *s >= SECP256K1N {
+        return Err(invalid_signature());
+    }
+
+    // EIP-2: malleable signatures are only accepted before Homestead
+    if *s > SECP256K1N_HALF && config.is_homestead(block_number) {
+        return Err(SenderRecoveryError::HighS {
+            block_number,
+            tx_index,
+        });
+    }
+
+    // EIP-155: replay protected transactions must be signed for this chain, and only once it is activated
+    if let Some(chain_id) = chain_id {
+        let legacy = matches!(tx, TransactionV2::Legacy(_));
+        if (legacy && !config.is_eip155(block_number)) || config.chain_id() != Some(chain_id) {
+            return Err(SenderRecoveryError::WrongChainId {
+                block_number,
+                tx_index,
+                chain_id,
+            });
+        }
+    }
+
+    let mut sig = [0u8; 64];
     sig[..32].copy_from_slice(r.as_bytes());
     sig[32..].copy_from_slice(s.as_bytes());
 
-    let rec = RecoveryId::from_i32(v as i32)?;
+    let rec = RecoveryId::from_i32(v as i32).map_err(|_| invalid_signature())?;
 
-    let public = &SECP256K1.recover(
-        &Message::from_slice(
-            match tx {
-                TransactionV2::Legacy(tx) => LegacyTransactionMessage::from(tx.clone()).hash(),
-                TransactionV2::EIP2930(tx) => EIP2930TransactionMessage::from(tx.clone()).hash(),
-                TransactionV2::EIP1559(tx) => EIP1559TransactionMessage::from(tx.clone()).hash(),
-            }
-            .as_bytes(),
-        )?,
-        &RecoverableSignature::from_compact(&sig, rec)?,
-    )?;
+    let message = match tx {
+        TransactionV2::Legacy(tx) => LegacyTransactionMessage::from(tx.clone()).hash(),
+        TransactionV2::EIP2930(tx) => EIP2930TransactionMessage::from(tx.clone()).hash(),
+        TransactionV2::EIP1559(tx) => EIP1559TransactionMessage::from(tx.clone()).hash(),
+    };
+
+    let public = RecoverableSignature::from_compact(&sig, rec)
+        .and_then(|sig| SECP256K1.recover(&Message::from_slice(message.as_bytes())?, &sig))
+        .map_err(|_| invalid_signature())?;
 
     let address_slice = &Keccak256::digest(&public.serialize_uncompressed()[1..])[12..];
     Ok(Address::from_slice(address_slice))
 }
 
-async fn process_block<'db: 'tx, 'tx, RwTx>(tx: &'tx mut RwTx, height: u64) -> anyhow::Result<()>
+/// Recovers senders on a bounded pool of blocking threads, keeping the order of `txs`.
+/// Recovery stops at the first invalid transaction, which is returned alongside the senders preceding it.
+async fn recover_senders(
+    config: &ChainConfig,
+    txs: Vec<(u64, usize, TransactionV2)>,
+) -> anyhow::Result<(Vec<Address>, Option<SenderRecoveryError>)> {
+    let total = txs.len();
+    let chunk_size = cmp::max((total + num_cpus::get() - 1) / num_cpus::get(), 1);
+
+    let mut tasks = vec![];
+    let mut txs = txs.into_iter().peekable();
+    while txs.peek().is_some() {
+        let chunk = txs.by_ref().take(chunk_size).collect::<Vec<_>>();
+        let config = config.clone();
+        tasks.push(tokio::task::spawn_blocking(move || {
+            let mut senders = Vec::with_capacity(chunk.len());
+            for (block_number, tx_index, tx) in &chunk {
+                match recover_sender(&config, *block_number, *tx_index, tx) {
+                    Ok(sender) => senders.push(sender),
+                    Err(e) => return (senders, Some(e)),
+                }
+            }
+            (senders, None)
+        }));
+    }
+
+    let mut senders = Vec::with_capacity(total);
+    for task in tasks {
+        let (chunk_senders, error) = task.await?;
+        senders.extend(chunk_senders);
+        if error.is_some() {
+            return Ok((senders, error));
+        }
+    }
+
+    Ok((senders, None))
+}
+
+async fn read_block<'db: 'tx, 'tx, RwTx>(
+    tx: &'tx RwTx,
+    height: u64,
+) -> anyhow::Result<(BodyForStorage, Vec<TransactionV2>)>
 where
     RwTx: MutableTransaction<'db>,
 {
@@ -74,16 +200,19 @@ where
         .ok_or(SenderRecoveryError::BlockBodyNotFound(height))?;
     let txs = chain::tx::read(tx, body.base_tx_id, body.tx_amount).await?;
 
-    let mut senders = vec![];
-    for tx in &txs {
-        senders.push(recover_sender(tx)?);
-    }
+    Ok((body, txs))
+}
 
-    chain::tx_sender::write(tx, body.base_tx_id, &senders).await
+#[derive(Debug, Default)]
+pub struct SenderRecovery {
+    pub bad_block_policy: BadBlockPolicy,
 }
 
-#[derive(Debug)]
-pub struct SenderRecovery;
+impl SenderRecovery {
+    pub fn new(bad_block_policy: BadBlockPolicy) -> Self {
+        Self { bad_block_policy }
+    }
+}
 
 #[async_trait]
 impl<'db, RwTx> Stage<'db, RwTx> for SenderRecovery
@@ -102,6 +231,8 @@ where
     where
         'db: 'tx,
     {
+        let config = metadata::read_genesis_chain_config(tx).await?;
+
         let from_height = input.stage_progress.unwrap_or(0);
         let max_height = if let Some((_, height)) = input.previous_stage {
             height
@@ -112,10 +243,64 @@ where
 
         let mut height = from_height;
         while height < to_height {
-            process_block(tx, height + 1)
-                .await
-                .with_context(|| format!("Failed to recover senders for block {}", height + 1))?;
-            height += 1;
+            let mut bodies = vec![];
+            let mut txs = vec![];
+            let mut read_height = height;
+            while read_height < to_height && txs.len() < RECOVERY_BATCH_SIZE {
+                read_height += 1;
+                let (body, block_txs) = read_block(tx, read_height).await.with_context(|| {
+                    format!("Failed to recover senders for block {}", read_height)
+                })?;
+                txs.extend(
+                    block_txs
+                        .into_iter()
+                        .enumerate()
+                        .map(|(tx_index, tx)| (read_height, tx_index, tx)),
+                );
+                bodies.push(body);
+            }
+
+            let (senders, bad_tx) = recover_senders(&config, txs).await?;
+
+            // Bodies are in block order, so senders are written out in ascending tx id order.
+            // Senders stop short at the bad block, if any, so it is never written.
+            let mut offset = 0;
+            for body in bodies {
+                let amount = body.tx_amount as usize;
+                if offset + amount > senders.len() {
+                    break;
+                }
+                chain::tx_sender::write(tx, body.base_tx_id, &senders[offset..offset + amount])
+                    .await?;
+                offset += amount;
+                height += 1;
+            }
+
+            if let Some(e) = bad_tx {
+                let bad_block = e.bad_block().unwrap_or(height + 1);
+                return match self.bad_block_policy {
+                    BadBlockPolicy::Fail => Err(e.into()),
+                    BadBlockPolicy::Halt => {
+                        error!("Bad block {}: {}", bad_block, e);
+                        Ok(ExecOutput::Progress {
+                            stage_progress: height,
+                            done: true,
+                            must_commit: height > from_height,
+                        })
+                    }
+                    BadBlockPolicy::Unwind => {
+                        error!("Bad block {}, unwinding: {}", bad_block, e);
+                        // Otherwise the same block is downloaded and unwound over and over again
+                        let hash = chain::canonical_hash::read(tx, bad_block)
+                            .await?
+                            .ok_or(SenderRecoveryError::HashNotFound(bad_block))?;
+                        chain::bad_header::write(tx, hash, bad_block).await?;
+                        Ok(ExecOutput::Unwind {
+                            unwind_to: bad_block - 1,
+                        })
+                    }
+                };
+            }
         }
 
         let made_progress = height > from_height;
@@ -126,21 +311,59 @@ where
         })
     }
 
-    async fn unwind<'tx>(&self, _tx: &'tx mut RwTx, _input: UnwindInput) -> anyhow::Result<()>
+    async fn unwind<'tx>(&self, tx: &'tx mut RwTx, input: UnwindInput) -> anyhow::Result<()>
     where
         'db: 'tx,
     {
-        todo!()
+        let mut senders_cursor = tx.mutable_cursor(&tables::TxSender).await?;
+
+        for height in input.unwind_to + 1..=input.stage_progress {
+            let hash = chain::canonical_hash::read(tx, height)
+                .await?
+                .ok_or(SenderRecoveryError::HashNotFound(height))?;
+            let body = chain::storage_body::read(tx, hash, height)
+                .await?
+                .ok_or(SenderRecoveryError::BlockBodyNotFound(height))?;
+
+            for tx_id in body.base_tx_id..body.base_tx_id + body.tx_amount as u64 {
+                senders_cursor.delete(&tx_id.to_be_bytes(), &[]).await?;
+            }
+        }
+
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{kv::traits::MutableKV, models::BodyForStorage, new_mem_database};
+    use crate::{
+        kv::traits::MutableKV, new_mem_database, stagedsync::test_util::unwind_stage, Transaction,
+    };
     use ethereum::{TransactionAction, TransactionSignature};
-    use ethereum_types::{H160, H256};
-    use hex_literal::hex;
+    use ethereum_types::{H160, U256};
+    use secp256k1::{PublicKey, SecretKey};
+
+    fn chain_config(homestead_block: u64, eip155_block: u64) -> ChainConfig {
+        serde_json::from_str(&format!(
+            r#"{{"chainId":1,"homesteadBlock":{},"daoForkSupport":false,"eip155Block":{}}}"#,
+            homestead_block, eip155_block
+        ))
+        .unwrap()
+    }
+
+    async fn write_chain_config<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(
+        tx: &'tx RwTx,
+        config: &ChainConfig,
+    ) {
+        let genesis_hash = H256::random();
+        chain::canonical_hash::write(tx, 0, genesis_hash)
+            .await
+            .unwrap();
+        metadata::write_chain_config(tx, genesis_hash, config)
+            .await
+            .unwrap();
+    }
 
     #[tokio::test]
     async fn recover_senders() {
@@ -291,7 +514,9 @@ mod tests {
             .await
             .unwrap();
 
-        let stage = SenderRecovery {};
+        write_chain_config(&tx, &chain_config(0, 0)).await;
+
+        let stage = SenderRecovery::default();
 
         let stage_input = StageInput {
             restarted: false,
@@ -319,4 +544,352 @@ mod tests {
         let senders3 = chain::tx_sender::read(&tx, block3.base_tx_id, block3.tx_amount);
         assert!(senders3.await.unwrap().is_empty());
     }
+
+    fn signed_transfer(secret_key: &SecretKey, nonce: u64, chain_id: Option<u64>) -> TransactionV2 {
+        let v_base = chain_id.map(|chain_id| 35 + chain_id * 2).unwrap_or(27);
+        let mut tx = ethereum::LegacyTransaction {
+            nonce: nonce.into(),
+            gas_limit: 21_000.into(),
+            gas_price: 1_000_000.into(),
+            action: TransactionAction::Call(H160::random()),
+            value: nonce.into(),
+            input: vec![],
+            signature: TransactionSignature::new(
+                v_base,
+                H256::repeat_byte(1),
+                H256::repeat_byte(1),
+            )
+            .unwrap(),
+        };
+
+        let hash = LegacyTransactionMessage::from(tx.clone()).hash();
+        let (rec, sig) = SECP256K1
+            .sign_recoverable(&Message::from_slice(hash.as_bytes()).unwrap(), secret_key)
+            .serialize_compact();
+        tx.signature = TransactionSignature::new(
+            v_base + rec.to_i32() as u64,
+            H256::from_slice(&sig[..32]),
+            H256::from_slice(&sig[32..]),
+        )
+        .unwrap();
+
+        TransactionV2::Legacy(tx)
+    }
+
+    #[tokio::test]
+    async fn recover_senders_in_parallel() {
+        const BLOCKS: u64 = 100;
+        const TXS_PER_BLOCK: u32 = 40;
+
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let config = chain_config(0, 0);
+        write_chain_config(&tx, &config).await;
+
+        let keys = (0..TXS_PER_BLOCK)
+            .map(|_| SecretKey::from_slice(&rand::random::<[u8; 32]>()).unwrap())
+            .collect::<Vec<_>>();
+        let addresses = keys
+            .iter()
+            .map(|key| {
+                let public = PublicKey::from_secret_key(SECP256K1, key);
+                H160::from_slice(&Keccak256::digest(&public.serialize_uncompressed()[1..])[12..])
+            })
+            .collect::<Vec<_>>();
+
+        let mut all_txs = vec![];
+        for block_number in 1..=BLOCKS {
+            let body = BodyForStorage {
+                base_tx_id: (block_number - 1) * TXS_PER_BLOCK as u64,
+                tx_amount: TXS_PER_BLOCK,
+                uncles: vec![],
+            };
+            let txs = keys
+                .iter()
+                .map(|key| signed_transfer(key, block_number, Some(1)))
+                .collect::<Vec<_>>();
+
+            let hash = H256::random();
+            chain::canonical_hash::write(&tx, block_number, hash)
+                .await
+                .unwrap();
+            chain::storage_body::write(&tx, hash, block_number, &body)
+                .await
+                .unwrap();
+            chain::tx::write(&tx, body.base_tx_id, &txs).await.unwrap();
+
+            all_txs.extend(
+                txs.into_iter()
+                    .enumerate()
+                    .map(|(tx_index, tx)| (block_number, tx_index, tx)),
+            );
+        }
+
+        let output = SenderRecovery::default()
+            .execute(
+                &mut tx,
+                StageInput {
+                    restarted: false,
+                    previous_stage: Some((StageId("BodyDownload"), BLOCKS)),
+                    stage_progress: Some(0),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            output,
+            ExecOutput::Progress {
+                stage_progress: BLOCKS,
+                done: false,
+                must_commit: true,
+            }
+        );
+
+        let senders = chain::tx_sender::read(&tx, 0, BLOCKS as u32 * TXS_PER_BLOCK)
+            .await
+            .unwrap();
+        assert_eq!(senders.len(), all_txs.len());
+        for (i, sender) in senders.iter().enumerate() {
+            assert_eq!(*sender, addresses[i % TXS_PER_BLOCK as usize]);
+        }
+
+        let sequential = all_txs
+            .iter()
+            .map(|(block_number, tx_index, tx)| {
+                recover_sender(&config, *block_number, *tx_index, tx).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let (parallel, bad_tx) = super::recover_senders(&config, all_txs).await.unwrap();
+        assert!(bad_tx.is_none());
+        assert_eq!(sequential, parallel);
+    }
+
+    #[test]
+    fn reject_invalid_signatures() {
+        let key = SecretKey::from_slice(&rand::random::<[u8; 32]>()).unwrap();
+        let public = PublicKey::from_secret_key(SECP256K1, &key);
+        let sender =
+            H160::from_slice(&Keccak256::digest(&public.serialize_uncompressed()[1..])[12..]);
+
+        let config = chain_config(5, 10);
+
+        // Replay protection is only valid after EIP-155, and only for our chain
+        let protected = signed_transfer(&key, 0, Some(1));
+        assert_eq!(recover_sender(&config, 10, 0, &protected).unwrap(), sender);
+        assert!(matches!(
+            recover_sender(&config, 9, 3, &protected),
+            Err(SenderRecoveryError::WrongChainId {
+                block_number: 9,
+                tx_index: 3,
+                chain_id: 1
+            })
+        ));
+        assert!(matches!(
+            recover_sender(&config, 10, 0, &signed_transfer(&key, 0, Some(5))),
+            Err(SenderRecoveryError::WrongChainId { chain_id: 5, .. })
+        ));
+
+        // Malleable counterpart of a valid signature recovers to the same sender, but only before Homestead
+        let malleable = match signed_transfer(&key, 0, None) {
+            TransactionV2::Legacy(mut tx) => {
+                let s = U256::from_big_endian(SECP256K1N.as_bytes())
+                    - U256::from_big_endian(tx.signature.s().as_bytes());
+                let mut s_bytes = [0; 32];
+                s.to_big_endian(&mut s_bytes);
+                tx.signature = TransactionSignature::new(
+                    27 + (1 - tx.signature.standard_v() as u64),
+                    *tx.signature.r(),
+                    H256(s_bytes),
+                )
+                .unwrap();
+                TransactionV2::Legacy(tx)
+            }
+            _ => unreachable!(),
+        };
+        assert_eq!(recover_sender(&config, 4, 0, &malleable).unwrap(), sender);
+        assert!(matches!(
+            recover_sender(&config, 5, 1, &malleable),
+            Err(SenderRecoveryError::HighS {
+                block_number: 5,
+                tx_index: 1
+            })
+        ));
+
+        let zero_r = TransactionV2::EIP2930(ethereum::EIP2930Transaction {
+            chain_id: 1,
+            nonce: 0.into(),
+            gas_price: 1_000_000.into(),
+            gas_limit: 21_000.into(),
+            action: TransactionAction::Call(H160::random()),
+            value: 0.into(),
+            input: vec![],
+            access_list: vec![],
+            odd_y_parity: false,
+            r: H256::zero(),
+            s: H256::repeat_byte(1),
+        });
+        assert!(matches!(
+            recover_sender(&config, 10, 2, &zero_r),
+            Err(SenderRecoveryError::InvalidSignature {
+                block_number: 10,
+                tx_index: 2
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn bad_block_policies() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        write_chain_config(&tx, &chain_config(0, 0)).await;
+
+        let key = SecretKey::from_slice(&rand::random::<[u8; 32]>()).unwrap();
+        let blocks = [
+            vec![signed_transfer(&key, 0, Some(1))],
+            vec![
+                signed_transfer(&key, 1, Some(1)),
+                signed_transfer(&key, 2, Some(5)),
+            ],
+            vec![signed_transfer(&key, 3, Some(1))],
+        ];
+        let mut base_tx_id = 0;
+        let mut hashes = vec![];
+        for (i, txs) in blocks.iter().enumerate() {
+            let block_number = i as u64 + 1;
+            let body = BodyForStorage {
+                base_tx_id,
+                tx_amount: txs.len() as u32,
+                uncles: vec![],
+            };
+            let hash = H256::random();
+            chain::canonical_hash::write(&tx, block_number, hash)
+                .await
+                .unwrap();
+            chain::storage_body::write(&tx, hash, block_number, &body)
+                .await
+                .unwrap();
+            chain::tx::write(&tx, base_tx_id, txs).await.unwrap();
+            base_tx_id += txs.len() as u64;
+            hashes.push(hash);
+        }
+
+        let input = StageInput {
+            restarted: false,
+            previous_stage: Some((StageId("BodyDownload"), 3)),
+            stage_progress: Some(0),
+        };
+
+        let e = SenderRecovery::new(BadBlockPolicy::Fail)
+            .execute(&mut tx, input)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<SenderRecoveryError>(),
+            Some(SenderRecoveryError::WrongChainId {
+                block_number: 2,
+                tx_index: 1,
+                chain_id: 5
+            })
+        ));
+
+        assert_eq!(
+            SenderRecovery::new(BadBlockPolicy::Unwind)
+                .execute(&mut tx, input)
+                .await
+                .unwrap(),
+            ExecOutput::Unwind { unwind_to: 1 }
+        );
+        assert_eq!(
+            chain::bad_header::read(&tx, hashes[1]).await.unwrap(),
+            Some(2)
+        );
+        assert_eq!(chain::bad_header::read(&tx, hashes[0]).await.unwrap(), None);
+
+        assert_eq!(
+            SenderRecovery::new(BadBlockPolicy::Halt)
+                .execute(&mut tx, input)
+                .await
+                .unwrap(),
+            ExecOutput::Progress {
+                stage_progress: 1,
+                done: true,
+                must_commit: true,
+            }
+        );
+        assert_eq!(chain::tx_sender::read(&tx, 0, 1).await.unwrap().len(), 1);
+        for tx_id in 1..4_u64 {
+            assert!(tx
+                .get(&tables::TxSender, &tx_id.to_be_bytes())
+                .await
+                .unwrap()
+                .is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn unwind_senders() {
+        let db = new_mem_database().unwrap();
+
+        let bodies = [
+            BodyForStorage {
+                base_tx_id: 0,
+                tx_amount: 2,
+                uncles: vec![],
+            },
+            BodyForStorage {
+                base_tx_id: 2,
+                tx_amount: 1,
+                uncles: vec![],
+            },
+            BodyForStorage {
+                base_tx_id: 3,
+                tx_amount: 2,
+                uncles: vec![],
+            },
+        ];
+
+        let tx = db.begin_mutable().await.unwrap();
+        for (i, body) in bodies.iter().enumerate() {
+            let block_number = i as u64 + 1;
+            let hash = H256::random();
+            chain::canonical_hash::write(&tx, block_number, hash)
+                .await
+                .unwrap();
+            chain::storage_body::write(&tx, hash, block_number, body)
+                .await
+                .unwrap();
+            chain::tx_sender::write(
+                &tx,
+                body.base_tx_id,
+                &vec![H160::random(); body.tx_amount as usize],
+            )
+            .await
+            .unwrap();
+        }
+        StageId("SenderRecovery")
+            .save_progress(&tx, 3)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        unwind_stage(&db, SenderRecovery::default(), 1).await;
+
+        let tx = db.begin_mutable().await.unwrap();
+        assert_eq!(
+            StageId("SenderRecovery").get_progress(&tx).await.unwrap(),
+            Some(1)
+        );
+        for tx_id in 0..5_u64 {
+            assert_eq!(
+                tx.get(&tables::TxSender, &tx_id.to_be_bytes())
+                    .await
+                    .unwrap()
+                    .is_some(),
+                tx_id < 2
+            );
+        }
+    }
 }
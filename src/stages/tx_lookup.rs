@@ -0,0 +1,293 @@
+use crate::{
+    accessors::chain,
+    common, dbutils,
+    etl::{
+        collector::{Collector, OPTIMAL_BUFFER_CAPACITY},
+        data_provider::Entry,
+    },
+    kv::tables,
+    stagedsync::{
+        stage::{ExecOutput, Stage, StageInput, UnwindInput},
+        stages::TX_LOOKUP,
+    },
+    MutableCursor, MutableTransaction, StageId, Transaction,
+};
+use async_trait::async_trait;
+use ethereum::{EnvelopedEncodable, TransactionV2};
+use thiserror::Error;
+use tracing::*;
+
+#[derive(Error, Debug)]
+pub enum TxLookupError {
+    #[error("Canonical hash for block {0} not found")]
+    HashNotFound(u64),
+    #[error("Block body for block {0} not found")]
+    BlockBodyNotFound(u64),
+}
+
+async fn read_transactions<'db: 'tx, 'tx, Tx: Transaction<'db>>(
+    tx: &'tx Tx,
+    height: u64,
+) -> anyhow::Result<Vec<TransactionV2>> {
+    let hash = chain::canonical_hash::read(tx, height)
+        .await?
+        .ok_or(TxLookupError::HashNotFound(height))?;
+    let body = chain::storage_body::read(tx, hash, height)
+        .await?
+        .ok_or(TxLookupError::BlockBodyNotFound(height))?;
+
+    chain::tx::read(tx, body.base_tx_id, body.tx_amount).await
+}
+
+/// Maps hashes of canonical transactions to numbers of their blocks.
+#[derive(Debug)]
+pub struct TxLookup;
+
+#[async_trait]
+impl<'db, RwTx> Stage<'db, RwTx> for TxLookup
+where
+    RwTx: MutableTransaction<'db>,
+{
+    fn id(&self) -> StageId {
+        TX_LOOKUP
+    }
+
+    fn description(&self) -> &'static str {
+        "Generating transaction hash => block number mapping"
+    }
+
+    async fn execute<'tx>(&self, tx: &'tx mut RwTx, input: StageInput) -> anyhow::Result<ExecOutput>
+    where
+        'db: 'tx,
+    {
+        let from_height = input.stage_progress.unwrap_or(0);
+        let max_height = if let Some((_, height)) = input.previous_stage {
+            height
+        } else {
+            0
+        };
+
+        let mut collector = Collector::new(OPTIMAL_BUFFER_CAPACITY);
+        for height in from_height + 1..=max_height {
+            let block_key = dbutils::encode_block_number(height);
+            for eth_tx in read_transactions(tx, height).await? {
+                collector.collect(Entry {
+                    key: common::hash_data(&EnvelopedEncodable::encode(&eth_tx)).to_vec(),
+                    value: block_key.to_vec(),
+                    id: 0, // Irrelevant here, could be anything
+                });
+            }
+        }
+        collector
+            .load(
+                &mut tx.mutable_cursor(&tables::BlockTransactionLookup).await?,
+                None,
+            )
+            .await?;
+
+        let stage_progress = std::cmp::max(from_height, max_height);
+        info!(highest = stage_progress, "Processed");
+        Ok(ExecOutput::Progress {
+            stage_progress,
+            done: true,
+            must_commit: stage_progress > from_height,
+        })
+    }
+
+    async fn unwind<'tx>(&self, tx: &'tx mut RwTx, input: UnwindInput) -> anyhow::Result<()>
+    where
+        'db: 'tx,
+    {
+        let mut lookup_cursor = tx.mutable_cursor(&tables::BlockTransactionLookup).await?;
+
+        for height in input.unwind_to + 1..=input.stage_progress {
+            for eth_tx in read_transactions(tx, height).await? {
+                let hash = common::hash_data(&EnvelopedEncodable::encode(&eth_tx));
+                lookup_cursor.delete(hash.as_bytes(), &[]).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        kv::traits::MutableKV, models::BodyForStorage, new_mem_database,
+        stagedsync::test_util::unwind_stage,
+    };
+    use ethereum::{TransactionAction, TransactionSignature};
+    use ethereum_types::{H160, H256};
+
+    fn transfer(nonce: u64) -> TransactionV2 {
+        TransactionV2::Legacy(ethereum::LegacyTransaction {
+            nonce: nonce.into(),
+            gas_price: 1_000_000.into(),
+            gas_limit: 21_000.into(),
+            action: TransactionAction::Call(H160::random()),
+            value: nonce.into(),
+            input: vec![],
+            signature: TransactionSignature::new(27, H256::repeat_byte(2), H256::repeat_byte(3))
+                .unwrap(),
+        })
+    }
+
+    fn hash(eth_tx: &TransactionV2) -> H256 {
+        common::hash_data(&EnvelopedEncodable::encode(eth_tx))
+    }
+
+    /// Writes canonical blocks 1..=`blocks.len()` with given transactions.
+    async fn write_blocks<'db: 'tx, 'tx, RwTx: MutableTransaction<'db>>(
+        tx: &'tx RwTx,
+        blocks: &[Vec<TransactionV2>],
+    ) {
+        let mut base_tx_id = 0;
+        for (i, txs) in blocks.iter().enumerate() {
+            let block_number = i as u64 + 1;
+            let block_hash = H256::random();
+            chain::canonical_hash::write(tx, block_number, block_hash)
+                .await
+                .unwrap();
+            chain::storage_body::write(
+                tx,
+                block_hash,
+                block_number,
+                &BodyForStorage {
+                    base_tx_id,
+                    tx_amount: txs.len() as u32,
+                    uncles: vec![],
+                },
+            )
+            .await
+            .unwrap();
+            chain::tx::write(tx, base_tx_id, txs).await.unwrap();
+            base_tx_id += txs.len() as u64;
+        }
+    }
+
+    #[tokio::test]
+    async fn lookup_transactions() {
+        let db = new_mem_database().unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
+
+        let blocks = vec![
+            vec![transfer(0), transfer(1)],
+            vec![],
+            vec![transfer(2)],
+            vec![transfer(3), transfer(4), transfer(5)],
+        ];
+        write_blocks(&tx, &blocks).await;
+
+        let output = TxLookup
+            .execute(
+                &mut tx,
+                StageInput {
+                    restarted: false,
+                    previous_stage: Some((StageId("SenderRecovery"), 3)),
+                    stage_progress: None,
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            output,
+            ExecOutput::Progress {
+                stage_progress: 3,
+                done: true,
+                must_commit: true,
+            }
+        );
+        for eth_tx in &blocks[3] {
+            assert_eq!(
+                chain::tx_lookup::read(&tx, hash(eth_tx)).await.unwrap(),
+                None
+            );
+        }
+
+        let output = TxLookup
+            .execute(
+                &mut tx,
+                StageInput {
+                    restarted: false,
+                    previous_stage: Some((StageId("SenderRecovery"), 4)),
+                    stage_progress: Some(3),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            output,
+            ExecOutput::Progress {
+                stage_progress: 4,
+                done: true,
+                must_commit: true,
+            }
+        );
+
+        for (i, txs) in blocks.iter().enumerate() {
+            for (index, eth_tx) in txs.iter().enumerate() {
+                let block_number = i as u64 + 1;
+                assert_eq!(
+                    chain::tx_lookup::read(&tx, hash(eth_tx)).await.unwrap(),
+                    Some(block_number)
+                );
+                assert_eq!(
+                    chain::tx_lookup::read_transaction(&tx, hash(eth_tx))
+                        .await
+                        .unwrap(),
+                    Some((block_number, index, eth_tx.clone()))
+                );
+            }
+        }
+        assert_eq!(
+            chain::tx_lookup::read_transaction(&tx, H256::random())
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn unwind_tx_lookup() {
+        let db = new_mem_database().unwrap();
+
+        let blocks = vec![
+            vec![transfer(0)],
+            vec![transfer(1), transfer(2)],
+            vec![transfer(3)],
+        ];
+
+        let mut tx = db.begin_mutable().await.unwrap();
+        write_blocks(&tx, &blocks).await;
+        TxLookup
+            .execute(
+                &mut tx,
+                StageInput {
+                    restarted: false,
+                    previous_stage: Some((StageId("SenderRecovery"), 3)),
+                    stage_progress: Some(0),
+                },
+            )
+            .await
+            .unwrap();
+        TX_LOOKUP.save_progress(&tx, 3).await.unwrap();
+        tx.commit().await.unwrap();
+
+        unwind_stage(&db, TxLookup, 1).await;
+
+        let tx = db.begin_mutable().await.unwrap();
+        assert_eq!(TX_LOOKUP.get_progress(&tx).await.unwrap(), Some(1));
+        for (i, txs) in blocks.iter().enumerate() {
+            let block_number = i as u64 + 1;
+            for eth_tx in txs {
+                let expected = (block_number <= 1).then(|| block_number);
+                assert_eq!(
+                    chain::tx_lookup::read(&tx, hash(eth_tx)).await.unwrap(),
+                    expected
+                );
+            }
+        }
+    }
+}
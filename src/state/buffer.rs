@@ -0,0 +1,452 @@
+use super::database::*;
+use crate::{
+    common,
+    dbutils::{self, PlainCompositeStorageKey, PlainStoragePrefix},
+    kv::tables,
+    models::Account,
+    MutableCursor, MutableTransaction,
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::collections::BTreeMap;
+
+/// Write-back buffer of state changes between the executor and the database.
+///
+/// Changes of many blocks are kept in sorted maps and written out in key order by `flush`, reads see buffered values.
+pub struct Buffer<'db: 'tx, 'tx, Tx: MutableTransaction<'db>> {
+    tx: &'tx Tx,
+    reader: PlainStateReader<'db, 'tx, Tx>,
+    accounts: BTreeMap<common::Address, Option<Account>>,
+    storage: BTreeMap<PlainCompositeStorageKey, common::Value>,
+    code: BTreeMap<common::Hash, Bytes<'static>>,
+    code_hashes: BTreeMap<PlainStoragePrefix, common::Hash>,
+    incarnations: BTreeMap<common::Address, common::Incarnation>,
+    changesets: Vec<ChangeSetWriter<'db, 'tx, Tx>>,
+    size: usize,
+}
+
+impl<'db: 'tx, 'tx, Tx: MutableTransaction<'db>> Buffer<'db, 'tx, Tx> {
+    pub fn new(tx: &'tx Tx) -> Self {
+        Self::with_code_cache(tx, CodeCache::default())
+    }
+
+    pub fn with_code_cache(tx: &'tx Tx, code_cache: CodeCache) -> Self {
+        Self {
+            tx,
+            reader: PlainStateReader::with_code_cache(tx, code_cache),
+            accounts: Default::default(),
+            storage: Default::default(),
+            code: Default::default(),
+            code_hashes: Default::default(),
+            incarnations: Default::default(),
+            changesets: Default::default(),
+            size: 0,
+        }
+    }
+
+    pub fn tx(&self) -> &'tx Tx {
+        self.tx
+    }
+
+    /// Approximate amount of memory taken by buffered changes, in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Starts collecting changesets of the next block, blocks must be buffered in ascending order.
+    pub fn begin_block(&mut self, block_number: u64) {
+        self.changesets
+            .push(ChangeSetWriter::new(self.tx, block_number));
+    }
+
+    fn changeset(&mut self) -> anyhow::Result<&mut ChangeSetWriter<'db, 'tx, Tx>> {
+        self.changesets
+            .last_mut()
+            .context("state change outside of block")
+    }
+
+    /// Writes out and clears all buffered changes.
+    pub async fn flush(&mut self) -> anyhow::Result<()> {
+        // Accounts and storage share the table, key of an account precedes keys of its storage
+        let mut cursor = self.tx.mutable_cursor(&tables::PlainState).await?;
+        let mut accounts = std::mem::take(&mut self.accounts).into_iter().peekable();
+        let mut storage = std::mem::take(&mut self.storage).into_iter().peekable();
+        loop {
+            let account_first = match (accounts.peek(), storage.peek()) {
+                (Some((address, _)), Some((key, _))) => address.as_bytes() <= &key[..],
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+
+            if account_first {
+                let (address, account) = accounts.next().unwrap();
+                match account {
+                    Some(account) => {
+                        cursor
+                            .put(address.as_bytes(), &account.encode_for_storage())
+                            .await?
+                    }
+                    None => cursor.delete(address.as_bytes(), &[]).await?,
+                }
+            } else {
+                let (key, value) = storage.next().unwrap();
+                if value.is_zero() {
+                    cursor.delete(&key, &[]).await?;
+                } else {
+                    cursor.put(&key, &common::value_to_bytes(value)).await?;
+                }
+            }
+        }
+
+        let mut cursor = self.tx.mutable_cursor(&tables::Code).await?;
+        for (code_hash, code) in std::mem::take(&mut self.code) {
+            cursor.put(code_hash.as_bytes(), &code).await?;
+        }
+
+        let mut cursor = self.tx.mutable_cursor(&tables::PlainCodeHash).await?;
+        for (prefix, code_hash) in std::mem::take(&mut self.code_hashes) {
+            cursor.put(&prefix, code_hash.as_bytes()).await?;
+        }
+
+        let mut cursor = self.tx.mutable_cursor(&tables::IncarnationMap).await?;
+        for (address, incarnation) in std::mem::take(&mut self.incarnations) {
+            cursor
+                .put(address.as_bytes(), &incarnation.to_be_bytes())
+                .await?;
+        }
+
+        // Changesets are appended, so they must go block after block
+        for mut changeset in std::mem::take(&mut self.changesets) {
+            changeset.write_changesets().await?;
+        }
+
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<'db: 'tx, 'tx, Tx: MutableTransaction<'db>> StateReader<'tx> for Buffer<'db, 'tx, Tx> {
+    async fn read_account_data(&self, address: common::Address) -> anyhow::Result<Option<Account>> {
+        if let Some(account) = self.accounts.get(&address) {
+            return Ok(account.clone());
+        }
+
+        self.reader.read_account_data(address).await
+    }
+
+    async fn read_account_storage(
+        &self,
+        address: common::Address,
+        incarnation: common::Incarnation,
+        key: common::Hash,
+    ) -> anyhow::Result<Option<Bytes<'tx>>> {
+        let composite_key =
+            dbutils::plain_generate_composite_storage_key(address, incarnation, key);
+        if let Some(value) = self.storage.get(&composite_key) {
+            return Ok((!value.is_zero()).then(|| common::value_to_bytes(*value).to_vec().into()));
+        }
+
+        self.reader
+            .read_account_storage(address, incarnation, key)
+            .await
+    }
+
+    async fn read_account_code(
+        &self,
+        address: common::Address,
+        incarnation: common::Incarnation,
+        code_hash: common::Hash,
+    ) -> anyhow::Result<Option<Bytes<'tx>>> {
+        let code_hash = if code_hash.is_zero() {
+            match self
+                .code_hashes
+                .get(&dbutils::plain_generate_storage_prefix(
+                    address,
+                    incarnation,
+                )) {
+                Some(code_hash) => *code_hash,
+                None => code_hash,
+            }
+        } else {
+            code_hash
+        };
+
+        if let Some(code) = self.code.get(&code_hash) {
+            return Ok(Some(code.clone()));
+        }
+
+        self.reader
+            .read_account_code(address, incarnation, code_hash)
+            .await
+    }
+
+    async fn read_account_code_size(
+        &self,
+        address: common::Address,
+        incarnation: common::Incarnation,
+        code_hash: common::Hash,
+    ) -> anyhow::Result<usize> {
+        Ok(self
+            .read_account_code(address, incarnation, code_hash)
+            .await?
+            .map(|code| code.len())
+            .unwrap_or(0))
+    }
+
+    async fn read_account_incarnation(
+        &self,
+        address: common::Address,
+    ) -> anyhow::Result<Option<u64>> {
+        if let Some(incarnation) = self.incarnations.get(&address) {
+            return Ok(Some(*incarnation));
+        }
+
+        self.reader.read_account_incarnation(address).await
+    }
+}
+
+#[async_trait]
+impl<'db: 'tx, 'tx, Tx: MutableTransaction<'db>> StateWriter for Buffer<'db, 'tx, Tx> {
+    async fn update_account_data(
+        &mut self,
+        address: common::Address,
+        original: &Account,
+        account: &Account,
+    ) -> anyhow::Result<()> {
+        self.changeset()?
+            .update_account_data(address, original, account)
+            .await?;
+
+        self.size += common::ADDRESS_LENGTH + account.encoding_length_for_storage();
+        self.accounts.insert(address, Some(account.clone()));
+
+        Ok(())
+    }
+
+    async fn update_account_code(
+        &mut self,
+        address: common::Address,
+        incarnation: common::Incarnation,
+        code_hash: common::Hash,
+        code: &[u8],
+    ) -> anyhow::Result<()> {
+        self.changeset()?
+            .update_account_code(address, incarnation, code_hash, code)
+            .await?;
+
+        self.size += common::HASH_LENGTH + code.len();
+        self.code.insert(code_hash, code.to_vec().into());
+        self.code_hashes.insert(
+            dbutils::plain_generate_storage_prefix(address, incarnation),
+            code_hash,
+        );
+
+        Ok(())
+    }
+
+    async fn delete_account(
+        &mut self,
+        address: common::Address,
+        original: &Account,
+    ) -> anyhow::Result<()> {
+        self.changeset()?.delete_account(address, original).await?;
+
+        self.size += common::ADDRESS_LENGTH + original.encoding_length_for_storage();
+        self.accounts.insert(address, None);
+        if original.incarnation > 0 {
+            self.incarnations.insert(address, original.incarnation);
+        }
+
+        Ok(())
+    }
+
+    async fn write_account_storage(
+        &mut self,
+        address: common::Address,
+        incarnation: common::Incarnation,
+        key: common::Hash,
+        original: common::Value,
+        value: common::Value,
+    ) -> anyhow::Result<()> {
+        self.changeset()?
+            .write_account_storage(address, incarnation, key, original, value)
+            .await?;
+
+        if original == value {
+            return Ok(());
+        }
+
+        self.size += 2 * dbutils::PLAIN_COMPOSITE_STORAGE_KEY_LENGTH;
+        self.storage.insert(
+            dbutils::plain_generate_composite_storage_key(address, incarnation, key),
+            value,
+        );
+
+        Ok(())
+    }
+
+    async fn create_contract(&mut self, address: common::Address) -> anyhow::Result<()> {
+        self.changeset()?.create_contract(address).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        changeset::{AccountHistory, HistoryKind, StorageHistory},
+        kv::traits::MutableKV,
+        new_mem_database, Cursor, Transaction,
+    };
+    use hex_literal::hex;
+    use std::collections::BTreeSet;
+
+    #[tokio::test]
+    async fn read_through_and_flush() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        let stored = common::Address::from_low_u64_be(1);
+        let contract = common::Address::from_low_u64_be(2);
+        let slot = common::Hash::from_low_u64_be(1);
+        let code = hex!("6001600055");
+        let code_hash = common::hash_data(&code);
+
+        let stored_account = Account {
+            balance: 5.into(),
+            ..Default::default()
+        };
+        let mut writer = PlainStateWriter::new(&tx, 1);
+        writer
+            .update_account_data(stored, &Account::default(), &stored_account)
+            .await
+            .unwrap();
+        writer.write_changesets().await.unwrap();
+
+        let mut buffer = Buffer::new(&tx);
+        assert!(buffer
+            .update_account_data(stored, &stored_account, &Account::default())
+            .await
+            .is_err());
+
+        let contract_account = Account {
+            nonce: 1,
+            code_hash: Some(code_hash),
+            incarnation: 1,
+            ..Default::default()
+        };
+        buffer.begin_block(2);
+        buffer
+            .update_account_data(contract, &Account::default(), &contract_account)
+            .await
+            .unwrap();
+        buffer
+            .update_account_code(contract, 1, code_hash, &code)
+            .await
+            .unwrap();
+        buffer
+            .write_account_storage(contract, 1, slot, 0.into(), 7.into())
+            .await
+            .unwrap();
+
+        buffer.begin_block(3);
+        buffer
+            .delete_account(stored, &stored_account)
+            .await
+            .unwrap();
+        buffer
+            .write_account_storage(contract, 1, slot, 7.into(), 8.into())
+            .await
+            .unwrap();
+
+        // Buffered values shadow the database, which is not written to yet
+        assert_eq!(buffer.read_account_data(stored).await.unwrap(), None);
+        assert_eq!(
+            buffer.read_account_data(contract).await.unwrap(),
+            Some(contract_account.clone())
+        );
+        assert_eq!(
+            buffer
+                .read_account_storage(contract, 1, slot)
+                .await
+                .unwrap()
+                .map(|v| common::Value::from_big_endian(&v)),
+            Some(8.into())
+        );
+        assert_eq!(
+            buffer
+                .read_account_code(contract, 1, common::Hash::zero())
+                .await
+                .unwrap()
+                .unwrap()
+                .to_vec(),
+            code
+        );
+        assert_eq!(buffer.read_account_incarnation(stored).await.unwrap(), None);
+        assert!(buffer.size() > 0);
+
+        let reader = PlainStateReader::new(&tx);
+        assert_eq!(
+            reader.read_account_data(stored).await.unwrap(),
+            Some(stored_account.clone())
+        );
+        assert_eq!(reader.read_account_data(contract).await.unwrap(), None);
+
+        buffer.flush().await.unwrap();
+        assert_eq!(buffer.size(), 0);
+
+        let reader = PlainStateReader::new(&tx);
+        assert_eq!(reader.read_account_data(stored).await.unwrap(), None);
+        assert_eq!(
+            reader.read_account_data(contract).await.unwrap(),
+            Some(contract_account)
+        );
+        assert_eq!(
+            reader
+                .read_account_storage(contract, 1, slot)
+                .await
+                .unwrap()
+                .map(|v| common::Value::from_big_endian(&v)),
+            Some(8.into())
+        );
+        assert_eq!(
+            reader
+                .read_account_code(contract, 1, code_hash)
+                .await
+                .unwrap()
+                .unwrap()
+                .to_vec(),
+            code
+        );
+
+        // Every block got its own changeset, after the ones already in the database
+        let mut account_changes = BTreeSet::new();
+        let mut cursor = tx.cursor(&tables::AccountChangeSet).await.unwrap();
+        let mut entry = cursor.first().await.unwrap();
+        while let Some((k, v)) = entry {
+            let (block, change) = AccountHistory::decode(k, v);
+            account_changes.insert((block, change.key));
+            entry = cursor.next().await.unwrap();
+        }
+        assert_eq!(
+            account_changes,
+            vec![(1, stored), (2, contract), (3, stored)]
+                .into_iter()
+                .collect()
+        );
+
+        let mut storage_changes = vec![];
+        let mut cursor = tx.cursor(&tables::StorageChangeSet).await.unwrap();
+        let mut entry = cursor.first().await.unwrap();
+        while let Some((k, v)) = entry {
+            let (block, change) = StorageHistory::decode(k, v);
+            storage_changes.push((block, common::Value::from_big_endian(&change.value)));
+            entry = cursor.next().await.unwrap();
+        }
+        assert_eq!(storage_changes, vec![(2, 0.into()), (3, 7.into())]);
+    }
+}
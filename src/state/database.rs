@@ -1,18 +1,19 @@
 use crate::{
-    bitmapdb,
     changeset::{AccountHistory, Change, HistoryKind, StorageHistory},
     common, dbutils,
     kv::tables,
     models::Account,
     ChangeSet, MutableCursor, MutableCursorDupSort, MutableTransaction,
 };
-use anyhow::Context;
 use arrayref::array_ref;
 use async_trait::async_trait;
 use bytes::Bytes;
+use lru::LruCache;
+use parking_lot::Mutex;
 use std::{
     collections::{HashMap, HashSet},
     marker::PhantomData,
+    sync::Arc,
 };
 
 #[async_trait]
@@ -76,7 +77,6 @@ pub trait StateWriter {
 #[async_trait]
 pub trait WriterWithChangesets: StateWriter {
     async fn write_changesets(&mut self) -> anyhow::Result<()>;
-    async fn write_history(&mut self) -> anyhow::Result<()>;
 }
 
 #[derive(Clone, Default, Debug)]
@@ -128,10 +128,6 @@ impl WriterWithChangesets for Noop {
     async fn write_changesets(&mut self) -> anyhow::Result<()> {
         Ok(())
     }
-
-    async fn write_history(&mut self) -> anyhow::Result<()> {
-        Ok(())
-    }
 }
 
 pub struct ChangeSetWriter<'db: 'tx, 'tx, Tx: MutableTransaction<'db>> {
@@ -236,32 +232,6 @@ impl<'db: 'tx, 'tx, Tx: MutableTransaction<'db>> StateWriter for ChangeSetWriter
     }
 }
 
-async fn write_index<'db: 'tx, 'tx, K: HistoryKind, Tx: MutableTransaction<'db>>(
-    tx: &'tx Tx,
-    block_number: u64,
-    changes: ChangeSet<'tx, K>,
-) -> anyhow::Result<()> {
-    let mut buf = vec![];
-    for change in changes {
-        let k = dbutils::composite_key_without_incarnation::<K>(&change.key);
-
-        let mut index = bitmapdb::get(tx, &K::IndexTable::default(), &k, 0, u64::MAX)
-            .await
-            .context("failed to find chunk")?;
-
-        index.push(block_number);
-
-        for (chunk_key, chunk) in bitmapdb::Chunks::new(index, bitmapdb::CHUNK_LIMIT).with_keys(&k)
-        {
-            buf.clear();
-            chunk.serialize_into(&mut buf)?;
-            tx.set(&K::IndexTable::default(), &chunk_key, &buf).await?;
-        }
-    }
-
-    Ok(())
-}
-
 #[async_trait]
 impl<'db: 'tx, 'tx, Tx: MutableTransaction<'db>> WriterWithChangesets
     for ChangeSetWriter<'db, 'tx, Tx>
@@ -315,15 +285,6 @@ impl<'db: 'tx, 'tx, Tx: MutableTransaction<'db>> WriterWithChangesets
 
         Ok(())
     }
-
-    async fn write_history(&mut self) -> anyhow::Result<()> {
-        write_index::<AccountHistory, _>(self.tx, self.block_number, self.get_account_changes())
-            .await?;
-        write_index::<StorageHistory, _>(self.tx, self.block_number, self.get_storage_changes())
-            .await?;
-
-        Ok(())
-    }
 }
 
 impl Account {
@@ -469,24 +430,84 @@ impl<'db: 'tx, 'tx, Tx: MutableTransaction<'db>> WriterWithChangesets
     async fn write_changesets(&mut self) -> anyhow::Result<()> {
         self.csw.write_changesets().await
     }
+}
+
+/// Number of contracts whose bytecode is kept by default `CodeCache`.
+pub const DEFAULT_CODE_CACHE_SIZE: usize = 4096;
+
+/// LRU cache of contract bytecode by code hash, can be shared between readers.
+#[derive(Clone)]
+pub struct CodeCache(Arc<Mutex<LruCache<common::Hash, Bytes<'static>>>>);
+
+impl CodeCache {
+    pub fn new(capacity: usize) -> Self {
+        Self(Arc::new(Mutex::new(LruCache::new(capacity))))
+    }
+
+    pub fn get(&self, code_hash: common::Hash) -> Option<Bytes<'static>> {
+        self.0.lock().get(&code_hash).cloned()
+    }
 
-    async fn write_history(&mut self) -> anyhow::Result<()> {
-        self.csw.write_history().await
+    pub fn insert(&self, code_hash: common::Hash, code: Bytes<'static>) {
+        self.0.lock().put(code_hash, code);
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for CodeCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CODE_CACHE_SIZE)
+    }
+}
+
+impl std::fmt::Debug for CodeCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CodeCache")
+            .field("len", &self.len())
+            .finish()
     }
 }
 
 pub struct PlainStateReader<'db: 'tx, 'tx, Tx: MutableTransaction<'db>> {
     tx: &'tx Tx,
+    code_cache: CodeCache,
     _marker: PhantomData<&'db ()>,
 }
 
 impl<'db: 'tx, 'tx, Tx: MutableTransaction<'db>> PlainStateReader<'db, 'tx, Tx> {
     pub fn new(tx: &'tx Tx) -> Self {
+        Self::with_code_cache(tx, CodeCache::default())
+    }
+
+    pub fn with_code_cache(tx: &'tx Tx, code_cache: CodeCache) -> Self {
         Self {
             tx,
+            code_cache,
             _marker: PhantomData,
         }
     }
+
+    async fn read_code_hash(
+        &self,
+        address: common::Address,
+        incarnation: common::Incarnation,
+    ) -> anyhow::Result<Option<common::Hash>> {
+        Ok(self
+            .tx
+            .get(
+                &tables::PlainCodeHash,
+                &dbutils::plain_generate_storage_prefix(address, incarnation),
+            )
+            .await?
+            .map(|code_hash| common::Hash::from_slice(&code_hash)))
+    }
 }
 
 #[async_trait]
@@ -512,13 +533,37 @@ impl<'db: 'tx, 'tx, Tx: MutableTransaction<'db>> StateReader<'tx>
         self.tx.get(&tables::PlainState, &composite_key).await
     }
 
+    /// Zero `code_hash` is resolved through the code hash of account's `incarnation`.
     async fn read_account_code(
         &self,
-        _: common::Address,
-        _: common::Incarnation,
+        address: common::Address,
+        incarnation: common::Incarnation,
         code_hash: common::Hash,
     ) -> anyhow::Result<Option<Bytes<'tx>>> {
-        self.tx.get(&tables::PlainState, code_hash.as_bytes()).await
+        let code_hash = if code_hash.is_zero() {
+            match self.read_code_hash(address, incarnation).await? {
+                Some(code_hash) => code_hash,
+                None => return Ok(None),
+            }
+        } else {
+            code_hash
+        };
+
+        if code_hash == common::EMPTY_HASH {
+            return Ok(None);
+        }
+
+        if let Some(code) = self.code_cache.get(code_hash) {
+            return Ok(Some(code));
+        }
+
+        let code = match self.tx.get(&tables::Code, code_hash.as_bytes()).await? {
+            Some(code) => Bytes::from(code.to_vec()),
+            None => return Ok(None),
+        };
+        self.code_cache.insert(code_hash, code.clone());
+
+        Ok(Some(code))
     }
 
     async fn read_account_code_size(
@@ -545,3 +590,92 @@ impl<'db: 'tx, 'tx, Tx: MutableTransaction<'db>> StateReader<'tx>
             .map(|b| u64::from_be_bytes(*array_ref!(&*b, 0, 8))))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{kv::traits::MutableKV, new_mem_database};
+    use hex_literal::hex;
+
+    #[tokio::test]
+    async fn account_code() {
+        let db = new_mem_database().unwrap();
+        let tx = db.begin_mutable().await.unwrap();
+
+        let address = common::Address::from_low_u64_be(0x1234);
+        let code = hex!("6001600055");
+        let code_hash = common::hash_data(&code);
+
+        PlainStateWriter::new(&tx, 1)
+            .update_account_code(address, 1, code_hash, &code)
+            .await
+            .unwrap();
+
+        let cache = CodeCache::new(16);
+        let reader = PlainStateReader::with_code_cache(&tx, cache.clone());
+        assert_eq!(
+            reader
+                .read_account_code(address, 1, code_hash)
+                .await
+                .unwrap()
+                .unwrap()
+                .to_vec(),
+            code
+        );
+        assert_eq!(
+            reader
+                .read_account_code_size(address, 1, code_hash)
+                .await
+                .unwrap(),
+            code.len()
+        );
+
+        // Zero hash is resolved through code hash of the incarnation
+        assert_eq!(
+            reader
+                .read_account_code(address, 1, common::Hash::zero())
+                .await
+                .unwrap()
+                .unwrap()
+                .to_vec(),
+            code
+        );
+        assert_eq!(
+            reader
+                .read_account_code(address, 2, common::Hash::zero())
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            reader
+                .read_account_code(address, 1, common::EMPTY_HASH)
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(cache.len(), 1);
+
+        // Cached code is served without reading the database
+        tx.mutable_cursor(&tables::Code)
+            .await
+            .unwrap()
+            .delete(code_hash.as_bytes(), &[])
+            .await
+            .unwrap();
+        assert_eq!(
+            PlainStateReader::with_code_cache(&tx, cache)
+                .read_account_code_size(address, 1, code_hash)
+                .await
+                .unwrap(),
+            code.len()
+        );
+        assert_eq!(
+            PlainStateReader::new(&tx)
+                .read_account_code(address, 1, code_hash)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
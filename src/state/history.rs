@@ -1,8 +1,43 @@
-use crate::{changeset::*, common::*, dbutils, dbutils::*, kv::*, models::*, Cursor, Transaction};
+use crate::{
+    changeset::*,
+    common::*,
+    dbutils,
+    dbutils::*,
+    kv::*,
+    models::*,
+    stagedsync::stages::{ACCOUNT_HISTORY_INDEX, STORAGE_HISTORY_INDEX},
+    Cursor, StageId, Transaction,
+};
 use arrayref::array_ref;
 use bytes::Bytes;
 use ethereum_types::H256;
 use roaring::RoaringTreemap;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum HistoryError {
+    #[error("History of block {block} has been pruned, earliest available block is {available}")]
+    Pruned { block: u64, available: u64 },
+}
+
+/// Fails if history of `block_number` has been pruned by the stage maintaining its index.
+async fn ensure_not_pruned<'db, Tx: Transaction<'db>>(
+    tx: &Tx,
+    stage: StageId,
+    block_number: u64,
+) -> anyhow::Result<()> {
+    if let Some(available) = stage.get_prune_progress(tx).await? {
+        if block_number < available {
+            return Err(HistoryError::Pruned {
+                block: block_number,
+                available,
+            }
+            .into());
+        }
+    }
+
+    Ok(())
+}
 
 pub async fn get_account_data_as_of<'db: 'tx, 'tx, Tx: Transaction<'db>>(
     tx: &'tx Tx,
@@ -36,6 +71,8 @@ pub async fn find_data_by_history<'db: 'tx, 'tx, Tx: Transaction<'db>>(
     address: Address,
     block_number: u64,
 ) -> anyhow::Result<Option<Bytes<'tx>>> {
+    ensure_not_pruned(tx, ACCOUNT_HISTORY_INDEX, block_number).await?;
+
     let mut ch = tx.cursor(&tables::AccountHistory).await?;
     if let Some((k, v)) = ch
         .seek(&AccountHistory::index_chunk_key(address, block_number))
@@ -94,6 +131,8 @@ pub async fn find_storage_by_history<'db: 'tx, 'tx, Tx: Transaction<'db>>(
     key: PlainCompositeStorageKey,
     timestamp: u64,
 ) -> anyhow::Result<Option<Bytes<'tx>>> {
+    ensure_not_pruned(tx, STORAGE_HISTORY_INDEX, timestamp).await?;
+
     let mut ch = tx.cursor(&tables::StorageHistory).await?;
     if let Some((k, v)) = ch
         .seek(&StorageHistory::index_chunk_key(key, timestamp))
@@ -138,6 +177,7 @@ mod tests {
     use crate::{
         bitmapdb, crypto,
         kv::traits::MutableKV,
+        stagedsync::test_util::index_history,
         state::database::{
             PlainStateReader, PlainStateWriter, StateReader, StateWriter, WriterWithChangesets,
         },
@@ -161,7 +201,7 @@ mod tests {
     #[tokio::test]
     async fn mutation_delete_timestamp() {
         let db = new_mem_database().unwrap();
-        let tx = db.begin_mutable().await.unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
 
         let mut block_writer = PlainStateWriter::new(&tx, 1);
 
@@ -179,7 +219,7 @@ mod tests {
         }
 
         block_writer.write_changesets().await.unwrap();
-        block_writer.write_history().await.unwrap();
+        index_history(&mut tx, 1).await;
 
         let mut cursor = tx.cursor(&tables::AccountChangeSet).await.unwrap();
         let s = txdb::walk(&mut cursor, &[], 0);
@@ -230,7 +270,7 @@ mod tests {
     #[tokio::test]
     async fn mutation_commit() {
         let db = new_mem_database().unwrap();
-        let tx = db.begin_mutable().await.unwrap();
+        let mut tx = db.begin_mutable().await.unwrap();
 
         let num_of_accounts = 5;
         let num_of_state_keys = 5;
@@ -242,6 +282,7 @@ mod tests {
                 num_of_state_keys,
             )
             .await;
+        index_history(&mut tx, 2).await;
 
         let mut plain_state = tx.cursor(&tables::PlainState).await.unwrap();
 
@@ -424,7 +465,6 @@ mod tests {
                 .unwrap();
         }
         block_writer.write_changesets().await.unwrap();
-        block_writer.write_history().await.unwrap();
 
         (
             addrs,
@@ -1,4 +1,5 @@
+mod buffer;
 mod database;
 mod history;
 
-pub use self::history::*;
+pub use self::{buffer::*, database::*, history::*};
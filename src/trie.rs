@@ -0,0 +1,480 @@
+use crate::common;
+use ethereum_types::H256;
+use hex_literal::hex;
+use rlp::RlpStream;
+
+/// Root hash of an empty trie.
+pub const EMPTY_ROOT: H256 = H256(hex!(
+    "56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421"
+));
+
+/// Branch node of a trie, as stored in `TrieAccount` and `TrieStorage`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BranchNode {
+    /// Bit `i` is set if the node has child with nibble `i`.
+    pub state_mask: u16,
+    /// Bit `i` is set if child with nibble `i` is referenced by hash rather than embedded.
+    pub hash_mask: u16,
+    /// Hashes of children in `hash_mask`, in nibble order.
+    pub hashes: Vec<H256>,
+}
+
+impl BranchNode {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(4 + self.hashes.len() * common::HASH_LENGTH);
+        out.extend_from_slice(&self.state_mask.to_be_bytes());
+        out.extend_from_slice(&self.hash_mask.to_be_bytes());
+        for hash in &self.hashes {
+            out.extend_from_slice(hash.as_bytes());
+        }
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() < 4 || (data.len() - 4) % common::HASH_LENGTH != 0 {
+            anyhow::bail!("invalid branch node length: {}", data.len());
+        }
+
+        Ok(Self {
+            state_mask: u16::from_be_bytes([data[0], data[1]]),
+            hash_mask: u16::from_be_bytes([data[2], data[3]]),
+            hashes: data[4..]
+                .chunks(common::HASH_LENGTH)
+                .map(H256::from_slice)
+                .collect(),
+        })
+    }
+
+    /// RLP of the node, if all of its children are referenced by hash and thus known from the node alone.
+    pub fn rlp(&self) -> Option<Vec<u8>> {
+        if self.state_mask != self.hash_mask {
+            return None;
+        }
+
+        let mut hashes = self.hashes.iter();
+        let mut s = RlpStream::new_list(17);
+        for nibble in 0..16 {
+            if self.hash_mask & (1 << nibble) != 0 {
+                s.append(hashes.next()?);
+            } else {
+                s.append_empty_data();
+            }
+        }
+        // Keys of the tries have fixed length, so branches never hold values
+        s.append_empty_data();
+        Some(s.out().to_vec())
+    }
+}
+
+/// Node found at the end of the path of an item passed to `root_hash`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Node {
+    /// Leaf with the value, the path being its full key.
+    Leaf(Vec<u8>),
+    /// RLP of a branch node kept from an earlier build, standing for all the leaves under the path.
+    Branch(Vec<u8>),
+}
+
+/// Splits bytes into nibbles, high nibble first.
+pub fn unpack_nibbles(data: &[u8]) -> Vec<u8> {
+    data.iter().flat_map(|b| [b >> 4, b & 0x0f]).collect()
+}
+
+/// Joins nibbles into bytes, high nibble first. Odd number of nibbles leaves the low half of the last byte zero.
+pub fn pack_nibbles(nibbles: &[u8]) -> Vec<u8> {
+    nibbles
+        .chunks(2)
+        .map(|pair| pair[0] << 4 | pair.get(1).copied().unwrap_or(0))
+        .collect()
+}
+
+fn hex_prefix(nibbles: &[u8], leaf: bool) -> Vec<u8> {
+    let odd = nibbles.len() % 2 == 1;
+    let flag = if leaf { 0x20 } else { 0x00 } | if odd { 0x10 } else { 0x00 };
+
+    let mut out = Vec::with_capacity(nibbles.len() / 2 + 1);
+    let rest = if odd {
+        out.push(flag | nibbles[0]);
+        &nibbles[1..]
+    } else {
+        out.push(flag);
+        nibbles
+    };
+    for pair in rest.chunks(2) {
+        out.push(pair[0] << 4 | pair[1]);
+    }
+    out
+}
+
+/// Reference to a node from its parent: the node itself if it is short enough, its hash otherwise.
+enum NodeRef {
+    Embedded(Vec<u8>),
+    Hash(H256),
+}
+
+impl NodeRef {
+    fn new(rlp: Vec<u8>) -> Self {
+        if rlp.len() < common::HASH_LENGTH {
+            Self::Embedded(rlp)
+        } else {
+            Self::Hash(common::hash_data(&rlp))
+        }
+    }
+
+    fn append_to(&self, s: &mut RlpStream) {
+        match self {
+            Self::Embedded(rlp) => {
+                s.append_raw(rlp, 1);
+            }
+            Self::Hash(hash) => {
+                s.append(hash);
+            }
+        }
+    }
+}
+
+fn extension(path: &[u8], child: Vec<u8>) -> Vec<u8> {
+    let mut s = RlpStream::new_list(2);
+    s.append(&hex_prefix(path, false));
+    NodeRef::new(child).append_to(&mut s);
+    s.out().to_vec()
+}
+
+fn node_rlp(path: &[u8], node: Node, depth: usize) -> Vec<u8> {
+    match node {
+        Node::Leaf(value) => {
+            let mut s = RlpStream::new_list(2);
+            s.append(&hex_prefix(&path[depth..], true));
+            s.append(&value);
+            s.out().to_vec()
+        }
+        Node::Branch(rlp) if path.len() == depth => rlp,
+        Node::Branch(rlp) => extension(&path[depth..], rlp),
+    }
+}
+
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b.iter()).take_while(|(a, b)| a == b).count()
+}
+
+/// Branch node under construction.
+struct Frame {
+    prefix: Vec<u8>,
+    children: [Option<NodeRef>; 16],
+    value: Option<Vec<u8>>,
+}
+
+impl Frame {
+    fn new(prefix: &[u8]) -> Self {
+        Self {
+            prefix: prefix.to_vec(),
+            children: Default::default(),
+            value: None,
+        }
+    }
+}
+
+/// Builds a trie from items added in order of their nibble paths, keeping in memory
+/// only the branch nodes on the path of the last item.
+///
+/// Kept branches given as `Node::Branch` cover all the leaves under their paths,
+/// so no item may lie under another one.
+pub struct HashBuilder<F> {
+    on_branch: F,
+    /// Open branch nodes, deepest last.
+    stack: Vec<Frame>,
+    /// Last added item, placed once the next one shows where it branches off.
+    pending: Option<(Vec<u8>, Node)>,
+    /// Length of the common prefix of the pending item and the one added before it.
+    pending_depth: Option<usize>,
+    root: Option<Vec<u8>>,
+}
+
+impl<F> HashBuilder<F>
+where
+    F: FnMut(&[u8], BranchNode),
+{
+    /// `on_branch` is called with the path and the contents of every branch node of the trie,
+    /// except the kept ones given as items.
+    pub fn new(on_branch: F) -> Self {
+        Self {
+            on_branch,
+            stack: vec![],
+            pending: None,
+            pending_depth: None,
+            root: None,
+        }
+    }
+
+    /// Adds an item, whose path must be greater than the paths of all the items added before.
+    pub fn add(&mut self, path: Vec<u8>, node: Node) {
+        if let Some((pending_path, pending_node)) = self.pending.take() {
+            let depth = common_prefix(&pending_path, &path);
+            self.place(pending_path, pending_node, Some(depth));
+            self.pending_depth = Some(depth);
+        }
+        self.pending = Some((path, node));
+    }
+
+    /// Root hash of the trie with all the added items.
+    pub fn root(mut self) -> H256 {
+        match self.pending.take() {
+            Some((path, node)) => {
+                self.place(path, node, None);
+                common::hash_data(self.root.as_ref().unwrap())
+            }
+            None => EMPTY_ROOT,
+        }
+    }
+
+    /// Attaches the item to its branch, then closes the branches the next item,
+    /// sharing `next_depth` nibbles with this one, does not go through.
+    fn place(&mut self, path: Vec<u8>, node: Node, next_depth: Option<usize>) {
+        let depth = match std::cmp::max(self.pending_depth, next_depth) {
+            Some(depth) => depth,
+            None => {
+                // The only item of the trie
+                self.root = Some(node_rlp(&path, node, 0));
+                return;
+            }
+        };
+
+        if self.stack.last().map(|frame| frame.prefix.len()) != Some(depth) {
+            self.stack.push(Frame::new(&path[..depth]));
+        }
+        let frame = self.stack.last_mut().unwrap();
+        match node {
+            Node::Leaf(value) if path.len() == depth => frame.value = Some(value),
+            node => {
+                frame.children[path[depth] as usize] =
+                    Some(NodeRef::new(node_rlp(&path, node, depth + 1)))
+            }
+        }
+
+        self.close(next_depth);
+    }
+
+    fn close(&mut self, next_depth: Option<usize>) {
+        while let Some(depth) = self.stack.last().map(|frame| frame.prefix.len()) {
+            if matches!(next_depth, Some(next_depth) if depth <= next_depth) {
+                return;
+            }
+
+            let frame = self.stack.pop().unwrap();
+            let rlp = self.branch_rlp(&frame);
+
+            let parent_depth = std::cmp::max(
+                self.stack.last().map(|frame| frame.prefix.len()),
+                next_depth,
+            );
+            match parent_depth {
+                Some(parent_depth) => {
+                    if self.stack.last().map(|frame| frame.prefix.len()) != Some(parent_depth) {
+                        self.stack.push(Frame::new(&frame.prefix[..parent_depth]));
+                    }
+                    let child = if depth > parent_depth + 1 {
+                        extension(&frame.prefix[parent_depth + 1..], rlp)
+                    } else {
+                        rlp
+                    };
+                    self.stack.last_mut().unwrap().children[frame.prefix[parent_depth] as usize] =
+                        Some(NodeRef::new(child));
+                }
+                None => {
+                    self.root = Some(if depth == 0 {
+                        rlp
+                    } else {
+                        extension(&frame.prefix, rlp)
+                    });
+                }
+            }
+        }
+    }
+
+    fn branch_rlp(&mut self, frame: &Frame) -> Vec<u8> {
+        let mut node = BranchNode {
+            state_mask: 0,
+            hash_mask: 0,
+            hashes: vec![],
+        };
+        let mut s = RlpStream::new_list(17);
+        for (nibble, child) in frame.children.iter().enumerate() {
+            match child {
+                Some(child) => {
+                    node.state_mask |= 1 << nibble;
+                    if let NodeRef::Hash(hash) = child {
+                        node.hash_mask |= 1 << nibble;
+                        node.hashes.push(*hash);
+                    }
+                    child.append_to(&mut s);
+                }
+                None => {
+                    s.append_empty_data();
+                }
+            }
+        }
+        match &frame.value {
+            Some(value) => {
+                s.append(value);
+            }
+            None => {
+                s.append_empty_data();
+            }
+        }
+
+        (self.on_branch)(&frame.prefix, node);
+
+        s.out().to_vec()
+    }
+}
+
+/// Computes root hash of the trie with `items` keyed by nibble paths, which must be sorted and unique.
+/// See `HashBuilder` for `on_branch`.
+pub fn root_hash<I, F>(items: I, on_branch: F) -> H256
+where
+    I: IntoIterator<Item = (Vec<u8>, Node)>,
+    F: FnMut(&[u8], BranchNode),
+{
+    let mut builder = HashBuilder::new(on_branch);
+    for (path, node) in items {
+        builder.add(path, node);
+    }
+    builder.root()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethereum::util::ordered_trie_root;
+
+    fn items<'a>(entries: impl IntoIterator<Item = (&'a [u8], &'a [u8])>) -> Vec<(Vec<u8>, Node)> {
+        let mut items = entries
+            .into_iter()
+            .map(|(k, v)| (unpack_nibbles(k), Node::Leaf(v.to_vec())))
+            .collect::<Vec<_>>();
+        items.sort_by(|(a, _), (b, _)| a.cmp(b));
+        items
+    }
+
+    #[test]
+    fn empty_trie() {
+        assert_eq!(root_hash(vec![], |_, _| {}), EMPTY_ROOT);
+        assert_eq!(common::hash_data(&rlp::NULL_RLP), EMPTY_ROOT);
+    }
+
+    #[test]
+    fn dogs() {
+        let items = items(vec![
+            (&b"do"[..], &b"verb"[..]),
+            (&b"dog"[..], &b"puppy"[..]),
+            (&b"doge"[..], &b"coin"[..]),
+            (&b"horse"[..], &b"stallion"[..]),
+        ]);
+
+        assert_eq!(
+            root_hash(items, |_, _| {}),
+            H256(hex!(
+                "5991bb8c6514148a29db676a14ac506cd2cd5775ace63c30a4fe457715e9ac84"
+            ))
+        );
+    }
+
+    #[test]
+    fn matches_ordered_trie_root() {
+        for len in [1, 2, 16, 17, 100, 1000] {
+            let values = (0..len)
+                .map(|i| {
+                    let mut value = vec![0; i % 40 + 1];
+                    value[0] = i as u8;
+                    value
+                })
+                .collect::<Vec<_>>();
+            let keys = (0..len).map(|i: usize| rlp::encode(&i)).collect::<Vec<_>>();
+
+            let items = items(
+                keys.iter()
+                    .map(|k| &k[..])
+                    .zip(values.iter().map(|v| &v[..])),
+            );
+
+            assert_eq!(
+                root_hash(items, |_, _| {}),
+                ordered_trie_root(values.iter().cloned())
+            );
+        }
+    }
+
+    #[test]
+    fn branch_nodes() {
+        let mut items = (0..=255_u8)
+            .map(|b| {
+                (
+                    unpack_nibbles(common::hash_data(&[b]).as_bytes()),
+                    Node::Leaf(vec![b; 40]),
+                )
+            })
+            .collect::<Vec<_>>();
+        items.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut branches = vec![];
+        let root_hash = root_hash(items, |prefix, node| branches.push((prefix.to_vec(), node)));
+
+        // Root is a full branch with all children large enough to be hashed
+        let (prefix, root) = branches.last().unwrap();
+        assert!(prefix.is_empty());
+        assert_eq!(root.state_mask, 0xffff);
+        assert_eq!(root.hash_mask, 0xffff);
+        assert_eq!(root.hashes.len(), 16);
+        assert_eq!(BranchNode::decode(&root.encode()).unwrap(), *root);
+        assert_eq!(common::hash_data(&root.rlp().unwrap()), root_hash);
+
+        for (prefix, node) in &branches {
+            assert_eq!(node.hashes.len(), node.hash_mask.count_ones() as usize);
+            assert_eq!(node.hash_mask & !node.state_mask, 0);
+            assert!(prefix.len() < 64);
+        }
+    }
+
+    #[test]
+    fn kept_branches() {
+        // Three leaves under a long common prefix, and one more aside
+        let mut leaves = (0..3_u8)
+            .map(|i| {
+                let mut key = vec![1; 64];
+                key[10] = i;
+                (key, Node::Leaf(vec![i; 40]))
+            })
+            .collect::<Vec<_>>();
+        leaves.push((vec![5; 64], Node::Leaf(vec![5; 40])));
+
+        let mut branches = vec![];
+        let root = root_hash(leaves.clone(), |prefix, node| {
+            branches.push((prefix.to_vec(), node))
+        });
+
+        let (prefix, node) = &branches[0];
+        assert_eq!(prefix, &vec![1; 10]);
+        let kept = (prefix.clone(), Node::Branch(node.rlp().unwrap()));
+
+        let mut calls = 0;
+        assert_eq!(
+            root_hash(vec![kept.clone(), leaves[3].clone()], |_, _| calls += 1),
+            root
+        );
+        // Only the root is built again
+        assert_eq!(calls, 1);
+
+        // Kept branch deeper than its parent is reached through an extension
+        assert_eq!(
+            root_hash(vec![kept], |_, _| {}),
+            root_hash(leaves[..3].to_vec(), |_, _| {})
+        );
+    }
+
+    #[test]
+    fn nibbles() {
+        assert_eq!(unpack_nibbles(&[0x12, 0xab]), vec![1, 2, 0xa, 0xb]);
+        assert_eq!(pack_nibbles(&[1, 2, 0xa, 0xb]), vec![0x12, 0xab]);
+        assert_eq!(pack_nibbles(&[1, 2, 0xa]), vec![0x12, 0xa0]);
+    }
+}